@@ -0,0 +1,57 @@
+// Adaptive block sizing. Blocks are still read and compared whole, but a
+// differing one is narrowed down by comparing it in pieces of the grain,
+// and only the span from the first to the last differing piece is
+// written. The grain halves with every differing block down to the
+// minimum, so in a region full of small changes less of what's the same
+// is written along, and doubles with every matching one back up to the
+// block size. It stays a multiple of the words compared, so no piece cuts
+// one in two.
+
+use crate::compare::CompareFn;
+
+pub struct Adaptive {
+    min: usize,
+    max: usize,
+    unit: usize,
+    grain: usize,
+}
+
+impl Adaptive {
+    /// Pieces from `min` to `max`, both multiples of `unit`
+    pub fn new(min: usize, max: usize, unit: usize) -> Self {
+        let min = std::cmp::min(min, max);
+        Adaptive {
+            min,
+            max,
+            unit,
+            grain: max,
+        }
+    }
+
+    // A whole number of units, never below the minimum
+    fn fit(&self, grain: usize) -> usize {
+        std::cmp::max(grain / self.unit * self.unit, self.min)
+    }
+
+    /// A matching block, the grain grows
+    pub fn same(&mut self) {
+        self.grain = self.fit(std::cmp::min(self.grain.saturating_mul(2), self.max));
+    }
+
+    /// The start and end of the part of a differing block that has to be
+    /// written, the grain shrinks
+    pub fn narrow(&mut self, source: &[u8], target: &[u8], same: &CompareFn) -> (usize, usize) {
+        let grain = self.grain;
+        self.grain = self.fit(self.grain / 2);
+
+        let mut span: Option<(usize, usize)> = None;
+        for start in (0..source.len()).step_by(grain) {
+            let end = std::cmp::min(start + grain, source.len());
+            if !same(&source[start..end], &target[start..end]) {
+                span = Some((span.map_or(start, |(first, _)| first), end));
+            }
+        }
+        // Doesn't happen with a comparison of bytes or words in pieces
+        span.unwrap_or((0, source.len()))
+    }
+}
@@ -0,0 +1,127 @@
+use {
+    crate::{
+        config,
+        error::{self, Context, Error},
+        Args, Driver, Summary, SyncArgs,
+    },
+    clap::Parser,
+    std::{
+        ffi::OsString,
+        fs::File,
+        io::{self, BufRead, BufReader},
+    },
+};
+
+// A pairs file has one "source target" pair per line. Empty lines and
+// lines starting with # are skipped.
+fn load(path: &str) -> io::Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: expected a source and a target", path, n + 1),
+            ));
+        }
+        pairs.push((fields[0].to_string(), fields[1].to_string()));
+    }
+    Ok(pairs)
+}
+
+// The options of a pair as the command line would take them for a sync
+// of its own, with those of the configuration file
+fn sync_args(options: &[String], source: &str, target: &str) -> error::Result<SyncArgs> {
+    let mut argv: Vec<OsString> = vec!["ssdsync".into()];
+    argv.extend(options.iter().map(OsString::from));
+    argv.extend(["--".into(), source.into(), target.into()]);
+    let args = Args::try_parse_from(config::expand(argv)?)
+        .map_err(|e| Error::Usage(e.to_string().trim_end().to_string()))?;
+    if args.command.is_some() || args.sync.apply_sparse_image || !args.sync.more_targets.is_empty()
+    {
+        return Err(Error::Usage(
+            "Only syncs of a source onto a target can be run in a batch".to_string(),
+        ));
+    }
+    Ok(args.sync)
+}
+
+/// Threads the syncs of a batch run on, for their --jobs
+pub fn jobs(options: &[String]) -> usize {
+    sync_args(options, "-", "-").map_or(1, |args| args.jobs)
+}
+
+// Sync one pair the way the command line does, interrupted with the batch
+async fn run_pair(
+    source: &str,
+    target: &str,
+    options: &[String],
+    interrupt: &Driver,
+) -> error::Result<Summary> {
+    let args = sync_args(options, source, target)?;
+    let driver = Driver {
+        bars: !args.json,
+        cancel: interrupt.cancel.clone(),
+        status: true,
+        ..Driver::default()
+    };
+    crate::sync_one(&args, &driver).await
+}
+
+/// Sync every pair of a pairs file one after the other with the same
+/// options, carrying on past failed ones. Fails if any of them did, an
+/// interrupt stops the batch.
+pub async fn run(path: &str, options: &[String]) -> error::Result<()> {
+    let pairs = load(path).context("load", path)?;
+    // Options that are no good would fail every pair, they fail the batch
+    sync_args(options, "-", "-")?;
+    let interrupt = Driver::interruptible()?;
+
+    let mut results = Vec::new();
+    for (i, (source, target)) in pairs.iter().enumerate() {
+        println!("\n[{}/{}] {} -> {}", i + 1, pairs.len(), source, target);
+        match run_pair(source, target, options, &interrupt).await {
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(e) => {
+                eprintln!("{}", e);
+                results.push(None);
+            }
+            Ok(summary) => results.push(Some(summary)),
+        }
+    }
+
+    println!();
+    for ((source, target), summary) in pairs.iter().zip(results.iter()) {
+        match summary {
+            Some(summary) => println!(
+                "{:>6} {} -> {}, different: {}, written: {} bytes",
+                "ok", source, target, summary.different, summary.written
+            ),
+            None => println!("{:>6} {} -> {}", "FAILED", source, target),
+        }
+    }
+    let failed = results.iter().filter(|summary| summary.is_none()).count();
+    let total: u64 = results
+        .iter()
+        .flatten()
+        .map(|summary| summary.written)
+        .sum();
+    println!(
+        "Batch finished. Pairs: {}, failed: {}, written: {} bytes",
+        pairs.len(),
+        failed,
+        total
+    );
+    if failed > 0 {
+        return Err(Error::BatchFailed {
+            failed,
+            pairs: pairs.len(),
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use std::{fs, io};
+
+// Layout of a bitmap, all integers little endian:
+//
+//   header  MAGIC, u64 block size, u64 block count
+//   bits    one per block, set where it differed, the first block in the
+//           lowest bit of the first byte
+const MAGIC: &[u8; 8] = b"SSDSBMP1";
+
+/// Which blocks of a sync differed
+pub struct Bitmap {
+    block_size: u64,
+    blocks: u64,
+    bits: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn new(size: u64, block_size: usize) -> Self {
+        let blocks = size.div_ceil(block_size as u64);
+        Bitmap {
+            block_size: block_size as u64,
+            blocks,
+            bits: vec![0; blocks.div_ceil(8) as usize],
+        }
+    }
+
+    /// Mark the block at `pos` as differing
+    pub fn set(&mut self, pos: u64) {
+        let block = pos / self.block_size;
+        self.bits[(block / 8) as usize] |= 1 << (block % 8);
+    }
+
+    /// Write the bitmap. Returns the number of blocks set and in all.
+    pub fn write(&self, path: &str) -> io::Result<(u64, u64)> {
+        let mut data = Vec::with_capacity(24 + self.bits.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.block_size.to_le_bytes());
+        data.extend_from_slice(&self.blocks.to_le_bytes());
+        data.extend_from_slice(&self.bits);
+        fs::write(path, data)?;
+        let set = self.bits.iter().map(|b| b.count_ones() as u64).sum();
+        Ok((set, self.blocks))
+    }
+}
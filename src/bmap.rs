@@ -0,0 +1,120 @@
+use std::io;
+
+// A bmap is the XML file bmaptool creates next to an image, listing the
+// blocks of the image that hold data:
+//
+//   <bmap version="2.0">
+//       <ImageSize> 821752 </ImageSize>
+//       <BlockSize> 4096 </BlockSize>
+//       ...
+//       <BlockMap>
+//           <Range chksum="..."> 0-1 </Range>
+//           <Range chksum="..."> 3 </Range>
+//       </BlockMap>
+//   </bmap>
+//
+// Only the sizes and the ranges are used, the checksums aren't checked.
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The text of every <tag ...>...</tag> in the document
+fn elements<'a>(xml: &'a str, tag: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let open = format!("<{}", tag);
+    let close = format!("</{}>", tag);
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)?;
+        rest = &rest[start + open.len()..];
+        // Not <tag but a longer name starting with it
+        if !rest.starts_with(|c: char| c == '>' || c.is_whitespace()) {
+            continue;
+        }
+        let text = &rest[rest.find('>')? + 1..];
+        let end = text.find(&close)?;
+        rest = &text[end..];
+        return Some(text[..end].trim());
+    })
+}
+
+fn number(xml: &str, tag: &str) -> io::Result<u64> {
+    let text = elements(xml, tag)
+        .next()
+        .ok_or_else(|| invalid(format!("No {} in the bmap", tag)))?;
+    text.parse()
+        .map_err(|e| invalid(format!("Bad {} {:?} in the bmap: {}", tag, text, e)))
+}
+
+/// The parts of an image that hold data, as bmaptool maps them
+pub struct Bmap {
+    pub image_size: u64,
+    // Byte ranges, sorted and apart
+    ranges: Vec<(u64, u64)>,
+}
+
+impl Bmap {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let xml = std::fs::read_to_string(path)?;
+        if !xml.contains("<bmap") {
+            return Err(invalid(format!("{} is not a bmap", path)));
+        }
+        let image_size = number(&xml, "ImageSize")?;
+        let block_size = number(&xml, "BlockSize")?;
+        if block_size == 0 {
+            return Err(invalid("Block size 0 in the bmap".to_string()));
+        }
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for range in elements(&xml, "Range") {
+            let bad = |e: std::num::ParseIntError| {
+                invalid(format!("Bad range {:?} in the bmap: {}", range, e))
+            };
+            let (first, last): (u64, u64) = match range.split_once('-') {
+                Some((first, last)) => (
+                    first.trim().parse().map_err(bad)?,
+                    last.trim().parse().map_err(bad)?,
+                ),
+                None => {
+                    let block = range.parse().map_err(bad)?;
+                    (block, block)
+                }
+            };
+            let start = first.saturating_mul(block_size);
+            let end = std::cmp::min(
+                last.saturating_add(1).saturating_mul(block_size),
+                image_size,
+            );
+            if last < first || start >= image_size {
+                return Err(invalid(format!("Range {:?} is outside the image", range)));
+            }
+            match ranges.last_mut() {
+                Some(prev) if prev.1 > start => {
+                    return Err(invalid(format!("Range {:?} is out of order", range)))
+                }
+                Some(prev) if prev.1 == start => prev.1 = end,
+                _ => ranges.push((start, end)),
+            }
+        }
+        Ok(Bmap { image_size, ranges })
+    }
+
+    /// Whether any of the `len` bytes at `pos` hold data
+    pub fn mapped(&self, pos: u64, len: u64) -> bool {
+        let i = self.ranges.partition_point(|&(_, end)| end <= pos);
+        self.ranges
+            .get(i)
+            .is_some_and(|&(start, _)| start < pos + len)
+    }
+
+    /// Bytes that hold data
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges.iter().map(|(start, end)| end - start).sum()
+    }
+
+    /// Whether none of the `len` bytes at `pos` hold data, and the image
+    /// doesn't end before them
+    pub fn unmapped(&self, pos: u64, len: u64) -> bool {
+        pos + len <= self.image_size && !self.mapped(pos, len)
+    }
+}
@@ -0,0 +1,189 @@
+use {
+    crate::source::BlockSource,
+    async_trait::async_trait,
+    nix::{
+        errno::Errno,
+        fcntl::{posix_fadvise, PosixFadviseAdvice},
+        libc,
+    },
+    std::{
+        fs::File,
+        io,
+        os::unix::{fs::FileTypeExt, io::AsRawFd},
+    },
+};
+
+// --drop-cache drops what's behind the cursors of the readers and the
+// writer from the page cache, a window at a time. What was written has
+// to be on the device first: the window before the last has its writeback
+// waited for while the last one's is only started, so the writer seldom
+// waits for the device.
+const WINDOW: u64 = 32 * 1024 * 1024;
+
+// Nothing for an empty range, a length of 0 would be up to the end
+fn drop_range(file: &impl AsRawFd, start: u64, end: u64) -> nix::Result<()> {
+    if end <= start {
+        return Ok(());
+    }
+    posix_fadvise(
+        file.as_raw_fd(),
+        start as i64,
+        (end - start) as i64,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    )
+}
+
+fn write_back(file: &impl AsRawFd, start: u64, end: u64, wait: bool) -> nix::Result<()> {
+    if end <= start {
+        return Ok(());
+    }
+    let flags = if wait {
+        libc::SYNC_FILE_RANGE_WAIT_BEFORE
+            | libc::SYNC_FILE_RANGE_WRITE
+            | libc::SYNC_FILE_RANGE_WAIT_AFTER
+    } else {
+        libc::SYNC_FILE_RANGE_WRITE
+    };
+    let result = unsafe {
+        libc::sync_file_range(file.as_raw_fd(), start as i64, (end - start) as i64, flags)
+    };
+    Errno::result(result).map(drop)
+}
+
+/// Where the pages of a file written through are dropped, behind the
+/// writer's position
+#[derive(Clone, Copy)]
+pub struct Behind {
+    // Dropped up to here
+    dropped: u64,
+    // Written back from here on, up to where the writer is
+    flushing: u64,
+}
+
+impl Behind {
+    pub fn new(start: u64) -> Self {
+        Behind {
+            dropped: start,
+            flushing: start,
+        }
+    }
+
+    /// Whether the writer at `pos` has gone a window further
+    pub fn due(&self, pos: u64) -> bool {
+        pos >= self.flushing + WINDOW
+    }
+
+    /// Start writing back what's before `pos`, and drop what was started
+    /// before once it's on the device. Blocks while it's written.
+    pub fn advance(&mut self, file: &File, pos: u64) -> nix::Result<()> {
+        write_back(file, self.flushing, pos, false)?;
+        write_back(file, self.dropped, self.flushing, true)?;
+        drop_range(file, self.dropped, self.flushing)?;
+        self.dropped = self.flushing;
+        self.flushing = pos;
+        Ok(())
+    }
+
+    /// Write back and drop everything up to `end`, once the writer is done
+    pub fn finish(&mut self, file: &File, end: u64) -> nix::Result<()> {
+        write_back(file, self.dropped, end, true)?;
+        drop_range(file, self.dropped, end)?;
+        self.dropped = end;
+        self.flushing = end;
+        Ok(())
+    }
+}
+
+/// A file or device read sequentially, its pages dropped from the page
+/// cache once they've been read. Other processes' cached pages are left
+/// alone, except those of the same file.
+pub struct DropBehind {
+    inner: Box<dyn BlockSource>,
+    // Another handle of the same file, which has the same pages
+    file: File,
+    pos: u64,
+    dropped: u64,
+}
+
+impl DropBehind {
+    /// Read `inner` from `file`, on which reads are announced as
+    /// sequential, so they're read ahead further
+    pub fn new(inner: Box<dyn BlockSource>, file: File) -> io::Result<Self> {
+        posix_fadvise(
+            file.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+        )?;
+        Ok(DropBehind {
+            inner,
+            file,
+            pos: 0,
+            dropped: 0,
+        })
+    }
+
+    fn moved(&mut self, len: u64) -> io::Result<()> {
+        self.pos += len;
+        if self.pos >= self.dropped + WINDOW {
+            drop_range(&self.file, self.dropped, self.pos)?;
+            self.dropped = self.pos;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockSource for DropBehind {
+    async fn size(&mut self) -> Option<u64> {
+        self.inner.size().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf).await?;
+        self.moved(n as u64)?;
+        Ok(n)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let skipped = self.inner.skip_hole(len).await?;
+        if skipped {
+            self.moved(len as u64)?;
+        }
+        Ok(skipped)
+    }
+
+    fn plain_holes(&self) -> bool {
+        self.inner.plain_holes()
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.inner.skip(len).await?;
+        // Skipped over, there's nothing of it to drop
+        self.pos += len;
+        self.dropped = self.pos;
+        Ok(())
+    }
+}
+
+/// `reader` reading the file or device at `path`, with its pages dropped
+/// behind it. Anything else, like an image or a download, has no pages
+/// of its own, it's read as it is.
+pub fn drop_behind(reader: Box<dyn BlockSource>, path: &str) -> io::Result<Box<dyn BlockSource>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(reader),
+        Err(e) => return Err(e),
+    };
+    let file_type = file.metadata()?.file_type();
+    if !(file_type.is_file() || file_type.is_block_device()) {
+        return Ok(reader);
+    }
+    Ok(Box::new(DropBehind::new(reader, file)?))
+}
+
+impl Drop for DropBehind {
+    fn drop(&mut self) {
+        let _ = drop_range(&self.file, self.dropped, self.pos);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    sync::{Arc, Mutex},
+};
+
+// A checkpoint is a small text file, replaced as a whole every time it's
+// saved, so an interrupted run leaves either the old or the new one:
+//
+//   # ssdsync checkpoint
+//   next 1073741824
+//   differs 16384
+//
+// A sync is split into stripes, each with its own progress:
+//
+//   stripe 0 536870912 104857600
+//   stripe 536870912 1073741824 641728512
+const HEADER: &str = "# ssdsync checkpoint";
+
+/// A part of the sync: from `start` to `end`, done up to `next`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stripe {
+    pub start: u64,
+    pub end: u64,
+    pub next: u64,
+}
+
+/// How far a run got, and the differences it found until then
+#[derive(Debug, Default)]
+pub struct Checkpoint {
+    pub next: u64,
+    pub differing: Vec<u64>,
+    pub stripes: Vec<Stripe>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Checkpoint {
+    pub fn load(path: &str) -> io::Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        if lines.next().transpose()?.as_deref() != Some(HEADER) {
+            return Err(invalid(format!("{} is not an ssdsync checkpoint", path)));
+        }
+
+        let mut checkpoint = Checkpoint::default();
+        for (n, line) in lines.enumerate() {
+            let line = line?;
+            let bad = || invalid(format!("{}:{}: bad checkpoint line", path, n + 2));
+            let mut fields = line.split(' ');
+            let key = fields.next().unwrap_or_default();
+            let values = fields
+                .map(|f| f.parse())
+                .collect::<Result<Vec<u64>, _>>()
+                .map_err(|_| bad())?;
+            match (key, &values[..]) {
+                ("next", [next]) => checkpoint.next = *next,
+                ("differs", [offset]) => checkpoint.differing.push(*offset),
+                ("stripe", [start, end, next]) => checkpoint.stripes.push(Stripe {
+                    start: *start,
+                    end: *end,
+                    next: *next,
+                }),
+                _ => return Err(bad()),
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    /// Replace the checkpoint at `path` with this one
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.tmp", path);
+        let mut file = File::create(&tmp)?;
+        writeln!(file, "{}", HEADER)?;
+        writeln!(file, "next {}", self.next)?;
+        for offset in self.differing.iter() {
+            writeln!(file, "differs {}", offset)?;
+        }
+        for stripe in self.stripes.iter() {
+            writeln!(
+                file,
+                "stripe {} {} {}",
+                stripe.start, stripe.end, stripe.next
+            )?;
+        }
+        file.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// Check that a checkpoint was taken of a sync split the same way, and
+/// return where each of its stripes is to continue
+pub fn resume_points(checkpoint: &Checkpoint, layout: &[(u64, u64)]) -> Result<Vec<u64>, String> {
+    let saved: Vec<(u64, u64)> = checkpoint
+        .stripes
+        .iter()
+        .map(|s| (s.start, s.end))
+        .collect();
+    if saved != layout {
+        return Err(format!(
+            "The checkpoint has {} stripes {:?}, this sync has {} stripes {:?}",
+            saved.len(),
+            saved,
+            layout.len(),
+            layout
+        ));
+    }
+    Ok(checkpoint
+        .stripes
+        .iter()
+        .map(|s| std::cmp::min(std::cmp::max(s.next, s.start), s.end))
+        .collect())
+}
+
+/// The stripe of one region of a sync split up with --jobs. The regions
+/// share the checkpoint, each saving how far it got into its own stripe.
+#[derive(Clone)]
+pub struct Part {
+    checkpoint: Arc<Mutex<Checkpoint>>,
+    pub path: String,
+    index: usize,
+}
+
+impl Part {
+    pub fn stripe(&self) -> Stripe {
+        self.checkpoint.lock().unwrap().stripes[self.index]
+    }
+
+    /// Save the checkpoint at the path with this stripe done up to `next`
+    pub fn save(&self, next: u64) -> io::Result<()> {
+        let mut checkpoint = self.checkpoint.lock().unwrap();
+        checkpoint.stripes[self.index].next = next;
+        checkpoint.save(&self.path)
+    }
+}
+
+/// Split `checkpoint`, saved at `path`, into a part for each of its stripes
+pub fn parts(checkpoint: Checkpoint, path: &str) -> Vec<Part> {
+    let stripes = checkpoint.stripes.len();
+    let checkpoint = Arc::new(Mutex::new(checkpoint));
+    (0..stripes)
+        .map(|index| Part {
+            checkpoint: checkpoint.clone(),
+            path: path.to_string(),
+            index,
+        })
+        .collect()
+}
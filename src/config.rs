@@ -0,0 +1,156 @@
+use {
+    crate::{
+        error::{self, Context, Error},
+        Args, Command, SyncArgs,
+    },
+    clap::Parser,
+    std::ffi::OsString,
+    toml::{Table, Value},
+};
+
+// A configuration file has sync options by their long names, without the
+// dashes, for every sync, and profiles of them:
+//
+//     block-size = "64K"
+//     hash = "blake3"
+//
+//     [profiles.nightly]
+//     source = "/dev/vg0/data"
+//     target = "/dev/sdb"
+//     limit-rate = "100M"
+//     fsfreeze = "auto"
+//
+// A profile is used by --profile, or when the source and target given are
+// its own. Options given on the command line win over both.
+
+/// The configuration file used without --config, if it's there
+fn default_path() -> Option<String> {
+    let dir = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => format!("{}/.config", std::env::var("HOME").ok()?),
+    };
+    let path = format!("{}/ssdsync.toml", dir);
+    std::path::Path::new(&path).exists().then_some(path)
+}
+
+// The value of an option given as --name VALUE or --name=VALUE, before
+// any --
+fn option(argv: &[OsString], name: &str) -> Option<String> {
+    let argv: Vec<_> = argv
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .collect();
+    let prefix = format!("{}=", name);
+    argv.iter()
+        .enumerate()
+        .find_map(|(i, arg)| match arg.strip_prefix(&prefix) {
+            Some(value) => Some(value.to_string()),
+            None if arg == name => argv.get(i + 1).map(|value| value.to_string()),
+            None => None,
+        })
+}
+
+// The options of a table as command line arguments, a flag for true and
+// one option for each value of an array
+fn arguments(path: &str, table: &Table) -> error::Result<Vec<OsString>> {
+    let mut arguments = Vec::new();
+    for (key, value) in table {
+        if key == "source" || key == "target" || value.is_table() {
+            continue;
+        }
+        let values = match value {
+            Value::Array(values) => values.clone(),
+            value => vec![value.clone()],
+        };
+        for value in values {
+            let flag = format!("--{}", key);
+            match value {
+                Value::Boolean(true) => arguments.push(flag.into()),
+                Value::Boolean(false) => (),
+                Value::String(s) => arguments.extend([flag.into(), s.into()]),
+                Value::Integer(n) => arguments.extend([flag.into(), n.to_string().into()]),
+                Value::Float(n) => arguments.extend([flag.into(), n.to_string().into()]),
+                _ => {
+                    return Err(Error::Usage(format!(
+                        "{}: {} has to be a string, a number, true or false",
+                        path, key
+                    )))
+                }
+            }
+        }
+    }
+    Ok(arguments)
+}
+
+/// The command line with the options of the configuration file and the
+/// profile used added before those given, which win over them
+pub fn expand(argv: Vec<OsString>) -> error::Result<Vec<OsString>> {
+    let profile = option(&argv, "--profile");
+    let path = match option(&argv, "--config").or_else(default_path) {
+        Some(path) => path,
+        None if profile.is_some() => {
+            return Err(Error::Usage(
+                "--profile needs a configuration file, there's none".to_string(),
+            ))
+        }
+        None => return Ok(argv),
+    };
+    let text = std::fs::read_to_string(&path).context("read", &path)?;
+    let config: Table = text
+        .parse()
+        .map_err(|e| Error::Usage(format!("{} is no good: {}", path, e)))?;
+
+    // Only a sync, of its own or of sync, clone or diff, takes the options. What's
+    // given already is found out by parsing the command line on its own.
+    let given = Args::try_parse_from(&argv).ok();
+    // The other side of a remote sync has its options sent
+    if given.as_ref().is_some_and(|args| args.server.is_some()) {
+        return Ok(argv);
+    }
+    // Right after the name of the subcommand
+    let after = |name: &str| argv.iter().position(|arg| arg == name).map_or(1, |i| i + 1);
+    let (sync, position) = match given.as_ref().map(|args| (&args.command, &args.sync)) {
+        Some((None, sync)) => (Some(sync), 1),
+        Some((Some(Command::Sync { sync }), _)) => (Some(&**sync), after("sync")),
+        Some((Some(Command::Clone { sync, .. }), _)) => (Some(&**sync), after("clone")),
+        Some((Some(Command::Diff { sync, .. }), _)) => (Some(&**sync), after("diff")),
+        Some((Some(_), _)) => return Ok(argv),
+        // Missing a source and a target, which the profile may have
+        None => (None, 1),
+    };
+    let given_pair =
+        sync.and_then(|sync: &SyncArgs| Some((sync.source.clone()?, sync.target.clone()?)));
+
+    let profiles = config.get("profiles").and_then(Value::as_table);
+    let profile = match profile {
+        Some(name) => Some(
+            profiles
+                .and_then(|profiles| profiles.get(&name))
+                .and_then(Value::as_table)
+                .ok_or_else(|| Error::Usage(format!("{} has no profile {}", path, name)))?,
+        ),
+        // The profile of the pair given, if there's one
+        None => given_pair.as_ref().and_then(|(source, target)| {
+            profiles?
+                .values()
+                .filter_map(Value::as_table)
+                .find(|profile| {
+                    profile.get("source").and_then(Value::as_str) == Some(source)
+                        && profile.get("target").and_then(Value::as_str) == Some(target)
+                })
+        }),
+    };
+
+    let mut expanded = argv[..position].to_vec();
+    expanded.extend(arguments(&path, &config)?);
+    if let Some(profile) = profile {
+        expanded.extend(arguments(&path, profile)?);
+        let pair = ["source", "target"].map(|key| profile.get(key).and_then(Value::as_str));
+        if let (None, [Some(source), Some(target)]) = (&given_pair, pair) {
+            expanded.extend([source.into(), target.into()]);
+        }
+    }
+    expanded.extend_from_slice(&argv[position..]);
+    Ok(expanded)
+}
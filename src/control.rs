@@ -0,0 +1,169 @@
+use {
+    indicatif::HumanBytes,
+    std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+        signal::unix::{signal, SignalKind},
+        sync::watch,
+        task::JoinHandle,
+    },
+};
+
+/// State shared between the sync loop, the writer and the control socket
+pub struct Control {
+    paused: watch::Sender<bool>,
+    pub size: u64,
+    start: Instant,
+    pub pos: AtomicU64,
+    pub diff: AtomicU64,
+    /// Bytes the writer has written so far
+    pub written: AtomicU64,
+    /// Writes the writer carried on with after they were cut short
+    pub retries: AtomicU64,
+}
+
+impl Control {
+    pub fn new(size: u64) -> Self {
+        let (paused, _) = watch::channel(false);
+        Control {
+            paused,
+            size,
+            start: Instant::now(),
+            pos: AtomicU64::new(0),
+            diff: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            retries: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns once the sync isn't paused
+    pub async fn wait_while_paused(&self) {
+        let mut paused = self.paused.subscribe();
+        while *paused.borrow_and_update() {
+            if paused.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn status(&self) -> String {
+        format!(
+            "{} {} of {} bytes scanned, {} blocks differ",
+            if *self.paused.borrow() {
+                "paused"
+            } else {
+                "running"
+            },
+            self.pos.load(Ordering::Relaxed),
+            self.size,
+            self.diff.load(Ordering::Relaxed)
+        )
+    }
+
+    /// Bytes scanned per second since the start
+    pub fn rate(&self) -> f64 {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        self.pos.load(Ordering::Relaxed) as f64 / elapsed.max(0.001)
+    }
+
+    // Like dd's, with the rate since the start
+    fn report(&self) -> String {
+        format!(
+            "At {} of {} bytes after {:.1} s, {}/s, different: {}, written: {} bytes",
+            self.pos.load(Ordering::Relaxed),
+            self.size,
+            self.start.elapsed().as_secs_f64(),
+            HumanBytes(self.rate() as u64),
+            self.diff.load(Ordering::Relaxed),
+            self.written.load(Ordering::Relaxed)
+        )
+    }
+
+    fn command(&self, cmd: &str) -> String {
+        match cmd {
+            "pause" => {
+                self.paused.send_replace(true);
+                "ok".to_string()
+            }
+            "resume" => {
+                self.paused.send_replace(false);
+                "ok".to_string()
+            }
+            "status" => self.status(),
+            _ => format!("unknown command: {}", cmd),
+        }
+    }
+}
+
+async fn handle(control: Arc<Control>, stream: UnixStream) -> std::io::Result<()> {
+    let (r, mut w) = stream.into_split();
+    let mut lines = BufReader::new(r).lines();
+    while let Some(line) = lines.next_line().await? {
+        let reply = control.command(line.trim());
+        w.write_all(format!("{}\n", reply).as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Accept pause, resume and status commands, one per line, on a unix socket
+pub async fn serve(control: Arc<Control>, listener: UnixListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle(control.clone(), stream));
+            }
+            Err(e) => {
+                tracing::error!("Control socket failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Print how far the sync is to stderr on every SIGUSR1, and SIGINFO
+/// where there is one, the way dd does
+pub fn report_on_signal(control: Arc<Control>) -> std::io::Result<JoinHandle<()>> {
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    #[cfg(any(
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    let mut info = signal(SignalKind::info())?;
+    Ok(tokio::spawn(async move {
+        loop {
+            #[cfg(any(
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "macos",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            ))]
+            let received = tokio::select! {
+                received = usr1.recv() => received,
+                received = info.recv() => received,
+            };
+            #[cfg(not(any(
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "macos",
+                target_os = "netbsd",
+                target_os = "openbsd"
+            )))]
+            let received = usr1.recv().await;
+            if received.is_none() {
+                return;
+            }
+            eprintln!("{}", control.report());
+        }
+    }))
+}
@@ -0,0 +1,223 @@
+use {
+    nix::{
+        ioctl_read_bad, ioctl_write_ptr_bad, request_code_none,
+        sys::stat::{major, makedev, minor},
+    },
+    std::{
+        os::unix::{
+            fs::{FileTypeExt, MetadataExt, OpenOptionsExt},
+            io::AsRawFd,
+        },
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+};
+
+// How often a removable target is checked for being still there
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Device number of a block device, None for anything else
+pub fn block_rdev(path: &str) -> Option<u64> {
+    let meta = std::fs::metadata(path).ok()?;
+    if meta.file_type().is_block_device() {
+        Some(meta.rdev())
+    } else {
+        None
+    }
+}
+
+// See linux/fs.h
+ioctl_read_bad!(blksszget, 0x1268, i32);
+
+/// Logical sector size of a block device, the unit it can be written in
+pub fn logical_block_size(device: &impl AsRawFd) -> Option<usize> {
+    let mut size = 0;
+    unsafe { blksszget(device.as_raw_fd(), &mut size) }.ok()?;
+    Some(size as usize)
+}
+
+ioctl_write_ptr_bad!(blkdiscard, request_code_none!(0x12, 119), [u64; 2]);
+
+/// Tell a block device `length` bytes at `pos` are no longer in use, so
+/// flash can erase them without writing
+pub fn discard(device: &impl AsRawFd, pos: u64, length: usize) -> nix::Result<()> {
+    let range = [pos, length as u64];
+    unsafe { blkdiscard(device.as_raw_fd(), &range) }.map(|_| ())
+}
+
+/// sysfs directory of a block device, e.g. /sys/devices/.../block/sdb/sdb1
+pub fn sysfs_dir(rdev: u64) -> Option<PathBuf> {
+    std::fs::canonicalize(format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev))).ok()
+}
+
+/// A filesystem mounted, from the mount table
+pub struct Mount {
+    pub device: u64,
+    // The directory of the filesystem that's mounted, / unless it's a
+    // bind mount of a part of it
+    pub root: String,
+    pub mountpoint: String,
+}
+
+/// What's mounted now
+pub fn mounts() -> std::io::Result<Vec<Mount>> {
+    let table = std::fs::read_to_string("/proc/self/mountinfo")?;
+    // ID, parent ID, major:minor, root, mount point, ...
+    Ok(table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split(' ').collect();
+            let (major, minor) = fields.get(2)?.split_once(':')?;
+            Some(Mount {
+                device: makedev(major.parse().ok()?, minor.parse().ok()?),
+                root: unescape(fields.get(3)?),
+                mountpoint: unescape(fields.get(4)?),
+            })
+        })
+        .collect())
+}
+
+// Spaces and the like are octal escapes in the mount table
+fn unescape(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = path
+            .get(i + 1..i + 4)
+            .and_then(|o| u8::from_str_radix(o, 8).ok());
+        match octal {
+            Some(c) if bytes[i] == b'\\' => {
+                unescaped.push(c);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+// The device number of a device's sysfs directory
+fn dev_number(dir: &Path) -> Option<u64> {
+    let dev = std::fs::read_to_string(dir.join("dev")).ok()?;
+    let (major, minor) = dev.trim().split_once(':')?;
+    Some(makedev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// The whole disk a partition is on, the device itself for anything else
+pub fn disk_of(rdev: u64) -> u64 {
+    sysfs_dir(rdev)
+        .filter(|dir| dir.join("partition").exists())
+        .and_then(|dir| dev_number(dir.parent()?))
+        .unwrap_or(rdev)
+}
+
+/// What a block device is in use by, if anything: it or one of its
+/// partitions being mounted, swapped on, or held by another device like
+/// one of the device mapper or of a RAID
+pub fn user(rdev: u64) -> Option<String> {
+    let dir = sysfs_dir(rdev)?;
+    let name = |dir: &Path| {
+        format!(
+            "/dev/{}",
+            dir.file_name().unwrap_or_default().to_string_lossy()
+        )
+    };
+    let mut devices = vec![(rdev, name(&dir))];
+    for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+        let partition = entry.path();
+        if !partition.join("partition").exists() {
+            continue;
+        }
+        if let Some(device) = dev_number(&partition) {
+            devices.push((device, name(&partition)));
+        }
+    }
+
+    let mounts = mounts().unwrap_or_default();
+    // The first line is a heading
+    let swaps = std::fs::read_to_string("/proc/swaps").unwrap_or_default();
+    let swaps: Vec<_> = swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| block_rdev(line.split_whitespace().next()?))
+        .collect();
+    for (device, path) in devices {
+        if let Some(mount) = mounts.iter().find(|m| m.device == device) {
+            return Some(format!("{} is mounted on {}", path, mount.mountpoint));
+        }
+        if swaps.contains(&device) {
+            return Some(format!("{} is used as swap", path));
+        }
+        let sysfs = match sysfs_dir(device) {
+            Some(sysfs) => sysfs,
+            None => continue,
+        };
+        let holder = std::fs::read_dir(sysfs.join("holders"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .next();
+        if let Some(holder) = holder {
+            return Some(format!(
+                "{} is in use by /dev/{}",
+                path,
+                holder.file_name().to_string_lossy()
+            ));
+        }
+    }
+    None
+}
+
+/// Open a block device exclusively, so that the kernel refuses if it's
+/// mounted or held by another device, and refuses to mount it while the
+/// handle is open. Anything but a block device isn't opened.
+pub fn claim(path: &str) -> std::io::Result<Option<std::fs::File>> {
+    if block_rdev(path).is_none() {
+        return Ok(None);
+    }
+    std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::libc::O_EXCL)
+        .open(path)
+        .map(Some)
+}
+
+/// Whether the disk the device is on is removable. Partitions have no
+/// removable flag of their own, their parent disk's is used then.
+pub fn is_removable(rdev: u64) -> bool {
+    let dir = match sysfs_dir(rdev) {
+        Some(dir) => dir,
+        None => return false,
+    };
+    let flag = match std::fs::read_to_string(dir.join("removable")) {
+        Ok(flag) => flag,
+        Err(_) => match dir.parent() {
+            Some(parent) => std::fs::read_to_string(parent.join("removable")).unwrap_or_default(),
+            None => return false,
+        },
+    };
+    flag.trim() == "1"
+}
+
+/// Watch a removable target and set `removed` as soon as it disappears
+/// or its node suddenly points to another device, which stops the sync.
+/// Otherwise it would fail with a confusing write error, or worse, carry
+/// on onto whatever got the name next.
+pub async fn watch_presence(path: String, rdev: u64, removed: Arc<AtomicBool>) {
+    let sysfs = format!("/sys/dev/block/{}:{}", major(rdev), minor(rdev));
+    loop {
+        tokio::time::sleep(PRESENCE_INTERVAL).await;
+        if block_rdev(&path) != Some(rdev) || !Path::new(&sysfs).exists() {
+            removed.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    alloc::{self, Layout},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    sync::Arc,
+};
+
+// O_DIRECT reads and writes go straight to the device, without passing
+// through the page cache. Their buffers, offsets and lengths have to be
+// aligned to the logical block size, which this is a multiple of.
+pub const ALIGN: usize = 4096;
+
+/// Zeroed heap buffer starting at an ALIGN boundary
+pub struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The buffer is owned like a Vec's
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    pub fn new(len: usize) -> Self {
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout(len)) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(Self::layout(len));
+        }
+        AlignedBuf { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(std::cmp::max(len, 1), ALIGN).unwrap()
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.len)) }
+    }
+}
+
+impl std::ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl std::ops::DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+pub fn open(path: &str, write: bool) -> io::Result<File> {
+    OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .custom_flags(nix::libc::O_DIRECT)
+        .open(path)
+}
+
+/// Writes aligned blocks through an O_DIRECT handle of the target. Each
+/// one is copied into an aligned buffer first and written from the
+/// blocking pool, the buffer is used again for the next one.
+pub struct DirectWriter {
+    file: Arc<File>,
+    // Offsets and lengths written have to be multiples of this
+    pub align: usize,
+    buf: Option<AlignedBuf>,
+}
+
+impl DirectWriter {
+    /// Open the target, aligned to its sectors if it's a device
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = open(path, true)?;
+        let align = crate::device::logical_block_size(&file).unwrap_or(ALIGN);
+        Ok(DirectWriter {
+            file: Arc::new(file),
+            align,
+            buf: None,
+        })
+    }
+
+    /// Whether a block of `len` bytes at `pos` can be written directly
+    pub fn fits(&self, pos: u64, len: usize) -> bool {
+        pos.is_multiple_of(self.align as u64) && len.is_multiple_of(self.align)
+    }
+
+    /// Write all of `data` at `pos`, both have to be aligned
+    pub async fn write_at(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+        let mut buf = match self.buf.take() {
+            Some(buf) if buf.len() == data.len() => buf,
+            _ => AlignedBuf::new(data.len()),
+        };
+        buf.copy_from_slice(data);
+        let file = self.file.clone();
+        let (buf, result) = tokio::task::spawn_blocking(move || {
+            let result = file.write_all_at(&buf, pos);
+            (buf, result)
+        })
+        .await
+        .map_err(io::Error::other)?;
+        self.buf = Some(buf);
+        result
+    }
+}
@@ -0,0 +1,166 @@
+use {
+    crate::{
+        error::{Error, Result},
+        source::{self, Segment},
+        sync, sync_from, Driver, Messages, Reference, SyncArgs,
+    },
+    std::sync::{atomic::AtomicBool, Arc},
+};
+
+/// What a finished sync did
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// The target as resolved, e.g. from a UUID=... specifier
+    pub target: String,
+    /// Blocks compared
+    pub blocks: u64,
+    /// Blocks that differed
+    pub different: u64,
+    /// Bytes written to the target
+    pub written: u64,
+    /// Size of the source, if it has one
+    pub source_size: Option<u64>,
+    /// Size of the target, after any truncating or extending
+    pub target_size: u64,
+    /// Bytes compared
+    pub scanned: u64,
+    /// Bytes read from the source and the target together, holes skipped
+    /// aren't read
+    pub read: u64,
+    /// Writes interrupted or cut short that were carried on with
+    pub retries: u64,
+    /// Bytes of the source that couldn't be read, the target keeps what
+    /// it had there
+    pub unreadable: u64,
+    /// How long each phase took: prepare, sync, finish and verify
+    pub phases: Vec<(&'static str, std::time::Duration)>,
+}
+
+/// A sync of a source onto a target, run from another program instead
+/// of the command line. Everything not set here is as the ssdsync
+/// defaults have it. Nothing is printed and no progress bars are drawn:
+/// the messages the command line prints go to the `tracing` subscriber of
+/// the program as info events, unless they're handed to `on_message`.
+/// Warnings and errors go to the subscriber as well.
+///
+/// ```no_run
+/// # async fn example() -> ssdsync::error::Result<()> {
+/// let summary = ssdsync::SyncEngine::new("/dev/sda", "/dev/sdb")
+///     .block_size(64 * 1024)
+///     .on_progress(|pos, size| eprintln!("{} of {}", pos, size))
+///     .run()
+///     .await?;
+/// println!("{} bytes written", summary.written);
+/// # Ok(())
+/// # }
+/// ```
+pub struct SyncEngine {
+    args: SyncArgs,
+    driver: Driver,
+}
+
+impl SyncEngine {
+    /// A sync of `source` onto `target`, files or devices given like
+    /// on the command line
+    pub fn new(source: &str, target: &str) -> Self {
+        SyncEngine {
+            args: SyncArgs::defaults(source, target),
+            driver: Driver {
+                bars: false,
+                messages: Messages(Some(Arc::new(|message| tracing::info!("{}", message)))),
+                ..Driver::default()
+            },
+        }
+    }
+
+    /// A sync onto `target` of the segments given to `run_segments`
+    pub fn onto(target: &str) -> Self {
+        SyncEngine::new("segments", target)
+    }
+
+    /// Bytes compared and written at a time
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.args.block_size = size;
+        self
+    }
+
+    /// Blocks read ahead on each side, at least one
+    pub fn buffers(mut self, n: usize) -> Self {
+        self.args.buffers = std::cmp::max(n, 1);
+        self
+    }
+
+    /// Blocks each queue between the readers, the comparison and the
+    /// writer holds, at least the number of buffers
+    pub fn queue_depth(mut self, n: usize) -> Self {
+        self.args.queue_depth = Some(n);
+        self
+    }
+
+    /// Check the source against the target as the reference instead of
+    /// syncing it, like `--reference target`. Nothing is written, a source
+    /// that deviates fails with `Error::Deviates`.
+    pub fn validate(mut self) -> Self {
+        self.args.reference = Reference::Target;
+        self
+    }
+
+    /// Call `progress` with the bytes synced so far and the bytes to
+    /// sync, a few times a second and once at the end
+    pub fn on_progress(mut self, progress: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        self.driver.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Call `message` with each of the messages the command line would
+    /// print, like what was resized or how far away the target was from
+    /// the source, instead of logging them
+    pub fn on_message(mut self, message: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.driver.messages = Messages(Some(Arc::new(message)));
+        self
+    }
+
+    /// Stop as soon as `cancel` is set, with `Error::Cancelled`. Blocks
+    /// already on their way are still written.
+    pub fn cancel_on(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.driver.cancel = Some(cancel);
+        self
+    }
+
+    pub async fn run(&self) -> Result<Summary> {
+        sync(&self.args, false, &self.driver).await
+    }
+
+    /// Sync each of `segments` on its own onto its part of the target,
+    /// `length` bytes from its `offset`, in the order of the offsets, and
+    /// report each of them. Nothing else of the target is read or
+    /// written. A source shorter than its segment only syncs what it has,
+    /// segments can't overlap. The first that fails stops the others.
+    ///
+    /// ```no_run
+    /// # async fn example(parts: Vec<ssdsync::Segment>) -> ssdsync::error::Result<()> {
+    /// let summaries = ssdsync::SyncEngine::onto("/dev/sdb")
+    ///     .run_segments(parts)
+    ///     .await?;
+    /// for summary in summaries {
+    ///     println!("{} bytes written", summary.written);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn run_segments(&self, mut segments: Vec<Segment>) -> Result<Vec<Summary>> {
+        source::sort_segments(&mut segments).map_err(|e| Error::Usage(e.to_string()))?;
+        let mut summaries = Vec::new();
+        for segment in segments {
+            let args = SyncArgs {
+                source: Some(format!("the segment at {}", segment.offset)),
+                target_offset: segment.offset,
+                length: Some(segment.length),
+                ..self.args.clone()
+            };
+            let summary = sync_from(&args, false, &self.driver, Some(segment.source)).await?;
+            summaries.push(summary);
+        }
+        Ok(summaries)
+    }
+}
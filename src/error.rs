@@ -0,0 +1,214 @@
+use std::io;
+
+/// Everything that can make a run fail, with where it happened
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A file or device couldn't be opened, created, read or written as a
+    /// whole, e.g. action "create the journal", path "sync.journal"
+    #[error("Could not {action} {path}: {source}")]
+    File {
+        action: &'static str,
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A file or device that was open failed in the middle of the run,
+    /// e.g. action "write", path "sync.journal"
+    #[error("Could not {action} {path}: {source}")]
+    Io {
+        action: &'static str,
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Could not resolve {spec}: no such entry in {dir}")]
+    Resolve { spec: String, dir: &'static str },
+
+    /// Reading a block failed, `side` is source or target
+    #[error("Could not read the {side} at {offset}: {source}")]
+    Read {
+        side: &'static str,
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("Could not write the target at {offset}: {source}")]
+    Write {
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+
+    /// A block read back from the target isn't what was written there
+    #[error("The target doesn't read back what was written at {offset}")]
+    WriteMismatch { offset: u64 },
+
+    /// Options that can't work together or with the files given
+    #[error("{0}")]
+    Usage(String),
+
+    #[error("The target {0} can't be a pipe, it has to be read and written")]
+    TargetIsPipe(String),
+
+    #[error("Not syncing, the target {path} is not {expected}")]
+    TargetType {
+        path: String,
+        expected: &'static str,
+    },
+
+    #[error("Not syncing, {0}. Give --force to write to it anyway")]
+    TargetInUse(String),
+
+    #[error("Target is smaller than the source ({target_size} < {source_size} bytes)")]
+    TargetTooSmall { source_size: u64, target_size: u64 },
+
+    #[error("Source and target differ in size ({source_size} != {target_size} bytes)")]
+    SizeMismatch { source_size: u64, target_size: u64 },
+
+    #[error(
+        "{needed} file descriptors are needed, but the hard limit is {hard}. \
+         Raise it first, e.g. with ulimit -Hn {needed}"
+    )]
+    FdLimit { needed: u64, hard: u64 },
+
+    /// A system call outside of reading and writing blocks failed
+    #[error("Could not {action}: {source}")]
+    System {
+        action: &'static str,
+        #[source]
+        source: nix::Error,
+    },
+
+    #[error("The target device {0} was removed, aborting")]
+    TargetRemoved(String),
+
+    #[error("Not syncing, the target doesn't hold the asserted bytes: {0}")]
+    AssertRegion(String),
+
+    #[error("The source block at {at} doesn't match the manifest, stopped before writing it")]
+    ManifestMismatch { offset: u64, at: String },
+
+    #[error("The source hash is {actual}, expected {expected}. The target has been written anyway{note}")]
+    SourceHash {
+        actual: String,
+        expected: String,
+        note: &'static str,
+    },
+
+    #[error("Footer verification failed: {0}")]
+    Footer(String),
+
+    /// The target was the reference and the source isn't the same
+    #[error("Validation failed, {diff} of {total} blocks deviate from the reference")]
+    Deviates { diff: u64, total: u64 },
+
+    #[error("{0} blocks differ from the manifest")]
+    ManifestDiffers(usize),
+
+    #[error("{failed} of {pairs} pairs failed")]
+    BatchFailed { failed: usize, pairs: usize },
+
+    /// The other side of a remote sync failed, with its message
+    #[error("The remote side failed: {0}")]
+    Remote(String),
+
+    #[error("{failed} of {targets} targets failed")]
+    TargetsFailed { failed: usize, targets: usize },
+
+    /// A snapshot of the source stopped holding what the source had
+    #[error("The snapshot of the source is no good: {0}")]
+    Snapshot(io::Error),
+
+    #[error("The sync was cancelled")]
+    Cancelled,
+
+    #[error("Could not handle signals: {0}")]
+    Signal(io::Error),
+
+    /// A reader or writer task ended without saying why
+    #[error("The {0} task stopped unexpectedly")]
+    Task(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Exit statuses, so scripts can tell what kind of failure it was. Bad
+// command lines are rejected with 2 already, the way clap does it.
+
+/// A check failed: differences were found, preflight found problems, or
+/// a pair of a batch or one of several targets failed
+pub const EXIT_CHECK: i32 = 1;
+/// Options that can't work, or a target unfit for them
+pub const EXIT_USAGE: i32 = 2;
+/// A file or device couldn't be found, opened, created or loaded
+pub const EXIT_OPEN: i32 = 3;
+/// Reading or writing a block or a file written along the way failed, or
+/// the target went away
+pub const EXIT_IO: i32 = 4;
+/// Something in the system failed, unrelated to the files
+pub const EXIT_SYSTEM: i32 = 5;
+/// A sync stopped by SIGINT or SIGTERM
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+impl Error {
+    /// What the process exits with when a run fails with this
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::AssertRegion(_)
+            | Error::ManifestMismatch { .. }
+            | Error::SourceHash { .. }
+            | Error::Footer(_)
+            | Error::Deviates { .. }
+            | Error::ManifestDiffers(_)
+            | Error::BatchFailed { .. }
+            | Error::TargetsFailed { .. } => EXIT_CHECK,
+            Error::Usage(_)
+            | Error::TargetIsPipe(_)
+            | Error::TargetType { .. }
+            | Error::TargetInUse(_)
+            | Error::TargetTooSmall { .. }
+            | Error::SizeMismatch { .. }
+            | Error::FdLimit { .. } => EXIT_USAGE,
+            Error::File { .. } | Error::Resolve { .. } => EXIT_OPEN,
+            Error::Io { .. }
+            | Error::Read { .. }
+            | Error::Write { .. }
+            | Error::WriteMismatch { .. }
+            | Error::TargetRemoved(_)
+            | Error::Snapshot(_)
+            | Error::Remote(_) => EXIT_IO,
+            Error::System { .. } | Error::Signal(_) | Error::Task(_) => EXIT_SYSTEM,
+            Error::Cancelled => EXIT_INTERRUPTED,
+        }
+    }
+}
+
+/// Attach what was being done to which file to an io::Error
+pub trait Context<T> {
+    fn context(self, action: &'static str, path: &str) -> Result<T>;
+
+    /// The same for a file that's open already and failed on the way, so
+    /// it's an I/O error and not one of opening it
+    fn io_context(self, action: &'static str, path: &str) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, action: &'static str, path: &str) -> Result<T> {
+        self.map_err(|source| Error::File {
+            action,
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    fn io_context(self, action: &'static str, path: &str) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            action,
+            path: path.to_string(),
+            source,
+        })
+    }
+}
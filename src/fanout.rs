@@ -0,0 +1,162 @@
+use {
+    crate::{
+        error::{self, Error},
+        source::{self, BlockSource},
+        Driver, Summary, SyncArgs,
+    },
+    async_trait::async_trait,
+    std::{io, sync::Arc},
+    tokio::sync::mpsc,
+};
+
+// What the source is read into once and handed to every target's sync
+type Piece = io::Result<Arc<Vec<u8>>>;
+
+/// One target's share of a source read for several. The pieces are
+/// handed out over as many blocks as they take.
+pub struct Branch {
+    size: Option<u64>,
+    rx: mpsc::Receiver<Piece>,
+    piece: Arc<Vec<u8>>,
+    // Where in piece the next block starts
+    at: usize,
+}
+
+#[async_trait]
+impl BlockSource for Branch {
+    async fn size(&mut self) -> Option<u64> {
+        self.size
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.at == self.piece.len() {
+                match self.rx.recv().await {
+                    Some(piece) => self.piece = piece?,
+                    None => break,
+                }
+                self.at = 0;
+            }
+            let n = std::cmp::min(buf.len() - filled, self.piece.len() - self.at);
+            buf[filled..filled + n].copy_from_slice(&self.piece[self.at..self.at + n]);
+            filled += n;
+            self.at += n;
+        }
+        Ok(filled)
+    }
+}
+
+// Read the source through and send every piece to each branch still
+// there. The slowest target sets the pace, the others wait for it.
+async fn tee(mut source: Box<dyn BlockSource>, size: usize, txs: Vec<mpsc::Sender<Piece>>) {
+    loop {
+        let mut data = vec![0; size];
+        let piece = match source.read(&mut data).await {
+            Ok(0) => return,
+            Ok(n) => {
+                data.truncate(n);
+                Ok(Arc::new(data))
+            }
+            Err(e) => Err(e),
+        };
+        let mut open = 0;
+        for tx in txs.iter() {
+            let piece = match &piece {
+                Ok(data) => Ok(data.clone()),
+                // Every branch gets an error of its own
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            open += tx.send(piece).await.is_ok() as usize;
+        }
+        if open == 0 || piece.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sync the source onto every target at the same time, reading it only
+/// once. Each target is compared and written on its own, a failed one
+/// doesn't stop the others, but fails the run once they're done.
+pub async fn run(args: &SyncArgs, driver: &Driver) -> error::Result<()> {
+    let mut targets = vec![args.target.clone().unwrap()];
+    targets.extend(args.more_targets.iter().cloned());
+    crate::ensure_fd_limit(targets.len() as u64 * crate::fds_needed(args))?;
+
+    let spec = args.source.as_ref().unwrap();
+    let mut source = crate::uncached(
+        args,
+        source::open(spec, args.io_backend, args.direct).await?,
+        spec,
+    )?;
+    let size = source.size().await;
+    let mut txs = Vec::new();
+    let mut syncs = Vec::new();
+    for target in targets.iter() {
+        let (tx, rx) = mpsc::channel(args.buffers);
+        txs.push(tx);
+        let branch = Branch {
+            size,
+            rx,
+            piece: Arc::new(Vec::new()),
+            at: 0,
+        };
+        let args = SyncArgs {
+            target: Some(target.clone()),
+            more_targets: Vec::new(),
+            ..args.clone()
+        };
+        // Several bars or status lines at once would only garble each
+        // other
+        let driver = Driver {
+            bars: false,
+            progress: None,
+            messages: driver.messages.clone(),
+            cancel: driver.cancel.clone(),
+            status: false,
+            region: false,
+            part: None,
+        };
+        syncs.push(tokio::spawn(async move {
+            let summary = crate::sync_from(&args, false, &driver, Some(Box::new(branch))).await?;
+            if args.verify_after {
+                crate::verify_after(&args, &summary.target, &driver).await?;
+            }
+            Ok::<Summary, Error>(summary)
+        }));
+    }
+    tokio::spawn(tee(source, crate::read_sizes(args).0, txs));
+
+    let mut failed = 0;
+    let mut results = Vec::new();
+    for (target, sync) in targets.iter().zip(syncs) {
+        results.push((target, sync.await.unwrap_or(Err(Error::Task("sync")))));
+    }
+    println!();
+    for (target, result) in results.iter() {
+        match result {
+            Ok(summary) => println!(
+                "{}: different: {}, written: {} bytes",
+                target, summary.different, summary.written
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("{}: {}", target, e);
+            }
+        }
+    }
+    // Stopped by a signal, not by what went wrong
+    if results
+        .iter()
+        .any(|(_, result)| matches!(result, Err(Error::Cancelled)))
+    {
+        return Err(Error::Cancelled);
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(Error::TargetsFailed {
+            failed,
+            targets: targets.len(),
+        }),
+    }
+}
@@ -0,0 +1,131 @@
+use {
+    crate::Messages,
+    nix::{ioctl_readwrite, libc::c_int},
+    std::{
+        fs::File,
+        io,
+        os::unix::{fs::MetadataExt, io::AsRawFd},
+        path::Path,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    },
+};
+
+// See linux/fs.h
+ioctl_readwrite!(fifreeze, b'X', 119, c_int);
+ioctl_readwrite!(fithaw, b'X', 120, c_int);
+
+// Descriptors of the filesystems frozen now, for thawing them when the
+// process exits without dropping what holds them
+static FROZEN: Mutex<Vec<c_int>> = Mutex::new(Vec::new());
+
+fn thaw(fd: c_int) -> io::Result<()> {
+    unsafe { fithaw(fd, &mut 0) }?;
+    Ok(())
+}
+
+/// Thaw whatever is still frozen, before exiting right away
+pub fn thaw_all() {
+    for fd in FROZEN.lock().unwrap().drain(..) {
+        let _ = thaw(fd);
+    }
+}
+
+/// Where the device `source` is mounted, if it is
+pub fn mountpoint(source: &str) -> io::Result<Option<String>> {
+    let device = std::fs::metadata(source)?.rdev();
+    Ok(crate::device::mounts()?
+        .into_iter()
+        .find(|mount| mount.device == device && mount.root == "/")
+        .map(|mount| mount.mountpoint))
+}
+
+/// Whether the file at `path`, or the directory it would be created in,
+/// is on the filesystem mounted at `mountpoint`. Writing to it while
+/// that's frozen would wait until it's thawed.
+pub fn holds(mountpoint: &str, path: &str) -> bool {
+    let path = Path::new(path);
+    let existing = match path.parent() {
+        _ if path.exists() => path,
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mounted = std::fs::metadata(mountpoint).map(|m| m.dev()).ok();
+    std::fs::metadata(existing).is_ok_and(|m| Some(m.dev()) == mounted)
+}
+
+/// A frozen filesystem, thawed again when this is dropped, or after the
+/// timeout if that comes first
+pub struct Frozen {
+    mountpoint: String,
+    messages: Messages,
+    dir: Arc<File>,
+    thawed: Arc<AtomicBool>,
+    timer: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Frozen {
+    pub fn freeze(
+        mountpoint: &str,
+        timeout: Option<Duration>,
+        messages: &Messages,
+    ) -> io::Result<Frozen> {
+        let dir = Arc::new(File::open(mountpoint)?);
+        unsafe { fifreeze(dir.as_raw_fd(), &mut 0) }?;
+        FROZEN.lock().unwrap().push(dir.as_raw_fd());
+        messages.say(format_args!("Froze {}.", mountpoint));
+
+        let thawed = Arc::new(AtomicBool::new(false));
+        let timer = timeout.map(|timeout| {
+            let (dir, thawed) = (dir.clone(), thawed.clone());
+            let mountpoint = mountpoint.to_string();
+            let messages = messages.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                if !thawed.swap(true, Ordering::Relaxed) {
+                    FROZEN.lock().unwrap().retain(|fd| *fd != dir.as_raw_fd());
+                    match thaw(dir.as_raw_fd()) {
+                        Ok(()) => messages.say(format_args!(
+                            "Thawed {} after {} seconds, what's written to it from now on \
+                             may leave the target inconsistent.",
+                            mountpoint,
+                            timeout.as_secs()
+                        )),
+                        Err(e) => tracing::error!("Could not thaw {}: {}", mountpoint, e),
+                    }
+                }
+            })
+        });
+        Ok(Frozen {
+            mountpoint: mountpoint.to_string(),
+            messages: messages.clone(),
+            dir,
+            thawed,
+            timer,
+        })
+    }
+}
+
+impl Drop for Frozen {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.timer {
+            timer.abort();
+        }
+        if self.thawed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        FROZEN
+            .lock()
+            .unwrap()
+            .retain(|fd| *fd != self.dir.as_raw_fd());
+        match thaw(self.dir.as_raw_fd()) {
+            Ok(()) => self
+                .messages
+                .say(format_args!("Thawed {}.", self.mountpoint)),
+            Err(e) => tracing::error!("Could not thaw {}, fsfreeze -u it: {}", self.mountpoint, e),
+        }
+    }
+}
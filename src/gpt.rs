@@ -0,0 +1,210 @@
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    os::unix::fs::FileExt,
+};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+// Logical sector sizes the GPT header is looked for at
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+// Offsets of the header fields used here, see the UEFI spec
+const HDR_SIZE: usize = 12;
+const HDR_CRC: usize = 16;
+const HDR_MY_LBA: usize = 24;
+const HDR_BACKUP_LBA: usize = 32;
+const HDR_LAST_USABLE_LBA: usize = 48;
+const HDR_DISK_GUID: usize = 56;
+const HDR_ENTRIES_LBA: usize = 72;
+const HDR_ENTRIES_COUNT: usize = 80;
+const HDR_ENTRY_SIZE: usize = 84;
+const HDR_ENTRIES_CRC: usize = 88;
+
+// Offsets inside a partition entry
+const ENTRY_TYPE_GUID: usize = 0;
+const ENTRY_UNIQUE_GUID: usize = 16;
+
+// Entries are 128 bytes, or that times a power of 2
+const ENTRY_SIZE_MIN: u32 = 128;
+
+// Far more than any partition table takes, so that a corrupt count of
+// entries isn't read
+const ENTRIES_LEN_MAX: usize = 1024 * 1024;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn set_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// A random version 4 GUID in the mixed endian on-disk layout
+fn random_guid(urandom: &mut File) -> io::Result<[u8; 16]> {
+    let mut guid = [0; 16];
+    urandom.read_exact(&mut guid)?;
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    Ok(guid)
+}
+
+struct Header {
+    lba: u64,
+    data: Vec<u8>,
+}
+
+impl Header {
+    // None if there's no header at all, an error if there's a bad one
+    fn read(f: &File, sector_size: u64, lba: u64) -> io::Result<Option<Self>> {
+        let mut data = vec![0; sector_size as usize];
+        f.read_exact_at(&mut data, lba * sector_size)?;
+        if &data[..8] != SIGNATURE {
+            return Ok(None);
+        }
+        let size = u32_at(&data, HDR_SIZE) as usize;
+        if size < HDR_ENTRIES_CRC + 4 || size > data.len() {
+            return Err(invalid(format!("Bad GPT header size {}", size)));
+        }
+        data.truncate(size);
+        let header = Header { lba, data };
+        if header.crc() != u32_at(&header.data, HDR_CRC) {
+            return Err(invalid(format!("The GPT header at LBA {} is corrupt", lba)));
+        }
+        let entry_size = u32_at(&header.data, HDR_ENTRY_SIZE);
+        if !entry_size.is_multiple_of(ENTRY_SIZE_MIN)
+            || !(entry_size / ENTRY_SIZE_MIN).is_power_of_two()
+        {
+            return Err(invalid(format!(
+                "Bad GPT partition entry size {}",
+                entry_size
+            )));
+        }
+        Ok(Some(header))
+    }
+
+    fn entries_len(&self) -> io::Result<usize> {
+        let count = u32_at(&self.data, HDR_ENTRIES_COUNT) as usize;
+        count
+            .checked_mul(u32_at(&self.data, HDR_ENTRY_SIZE) as usize)
+            .filter(|len| *len <= ENTRIES_LEN_MAX)
+            .ok_or_else(|| invalid(format!("Bad GPT partition entry count {}", count)))
+    }
+
+    // The CRC of the header, taken with its own field zeroed
+    fn crc(&self) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.data[..HDR_CRC]);
+        hasher.update(&[0; 4]);
+        hasher.update(&self.data[HDR_CRC + 4..]);
+        hasher.finalize()
+    }
+
+    fn update_crc(&mut self) {
+        let crc = self.crc();
+        self.data[HDR_CRC..HDR_CRC + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+// Find the primary header, trying the usual logical sector sizes
+fn find_primary(f: &File) -> io::Result<(u64, Header)> {
+    for sector_size in SECTOR_SIZES.iter() {
+        if let Some(header) = Header::read(f, *sector_size, 1)? {
+            return Ok((*sector_size, header));
+        }
+    }
+    Err(invalid("No GPT found on the target".to_string()))
+}
+
+/// Give the disk and all of its partitions new random GUIDs, updating
+/// both the primary and the backup GPT. The backup is written at the end
+/// of the target, which may be larger than the disk the GPT was made
+/// for. Returns the number of partitions.
+pub fn randomize_guids(path: &str) -> io::Result<usize> {
+    let f = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut urandom = File::open("/dev/urandom")?;
+
+    let (sector_size, mut primary) = find_primary(&f)?;
+    let mut entries = vec![0; primary.entries_len()?];
+    let entries_lba = u64_at(&primary.data, HDR_ENTRIES_LBA);
+    f.read_exact_at(&mut entries, entries_lba * sector_size)?;
+    if crc32fast::hash(&entries) != u32_at(&primary.data, HDR_ENTRIES_CRC) {
+        return Err(invalid("GPT partition entries are corrupt".to_string()));
+    }
+
+    // The backup table takes the sectors right before the backup header,
+    // in the last one. The partitions may grow up to the table.
+    let last_lba = ((&f).seek(SeekFrom::End(0))? / sector_size).saturating_sub(1);
+    let entries_sectors = (entries.len() as u64).div_ceil(sector_size);
+    let last_usable = u64_at(&primary.data, HDR_LAST_USABLE_LBA);
+    let backup_entries_lba = last_lba
+        .checked_sub(entries_sectors)
+        .filter(|lba| *lba > last_usable)
+        .ok_or_else(|| invalid("The GPT doesn't fit onto the target".to_string()))?;
+    let stale_backup_lba = u64_at(&primary.data, HDR_BACKUP_LBA);
+
+    let entry_size = u32_at(&primary.data, HDR_ENTRY_SIZE) as usize;
+    let mut partitions = 0;
+    for entry in entries.chunks_mut(entry_size) {
+        // Unused entries have an all zero type GUID
+        if entry[ENTRY_TYPE_GUID..ENTRY_TYPE_GUID + 16]
+            .iter()
+            .all(|b| *b == 0)
+        {
+            continue;
+        }
+        let guid = random_guid(&mut urandom)?;
+        entry[ENTRY_UNIQUE_GUID..ENTRY_UNIQUE_GUID + 16].copy_from_slice(&guid);
+        partitions += 1;
+    }
+    let entries_crc = crc32fast::hash(&entries).to_le_bytes();
+    let disk_guid = random_guid(&mut urandom)?;
+
+    primary.data[HDR_DISK_GUID..HDR_DISK_GUID + 16].copy_from_slice(&disk_guid);
+    primary.data[HDR_ENTRIES_CRC..HDR_ENTRIES_CRC + 4].copy_from_slice(&entries_crc);
+    set_u64(&mut primary.data, HDR_BACKUP_LBA, last_lba);
+    set_u64(
+        &mut primary.data,
+        HDR_LAST_USABLE_LBA,
+        backup_entries_lba - 1,
+    );
+    // The backup is the primary with the places of the two swapped
+    let mut backup = Header {
+        lba: last_lba,
+        data: primary.data.clone(),
+    };
+    set_u64(&mut backup.data, HDR_MY_LBA, last_lba);
+    set_u64(&mut backup.data, HDR_BACKUP_LBA, primary.lba);
+    set_u64(&mut backup.data, HDR_ENTRIES_LBA, backup_entries_lba);
+
+    for header in [&mut primary, &mut backup] {
+        header.update_crc();
+        let lba = u64_at(&header.data, HDR_ENTRIES_LBA);
+        f.write_all_at(&entries, lba * sector_size)?;
+        f.write_all_at(&header.data, header.lba * sector_size)?;
+    }
+
+    // The backup of a smaller source is left behind in what's now free
+    // space, where it'd be found by tools looking for a lost GPT
+    if stale_backup_lba > last_usable
+        && stale_backup_lba < backup_entries_lba
+        && matches!(Header::read(&f, sector_size, stale_backup_lba), Ok(Some(_)))
+    {
+        f.write_all_at(
+            &vec![0; sector_size as usize],
+            stale_backup_lba * sector_size,
+        )?;
+    }
+
+    f.sync_all()?;
+    Ok(partitions)
+}
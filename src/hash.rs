@@ -0,0 +1,146 @@
+use {sha2::Digest, std::str::FromStr};
+
+/// Hash algorithms usable for integrity checks, from the cryptographic
+/// ones to checksums that are only fast
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+    Xxh3,
+    Crc32,
+    Crc32c,
+}
+
+impl HashAlgo {
+    /// Length of the digest in bytes
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgo::Blake3 | HashAlgo::Sha256 => 32,
+            HashAlgo::Xxh3 => 8,
+            HashAlgo::Crc32 | HashAlgo::Crc32c => 4,
+        }
+    }
+
+    /// Name as it's given on the command line and in manifests
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Blake3 => "blake3",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Xxh3 => "xxh3",
+            HashAlgo::Crc32 => "crc32",
+            HashAlgo::Crc32c => "crc32c",
+        }
+    }
+
+    /// Digest of `data` all at once
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashAlgo::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgo::Xxh3 => Hasher::Xxh3(Box::default()),
+            HashAlgo::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgo::Crc32c => Hasher::Crc32c(0),
+        }
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "blake3" => Ok(HashAlgo::Blake3),
+            "sha256" => Ok(HashAlgo::Sha256),
+            "xxh3" => Ok(HashAlgo::Xxh3),
+            "crc32" => Ok(HashAlgo::Crc32),
+            "crc32c" => Ok(HashAlgo::Crc32c),
+            _ => Err(format!("Unknown hash algorithm: {}", s)),
+        }
+    }
+}
+
+/// Incremental hasher for any of the supported algorithms
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    Crc32(crc32fast::Hasher),
+    // The CRC so far
+    Crc32c(u32),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Xxh3(h) => h.update(data),
+            Hasher::Crc32(h) => h.update(data),
+            Hasher::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+        }
+    }
+
+    /// Digest bytes, the checksums in big endian as they're usually
+    /// printed
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Xxh3(h) => h.digest().to_be_bytes().to_vec(),
+            Hasher::Crc32(h) => h.finalize().to_be_bytes().to_vec(),
+            Hasher::Crc32c(crc) => crc.to_be_bytes().to_vec(),
+        }
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(format!("Invalid hex string: {}", s));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| format!("Invalid hex {}: {}", s, e))
+        })
+        .collect()
+}
+
+/// Expected digest of some data, given as HASH:ALGO
+#[derive(Clone, Debug)]
+pub struct ExpectedHash {
+    pub digest: Vec<u8>,
+    pub algo: HashAlgo,
+}
+
+impl FromStr for ExpectedHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digest, algo) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected HASH:ALGO, got {}", s))?;
+        let algo: HashAlgo = algo.parse()?;
+        let digest = from_hex(digest)?;
+        if digest.len() != algo.digest_len() {
+            return Err(format!(
+                "A {:?} digest is {} bytes, got {}",
+                algo,
+                algo.digest_len(),
+                digest.len()
+            ));
+        }
+        Ok(ExpectedHash { digest, algo })
+    }
+}
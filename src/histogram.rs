@@ -0,0 +1,57 @@
+// Where the differences are: the synced range split into equal buckets,
+// each counting the bytes of differing blocks that fall into it. Shows
+// whether changes are confined to one spot, like a single partition, or
+// spread all over.
+
+const BUCKETS: u64 = 50;
+
+// Width of the bar of a bucket that differs completely
+const BAR_WIDTH: u64 = 50;
+
+pub struct Histogram {
+    size: u64,
+    bucket: u64,
+    diff: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn new(size: u64) -> Self {
+        // Fewer buckets for less than one byte each
+        let bucket = std::cmp::max(size.div_ceil(BUCKETS), 1);
+        Histogram {
+            size,
+            bucket,
+            diff: vec![0; size.div_ceil(bucket) as usize],
+        }
+    }
+
+    /// Count a differing block of `length` bytes at `pos`, a block
+    /// across a bucket boundary counts in both
+    pub fn record(&mut self, pos: u64, length: usize) {
+        let end = std::cmp::min(pos + length as u64, self.size);
+        let mut from = pos;
+        while from < end {
+            let i = from / self.bucket;
+            let to = std::cmp::min((i + 1) * self.bucket, end);
+            self.diff[i as usize] += to - from;
+            from = to;
+        }
+    }
+
+    /// A line per bucket, with its share of differing bytes as a bar.
+    /// Any difference at all shows, however little.
+    pub fn report(&self) -> String {
+        let mut ret = format!("{:>16} {:>9}\n", "offset", "differing");
+        for (i, diff) in self.diff.iter().enumerate() {
+            let start = i as u64 * self.bucket;
+            let length = std::cmp::min(self.bucket, self.size - start);
+            ret.push_str(&format!(
+                "{:>16} {:>8.1}% |{}\n",
+                start,
+                *diff as f64 * 100.0 / length as f64,
+                "#".repeat((diff * BAR_WIDTH).div_ceil(length) as usize)
+            ));
+        }
+        ret
+    }
+}
@@ -0,0 +1,262 @@
+use {
+    crate::{manifest::Manifest, source::BlockSource},
+    async_trait::async_trait,
+    reqwest::{header, StatusCode},
+    std::{
+        io,
+        os::unix::fs::FileExt,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    },
+};
+
+// A source can be an image served over HTTP(S) by anything that answers
+// Range requests, which is about every web server and CDN. With a
+// manifest of the image, only the blocks the target doesn't have already
+// are downloaded, the way zsync does it.
+
+/// Whether a source is given as an http:// or https:// URL
+pub fn is_url(spec: &str) -> bool {
+    spec.starts_with("http://") || spec.starts_with("https://")
+}
+
+fn failed(status: StatusCode, url: &str) -> io::Error {
+    io::Error::other(format!("{} answered {}", url, status))
+}
+
+// The size at the end of a Content-Range, bytes 0-0/SIZE or bytes */SIZE
+fn total(response: &reqwest::Response) -> Option<u64> {
+    let range = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    range.rsplit_once('/')?.1.parse().ok()
+}
+
+/// The manifest at a URL
+pub async fn manifest(url: &str) -> io::Result<Manifest> {
+    let response = reqwest::get(url).await.map_err(io::Error::other)?;
+    if !response.status().is_success() {
+        return Err(failed(response.status(), url));
+    }
+    let body = response.bytes().await.map_err(io::Error::other)?;
+    Manifest::parse(url, body.as_ref())
+}
+
+/// A file downloaded in Range requests as it's read. Skipping over a part
+/// downloads nothing.
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+    size: u64,
+    pos: u64,
+}
+
+impl HttpSource {
+    pub async fn open(url: &str) -> io::Result<Self> {
+        let client = reqwest::Client::new();
+        // A range of one byte tells the size, and that ranges are served
+        let response = client
+            .get(url)
+            .header(header::RANGE, "bytes=0-0")
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        let size = match response.status() {
+            StatusCode::PARTIAL_CONTENT => total(&response),
+            // An empty file has no first byte
+            StatusCode::RANGE_NOT_SATISFIABLE => total(&response).filter(|size| *size == 0),
+            status if status.is_success() => {
+                return Err(io::Error::other(format!(
+                    "{} is served without Range requests",
+                    url
+                )))
+            }
+            status => return Err(failed(status, url)),
+        };
+        let size = size.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is served without its size", url),
+            )
+        })?;
+        Ok(HttpSource {
+            client,
+            url: url.to_string(),
+            size,
+            pos: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockSource for HttpSource {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.size - self.pos) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut response = self
+            .client
+            .get(&self.url)
+            .header(
+                header::RANGE,
+                format!("bytes={}-{}", self.pos, self.pos + len as u64 - 1),
+            )
+            .send()
+            .await
+            .map_err(io::Error::other)?;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(failed(response.status(), &self.url));
+        }
+        let mut filled = 0;
+        while let Some(chunk) = response.chunk().await.map_err(io::Error::other)? {
+            if filled + chunk.len() > len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} sent more than the range asked for", self.url),
+                ));
+            }
+            buf[filled..filled + chunk.len()].copy_from_slice(&chunk);
+            filled += chunk.len();
+        }
+        if filled < len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} ended {} bytes into a range", self.url, filled),
+            ));
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos.saturating_add(len), self.size);
+        Ok(())
+    }
+}
+
+/// A source whose blocks the target already has, as a manifest of the
+/// source tells, are taken from the target and skipped on the source.
+/// Only the rest is read from it, in runs as long as they get.
+pub struct Reuse {
+    inner: Box<dyn BlockSource>,
+    manifest: Arc<Manifest>,
+    target: Arc<std::fs::File>,
+    // Where the target starts being synced
+    offset: u64,
+    pos: u64,
+    reused: Arc<AtomicU64>,
+    // What the target has where the source is read, taken along to the
+    // blocking pool
+    scratch: Option<Vec<u8>>,
+}
+
+impl Reuse {
+    pub fn new(
+        inner: Box<dyn BlockSource>,
+        manifest: Arc<Manifest>,
+        target: std::fs::File,
+        offset: u64,
+        reused: Arc<AtomicU64>,
+    ) -> Self {
+        Reuse {
+            inner,
+            manifest,
+            target: Arc::new(target),
+            offset,
+            pos: 0,
+            reused,
+            scratch: Some(Vec::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for Reuse {
+    async fn size(&mut self) -> Option<u64> {
+        self.inner.size().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut scratch = self.scratch.take().unwrap_or_default();
+        scratch.resize(buf.len(), 0);
+        let (target, at) = (self.target.clone(), self.offset + self.pos);
+        // A target that can't be read there has nothing to reuse
+        let (scratch, on_target) = tokio::task::spawn_blocking(move || {
+            let mut filled = 0;
+            while filled < scratch.len() {
+                match target.read_at(&mut scratch[filled..], at + filled as u64) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(_) => break,
+                }
+            }
+            (scratch, filled)
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        // Runs of blocks to take from the target or to read
+        let mut runs: Vec<(usize, usize, bool)> = Vec::new();
+        let mut at = 0;
+        while at < buf.len() {
+            let pos = self.pos + at as u64;
+            let (len, reuse) = match self.manifest.get(pos) {
+                Some(entry) if entry.length > 0 && at + entry.length <= buf.len() => (
+                    entry.length,
+                    at + entry.length <= on_target
+                        && self.manifest.check(pos, &scratch[at..at + entry.length]),
+                ),
+                // Not a whole block of the manifest
+                _ => (buf.len() - at, false),
+            };
+            match runs.last_mut() {
+                Some(last) if last.2 == reuse => last.1 += len,
+                _ => runs.push((at, len, reuse)),
+            }
+            at += len;
+        }
+
+        let mut filled = 0;
+        for (at, len, reuse) in runs {
+            if reuse {
+                buf[at..at + len].copy_from_slice(&scratch[at..at + len]);
+                self.inner.skip(len as u64).await?;
+                self.reused.fetch_add(len as u64, Ordering::Relaxed);
+                filled = at + len;
+            } else {
+                let n = self.inner.read(&mut buf[at..at + len]).await?;
+                filled = at + n;
+                if n < len {
+                    break;
+                }
+            }
+        }
+        self.scratch = Some(scratch);
+        self.pos += filled as u64;
+        Ok(filled)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let skipped = self.inner.skip_hole(len).await?;
+        if skipped {
+            self.pos += len as u64;
+        }
+        Ok(skipped)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.inner.skip(len).await?;
+        self.pos += len;
+        Ok(())
+    }
+}
@@ -0,0 +1,237 @@
+use {
+    crate::{qcow2::Qcow2, source::BlockSource, vhd::Vhd, vhdx::Vhdx, vmdk::Vmdk},
+    async_trait::async_trait,
+    flate2::{Decompress, FlushDecompress},
+    std::{
+        convert::TryInto,
+        io::{self, Read},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncReadExt, AsyncSeekExt},
+    },
+};
+
+// Disk images of virtual machines keep their content in clusters, each
+// found through tables in the file or not there at all, which reads as
+// zeroes. A source given as FORMAT:PATH is read through the tables, so
+// the content is synced instead of the file.
+
+/// Image formats a source can be read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Qcow2,
+    Vhd,
+    Vhdx,
+    Vmdk,
+}
+
+/// The format and the path of a `FORMAT:PATH` source
+pub fn parse(spec: &str) -> Option<(Format, &str)> {
+    let (format, path) = spec.split_once(':')?;
+    match format {
+        "qcow2" => Some((Format::Qcow2, path)),
+        "vhd" => Some((Format::Vhd, path)),
+        "vhdx" => Some((Format::Vhdx, path)),
+        "vmdk" => Some((Format::Vmdk, path)),
+        _ => None,
+    }
+}
+
+pub fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn be32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+pub fn be64(b: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+pub fn le16(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+pub fn le32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+pub fn le64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// Read all of `buf` at `pos` of the file
+pub async fn read_at(file: &mut File, pos: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(pos)).await?;
+    file.read_exact(buf).await.map(drop)
+}
+
+/// How a compressed cluster is compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    // Without the zlib header
+    Deflate,
+    Zlib,
+    Zstd,
+}
+
+/// Where the content of a cluster is in the file
+pub enum Cluster {
+    Zero,
+    Data(u64),
+    /// `len` bytes at `offset` hold it, or less if the file ends first
+    Compressed {
+        offset: u64,
+        len: usize,
+        codec: Codec,
+    },
+}
+
+/// The tables of an image format, mapping its clusters
+#[async_trait]
+pub trait Layout: Send {
+    /// Size of the content in bytes
+    fn size(&self) -> u64;
+
+    fn cluster_size(&self) -> u64;
+
+    /// Where cluster `index` of the content is
+    async fn cluster(&mut self, file: &mut File, index: u64) -> io::Result<Cluster>;
+}
+
+fn decompress(codec: Codec, input: &[u8], out: &mut [u8]) -> io::Result<()> {
+    let filled = match codec {
+        Codec::Deflate | Codec::Zlib => {
+            let mut inflate = Decompress::new(codec == Codec::Zlib);
+            inflate
+                .decompress(input, out, FlushDecompress::Finish)
+                .map_err(|e| invalid(format!("Bad compressed cluster: {}", e)))?;
+            inflate.total_out() as usize
+        }
+        // Whatever follows the frame is padding up to the next sector
+        Codec::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(input)?.single_frame();
+            decoder.read_exact(out)?;
+            out.len()
+        }
+    };
+    if filled < out.len() {
+        return Err(invalid(format!(
+            "A compressed cluster holds {} bytes instead of {}",
+            filled,
+            out.len()
+        )));
+    }
+    Ok(())
+}
+
+/// The content of an image, read cluster by cluster. Clusters of zeroes
+/// are skipped as holes.
+pub struct ImageSource<L> {
+    file: File,
+    file_size: u64,
+    layout: L,
+    pos: u64,
+    // The compressed cluster read last, by its offset
+    decompressed: Option<(u64, Vec<u8>)>,
+}
+
+impl<L: Layout> ImageSource<L> {
+    pub async fn new(file: File, layout: L) -> io::Result<Self> {
+        let file_size = file.metadata().await?.len();
+        Ok(ImageSource {
+            file,
+            file_size,
+            layout,
+            pos: 0,
+            decompressed: None,
+        })
+    }
+}
+
+/// Open the image at `path`
+pub async fn open(format: Format, path: &str) -> io::Result<Box<dyn BlockSource>> {
+    let mut file = File::open(path).await?;
+    match format {
+        Format::Qcow2 => {
+            let layout = Qcow2::open(&mut file, path).await?;
+            Ok(Box::new(ImageSource::new(file, layout).await?))
+        }
+        Format::Vhd => {
+            let layout = Vhd::open(&mut file, path).await?;
+            Ok(Box::new(ImageSource::new(file, layout).await?))
+        }
+        Format::Vhdx => {
+            let layout = Vhdx::open(&mut file, path).await?;
+            Ok(Box::new(ImageSource::new(file, layout).await?))
+        }
+        Format::Vmdk => {
+            let layout = Vmdk::open(&mut file, path).await?;
+            Ok(Box::new(ImageSource::new(file, layout).await?))
+        }
+    }
+}
+
+#[async_trait]
+impl<L: Layout> BlockSource for ImageSource<L> {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.layout.size())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cluster_size = self.layout.cluster_size();
+        let end = std::cmp::min(self.pos + buf.len() as u64, self.layout.size());
+        let mut filled = 0;
+        while self.pos < end {
+            let within = self.pos % cluster_size;
+            let n = std::cmp::min(cluster_size - within, end - self.pos) as usize;
+            let chunk = &mut buf[filled..filled + n];
+            match self
+                .layout
+                .cluster(&mut self.file, self.pos / cluster_size)
+                .await?
+            {
+                Cluster::Zero => chunk.fill(0),
+                Cluster::Data(offset) => read_at(&mut self.file, offset + within, chunk).await?,
+                Cluster::Compressed { offset, len, codec } => {
+                    if self.decompressed.as_ref().map(|(at, _)| *at) != Some(offset) {
+                        let len = std::cmp::min(len as u64, self.file_size.saturating_sub(offset));
+                        let mut input = vec![0; len as usize];
+                        read_at(&mut self.file, offset, &mut input).await?;
+                        let mut out = vec![0; cluster_size as usize];
+                        decompress(codec, &input, &mut out)?;
+                        self.decompressed = Some((offset, out));
+                    }
+                    let (_, data) = self.decompressed.as_ref().unwrap();
+                    chunk.copy_from_slice(&data[within as usize..within as usize + n]);
+                }
+            }
+            filled += n;
+            self.pos += n as u64;
+        }
+        Ok(filled)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let end = self.pos + len as u64;
+        if len == 0 || end > self.layout.size() {
+            return Ok(false);
+        }
+        let cluster_size = self.layout.cluster_size();
+        for index in self.pos / cluster_size..end.div_ceil(cluster_size) {
+            match self.layout.cluster(&mut self.file, index).await? {
+                Cluster::Zero => (),
+                _ => return Ok(false),
+            }
+        }
+        self.pos = end;
+        Ok(true)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos.saturating_add(len), self.layout.size());
+        Ok(())
+    }
+}
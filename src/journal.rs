@@ -0,0 +1,73 @@
+use {
+    std::{
+        convert::TryInto,
+        io::{self, Read},
+        os::unix::fs::FileExt,
+    },
+    tokio::{fs::File, io::AsyncWriteExt},
+};
+
+// The journal starts with this, followed by records of a little endian
+// u64 offset, a u32 length and the original content of the target there.
+const MAGIC: &[u8; 8] = b"SSDJRNL1";
+
+const RECORD_HEADER: usize = 12;
+
+/// Write-ahead journal of target blocks about to be overwritten
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    pub async fn create(path: &str) -> io::Result<Self> {
+        let mut file = File::create(path).await?;
+        file.write_all(MAGIC).await?;
+        file.sync_all().await?;
+        Ok(Journal { file })
+    }
+
+    /// Record the old content at `pos`. Only returns once it's on disk,
+    /// so the target may be overwritten afterwards.
+    pub async fn append(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+        let mut header = [0; RECORD_HEADER];
+        header[..8].copy_from_slice(&pos.to_le_bytes());
+        header[8..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        self.file.write_all(&header).await?;
+        self.file.write_all(data).await?;
+        self.file.sync_data().await
+    }
+}
+
+/// Restore the target to its state before the journaled sync. Returns the
+/// number of blocks restored. A torn last record is ignored: it was being
+/// written when the sync died, so its block was never overwritten.
+pub fn rollback(journal: &str, target: &str) -> io::Result<usize> {
+    let mut data = Vec::new();
+    std::fs::File::open(journal)?.read_to_end(&mut data)?;
+    if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not an ssdsync journal", journal),
+        ));
+    }
+
+    let mut records = Vec::new();
+    let mut rest = &data[MAGIC.len()..];
+    while rest.len() >= RECORD_HEADER {
+        let pos = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let length = u32::from_le_bytes(rest[8..RECORD_HEADER].try_into().unwrap()) as usize;
+        if rest.len() < RECORD_HEADER + length {
+            break;
+        }
+        records.push((pos, &rest[RECORD_HEADER..RECORD_HEADER + length]));
+        rest = &rest[RECORD_HEADER + length..];
+    }
+
+    let target = std::fs::OpenOptions::new().write(true).open(target)?;
+    // Newest first, so the oldest content of a block wins
+    for (pos, block) in records.iter().rev() {
+        target.write_all_at(block, *pos)?;
+    }
+    target.sync_all()?;
+    Ok(records.len())
+}
@@ -0,0 +1,133 @@
+use {
+    crate::{
+        error::{self, Error},
+        Summary, SyncArgs,
+    },
+    nix::{
+        fcntl::{open, OFlag},
+        sys::stat::Mode,
+        unistd::{close, dup, dup2},
+    },
+    std::{fs, future::Future, io, io::Write, time::Instant},
+};
+
+const STDOUT: i32 = 1;
+
+// A JSON string, quoted and escaped
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn number(n: Option<u64>) -> String {
+    n.map_or("null".to_string(), |n| n.to_string())
+}
+
+fn system(action: &'static str) -> impl Fn(nix::Error) -> Error {
+    move |source| Error::System { action, source }
+}
+
+/// Run a sync with its messages on stdout sent nowhere, then print what
+/// it did as a single JSON document there. Errors still go to stderr, and
+/// the summary is handed back as well.
+pub async fn report<F>(args: &SyncArgs, sync: F) -> error::Result<Summary>
+where
+    F: Future<Output = error::Result<Summary>>,
+{
+    let _ = std::io::stdout().flush();
+    let saved = dup(STDOUT).map_err(system("keep stdout"))?;
+    let null =
+        open("/dev/null", OFlag::O_WRONLY, Mode::empty()).map_err(system("open /dev/null"))?;
+    dup2(null, STDOUT).map_err(system("silence stdout"))?;
+    let _ = close(null);
+
+    let start = Instant::now();
+    let result = sync.await;
+    let duration = start.elapsed();
+
+    let _ = std::io::stdout().flush();
+    dup2(saved, STDOUT).map_err(system("restore stdout"))?;
+    let _ = close(saved);
+
+    let summary = result.as_ref().ok();
+    // Nothing is written on a dry run or with the target as the reference,
+    // whatever differs
+    let blocks_written = summary.map(|s| if s.written > 0 { s.different } else { 0 });
+    let errors = match &result {
+        Ok(_) => Vec::new(),
+        Err(e) => vec![string(&e.to_string())],
+    };
+    println!(
+        "{{\"source\":{},\"target\":{},\"source_size\":{},\"target_size\":{},\
+         \"blocks_scanned\":{},\"blocks_different\":{},\"blocks_written\":{},\
+         \"bytes_written\":{},\"duration\":{:.3},\"errors\":[{}]}}",
+        string(args.source.as_deref().unwrap_or_default()),
+        string(
+            summary
+                .map(|s| s.target.as_str())
+                .or(args.target.as_deref())
+                .unwrap_or_default()
+        ),
+        number(summary.and_then(|s| s.source_size)),
+        number(summary.map(|s| s.target_size)),
+        number(summary.map(|s| s.blocks)),
+        number(summary.map(|s| s.different)),
+        number(blocks_written),
+        number(summary.map(|s| s.written)),
+        duration.as_secs_f64(),
+        errors.join(",")
+    );
+    result
+}
+
+/// Write everything known about a finished sync to `path`, for keeping
+pub fn write_stats(path: &str, args: &SyncArgs, summary: &Summary) -> io::Result<()> {
+    let command_line = std::env::args().map(|arg| string(&arg)).collect::<Vec<_>>();
+    let phases = summary
+        .phases
+        .iter()
+        .map(|(phase, took)| format!("{}:{:.3}", string(phase), took.as_secs_f64()))
+        .collect::<Vec<_>>();
+    let duration: f64 = summary
+        .phases
+        .iter()
+        .map(|(_, took)| took.as_secs_f64())
+        .sum();
+    let ratio = match summary.blocks {
+        0 => 0.0,
+        blocks => summary.different as f64 / blocks as f64,
+    };
+    let stats = format!(
+        "{{\"command_line\":[{}],\"source\":{},\"target\":{},\"source_size\":{},\
+         \"target_size\":{},\"phases\":{{{}}},\"duration\":{:.3},\"bytes_scanned\":{},\
+         \"bytes_read\":{},\"bytes_written\":{},\"bytes_skipped\":{},\"blocks_scanned\":{},\
+         \"blocks_different\":{},\"diff_ratio\":{:.6},\"retries\":{},\"bytes_unreadable\":{}}}\n",
+        command_line.join(","),
+        string(args.source.as_deref().unwrap_or_default()),
+        string(&summary.target),
+        number(summary.source_size),
+        summary.target_size,
+        phases.join(","),
+        duration,
+        summary.scanned,
+        summary.read,
+        summary.written,
+        summary.scanned.saturating_sub(summary.written),
+        summary.blocks,
+        summary.different,
+        ratio,
+        summary.retries,
+        summary.unreadable
+    );
+    fs::write(path, stats)
+}
@@ -0,0 +1,4550 @@
+//! Block level diff-sync: only the blocks of a target that differ from the
+//! source are written. The `ssdsync` binary is a thin layer over `run`, a
+//! sync can be run from other programs with a `SyncEngine`.
+
+mod adaptive;
+mod batch;
+mod bitmap;
+mod bmap;
+mod cache;
+mod checkpoint;
+mod compare;
+mod config;
+mod control;
+mod device;
+mod direct;
+mod engine;
+pub mod error;
+mod fanout;
+mod fsfreeze;
+mod gpt;
+mod hash;
+mod histogram;
+mod http;
+mod image;
+mod journal;
+mod json;
+mod lock;
+mod log;
+mod loopdev;
+mod lvm;
+mod manifest;
+mod mapfile;
+mod metrics;
+mod mmap;
+mod multigrain;
+mod nbd;
+mod oci;
+mod preflight;
+mod qcow2;
+mod reflink;
+mod regions;
+mod remote;
+mod s3;
+mod smart;
+mod source;
+mod sparse;
+mod stream;
+mod throttle;
+mod tls;
+mod uring;
+mod vhd;
+mod vhdx;
+mod vmdk;
+
+pub use {
+    engine::{Summary, SyncEngine},
+    error::Error,
+    log::init as init_log,
+    source::{BlockSource, Segment},
+};
+
+use {
+    clap::{FromArgMatches, Parser, Subcommand, ValueEnum},
+    control::Control,
+    error::Context,
+    hash::{ExpectedHash, HashAlgo},
+    indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle},
+    nix::{
+        fcntl::{fallocate, posix_fadvise, FallocateFlags, PosixFadviseAdvice},
+        ioctl_read,
+        sched::{sched_setaffinity, CpuSet},
+        sys::resource::{getrlimit, setrlimit, Resource},
+        unistd::Pid,
+    },
+    std::{
+        collections::VecDeque,
+        io::{SeekFrom, Write},
+        os::unix::{
+            fs::{FileExt, FileTypeExt, MetadataExt},
+            io::AsRawFd,
+        },
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    },
+    tokio::{
+        fs::{File, OpenOptions},
+        io::{AsyncReadExt, AsyncSeekExt},
+        join,
+        net::UnixListener,
+        signal::unix::{signal, SignalKind},
+        sync::mpsc,
+    },
+};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+#[clap(args_override_self = true)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Only run on these CPU cores, e.g. 2,3 or 0-3
+    #[clap(long, global = true, value_name = "CPUS")]
+    pub cpu_affinity: Option<CpuList>,
+
+    /// Log more: -v what's being done, -vv the decisions taken on the way,
+    /// -vvv every block read and written
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log to the end of this file instead of to stderr
+    #[clap(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+
+    /// Take options from this configuration file, ~/.config/ssdsync.toml
+    /// if it's there otherwise
+    #[clap(long, global = true, value_name = "PATH")]
+    config: Option<String>,
+
+    /// Take the options of this profile of the configuration file, and its
+    /// source and target unless they're given
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Serve a sync from the other side of an SSH connection: the target
+    /// is read and written for it over stdin and stdout
+    #[clap(long, value_name = "TARGET", exclusive = true, hide = true)]
+    server: Option<String>,
+
+    #[clap(flatten)]
+    sync: SyncArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Sync a source onto one or more targets, the same as without a
+    /// subcommand
+    Sync {
+        #[clap(flatten)]
+        sync: Box<SyncArgs>,
+    },
+
+    /// Clone a whole disk, partition table and boot sectors included
+    Clone {
+        #[clap(flatten)]
+        sync: Box<SyncArgs>,
+
+        /// Give the target's GPT new disk and partition GUIDs afterwards,
+        /// so the clone can be attached next to the original
+        #[clap(long)]
+        new_guid: bool,
+    },
+
+    /// Write the blocks where the target differs from the source into a
+    /// patch file, to be applied onto a copy of the target elsewhere
+    ///
+    /// The target isn't touched. The patch is a sparse image, the same as
+    /// --sparse-image-out writes.
+    Diff {
+        /// Patch file to write
+        #[clap(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["sparse_image_out", "oci_out", "apply_sparse_image", "more_targets"]
+        )]
+        output: String,
+
+        #[clap(flatten)]
+        sync: Box<SyncArgs>,
+    },
+
+    /// Write the blocks of a patch file made by diff onto a target
+    ///
+    /// The whole patch is checked before the target is touched, and the
+    /// target has to be at least as large as the source it was made of.
+    Apply {
+        /// Patch file written by diff
+        patch: String,
+
+        /// Target file or device to write the blocks onto
+        target: String,
+    },
+
+    /// Restore a target to its state before a sync run with --journal
+    Rollback {
+        /// Journal written by the sync
+        #[clap(long, value_name = "PATH")]
+        journal: String,
+
+        /// Target file or device the journal was written for
+        target: String,
+    },
+
+    /// Check a target against a manifest of source block hashes
+    ///
+    /// The source isn't read and nothing is written: the manifest only
+    /// tells which blocks differ, a sync with the source fixes them.
+    Verify(VerifyArgs),
+
+    /// Serve syncs onto a target from other machines over TCP
+    ///
+    /// A sync onto tcp://HOST:PORT connects and is served here. There is
+    /// no authentication or encryption, only use it on a trusted network,
+    /// or with --tls-cert and --tls-key for syncs onto tls://HOST:PORT.
+    Serve {
+        /// Address and port to listen on, e.g. 0.0.0.0:9000
+        #[clap(long, value_name = "ADDR")]
+        listen: String,
+
+        /// Serve over TLS with this certificate chain, a PEM file
+        #[clap(long, value_name = "PATH", requires = "tls_key")]
+        tls_cert: Option<String>,
+
+        /// The private key of the certificate, a PEM file
+        #[clap(long, value_name = "PATH", requires = "tls_cert")]
+        tls_key: Option<String>,
+
+        /// Only let in clients with a certificate signed by one of these,
+        /// a PEM file
+        #[clap(long, value_name = "PATH", requires = "tls_cert")]
+        tls_ca: Option<String>,
+
+        /// Stop after serving one sync, with its result
+        #[clap(long)]
+        once: bool,
+
+        /// Target file or device the syncs write to
+        target: String,
+    },
+
+    /// Export a file or device read-only over NBD
+    ///
+    /// Other machines sync from it as nbd://HOST:PORT/, or with any NBD
+    /// client. There is no authentication or encryption, only use it on
+    /// a trusted network.
+    NbdServe {
+        /// Address and port to listen on
+        #[clap(long, value_name = "ADDR", default_value = "0.0.0.0:10809")]
+        listen: String,
+
+        /// Stop after serving one client
+        #[clap(long)]
+        once: bool,
+
+        /// File or device to export
+        device: String,
+    },
+
+    /// Print the hash of a source, and write a manifest of its blocks to
+    /// check a target against later
+    Hash {
+        /// Source file or device, anything a sync reads from
+        source: String,
+
+        /// blake3, sha256, xxh3, crc32 or crc32c
+        #[clap(long, value_name = "ALGO", default_value = "blake3")]
+        hash: HashAlgo,
+
+        /// Write a manifest of the blocks to this file, for verify and
+        /// --expect-source-manifest
+        #[clap(long, value_name = "PATH")]
+        write_manifest: Option<String>,
+
+        /// Size of the blocks of the manifest, e.g. 64K
+        #[clap(short, long, default_value_t = 16 * 1024, value_parser = parse_block_size)]
+        block_size: usize,
+    },
+
+    /// Sync several source and target pairs one after the other
+    Batch {
+        /// File with a "SOURCE TARGET" pair on each line
+        pairs: String,
+
+        /// Sync options used for every pair, after a --
+        #[clap(last = true, value_name = "OPTIONS")]
+        options: Vec<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct VerifyArgs {
+    /// Manifest of the source, as used with --expect-source-manifest
+    #[clap(long, value_name = "PATH")]
+    source_manifest: String,
+
+    /// Target file or device to check
+    target: String,
+
+    /// Report differences as byte offsets or as block numbers
+    #[clap(long, value_enum, default_value_t = ReportUnits::Bytes)]
+    report_units: ReportUnits,
+
+    /// Save how far the check got to this file now and then, and remove
+    /// it once the check is complete
+    #[clap(long, value_name = "PATH")]
+    checkpoint: Option<String>,
+
+    /// Continue an interrupted check from its checkpoint
+    #[clap(long, requires = "checkpoint")]
+    resume: bool,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+struct SyncArgs {
+    /// Source file or device (UUID=, PARTUUID=, LABEL= and PARTLABEL= are resolved),
+    /// overlay:BASE:DELTA to read a sparse DELTA file merged onto BASE,
+    /// segments:FILE to assemble an image from the "OFFSET LENGTH PATH" lines of FILE,
+    /// qcow2:PATH, vhd:PATH, vhdx:PATH or vmdk:PATH to read the content of a VM image,
+    /// an http:// or https:// URL to download it in Range requests,
+    /// nbd://HOST[:PORT]/EXPORT to read an export of an NBD server, or - for stdin
+    #[clap(required = true)]
+    source: Option<String>,
+
+    /// Target file or device (UUID=, PARTUUID=, LABEL= and PARTLABEL= are resolved),
+    /// user@host:PATH to sync onto a file or device of another machine over SSH, or
+    /// tcp://HOST:PORT or tls://HOST:PORT for one served there by ssdsync serve,
+    /// s3://BUCKET/PREFIX to back the source up into object storage,
+    /// nbd://HOST[:PORT]/EXPORT for an export of an NBD server, or - to
+    /// write the whole source to stdout
+    #[clap(required = true)]
+    target: Option<String>,
+
+    /// More targets, the source is read once and synced onto all of them
+    /// at the same time
+    #[clap(
+        value_name = "TARGETS",
+        conflicts_with_all = [
+            "checkpoint",
+            "journal",
+            "control_socket",
+            "metrics_listen",
+            "slow_log",
+            "sparse_image_out",
+            "bitmap_out",
+            "mapfile",
+            "badblocks_out",
+            "oci_out",
+            "write_manifest",
+            "apply_sparse_image",
+            "dual_bar"
+        ]
+    )]
+    more_targets: Vec<String>,
+
+    /// Size of blocks in bytes to read/write at once, e.g. 16384 or 64K
+    #[clap(short, long, default_value_t = 16 * 1024, value_parser = parse_block_size)]
+    block_size: usize,
+
+    /// Read the source this many bytes at a time instead of the block
+    /// size. Blocks are compared in the largest size both read sizes are
+    /// a multiple of.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    source_block_size: Option<u64>,
+
+    /// Read the target this many bytes at a time instead of the block
+    /// size. Blocks are compared in the largest size both read sizes are
+    /// a multiple of.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    target_block_size: Option<u64>,
+
+    /// Adaptive block sizing: of a differing block, only write the part
+    /// that differs, narrowed down in pieces that shrink down to MIN
+    /// where blocks differ and grow back to the block size where they
+    /// don't
+    #[clap(long, value_name = "MIN", value_parser = parse_size)]
+    adaptive: Option<u64>,
+
+    /// After syncing, check the target against a hash footer at OFFSET
+    /// (negative counts from the end) covering everything before it, e.g. -32:sha256
+    #[clap(long, value_name = "OFFSET:ALGO", allow_hyphen_values = true)]
+    verify_footer: Option<FooterSpec>,
+
+    /// Log offsets of reads that took much longer than the median to this file
+    #[clap(long, value_name = "PATH")]
+    slow_log: Option<String>,
+
+    /// Show separate progress bars for bytes scanned and bytes written
+    #[clap(long)]
+    dual_bar: bool,
+
+    /// Listen for pause, resume and status commands on this unix socket
+    #[clap(long, value_name = "PATH")]
+    control_socket: Option<String>,
+
+    /// Serve Prometheus metrics of the sync over HTTP on this address,
+    /// e.g. 127.0.0.1:9184
+    #[clap(long, value_name = "ADDR")]
+    metrics_listen: Option<String>,
+
+    /// Save the original content of every block before overwriting it,
+    /// so the target can be restored with the rollback command
+    #[clap(long, value_name = "PATH")]
+    journal: Option<String>,
+
+    /// Don't touch the target, write the differing blocks and an index
+    /// of where they go into a sparse image instead
+    #[clap(long, value_name = "PATH")]
+    sparse_image_out: Option<String>,
+
+    /// Write a bitmap of the blocks that differed to this file, one bit
+    /// per block after a header with the block size and count
+    #[clap(long, value_name = "PATH", conflicts_with = "resume")]
+    bitmap_out: Option<String>,
+
+    /// Don't touch the target, write the differing regions into a tar,
+    /// one entry per region named by its offset, with a JSON manifest
+    #[clap(long, value_name = "PATH", conflicts_with = "sparse_image_out")]
+    oci_out: Option<String>,
+
+    /// The source is a sparse image, write its blocks onto the target
+    #[clap(long, conflicts_with = "sparse_image_out")]
+    apply_sparse_image: bool,
+
+    /// Which side is trusted. With target, nothing is written and every
+    /// block where the source deviates from the target is reported.
+    #[clap(long, value_enum, default_value_t = Reference::Source)]
+    reference: Reference,
+
+    /// Report differences as byte offsets or as block numbers
+    #[clap(long, value_enum, default_value_t = ReportUnits::Bytes)]
+    report_units: ReportUnits,
+
+    /// How blocks are compared: exact, words:N[:swap] to compare N byte
+    /// words, byte-swapped on the target with swap, or text:normalize-eol
+    /// to take CRLF and LF line endings as the same
+    #[clap(long, value_name = "HOW", default_value = "exact")]
+    compare: compare::Comparison,
+
+    /// Number of file descriptors to make sure are available before
+    /// starting, default is what this run needs
+    #[clap(long, value_name = "N")]
+    max_open_fds: Option<u64>,
+
+    /// Don't write, count differences at each of these granularities
+    /// (e.g. 4K,64K,1M) and print a table of them
+    #[clap(long, value_name = "SIZES", value_delimiter = ',', value_parser = parse_size)]
+    multigrain: Vec<u64>,
+
+    /// Use this as the size of the target instead of the detected one.
+    /// Nothing is ever written past it.
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    target_size: Option<u64>,
+
+    /// Only sync the blocks of the source a bmap of bmaptool maps, the
+    /// others are neither read nor written, like bmaptool copy does
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["expect_source_hash", "expect_source_manifest", "write_manifest"]
+    )]
+    bmap: Option<String>,
+
+    /// Skip what a GNU ddrescue mapfile has as finished or bad, and
+    /// record what's synced in it, a new one if it doesn't exist
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["dry_run", "sparse_image_out", "oci_out", "multigrain"]
+    )]
+    mapfile: Option<String>,
+
+    /// What to do about sectors of the source that still can't be read
+    /// after trying again in smaller parts: abort the sync at the first
+    /// read error, skip them and leave the target as it is there, or fill
+    /// them with a byte like fill=0x00
+    #[clap(long, value_name = "POLICY", default_value = "skip")]
+    on_read_error: OnReadError,
+
+    /// Write every range of the source that couldn't be read to this
+    /// file, an offset and a length in bytes per line
+    #[clap(long, value_name = "PATH")]
+    badblocks_out: Option<String>,
+
+    /// Fail the sync at the end if the source doesn't have this hash,
+    /// given in hex with its algorithm, like sha256. It's of the whole
+    /// source, what's past the end of a smaller target is read for it too.
+    #[clap(long, value_name = "HASH:ALGO")]
+    expect_source_hash: Option<ExpectedHash>,
+
+    /// Check every source block against a manifest before it's written,
+    /// stop at the first one that doesn't match. Sets the block size. It can
+    /// be a URL, and with an http:// or https:// source only the blocks the
+    /// target doesn't have already are downloaded.
+    #[clap(long, value_name = "PATH")]
+    expect_source_manifest: Option<String>,
+
+    /// The source is a logical volume: sync from a snapshot of it taken
+    /// up front, so the target ends up the way the source was at one
+    /// moment. SIZE is the room a thick snapshot has for what changes on
+    /// the source meanwhile, 10% of its size by default. The snapshot is
+    /// removed afterwards.
+    #[clap(long, value_name = "lvm[:SIZE]", conflicts_with = "verify_after")]
+    snapshot_source: Option<lvm::Spec>,
+
+    /// Freeze the filesystem mounted here while the source is read, so
+    /// that a device mounted while it's synced ends up consistent on the
+    /// target. auto finds where the source is mounted. With
+    /// --snapshot-source, it's only frozen while the snapshot is taken.
+    #[clap(long, value_name = "MOUNTPOINT")]
+    fsfreeze: Option<String>,
+
+    /// Thaw the filesystem of --fsfreeze after this many seconds even if
+    /// the sync isn't done, so what runs on it isn't held up for longer
+    #[clap(long, value_name = "SECONDS", requires = "fsfreeze")]
+    fsfreeze_timeout: Option<u64>,
+
+    /// The target is an image file: attach it to a loop device and sync
+    /// to that. The device is detached when ssdsync exits.
+    #[clap(long)]
+    loop_setup: bool,
+
+    /// Experimental: if source and target are regular files on the same
+    /// filesystem, share the source's extents for differing blocks
+    /// instead of copying them. Falls back to copying where it can't.
+    #[clap(long)]
+    reflink: bool,
+
+    /// Don't sync, only check everything that can be checked up front
+    /// and report all problems found at once
+    #[clap(long)]
+    preflight: bool,
+
+    /// Only sync if the target holds these hex bytes at OFFSET, like a
+    /// known signature, so the wrong disk isn't overwritten. Can be repeated.
+    #[clap(long, value_name = "OFFSET:HEX")]
+    assert_region: Vec<RegionAssertion>,
+
+    /// Save how far the sync got to this file now and then, and remove
+    /// it once the sync is complete
+    #[clap(long, value_name = "PATH")]
+    checkpoint: Option<String>,
+
+    /// Continue an interrupted sync from its checkpoint
+    #[clap(
+        long,
+        requires = "checkpoint",
+        conflicts_with_all = ["journal", "sparse_image_out", "oci_out", "expect_source_hash"]
+    )]
+    resume: bool,
+
+    /// Read the target's SMART wear counters before and after the sync
+    /// and show how they changed, next to what ssdsync wrote
+    #[clap(long)]
+    smart_report: bool,
+
+    /// Refuse to sync unless the target is a block device, so a typo
+    /// can't make a regular file of that name the target
+    #[clap(long, conflicts_with = "require_regular_file")]
+    require_block_device: bool,
+
+    /// Refuse to sync unless the target is a regular file, so an image
+    /// sync can't end up on a device
+    #[clap(long)]
+    require_regular_file: bool,
+
+    /// Write to a target device even though it or one of its partitions
+    /// is mounted or otherwise in use
+    #[clap(long)]
+    force: bool,
+
+    /// Don't open a target device exclusively. By default the kernel is
+    /// asked to, which it refuses while anything else does or has it
+    /// mounted, and nothing can mount it during the sync.
+    #[clap(long)]
+    no_excl: bool,
+
+    /// Open a source device exclusively as well, so nothing can mount it
+    /// while it's read
+    #[clap(long)]
+    excl_source: bool,
+
+    /// Wait for another ssdsync writing to the target to finish, instead
+    /// of failing right away
+    #[clap(long)]
+    wait_lock: bool,
+
+    /// Show where the differences are, as a histogram of differing bytes
+    /// over 50 equal parts of what was synced
+    #[clap(long)]
+    diff_histogram: bool,
+
+    /// Read and compare everything, but don't write anything, only
+    /// report how many blocks and bytes a sync would write
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "journal",
+            "sparse_image_out",
+            "oci_out",
+            "apply_sparse_image",
+            "checkpoint",
+            "reflink"
+        ]
+    )]
+    dry_run: bool,
+
+    /// After the sync, read the source and the target again and report
+    /// every block where they still differ
+    #[clap(
+        long,
+        conflicts_with_all = ["sparse_image_out", "oci_out", "multigrain", "dry_run"]
+    )]
+    verify_after: bool,
+
+    /// Read every block back from the target right after writing it, past
+    /// the page cache, and stop if it doesn't have what was written. For
+    /// devices that acknowledge writes they drop, slow as every block is
+    /// flushed on its own.
+    #[clap(long, conflicts_with_all = ["dry_run", "sparse_image_out", "oci_out"])]
+    verify_writes: bool,
+
+    /// Print nothing but a JSON document of the result at the end: the
+    /// sizes, the blocks scanned, differing and written, the bytes
+    /// written, the duration in seconds and the errors
+    #[clap(long, conflicts_with = "more_targets")]
+    json: bool,
+
+    /// Write the statistics of the run to this file at the end, as JSON:
+    /// how long each phase took, the bytes read, written and left alone,
+    /// the share of blocks that differed, the writes retried and the
+    /// command line
+    #[clap(long, value_name = "PATH", conflicts_with = "more_targets")]
+    stats_file: Option<String>,
+
+    /// Write the blocks the verify pass finds differing again
+    #[clap(long, requires = "verify_after", conflicts_with = "journal")]
+    repair: bool,
+
+    /// Write a manifest of the source's blocks to this file, with the
+    /// hash of each, to check the target against later on
+    #[clap(long, value_name = "PATH", conflicts_with = "resume")]
+    write_manifest: Option<String>,
+
+    /// How blocks are hashed in a manifest written and for a remote
+    /// target: blake3, sha256, xxh3, crc32 or crc32c. The checksums are faster,
+    /// but blocks can be made to match them on purpose.
+    #[clap(long, value_name = "ALGO", default_value = "blake3")]
+    hash: HashAlgo,
+
+    /// How the source and the target are read: tokio's blocking pool one
+    /// block at a time, io_uring with many reads queued ahead, or mmap,
+    /// copying the blocks out of the page cache without a system call for
+    /// each. Only plain files and devices are read with io_uring or mmap,
+    /// --direct reads with io_uring either way. With io_uring the writes
+    /// to the target are queued too, unless --verify-writes reads each one
+    /// back.
+    #[clap(long, value_enum, default_value_t = IoBackend::Tokio)]
+    io_backend: IoBackend,
+
+    /// Read and write with O_DIRECT, bypassing the page cache, so a sync of
+    /// a large device doesn't evict everything else from it. Reads go
+    /// through io_uring. The block size has to be a multiple of the
+    /// target's sectors, or of 4096 for a file.
+    #[clap(long)]
+    direct: bool,
+
+    /// Drop what's been read and written from the page cache, right
+    /// behind where the sync is, so a sync of a large device doesn't evict
+    /// what other programs have cached. Unlike --direct, reads still go
+    /// through the cache and are read ahead.
+    #[clap(long, conflicts_with = "direct")]
+    drop_cache: bool,
+
+    /// Blocks read ahead on each side, more keep a fast device busier at
+    /// the cost of a block of memory each
+    #[clap(long, value_name = "N", default_value_t = DEFAULT_BUFFERS, value_parser = parse_count)]
+    buffers: usize,
+
+    /// Blocks each queue between the readers, the comparison and the writer
+    /// holds, at least the number of buffers [default: twice the buffers]
+    #[clap(long, value_name = "N", value_parser = parse_count)]
+    queue_depth: Option<usize>,
+
+    /// Split the pair into N regions synced at the same time, each read,
+    /// compared and written on its own, on N threads. One sequence of
+    /// reads is seldom enough to keep a fast SSD busy.
+    #[clap(short, long, value_name = "N", default_value_t = 1, value_parser = parse_count, conflicts_with = "more_targets")]
+    jobs: usize,
+
+    /// fsync the target once everything is written, so it's on the device
+    /// before the sync reports success
+    #[clap(long)]
+    fsync: bool,
+
+    /// fdatasync the target every SIZE bytes written and once at the end,
+    /// so not too much is ever waiting in the page cache
+    #[clap(long, value_name = "SIZE", value_parser = parse_size)]
+    fsync_interval: Option<u64>,
+
+    /// Read at most RATE bytes per second from the source and the target
+    /// together, e.g. 100M, so other work on them isn't starved
+    #[clap(long, value_name = "RATE", value_parser = parse_size)]
+    limit_rate: Option<u64>,
+
+    /// Write at most RATE bytes per second to the target
+    #[clap(long, value_name = "RATE", value_parser = parse_size)]
+    limit_write_rate: Option<u64>,
+
+    /// Where the source is all zeroes and the target is a block device,
+    /// discard the blocks instead of writing zeroes, if the device reads
+    /// back zeroes from discarded blocks. Saves flash wear and is often
+    /// faster. Zero blocks on files are cleared without writing anyway.
+    #[clap(long)]
+    discard_zeroes: bool,
+
+    /// Where the source is all zeroes and the target is a regular file,
+    /// punch holes into it instead of writing zeroes, so it ends up a
+    /// sparse image. Blocks that are zeroes on both sides already are left
+    /// as they are.
+    #[clap(long)]
+    sparse: bool,
+
+    /// What to do when the source and the target differ in size: fail,
+    /// only sync the part both have, cut a larger target file down to the
+    /// source's size or grow a smaller one to it with fallocate. A target
+    /// file that isn't cut or grown is synced like with sync-min.
+    #[clap(long, value_enum, default_value_t = SizeMismatch::SyncMin)]
+    size_mismatch: SizeMismatch,
+
+    /// Only sync the source from this byte on, e.g. where a partition
+    /// starts in a disk image
+    #[clap(
+        long,
+        value_name = "OFFSET",
+        default_value_t = 0,
+        value_parser = parse_offset,
+        conflicts_with_all = WINDOW_CONFLICTS
+    )]
+    source_offset: u64,
+
+    /// Sync onto the target from this byte on
+    #[clap(
+        long,
+        value_name = "OFFSET",
+        default_value_t = 0,
+        value_parser = parse_offset,
+        conflicts_with_all = WINDOW_CONFLICTS
+    )]
+    target_offset: u64,
+
+    /// Only sync this many bytes from the offsets on, default is up to
+    /// the end of the smaller side
+    #[clap(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        conflicts_with_all = WINDOW_CONFLICTS
+    )]
+    length: Option<u64>,
+
+    /// How a remote target's machine is reached, the host and the command
+    /// to run there are added
+    #[clap(long, value_name = "COMMAND", default_value = "ssh")]
+    ssh: String,
+
+    /// Blocks a remote target hashes together. Only the blocks of groups
+    /// that differ are hashed one by one, so where little differs hardly
+    /// any hashes go over the connection.
+    #[clap(long, value_name = "N", default_value_t = 64, value_parser = parse_count)]
+    remote_group: usize,
+
+    /// Compress the blocks sent to a remote target with zstd, at LEVEL
+    /// from 1 to 22, 3 if it's not given. Worth it where the connection is
+    /// slower than the disks.
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "3",
+        value_parser = clap::value_parser!(i32).range(1..=22)
+    )]
+    compress: Option<i32>,
+
+    /// Check the server of a tls:// target against these certificates,
+    /// a PEM file
+    #[clap(long, value_name = "PATH")]
+    tls_ca: Option<String>,
+
+    /// Show the server of a tls:// target this certificate chain, for
+    /// servers that only let known clients in
+    #[clap(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// The private key of the client certificate
+    #[clap(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// Where the S3 service of an s3:// target is, e.g. http://localhost:9000
+    /// for MinIO. AWS_ENDPOINT_URL or AWS itself otherwise.
+    #[clap(long, value_name = "URL")]
+    s3_endpoint: Option<String>,
+
+    /// The ssdsync to run on a remote target's machine
+    #[clap(long, value_name = "PATH", default_value = "ssdsync")]
+    remote_ssdsync: String,
+}
+
+// Whatever stores offsets of the source or the whole target can't work on
+// a part of them
+const WINDOW_CONFLICTS: [&str; 10] = [
+    "sparse_image_out",
+    "bitmap_out",
+    "bmap",
+    "mapfile",
+    "badblocks_out",
+    "oci_out",
+    "apply_sparse_image",
+    "verify_footer",
+    "write_manifest",
+    "expect_source_manifest",
+];
+
+impl SyncArgs {
+    // A sync of `source` onto `target` with the defaults of the command
+    // line, parsed the way it would be
+    fn defaults(source: &str, target: &str) -> Self {
+        let matches = <SyncArgs as clap::Args>::augment_args(clap::Command::new("ssdsync"))
+            .try_get_matches_from(["ssdsync", "--", source, target])
+            .expect("A source and a target are all that's needed");
+        SyncArgs::from_arg_matches(&matches).expect("Options from the same definition")
+    }
+
+    // The offsets or the length narrow the sync down to a part
+    fn windowed(&self) -> bool {
+        self.source_offset > 0 || self.target_offset > 0 || self.length.is_some()
+    }
+
+    // Nothing is written to the target: it's the reference, or only
+    // compared with the source
+    fn read_only(&self) -> bool {
+        self.reference == Reference::Target || !self.multigrain.is_empty() || self.dry_run
+    }
+
+    // What's left of a side of `size` bytes from `offset` on, up to the
+    // length
+    fn window(&self, size: u64, offset: u64) -> u64 {
+        let rest = size.saturating_sub(offset);
+        self.length
+            .map_or(rest, |length| std::cmp::min(rest, length))
+    }
+}
+
+// Parse sizes like 4096, 64K or 1M. Suffixes are binary, K is 1024.
+fn parse_size(s: &str) -> Result<u64, String> {
+    match parse_offset(s)? {
+        0 => Err("Size can't be zero".to_string()),
+        n => Ok(n),
+    }
+}
+
+// Like a size, but zero is fine
+fn parse_offset(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match s[digits.len()..].to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        suffix => return Err(format!("Unknown size suffix: {}", suffix)),
+    };
+    let n: u64 = digits
+        .parse()
+        .map_err(|e| format!("Invalid size {}: {}", s, e))?;
+    n.checked_mul(multiplier)
+        .ok_or_else(|| format!("Size {} is too large", s))
+}
+
+fn parse_block_size(s: &str) -> Result<usize, String> {
+    std::convert::TryFrom::try_from(parse_size(s)?)
+        .map_err(|e| format!("Block size {} is too large: {}", s, e))
+}
+
+// Parse a number of things, at least one
+fn parse_count(s: &str) -> Result<usize, String> {
+    match s.trim().parse() {
+        Ok(0) => Err("Can't be zero".to_string()),
+        Ok(n) => Ok(n),
+        Err(e) => Err(format!("Invalid number {}: {}", s, e)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SizeMismatch {
+    Error,
+    SyncMin,
+    Truncate,
+    Extend,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Reference {
+    Source,
+    Target,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum IoBackend {
+    Tokio,
+    Uring,
+    Mmap,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ReportUnits {
+    Bytes,
+    Blocks,
+}
+
+impl ReportUnits {
+    /// Where the block at `pos` is, as an offset or e.g. "block 1042"
+    fn at(self, pos: u64, block_size: usize) -> String {
+        match self {
+            ReportUnits::Bytes => pos.to_string(),
+            ReportUnits::Blocks => format!("block {}", pos / block_size as u64),
+        }
+    }
+}
+
+/// CPU cores to run on
+#[derive(Clone, Debug)]
+pub struct CpuList(Vec<usize>);
+
+impl std::str::FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cpus = Vec::new();
+        for item in s.split(',') {
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid CPU {}: {}", n, e))
+            };
+            match item.split_once('-') {
+                Some((from, to)) => cpus.extend(parse(from)?..=parse(to)?),
+                None => cpus.push(parse(item)?),
+            }
+        }
+        Ok(CpuList(cpus))
+    }
+}
+
+/// Pin the calling thread to the given cores
+pub fn set_affinity(cpus: &CpuList) -> nix::Result<()> {
+    let mut set = CpuSet::new();
+    for cpu in cpus.0.iter() {
+        set.set(*cpu)?;
+    }
+    sched_setaffinity(Pid::from_raw(0), &set)
+}
+
+/// Location and algorithm of a hash footer embedded in an image
+#[derive(Clone, Debug)]
+struct FooterSpec {
+    offset: i64,
+    algo: HashAlgo,
+}
+
+impl std::str::FromStr for FooterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (offset, algo) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected OFFSET:ALGO, got {}", s))?;
+        Ok(FooterSpec {
+            offset: offset
+                .parse()
+                .map_err(|e| format!("Invalid offset {}: {}", offset, e))?,
+            algo: algo.parse()?,
+        })
+    }
+}
+
+/// Bytes the target has to hold at an offset for the sync to go ahead
+#[derive(Clone, Debug)]
+struct RegionAssertion {
+    offset: u64,
+    expected: Vec<u8>,
+}
+
+impl std::str::FromStr for RegionAssertion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (offset, expected) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Expected OFFSET:HEX, got {}", s))?;
+        let expected = hash::from_hex(expected)?;
+        if expected.is_empty() {
+            return Err("The expected bytes can't be empty".to_string());
+        }
+        Ok(RegionAssertion {
+            offset: offset
+                .parse()
+                .map_err(|e| format!("Invalid offset {}: {}", offset, e))?,
+            expected,
+        })
+    }
+}
+
+/// What's done about sectors of the source that can't be read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OnReadError {
+    /// End the sync at the first read error
+    Abort,
+    /// Leave the target as it is there
+    Skip,
+    /// Write this byte there instead
+    Fill(u8),
+}
+
+impl std::str::FromStr for OnReadError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            None if s == "abort" => Ok(OnReadError::Abort),
+            None if s == "skip" => Ok(OnReadError::Skip),
+            None if s == "fill" => Ok(OnReadError::Fill(0)),
+            Some(("fill", byte)) => {
+                let parsed = match byte.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => byte.parse(),
+                };
+                parsed
+                    .map(OnReadError::Fill)
+                    .map_err(|e| format!("Invalid fill byte {}: {}", byte, e))
+            }
+            _ => Err(format!("Expected abort, skip or fill=BYTE, got {}", s)),
+        }
+    }
+}
+
+impl RegionAssertion {
+    /// Compare with what's in `target` now, Err describes a mismatch
+    fn check(&self, target: &str) -> Result<(), String> {
+        let file = std::fs::File::open(target).map_err(|e| e.to_string())?;
+        let mut actual = vec![0; self.expected.len()];
+        let mut filled = 0;
+        while filled < actual.len() {
+            match file.read_at(&mut actual[filled..], self.offset + filled as u64) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        if actual[..filled] == self.expected[..] {
+            return Ok(());
+        }
+        Err(format!(
+            "{} holds {} at {}, expected {}",
+            target,
+            hash::to_hex(&actual[..filled]),
+            self.offset,
+            hash::to_hex(&self.expected)
+        ))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+struct Buf {
+    length: usize,
+    data: Vec<u8>,
+    // The block came from a hole, it's all zeroes without being read
+    hole: bool,
+    // All zeroes, from a hole or as read. Found by the reader, so nothing
+    // after it has to look again.
+    zero: bool,
+    // A block of zeroes that this many bytes of whole zero blocks follow,
+    // handed on as one. Whoever takes it lowers it by what of them it
+    // took, the reader hands on the rest once it's back.
+    run: Option<u64>,
+    // The run was written, the rest of it is handed on a block at a time
+    // without waiting for each to be written too
+    split: bool,
+}
+
+impl Buf {
+    fn new(size: usize) -> Self {
+        Buf {
+            length: 0,
+            data: vec![0; size],
+            hole: false,
+            zero: false,
+            run: None,
+            split: false,
+        }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        let (ret, _) = self.data.as_slice().split_at(self.length);
+        ret
+    }
+}
+
+// Specifier prefixes and the udev symlink directories they're resolved in
+const DEVICE_SPECIFIERS: [(&str, &str); 4] = [
+    ("UUID=", "/dev/disk/by-uuid"),
+    ("PARTUUID=", "/dev/disk/by-partuuid"),
+    ("LABEL=", "/dev/disk/by-label"),
+    ("PARTLABEL=", "/dev/disk/by-partlabel"),
+];
+
+// udev escapes every byte outside of [0-9A-Za-z#+-.:=@_] as \xNN
+// in the names of the by-label and by-partlabel symlinks.
+fn udev_encode(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c.is_ascii_alphanumeric() || "#+-.:=@_".contains(c) || !c.is_ascii() {
+            ret.push(c);
+        } else {
+            ret.push_str(&format!("\\x{:02x}", c as u8));
+        }
+    }
+    ret
+}
+
+// Resolve UUID=, PARTUUID=, LABEL= and PARTLABEL= style specifiers to
+// the device node they point to. Anything else is returned as is.
+fn resolve_device(spec: &str) -> error::Result<String> {
+    for (prefix, dir) in DEVICE_SPECIFIERS.iter() {
+        let value = match spec.strip_prefix(prefix) {
+            Some(value) => value,
+            None => continue,
+        };
+        // UUIDs are usually lowercase in /dev/disk/by-uuid, but FAT
+        // volume IDs are uppercase, so try the value as given first.
+        let candidates = [udev_encode(value), udev_encode(&value.to_lowercase())];
+        for name in candidates.iter() {
+            let link = std::path::Path::new(dir).join(name);
+            if let Ok(path) = std::fs::canonicalize(&link) {
+                let path = path.to_string_lossy().into_owned();
+                tracing::info!(spec, path = path.as_str(), "Resolved");
+                return Ok(path);
+            }
+        }
+        return Err(Error::Resolve {
+            spec: spec.to_string(),
+            dir,
+        });
+    }
+    Ok(spec.to_string())
+}
+
+// Descriptors taken by stdio and the runtime, plus some headroom
+const BASE_FDS: u64 = 16;
+
+// Make sure `needed` file descriptors can be opened, raising the soft
+// limit as far as the hard limit allows. Better to fail now than with
+// EMFILE in the middle of a sync.
+fn ensure_fd_limit(needed: u64) -> error::Result<()> {
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE).map_err(|source| Error::System {
+        action: "get RLIMIT_NOFILE",
+        source,
+    })?;
+    if needed <= soft {
+        return Ok(());
+    }
+    if needed > hard {
+        return Err(Error::FdLimit { needed, hard });
+    }
+    setrlimit(Resource::RLIMIT_NOFILE, needed, hard).map_err(|source| Error::System {
+        action: "raise RLIMIT_NOFILE",
+        source,
+    })
+}
+
+// See linux/fs.h
+const BLKGETSIZE64_CODE: u8 = 0x12;
+const BLKGETSIZE64_SEQ: u8 = 114;
+
+ioctl_read!(ioctl_blkgetsize64, BLKGETSIZE64_CODE, BLKGETSIZE64_SEQ, u64);
+
+// Size of a file or block device, None for pipes which have no size
+async fn get_size(f: &File) -> std::io::Result<Option<u64>> {
+    let meta = f.metadata().await?;
+    let file_type = meta.file_type();
+
+    if file_type.is_file() {
+        Ok(Some(meta.len()))
+    } else if file_type.is_fifo() {
+        Ok(None)
+    } else if file_type.is_block_device() {
+        let std_file = f.try_clone().await?.into_std().await;
+        let mut size: u64 = 0;
+        let size_ptr = &mut size as *mut u64;
+        unsafe {
+            ioctl_blkgetsize64(std_file.as_raw_fd(), size_ptr)?;
+        }
+        Ok(Some(size))
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Only regular files, block devices, pipes and symlinks to them are supported.",
+        ))
+    }
+}
+
+// Fail unless the target is of the type --require-block-device or
+// --require-regular-file asks for. Symlinks are followed.
+fn check_target_type(args: &SyncArgs, target: &str) -> error::Result<()> {
+    let expected = match (args.require_block_device, args.require_regular_file) {
+        (true, _) => "a block device",
+        (_, true) => "a regular file",
+        _ => return Ok(()),
+    };
+    let file_type = std::fs::metadata(target)
+        .context("access", target)?
+        .file_type();
+    let matches = if args.require_block_device {
+        file_type.is_block_device()
+    } else {
+        file_type.is_file()
+    };
+    if !matches {
+        return Err(Error::TargetType {
+            path: target.to_string(),
+            expected,
+        });
+    }
+    Ok(())
+}
+
+// Open a device exclusively, failing if something else has it
+fn claim(path: &str, otherwise: &str) -> error::Result<Option<std::fs::File>> {
+    match device::claim(path) {
+        Err(e) if e.raw_os_error() == Some(nix::libc::EBUSY) => Err(Error::Usage(format!(
+            "Not syncing, {} is in use, the kernel won't open it exclusively. {}",
+            path, otherwise
+        ))),
+        claimed => claimed.context("open", path),
+    }
+}
+
+// Fail if the target is a device that's mounted or in use, writing to it
+// would clobber what uses it
+fn check_target_unused(args: &SyncArgs, target: &str) -> error::Result<()> {
+    if args.force || args.dry_run {
+        return Ok(());
+    }
+    match device::block_rdev(target).and_then(device::user) {
+        Some(user) => Err(Error::TargetInUse(user)),
+        None => Ok(()),
+    }
+}
+
+// Fail if the target is the source itself, or of devices, the disk the
+// source is a partition of or a partition of the source. Links like those
+// in /dev/disk/by-id are followed to what they point at.
+fn check_not_source(args: &SyncArgs, target: &str) -> error::Result<()> {
+    let source = match args.source.as_deref().map(resolve_device) {
+        Some(Ok(source)) => source,
+        _ => return Ok(()),
+    };
+    let (s, t) = match (std::fs::metadata(&source), std::fs::metadata(target)) {
+        (Ok(s), Ok(t)) => (s, t),
+        _ => return Ok(()),
+    };
+    let how = if s.file_type().is_block_device() && t.file_type().is_block_device() {
+        if s.rdev() == t.rdev() {
+            Some("the source")
+        } else if device::disk_of(s.rdev()) == t.rdev() {
+            Some("the disk of the source")
+        } else if device::disk_of(t.rdev()) == s.rdev() {
+            Some("a partition of the source")
+        } else {
+            None
+        }
+    } else if (s.dev(), s.ino()) == (t.dev(), t.ino()) {
+        Some("the source")
+    } else {
+        None
+    };
+    match how {
+        Some(how) => Err(Error::Usage(format!(
+            "Not syncing, the target {} is {} {}",
+            target, how, source
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Hash everything before the footer and compare it to the footer itself.
+// `end` is the size of the synced image, negative offsets are relative to it.
+async fn verify_footer(
+    mut file: File,
+    end: u64,
+    footer: &FooterSpec,
+    block_size: usize,
+) -> Result<(), String> {
+    let offset = if footer.offset < 0 {
+        end.checked_sub(footer.offset.unsigned_abs())
+    } else {
+        Some(footer.offset as u64)
+    };
+    let digest_len = footer.algo.digest_len() as u64;
+    let offset = match offset {
+        Some(offset) if offset + digest_len <= end => offset,
+        _ => {
+            return Err(format!(
+                "Footer at {} lies outside the image",
+                footer.offset
+            ))
+        }
+    };
+
+    let mut hasher = footer.algo.hasher();
+    let mut buf = vec![0; block_size];
+    let mut pos = 0;
+    file.seek(SeekFrom::Start(0))
+        .await
+        .map_err(|e| e.to_string())?;
+    while pos < offset {
+        let n = std::cmp::min(block_size as u64, offset - pos) as usize;
+        file.read_exact(&mut buf[..n])
+            .await
+            .map_err(|e| format!("Failed to read target at {}: {}", pos, e))?;
+        hasher.update(&buf[..n]);
+        pos += n as u64;
+    }
+
+    let mut expected = vec![0; digest_len as usize];
+    file.read_exact(&mut expected)
+        .await
+        .map_err(|e| format!("Failed to read footer at {}: {}", offset, e))?;
+
+    let actual = hasher.finalize();
+    if actual != expected {
+        return Err(format!(
+            "expected {}, got {}",
+            hash::to_hex(&expected),
+            hash::to_hex(&actual)
+        ));
+    }
+    Ok(())
+}
+
+// How often progress is updated while blocks keep matching
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often a checkpoint is saved
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+// A read is considered slow if it took this many times the rolling median
+const SLOW_READ_FACTOR: u32 = 10;
+
+// Number of recent reads the median is taken over
+const SLOW_READ_WINDOW: usize = 64;
+
+// Reads that take far longer than usual are a sign of the drive retrying
+// or remapping failing sectors. SlowLog keeps a rolling window of read
+// latencies and writes the offset of every outlier to a shared log file.
+struct SlowLog {
+    label: &'static str,
+    file: Arc<Mutex<std::fs::File>>,
+    window: VecDeque<Duration>,
+}
+
+impl SlowLog {
+    fn new(label: &'static str, file: Arc<Mutex<std::fs::File>>) -> Self {
+        SlowLog {
+            label,
+            file,
+            window: VecDeque::with_capacity(SLOW_READ_WINDOW),
+        }
+    }
+
+    fn record(&mut self, pos: u64, length: usize, elapsed: Duration) {
+        // Wait for a few samples before judging anything
+        if self.window.len() >= SLOW_READ_WINDOW / 8 {
+            let mut sorted: Vec<Duration> = self.window.iter().copied().collect();
+            sorted.sort_unstable();
+            let median = sorted[sorted.len() / 2];
+            if elapsed > median * SLOW_READ_FACTOR {
+                let mut file = self.file.lock().unwrap();
+                if let Err(e) = writeln!(
+                    file,
+                    "{} {} {} {:?} (median {:?})",
+                    self.label, pos, length, elapsed, median
+                ) {
+                    tracing::warn!("Failed to write slow read log: {}", e);
+                }
+            }
+        }
+
+        if self.window.len() == SLOW_READ_WINDOW {
+            self.window.pop_front();
+        }
+        self.window.push_back(elapsed);
+    }
+}
+
+// Zero blocks past a zero one are looked for this many bytes at a time
+// where that can be, up to a run of this many bytes handed on as one
+const ZERO_SCAN: usize = 4 * 1024 * 1024;
+const ZERO_RUN_MAX: u64 = 64 * 1024 * 1024;
+
+// Zero blocks handed on one by one once a run was written, before it's
+// looked again if they're a run on the other side too
+const ZERO_SPLIT_BLOCKS: u64 = 256;
+
+// What a reader read ahead while looking for zeroes, and how much of it
+// was handed on already
+#[derive(Default)]
+struct Staged {
+    data: Vec<u8>,
+    at: usize,
+    end: usize,
+}
+
+impl Staged {
+    fn is_empty(&self) -> bool {
+        self.at >= self.end
+    }
+
+    // Hand on as much as fits into `buf`
+    fn take(&mut self, buf: &mut [u8]) -> usize {
+        let n = std::cmp::min(buf.len(), self.end - self.at);
+        buf[..n].copy_from_slice(&self.data[self.at..self.at + n]);
+        self.at += n;
+        n
+    }
+}
+
+// Look for whole zero blocks of `len` bytes in what comes next, as far
+// as ZERO_RUN_MAX: in what's staged, then in the source. Holes are
+// skipped, the rest is read ZERO_SCAN bytes at a time if the holes of
+// the source read as zeroes too, or else a block at a time like it's
+// handed on. What's read past the zeroes is staged. Returns how many
+// bytes of them there are, and how many were read.
+async fn scan_zeroes(
+    file: &mut Box<dyn BlockSource>,
+    pos: u64,
+    len: usize,
+    staged: &mut Staged,
+    throttle: &Option<throttle::Throttle>,
+    slow_log: &mut Option<SlowLog>,
+) -> std::io::Result<(u64, u64)> {
+    let chunk = match file.plain_holes() {
+        true => std::cmp::max(ZERO_SCAN / len, 1) * len,
+        false => len,
+    };
+    if staged.data.len() < chunk {
+        staged.data.resize(chunk, 0);
+    }
+    let staged_zeroes = compare::zero_prefix(&staged.data[staged.at..staged.end]) / len * len;
+    staged.at += staged_zeroes;
+    let (mut zeroes, mut read) = (staged_zeroes as u64, 0);
+    while staged.is_empty() && zeroes < ZERO_RUN_MAX {
+        if file.skip_hole(len).await? {
+            zeroes += len as u64;
+            continue;
+        }
+        if let Some(throttle) = throttle {
+            throttle.take(chunk).await;
+        }
+        let start = Instant::now();
+        let n = file.read(&mut staged.data[..chunk]).await?;
+        if let Some(slow_log) = slow_log {
+            slow_log.record(pos + zeroes, n, start.elapsed());
+        }
+        read += n as u64;
+        staged.at = compare::zero_prefix(&staged.data[..n]) / len * len;
+        staged.end = n;
+        zeroes += staged.at as u64;
+        // Up to the end, if not something that isn't zero
+        if n < chunk {
+            break;
+        }
+    }
+    Ok((zeroes, read))
+}
+
+// How a side is read, other than from where
+struct Reading {
+    // Which one it is, in errors
+    side: &'static str,
+    slow_log: Option<SlowLog>,
+    throttle: Option<throttle::Throttle>,
+    // Whether runs of zero blocks are handed on as one
+    runs: bool,
+}
+
+// Read blocks from `pos` on.
+//
+// With `runs`, the whole zero blocks that follow a zero one are looked
+// for ahead and handed on with it as a run. Nothing more is handed on
+// until the run is back, to tell how much of it was taken: as far as
+// the other side has zeroes too. What's left of a run that was written
+// is handed on a block at a time for a while, so that the writer isn't
+// waited for on every one.
+async fn read_blocks(
+    mut file: Box<dyn BlockSource>,
+    mut pos: u64,
+    mut buf_rx: tokio::sync::mpsc::Receiver<Buf>,
+    buf_tx: tokio::sync::mpsc::Sender<Buf>,
+    reading: Reading,
+) -> error::Result<u64> {
+    let Reading {
+        side,
+        mut slow_log,
+        throttle,
+        runs,
+    } = reading;
+    let mut read = 0;
+    let failed = |offset, source| {
+        let error = Error::Read {
+            side,
+            offset,
+            source,
+        };
+        tracing::error!(side, offset, "Reader stopped: {}", error);
+        error
+    };
+    let mut staged = Staged::default();
+    // Bytes of zero blocks found ahead, before what's staged
+    let mut zeroes = 0;
+    // How many of them are still handed on a block at a time
+    let mut singly = 0;
+    // A run was handed on, buffers that come back meanwhile wait for it
+    let mut running = false;
+    let mut waiting = VecDeque::new();
+    loop {
+        let next = if running { None } else { waiting.pop_front() };
+        let mut buf = match next {
+            Some(buf) => buf,
+            None => match buf_rx.recv().await {
+                Some(buf) => buf,
+                None => break,
+            },
+        };
+        if let Some(left) = buf.run.take() {
+            // What of it wasn't taken is still to come
+            pos += zeroes - left;
+            if std::mem::take(&mut buf.split) {
+                singly = ZERO_SPLIT_BLOCKS;
+            }
+            zeroes = left;
+            running = false;
+        } else if running {
+            waiting.push_back(buf);
+            continue;
+        }
+
+        let len = buf.data.len();
+        buf.hole = false;
+        if zeroes > 0 {
+            buf.data.fill(0);
+            buf.length = len;
+            buf.zero = true;
+            zeroes -= len as u64;
+            singly = if zeroes > 0 {
+                singly.saturating_sub(1)
+            } else {
+                0
+            };
+        } else if !staged.is_empty() {
+            buf.length = staged.take(&mut buf.data);
+            buf.zero = compare::is_zero(buf.as_slice());
+        } else {
+            buf.hole = file.skip_hole(len).await.map_err(|e| failed(pos, e))?;
+            if buf.hole {
+                // Still zeroed, the other side might not be a hole
+                buf.data.fill(0);
+                buf.length = len;
+            } else {
+                if let Some(throttle) = &throttle {
+                    throttle.take(len).await;
+                }
+                let start = Instant::now();
+                buf.length = file.read(&mut buf.data).await.map_err(|e| failed(pos, e))?;
+                read += buf.length as u64;
+                if let Some(slow_log) = &mut slow_log {
+                    slow_log.record(pos, buf.length, start.elapsed());
+                }
+            }
+            buf.zero = buf.hole || compare::is_zero(buf.as_slice());
+        }
+
+        if runs && singly == 0 && buf.zero && buf.length == len {
+            if zeroes == 0 {
+                let (found, scanned) = scan_zeroes(
+                    &mut file,
+                    pos + len as u64,
+                    len,
+                    &mut staged,
+                    &throttle,
+                    &mut slow_log,
+                )
+                .await
+                .map_err(|e| failed(pos + len as u64, e))?;
+                zeroes = found;
+                read += scanned;
+            }
+            if zeroes > 0 {
+                buf.run = Some(zeroes);
+                running = true;
+            }
+        }
+        tracing::trace!(
+            side,
+            offset = pos,
+            length = buf.length,
+            hole = buf.hole,
+            run = buf.run.unwrap_or(0),
+            "Read"
+        );
+        pos += buf.length as u64;
+        if buf_tx.send(buf).await.is_err() {
+            // Nobody's listening
+            return Ok(read);
+        }
+    }
+    Ok(read)
+}
+
+// Take `len` bytes of zeroes into a hash
+fn hash_zeroes(hasher: &mut hash::Hasher, mut len: u64) {
+    let zeroes = [0; 4096];
+    while len > 0 {
+        let n = std::cmp::min(len, zeroes.len() as u64) as usize;
+        hasher.update(&zeroes[..n]);
+        len -= n as u64;
+    }
+}
+
+// The parts of the ranges within the `len` bytes at `pos`
+fn overlapping(
+    ranges: &[(u64, u64)],
+    pos: u64,
+    len: usize,
+) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let end = pos + len as u64;
+    let i = ranges.partition_point(|&(_, range_end)| range_end <= pos);
+    ranges[i..]
+        .iter()
+        .take_while(move |&&(start, _)| start < end)
+        .map(move |&(start, range_end)| (std::cmp::max(start, pos), std::cmp::min(range_end, end)))
+}
+
+// A task that panicked or was cancelled took its reason along, only the
+// log can still tell
+fn task_failed(task: &'static str, e: tokio::task::JoinError) -> Error {
+    tracing::error!("The {} task failed: {}", task, e);
+    Error::Task(task)
+}
+
+// Clear a zero block without writing it, with the best way `zeroing`
+// still allows. Returns false if it has to be written after all.
+fn zero_range(
+    f: &impl AsRawFd,
+    zeroing: &mut Option<FallocateFlags>,
+    pos: u64,
+    length: usize,
+) -> bool {
+    while let Some(flags) = *zeroing {
+        match fallocate(f.as_raw_fd(), flags, pos as i64, length as i64) {
+            Ok(()) => return true,
+            Err(e) if flags == FallocateFlags::FALLOC_FL_ZERO_RANGE => {
+                tracing::debug!(offset = pos, "Can't zero a range ({}), punching holes", e);
+                *zeroing =
+                    Some(FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE)
+            }
+            Err(e) => {
+                tracing::debug!(offset = pos, "Can't punch a hole ({}), writing zeroes", e);
+                *zeroing = None
+            }
+        }
+    }
+    false
+}
+
+// Discard a zero block on a block device. With `checked` false, read it
+// back first to see it's zeroes now. Gives up on discarding for good if
+// the device can't or doesn't read back zeroes, returns false if it has
+// to be written after all.
+fn discard_range(
+    f: &Arc<std::fs::File>,
+    discarding: &mut Option<bool>,
+    checked: bool,
+    pos: u64,
+    length: usize,
+) -> bool {
+    match device::discard(&**f, pos, length) {
+        Ok(()) => (),
+        // Not aligned to what the device discards, like a short block
+        Err(nix::errno::Errno::EINVAL) => return false,
+        Err(e) => {
+            tracing::warn!(
+                offset = pos,
+                "Can't discard ({}), writing zeroes instead.",
+                e
+            );
+            *discarding = None;
+            return false;
+        }
+    }
+    if checked {
+        return true;
+    }
+    let mut back = vec![0xff; length];
+    let zeroes = f.read_exact_at(&mut back, pos).is_ok() && compare::is_zero(&back);
+    if zeroes {
+        *discarding = Some(true);
+    } else {
+        tracing::warn!(
+            offset = pos,
+            "Discarded blocks don't read back as zeroes, writing them instead."
+        );
+        *discarding = None;
+    }
+    zeroes
+}
+
+// Hand back the failed buffer and the ones still queued, so the source
+// reader isn't left waiting for them, then give up
+async fn give_up(
+    buf_rx: &mut tokio::sync::mpsc::Receiver<(u64, Buf)>,
+    buf_tx: &tokio::sync::mpsc::Sender<Buf>,
+    buf: Buf,
+    error: Error,
+) -> error::Result<u64> {
+    tracing::error!("Writer stopped: {}", error);
+    buf_rx.close();
+    let _ = buf_tx.send(buf).await;
+    while let Some((_, buf)) = buf_rx.recv().await {
+        let _ = buf_tx.send(buf).await;
+    }
+    Err(error)
+}
+
+// Write a block at `pos` with pwrite on the blocking pool. A short write
+// still changed the target, so it carries on with the rest of the block
+// instead of giving up on it. Returns the buffer and the bytes written,
+// with the error that stopped it if it didn't get through.
+async fn write_block(
+    f: &Arc<std::fs::File>,
+    pos: u64,
+    buf: Buf,
+    control: &Arc<Control>,
+) -> (Buf, usize, Option<std::io::Error>) {
+    let f = f.clone();
+    let control = control.clone();
+    let length = buf.length;
+    let task = tokio::task::spawn_blocking(move || {
+        let mut done = 0;
+        while done < buf.length {
+            match f.write_at(&buf.as_slice()[done..], pos + done as u64) {
+                Ok(0) => {
+                    let failed = std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        format!("only {} of {} bytes written", done, buf.length),
+                    );
+                    return (buf, done, Some(failed));
+                }
+                Ok(n) => {
+                    done += n;
+                    if done < buf.length {
+                        control.retries.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                    control.retries.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => return (buf, done, Some(e)),
+            }
+        }
+        (buf, done, None)
+    });
+    match task.await {
+        Ok(result) => result,
+        // The buffer is gone with the task, a new one takes its place
+        Err(e) => (Buf::new(length), 0, Some(std::io::Error::other(e))),
+    }
+}
+
+// Hand back the buffers whose writes through the ring are complete, or
+// with `all` every one once it is, counting what they wrote. The first
+// write that failed is returned with its buffer, the others go back
+// when they're done.
+async fn hand_back(
+    ring: &mut uring::UringWriter,
+    all: bool,
+    buf_tx: &tokio::sync::mpsc::Sender<Buf>,
+    write_bar: &Option<ProgressBar>,
+    control: &Control,
+    written: &mut u64,
+) -> Result<(), (Buf, Error)> {
+    let mut failed = None;
+    loop {
+        let next = match all || failed.is_some() {
+            true => ring.next().await,
+            false => ring.ready(),
+        };
+        let done = match next {
+            Some(done) => done,
+            None => break,
+        };
+        control.retries.fetch_add(done.retries, Ordering::Relaxed);
+        *written += done.done as u64;
+        if let Some(bar) = write_bar {
+            bar.inc(done.done as u64);
+        }
+        match done.failed {
+            Some(source) if failed.is_none() => {
+                let error = Error::Write {
+                    offset: done.pos + done.done as u64,
+                    source,
+                };
+                failed = Some((done.buf, error));
+            }
+            _ => {
+                let _ = buf_tx.send(done.buf).await;
+            }
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+// Read a block just written at `pos` back from the target itself, not
+// from the page cache, which has it anyway. Returns the buffer and
+// whether the target has what's in it.
+async fn read_back(f: &Arc<std::fs::File>, pos: u64, buf: Buf) -> (Buf, std::io::Result<bool>) {
+    let f = f.clone();
+    let length = buf.length;
+    let task = tokio::task::spawn_blocking(move || {
+        let check = || {
+            // Only pages that are on the device can be dropped
+            f.sync_data()?;
+            posix_fadvise(
+                f.as_raw_fd(),
+                pos as i64,
+                buf.length as i64,
+                PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+            )?;
+            let mut back = vec![0; buf.length];
+            f.read_exact_at(&mut back, pos)?;
+            Ok(back == buf.as_slice())
+        };
+        let result = check();
+        (buf, result)
+    });
+    match task.await {
+        Ok(result) => result,
+        Err(e) => (Buf::new(length), Err(std::io::Error::other(e))),
+    }
+}
+
+// Write blocks at their offsets, returns the number of bytes written.
+// Every write carries its offset, nothing depends on a file position.
+async fn write_blocks(
+    target: WriteTarget,
+    mut buf_rx: tokio::sync::mpsc::Receiver<(u64, Buf)>,
+    buf_tx: tokio::sync::mpsc::Sender<Buf>,
+    write_bar: Option<ProgressBar>,
+) -> error::Result<u64> {
+    let WriteTarget {
+        file: f,
+        name,
+        size: target_size,
+        offset,
+        mut reflink,
+        mut direct,
+        fsync,
+        fsync_interval,
+        throttle,
+        discard_zeroes,
+        sparse,
+        control,
+        verify,
+        drop_cache,
+        messages,
+        uring,
+    } = target;
+    let mut written = 0;
+    let mut synced = 0;
+    let mut behind = drop_cache.then(|| cache::Behind::new(offset));
+
+    // Zero blocks are cleared by the filesystem on regular files, which
+    // may leave them unwritten. Falls back to punching a hole, then to
+    // plain writes, as soon as one isn't supported. With --sparse holes
+    // are punched right away, so the target ends up sparse.
+    let punch = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    let mut zeroing = match f.metadata().await {
+        Ok(meta) if meta.is_file() && sparse => Some(punch),
+        Ok(meta) if meta.is_file() => Some(FallocateFlags::FALLOC_FL_ZERO_RANGE),
+        _ => {
+            if sparse {
+                messages.say(format_args!(
+                    "Not punching holes, {} is not a regular file.",
+                    name
+                ));
+            }
+            None
+        }
+    };
+    let f = Arc::new(f.into_std().await);
+
+    // Plain writes are queued on a ring, and each buffer goes back once
+    // its write is complete
+    let mut ring = match uring {
+        true => match uring::UringWriter::new(f.clone()) {
+            Ok(ring) => Some(ring),
+            Err(e) => {
+                tracing::warn!(
+                    "Can't set up io_uring for {} ({}), writing without.",
+                    name,
+                    e
+                );
+                None
+            }
+        },
+        false => None,
+    };
+
+    // Zero blocks are discarded on a block device instead. Whether they
+    // read back as zeroes afterwards is up to the device, so that's
+    // checked on the first one.
+    let mut discarding = match discard_zeroes {
+        true if zeroing.is_none() => Some(false),
+        _ => None,
+    };
+
+    loop {
+        let (pos, mut buf) = match ring.as_mut().filter(|ring| ring.busy()) {
+            // Written buffers go back as soon as they're done, the readers
+            // may be waiting for them before sending more
+            Some(ring) => match buf_rx.try_recv() {
+                Ok(received) => received,
+                Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {
+                    ring.complete().await;
+                    let handed =
+                        hand_back(ring, false, &buf_tx, &write_bar, &control, &mut written);
+                    if let Err((buf, e)) = handed.await {
+                        return give_up(&mut buf_rx, &buf_tx, buf, e).await;
+                    }
+                    continue;
+                }
+                Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => break,
+            },
+            None => match buf_rx.recv().await {
+                Some(received) => received,
+                None => break,
+            },
+        };
+
+        let flushing = fsync_interval.is_some_and(|interval| written - synced >= interval);
+        let dropping = behind.is_some_and(|b| b.due(offset + pos));
+        // What's still being written has to be done first
+        if let Some(ring) = ring.as_mut().filter(|_| flushing || dropping) {
+            let handed = hand_back(ring, true, &buf_tx, &write_bar, &control, &mut written);
+            if let Err((failed, e)) = handed.await {
+                let _ = buf_tx.send(buf).await;
+                return give_up(&mut buf_rx, &buf_tx, failed, e).await;
+            }
+        }
+        control.written.store(written, Ordering::Relaxed);
+        if flushing {
+            if let Err(e) = flush(&f, false).await.io_context("flush", &name) {
+                return give_up(&mut buf_rx, &buf_tx, buf, e).await;
+            }
+            synced = written;
+        }
+        // Everything before the block is written, the writes come in order
+        if let Some(mut moved) = behind.filter(|_| dropping) {
+            let file = f.clone();
+            let at = offset + pos;
+            let advanced =
+                tokio::task::spawn_blocking(move || moved.advance(&file, at).map(|()| moved))
+                    .await
+                    .map_err(std::io::Error::other)
+                    .and_then(|result| result.map_err(std::io::Error::from))
+                    .io_context("drop the cached pages of", &name);
+            match advanced {
+                Ok(moved) => behind = Some(moved),
+                Err(e) => return give_up(&mut buf_rx, &buf_tx, buf, e).await,
+            }
+        }
+
+        // Never write past the end of the target, whatever the readers saw
+        let room = target_size.saturating_sub(pos);
+        if (buf.length as u64) > room {
+            tracing::warn!(
+                offset = pos,
+                "Not writing {} bytes past the end of the target at {}",
+                buf.length as u64 - room,
+                target_size
+            );
+            buf.length = room as usize;
+        }
+        if buf.length == 0 {
+            let _ = buf_tx.send(buf).await;
+            continue;
+        }
+        // Where it goes on the whole target
+        let pos = offset + pos;
+        tracing::trace!(offset = pos, length = buf.length, zero = buf.zero, "Write");
+
+        if let Some(source) = &reflink {
+            match source.clone_range(&*f, pos, buf.length) {
+                Ok(()) => {
+                    written += buf.length as u64;
+                    if let Some(bar) = &write_bar {
+                        bar.inc(buf.length as u64);
+                    }
+                    let _ = buf_tx.send(buf).await;
+                    continue;
+                }
+                // Misaligned, like a short block before the end of the
+                // source. Only this one is copied.
+                Err(e) if e.raw_os_error() == Some(nix::libc::EINVAL) => (),
+                Err(e) => {
+                    tracing::warn!(offset = pos, "Can't reflink ({}), copying instead.", e);
+                    reflink = None;
+                }
+            }
+        }
+
+        let zero = (zeroing.is_some() || discarding.is_some()) && buf.zero;
+        let cleared = match discarding {
+            _ if !zero => false,
+            Some(checked) => discard_range(&f, &mut discarding, checked, pos, buf.length),
+            None => zero_range(&*f, &mut zeroing, pos, buf.length),
+        };
+        if cleared {
+            written += buf.length as u64;
+            if let Some(bar) = &write_bar {
+                bar.inc(buf.length as u64);
+            }
+            let _ = buf_tx.send(buf).await;
+            continue;
+        }
+
+        if let Some(throttle) = &throttle {
+            throttle.take(buf.length).await;
+        }
+
+        // Aligned blocks bypass the page cache, others like a short one at
+        // the end are written the usual way
+        if let Some(writer) = direct.as_mut().filter(|w| w.fits(pos, buf.length)) {
+            if let Err(source) = writer.write_at(pos, buf.as_slice()).await {
+                let error = Error::Write {
+                    offset: pos,
+                    source,
+                };
+                return give_up(&mut buf_rx, &buf_tx, buf, error).await;
+            }
+            written += buf.length as u64;
+            if let Some(bar) = &write_bar {
+                bar.inc(buf.length as u64);
+            }
+        } else if let Some(ring) = ring.as_mut() {
+            ring.write(pos, buf).await;
+            let handed = hand_back(ring, false, &buf_tx, &write_bar, &control, &mut written);
+            if let Err((buf, e)) = handed.await {
+                return give_up(&mut buf_rx, &buf_tx, buf, e).await;
+            }
+            // Not read back, --verify-writes writes without the ring
+            continue;
+        } else {
+            let (returned, done, failed) = write_block(&f, pos, buf, &control).await;
+            buf = returned;
+            written += done as u64;
+            if let Some(bar) = &write_bar {
+                bar.inc(done as u64);
+            }
+            if let Some(source) = failed {
+                let error = Error::Write {
+                    offset: pos + done as u64,
+                    source,
+                };
+                return give_up(&mut buf_rx, &buf_tx, buf, error).await;
+            }
+        }
+
+        // The write may have been acknowledged and dropped all the same
+        if verify {
+            let (returned, result) = read_back(&f, pos, buf).await;
+            buf = returned;
+            let error = match result {
+                Ok(true) => None,
+                Ok(false) => Some(Error::WriteMismatch { offset: pos }),
+                Err(source) => Some(Error::Read {
+                    side: "target",
+                    offset: pos,
+                    source,
+                }),
+            };
+            if let Some(error) = error {
+                return give_up(&mut buf_rx, &buf_tx, buf, error).await;
+            }
+        }
+
+        // If no one needs the buffer, that's fine. We still might
+        // have buffers to be written.
+        let _ = buf_tx.send(buf).await;
+    }
+    if let Some(ring) = ring.as_mut() {
+        let handed = hand_back(ring, true, &buf_tx, &write_bar, &control, &mut written);
+        if let Err((buf, e)) = handed.await {
+            return give_up(&mut buf_rx, &buf_tx, buf, e).await;
+        }
+    }
+
+    // Only written once it's on the device
+    if fsync || fsync_interval.is_some() {
+        flush(&f, fsync).await.io_context("flush", &name)?;
+    }
+    if let Some(mut behind) = behind {
+        let file = f.clone();
+        let end = offset + target_size;
+        tokio::task::spawn_blocking(move || behind.finish(&file, end))
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|result| result.map_err(std::io::Error::from))
+            .io_context("drop the cached pages of", &name)?;
+    }
+
+    Ok(written)
+}
+
+// What the writer writes to and how
+struct WriteTarget {
+    file: File,
+    name: String,
+    // Of the part synced, which starts at offset
+    size: u64,
+    offset: u64,
+    reflink: Option<reflink::Reflink>,
+    direct: Option<direct::DirectWriter>,
+    // fsync at the end
+    fsync: bool,
+    // fdatasync every this many bytes written, and at the end
+    fsync_interval: Option<u64>,
+    throttle: Option<throttle::Throttle>,
+    discard_zeroes: bool,
+    // Punch holes for zero blocks
+    sparse: bool,
+    // Where the bytes written so far are kept for the status
+    control: Arc<Control>,
+    // Read every block back after writing it
+    verify: bool,
+    // Drop the written pages from the page cache
+    drop_cache: bool,
+    messages: Messages,
+    // Queue the plain writes on an io_uring ring
+    uring: bool,
+}
+
+// fsync the target, or with `all` false only fdatasync it
+async fn flush(f: &Arc<std::fs::File>, all: bool) -> std::io::Result<()> {
+    let f = f.clone();
+    tokio::task::spawn_blocking(move || if all { f.sync_all() } else { f.sync_data() })
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// The command line, with the options of the configuration file
+pub fn parse_args() -> error::Result<Args> {
+    let argv = config::expand(std::env::args_os().collect())?;
+    Ok(Args::parse_from(argv))
+}
+
+impl Args {
+    /// Regions a sync is split into with --jobs, as many threads run them
+    pub fn jobs(&self) -> usize {
+        match &self.command {
+            None => self.sync.jobs,
+            Some(Command::Sync { sync })
+            | Some(Command::Clone { sync, .. })
+            | Some(Command::Diff { sync, .. }) => sync.jobs,
+            Some(Command::Batch { options, .. }) => batch::jobs(options),
+            Some(_) => 1,
+        }
+    }
+}
+
+/// Run what the command line asks for
+pub async fn run(args: Args) -> error::Result<()> {
+    if let Some(target) = &args.server {
+        let target = resolve_device(target)?;
+        return remote::serve(tokio::io::stdin(), tokio::io::stdout(), &target).await;
+    }
+    match &args.command {
+        None => run_sync(&args.sync).await?,
+        Some(Command::Sync { sync }) => run_sync(sync).await?,
+        Some(Command::Clone {
+            sync: sync_args,
+            new_guid,
+        }) => {
+            let driver = Driver::for_args(sync_args)?;
+            finish(sync_args, clone(sync_args, *new_guid, &driver)).await?;
+        }
+        Some(Command::Diff {
+            output,
+            sync: sync_args,
+        }) => {
+            let sync_args = SyncArgs {
+                sparse_image_out: Some(output.clone()),
+                ..(**sync_args).clone()
+            };
+            let driver = Driver::for_args(&sync_args)?;
+            finish(&sync_args, sync(&sync_args, false, &driver)).await?;
+        }
+        Some(Command::Apply { patch, target }) => apply(patch, target).await?,
+        Some(Command::Rollback { journal, target }) => {
+            let target = resolve_device(target)?;
+            let n = journal::rollback(journal, &target).context("roll back with", journal)?;
+            println!("Restored {} blocks.", n);
+        }
+        Some(Command::Verify(verify_args)) => {
+            verify_manifest(verify_args)?;
+        }
+        Some(Command::Serve {
+            listen,
+            tls_cert,
+            tls_key,
+            tls_ca,
+            once,
+            target,
+        }) => {
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert), Some(key)) => Some(tls::acceptor(cert, key, tls_ca.as_deref())?),
+                _ => None,
+            };
+            remote::listen(listen, &resolve_device(target)?, *once, tls).await?
+        }
+        Some(Command::NbdServe {
+            listen,
+            once,
+            device,
+        }) => nbd::serve(listen, &resolve_device(device)?, *once).await?,
+        Some(Command::Hash {
+            source,
+            hash,
+            write_manifest,
+            block_size,
+        }) => hash_source(source, *hash, write_manifest.as_deref(), *block_size).await?,
+        Some(Command::Batch { pairs, options }) => batch::run(pairs, options).await?,
+    }
+    Ok(())
+}
+
+// A sync, with or without the sync subcommand
+async fn run_sync(args: &SyncArgs) -> error::Result<()> {
+    if args.apply_sparse_image {
+        return apply(args.source.as_ref().unwrap(), args.target.as_ref().unwrap()).await;
+    }
+    if !args.more_targets.is_empty() {
+        return fanout::run(args, &Driver::interruptible()?).await;
+    }
+    sync_one(args, &Driver::for_args(args)?).await.map(drop)
+}
+
+// A sync onto one target and its --verify-after, as the command line has it
+async fn sync_one(args: &SyncArgs, driver: &Driver) -> error::Result<Summary> {
+    finish(args, async {
+        let mut summary = sync(args, false, driver).await?;
+        verify_phase(args, &mut summary, driver).await?;
+        Ok(summary)
+    })
+    .await
+}
+
+// Wait for a sync and write its --stats-file, with --json reporting on it
+// instead of its messages
+async fn finish(
+    args: &SyncArgs,
+    sync: impl std::future::Future<Output = error::Result<Summary>>,
+) -> error::Result<Summary> {
+    let sync = async {
+        let summary = sync.await?;
+        if let Some(path) = &args.stats_file {
+            json::write_stats(path, args, &summary).io_context("write", path)?;
+            println!("Statistics written to {}.", path);
+        }
+        Ok(summary)
+    };
+    if args.json {
+        json::report(args, sync).await
+    } else {
+        sync.await
+    }
+}
+
+// The pass of --verify-after, timed as a phase of the sync
+async fn verify_phase(
+    args: &SyncArgs,
+    summary: &mut Summary,
+    driver: &Driver,
+) -> error::Result<()> {
+    if args.verify_after {
+        let started = Instant::now();
+        verify_after(args, &summary.target, driver).await?;
+        summary.phases.push(("verify", started.elapsed()));
+    }
+    Ok(())
+}
+
+async fn apply(image: &str, target: &str) -> error::Result<()> {
+    let target = resolve_device(target)?;
+    let (regions, bytes) = sparse::apply(image, &target)
+        .await
+        .context("apply the sparse image", image)?;
+    println!("Applied {} regions, {} bytes.", regions, bytes);
+    Ok(())
+}
+
+// Hash the target block by block and compare with the manifest. Exits
+// with 1 if any block differs, like a failed --reference target check.
+fn verify_manifest(args: &VerifyArgs) -> error::Result<()> {
+    let path = &args.source_manifest;
+    let target = resolve_device(&args.target)?;
+    let manifest = manifest::Manifest::load(path).context("load", path)?;
+    let report = |offset: u64| {
+        format!(
+            "Differs from the manifest at {}",
+            args.report_units.at(offset, manifest.block_size)
+        )
+    };
+
+    // The differences found before the interruption were already
+    // reported, but still count for the result
+    let mut checkpoint = match &args.checkpoint {
+        Some(path) if args.resume => {
+            let checkpoint = checkpoint::Checkpoint::load(path).context("load", path)?;
+            println!(
+                "Resuming at {}, {} differing blocks so far.",
+                checkpoint.next,
+                checkpoint.differing.len()
+            );
+            checkpoint
+        }
+        _ => checkpoint::Checkpoint::default(),
+    };
+
+    let bar = ProgressBar::new(manifest.size_from(checkpoint.next));
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]",
+        )
+        .unwrap(),
+    );
+    let mut last_save = Instant::now();
+    manifest
+        .verify(&target, checkpoint.next, |offset, length, matches| {
+            if !matches {
+                bar.suspend(|| println!("{}", report(offset)));
+                checkpoint.differing.push(offset);
+            }
+            bar.inc(length as u64);
+            checkpoint.next = offset + length as u64;
+            match &args.checkpoint {
+                Some(path) if last_save.elapsed() >= CHECKPOINT_INTERVAL => {
+                    last_save = Instant::now();
+                    checkpoint.save(path)
+                }
+                _ => Ok(()),
+            }
+        })
+        .context("verify", &target)?;
+    bar.finish();
+
+    if let Some(path) = &args.checkpoint {
+        let _ = std::fs::remove_file(path);
+    }
+
+    println!(
+        "Finished. Checked: {} bytes, differing blocks: {}",
+        manifest.size_from(0),
+        checkpoint.differing.len()
+    );
+    if !checkpoint.differing.is_empty() {
+        return Err(Error::ManifestDiffers(checkpoint.differing.len()));
+    }
+    Ok(())
+}
+
+// Hash the whole source, and each block of it into a manifest
+async fn hash_source(
+    spec: &str,
+    algo: HashAlgo,
+    manifest: Option<&str>,
+    block_size: usize,
+) -> error::Result<()> {
+    let mut source = source::open(spec, IoBackend::Tokio, false).await?;
+    let mut writer = match manifest {
+        Some(path) => {
+            Some(manifest::ManifestWriter::create(path, algo, block_size).context("create", path)?)
+        }
+        None => None,
+    };
+    let bar = match source.size().await {
+        Some(size) => ProgressBar::new(size),
+        None => ProgressBar::new_spinner(),
+    };
+    bar.set_style(
+        ProgressStyle::with_template(
+            "{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]",
+        )
+        .unwrap(),
+    );
+
+    let mut hasher = algo.hasher();
+    let mut block = vec![0; block_size];
+    let mut pos = 0;
+    loop {
+        // A block is filled up, short reads only end the source
+        let mut len = 0;
+        while len < block_size {
+            let n = source
+                .read(&mut block[len..])
+                .await
+                .map_err(|source| Error::Read {
+                    side: "source",
+                    offset: pos + len as u64,
+                    source,
+                })?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        if len == 0 {
+            break;
+        }
+        hasher.update(&block[..len]);
+        if let (Some(writer), Some(path)) = (&mut writer, manifest) {
+            writer
+                .append(pos, &block[..len])
+                .io_context("write", path)?;
+        }
+        pos += len as u64;
+        bar.set_position(pos);
+    }
+    bar.finish_and_clear();
+
+    if let (Some(writer), Some(path)) = (writer, manifest) {
+        writer.finish().io_context("write", path)?;
+        println!("Manifest written to {}.", path);
+    }
+    println!("{}  {}", hash::to_hex(&hasher.finalize()), spec);
+    Ok(())
+}
+
+// Compare the source and the target once more the same way, as a sync
+// with the target as the reference, or a plain sync again to repair.
+// Only the source and the target are used, nothing of the first pass is
+// repeated, from the checks before it to the reports after it.
+async fn verify_after(args: &SyncArgs, target: &str, driver: &Driver) -> error::Result<()> {
+    // What was written may still be cached, it has to come from the
+    // target itself
+    let file = std::fs::File::open(target).context("open", target)?;
+    file.sync_all().io_context("flush", target)?;
+    posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    )
+    .map_err(|source| Error::System {
+        action: "drop the cached target",
+        source,
+    })?;
+    drop(file);
+
+    // Only what's about reading and comparing the pair, or writing it with
+    // --repair, is taken over. Whatever the sync wrote along the way, like
+    // a bitmap or a mapfile, isn't written again.
+    let pass = SyncArgs {
+        block_size: args.block_size,
+        source_block_size: args.source_block_size,
+        target_block_size: args.target_block_size,
+        adaptive: args.adaptive,
+        dual_bar: args.dual_bar,
+        reference: if args.repair {
+            Reference::Source
+        } else {
+            Reference::Target
+        },
+        report_units: args.report_units,
+        compare: args.compare.clone(),
+        max_open_fds: args.max_open_fds,
+        target_size: args.target_size,
+        bmap: args.bmap.clone(),
+        on_read_error: args.on_read_error,
+        snapshot_source: args.snapshot_source.clone(),
+        fsfreeze: args.fsfreeze.clone(),
+        fsfreeze_timeout: args.fsfreeze_timeout,
+        loop_setup: args.loop_setup,
+        require_block_device: args.require_block_device,
+        require_regular_file: args.require_regular_file,
+        force: args.force,
+        no_excl: args.no_excl,
+        excl_source: args.excl_source,
+        wait_lock: args.wait_lock,
+        dry_run: args.dry_run,
+        verify_writes: args.verify_writes,
+        json: args.json,
+        repair: args.repair,
+        hash: args.hash,
+        io_backend: args.io_backend,
+        direct: args.direct,
+        drop_cache: args.drop_cache,
+        buffers: args.buffers,
+        queue_depth: args.queue_depth,
+        jobs: args.jobs,
+        fsync: args.fsync,
+        fsync_interval: args.fsync_interval,
+        limit_rate: args.limit_rate,
+        limit_write_rate: args.limit_write_rate,
+        discard_zeroes: args.discard_zeroes,
+        sparse: args.sparse,
+        size_mismatch: args.size_mismatch,
+        source_offset: args.source_offset,
+        target_offset: args.target_offset,
+        length: args.length,
+        ssh: args.ssh.clone(),
+        remote_group: args.remote_group,
+        compress: args.compress,
+        tls_ca: args.tls_ca.clone(),
+        tls_cert: args.tls_cert.clone(),
+        tls_key: args.tls_key.clone(),
+        s3_endpoint: args.s3_endpoint.clone(),
+        remote_ssdsync: args.remote_ssdsync.clone(),
+        ..SyncArgs::defaults(args.source.as_ref().unwrap(), target)
+    };
+    driver.say(format_args!(
+        "\nVerifying {} against {}",
+        target,
+        args.source.as_ref().unwrap()
+    ));
+    sync(&pass, false, driver).await.map(|_| ())
+}
+
+// Cloning is a sync of the whole device, the partition table and the
+// boot code come along as the first blocks.
+async fn clone(args: &SyncArgs, new_guid: bool, driver: &Driver) -> error::Result<Summary> {
+    if !args.more_targets.is_empty() {
+        return Err(Error::Usage(
+            "A disk is cloned onto one target at a time".to_string(),
+        ));
+    }
+    let mut summary = sync(args, true, driver).await?;
+    verify_phase(args, &mut summary, driver).await?;
+    let target_name = &summary.target;
+
+    if new_guid && args.dry_run {
+        driver.say("Dry run, the GUIDs on the target are left alone.");
+    } else if new_guid {
+        let n = gpt::randomize_guids(target_name).context("update the GPT on", target_name)?;
+        driver.say(format_args!(
+            "Gave the target a new disk GUID and {} new partition GUIDs.",
+            n
+        ));
+    }
+    Ok(summary)
+}
+
+// How many bytes the source and the target are read at a time
+fn read_sizes(args: &SyncArgs) -> (usize, usize) {
+    let size = |side: Option<u64>| side.map_or(args.block_size, |s| s as usize);
+    (size(args.source_block_size), size(args.target_block_size))
+}
+
+// The largest size both are a multiple of, so blocks of it never stand
+// across the end of a read
+fn gcd(a: usize, b: usize) -> usize {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+// Every handle a sync keeps open: the source, the target for reading and
+// writing, and the optional extra files. Or as many as the user wants.
+fn fds_needed(args: &SyncArgs) -> u64 {
+    let extra = [
+        &args.slow_log,
+        &args.control_socket,
+        &args.metrics_listen,
+        &args.journal,
+        &args.sparse_image_out,
+        &args.oci_out,
+        &args.write_manifest,
+    ];
+    let handles = BASE_FDS
+        + source::handle_count(args.source.as_ref().unwrap())
+        + 2
+        + extra.iter().filter(|f| f.is_some()).count() as u64
+        + args.loop_setup as u64
+        + !args.dry_run as u64
+        + !args.no_excl as u64
+        + args.excl_source as u64
+        + args.reflink as u64
+        + uring_fds(args);
+    args.max_open_fds.unwrap_or(handles)
+}
+
+// Apply --size-mismatch to a target of another size than the source,
+// returns its size afterwards. Only a regular file that's written to is
+// cut or grown.
+fn resize_target(
+    args: &SyncArgs,
+    driver: &Driver,
+    target: &str,
+    written: bool,
+    source_size: u64,
+    target_size: u64,
+) -> error::Result<u64> {
+    let resize = match args.size_mismatch {
+        SizeMismatch::Error => {
+            return Err(Error::SizeMismatch {
+                source_size,
+                target_size,
+            })
+        }
+        SizeMismatch::SyncMin => false,
+        SizeMismatch::Truncate => target_size > source_size,
+        SizeMismatch::Extend => target_size < source_size,
+    };
+    if !resize {
+        return Ok(target_size);
+    }
+    if args.target_size.is_some() {
+        return Err(Error::Usage(
+            "A target with a size given by --target-size can't be resized".to_string(),
+        ));
+    }
+    if !written {
+        driver.say(format_args!(
+            "Not resizing {}, nothing is written to it.",
+            target
+        ));
+        return Ok(target_size);
+    }
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(target)
+        .context("open for resizing", target)?;
+    if !file.metadata().context("resize", target)?.is_file() {
+        return Err(Error::TargetType {
+            path: target.to_string(),
+            expected: "a regular file, only those can be resized",
+        });
+    }
+    if target_size > source_size {
+        file.set_len(source_size).context("truncate", target)?;
+        driver.say(format_args!(
+            "Truncated {} to {} bytes.",
+            target, source_size
+        ));
+    } else {
+        let grown = fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::empty(),
+            target_size as i64,
+            (source_size - target_size) as i64,
+        );
+        // Without fallocate the file only grows by a hole
+        if grown.is_err() {
+            file.set_len(source_size).context("extend", target)?;
+        }
+        driver.say(format_args!(
+            "Extended {} to {} bytes.",
+            target, source_size
+        ));
+    }
+    Ok(source_size)
+}
+
+// A ring and its eventfd for each side read through io_uring, with an
+// O_DIRECT handle besides for each side and the writer with --direct, and
+// another ring and eventfd for the writes with --io-backend uring
+fn uring_fds(args: &SyncArgs) -> u64 {
+    let reads = match (args.io_backend, args.direct) {
+        (_, true) => 2 * 3 + 1,
+        (IoBackend::Uring, false) => 2 * 2,
+        (IoBackend::Tokio | IoBackend::Mmap, false) => 0,
+    };
+    let writes = match args.io_backend {
+        IoBackend::Uring if !args.verify_writes => 2,
+        _ => 0,
+    };
+    reads + writes
+}
+
+// The first option given that only works on a target that's here, read
+// and written by this process
+fn local_option(args: &SyncArgs) -> Option<&'static str> {
+    let local_only = [
+        (args.loop_setup, "--loop-setup"),
+        (args.reflink, "--reflink"),
+        (args.direct, "--direct"),
+        (args.drop_cache, "--drop-cache"),
+        (args.journal.is_some(), "--journal"),
+        (args.sparse_image_out.is_some(), "--sparse-image-out"),
+        (args.bitmap_out.is_some(), "--bitmap-out"),
+        (args.bmap.is_some(), "--bmap"),
+        (args.mapfile.is_some(), "--mapfile"),
+        (args.badblocks_out.is_some(), "--badblocks-out"),
+        (args.oci_out.is_some(), "--oci-out"),
+        (args.checkpoint.is_some(), "--checkpoint"),
+        (args.smart_report, "--smart-report"),
+        (args.verify_after, "--verify-after"),
+        (args.verify_writes, "--verify-writes"),
+        (args.verify_footer.is_some(), "--verify-footer"),
+        (!args.assert_region.is_empty(), "--assert-region"),
+        (args.target_size.is_some(), "--target-size"),
+        (
+            args.windowed(),
+            "--source-offset, --target-offset and --length",
+        ),
+        (args.adaptive.is_some(), "--adaptive"),
+        (args.compare != compare::Comparison::Exact, "--compare"),
+        (args.reference == Reference::Target, "--reference target"),
+        (!args.multigrain.is_empty(), "--multigrain"),
+        (args.discard_zeroes, "--discard-zeroes"),
+        (args.sparse, "--sparse"),
+        (args.control_socket.is_some(), "--control-socket"),
+        (args.metrics_listen.is_some(), "--metrics-listen"),
+        (args.preflight, "--preflight"),
+        (
+            args.expect_source_manifest.is_some(),
+            "--expect-source-manifest",
+        ),
+    ];
+    local_only
+        .iter()
+        .find(|(set, _)| *set)
+        .map(|(_, option)| *option)
+}
+
+type MessageFn = Arc<dyn Fn(&str) + Send + Sync>;
+
+// Where the messages of a sync go: printed by the command line, handed
+// to a callback of a SyncEngine
+#[derive(Clone, Default)]
+struct Messages(Option<MessageFn>);
+
+impl Messages {
+    fn say(&self, message: impl std::fmt::Display) {
+        match &self.0 {
+            // Without the blank lines that set it apart on a terminal
+            Some(to) => to(message.to_string().trim_start_matches('\n')),
+            None => println!("{}", message),
+        }
+    }
+}
+
+// What runs a sync besides its options. The command line shows progress
+// bars and prints messages, a SyncEngine may report progress to a
+// callback and cancel.
+struct Driver {
+    bars: bool,
+    progress: Option<Box<dyn Fn(u64, u64) + Send + Sync>>,
+    messages: Messages,
+    cancel: Option<Arc<AtomicBool>>,
+    // Print the status on SIGUSR1
+    status: bool,
+    // A region of a sync with --jobs, which locks and claims the target
+    // for all of them
+    region: bool,
+    // The stripe of the region in the checkpoint of all of them
+    part: Option<checkpoint::Part>,
+}
+
+impl Driver {
+    fn say(&self, message: impl std::fmt::Display) {
+        self.messages.say(message)
+    }
+
+    // The command line's: SIGINT or SIGTERM cancel the sync, which
+    // finishes the writes in flight and reports how far it got. A second
+    // one ends the process right away. SIGUSR1 prints how far it is.
+    fn interruptible() -> error::Result<Self> {
+        let mut interrupt = signal(SignalKind::interrupt()).map_err(Error::Signal)?;
+        let mut terminate = signal(SignalKind::terminate()).map_err(Error::Signal)?;
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = interrupt.recv() => (),
+                    _ = terminate.recv() => (),
+                }
+                if flag.swap(true, Ordering::Relaxed) {
+                    eprintln!("Interrupted again, exiting right away.");
+                    fsfreeze::thaw_all();
+                    std::process::exit(error::EXIT_INTERRUPTED);
+                }
+                eprintln!("\nInterrupted, finishing the writes in flight.");
+            }
+        });
+        Ok(Driver {
+            cancel: Some(cancel),
+            status: true,
+            ..Driver::default()
+        })
+    }
+
+    // With --json nothing but the result is shown, no bars either
+    fn for_args(args: &SyncArgs) -> error::Result<Self> {
+        Ok(Driver {
+            bars: !args.json,
+            ..Driver::interruptible()?
+        })
+    }
+}
+
+// Buffers per reader, the channels hold twice as many unless told otherwise
+const DEFAULT_BUFFERS: usize = 4;
+
+impl Default for Driver {
+    fn default() -> Self {
+        Driver {
+            bars: true,
+            progress: None,
+            messages: Messages::default(),
+            cancel: None,
+            status: false,
+            region: false,
+            part: None,
+        }
+    }
+}
+
+// A reader of `spec` with --drop-cache dropping what it has read from the
+// page cache. A mapped file is read from the cache itself.
+fn uncached(
+    args: &SyncArgs,
+    reader: Box<dyn BlockSource>,
+    spec: &str,
+) -> error::Result<Box<dyn BlockSource>> {
+    if !args.drop_cache || spec == "-" {
+        return Ok(reader);
+    }
+    if args.io_backend == IoBackend::Mmap {
+        return Err(Error::Usage(
+            "--drop-cache doesn't work with --io-backend mmap, which reads the cache itself"
+                .to_string(),
+        ));
+    }
+    let path = resolve_device(spec)?;
+    cache::drop_behind(reader, &path).context("open", &path)
+}
+
+// Sync source to target and return the resolved target path. With `whole`
+// set, the source has to fit onto the target in its entirety.
+async fn sync(args: &SyncArgs, whole: bool, driver: &Driver) -> error::Result<Summary> {
+    sync_from(args, whole, driver, None).await
+}
+
+// Freeze the filesystem of --fsfreeze, the one the source is mounted as
+// with auto, until what's returned is dropped
+fn freeze(args: &SyncArgs, driver: &Driver) -> error::Result<Option<fsfreeze::Frozen>> {
+    let mountpoint = match args.fsfreeze.as_deref() {
+        None => return Ok(None),
+        Some("auto") => {
+            let source = resolve_device(args.source.as_ref().unwrap())?;
+            match fsfreeze::mountpoint(&source).context("find the mount point of", &source)? {
+                Some(mountpoint) => mountpoint,
+                None => {
+                    driver.say(format_args!(
+                        "{} isn't mounted, there's nothing to freeze.",
+                        source
+                    ));
+                    return Ok(None);
+                }
+            }
+        }
+        Some(mountpoint) => mountpoint.to_string(),
+    };
+    // Everything the sync writes to along the way would wait for the thaw
+    let written = [
+        args.target.as_deref(),
+        args.journal.as_deref(),
+        args.checkpoint.as_deref(),
+        args.write_manifest.as_deref(),
+        args.stats_file.as_deref(),
+        args.slow_log.as_deref(),
+        args.bitmap_out.as_deref(),
+        args.sparse_image_out.as_deref(),
+        args.oci_out.as_deref(),
+        args.mapfile.as_deref(),
+        args.badblocks_out.as_deref(),
+        args.control_socket.as_deref(),
+        log::file(),
+    ];
+    let more = args.more_targets.iter().map(String::as_str);
+    if let Some(path) = written
+        .iter()
+        .copied()
+        .flatten()
+        .chain(more)
+        .find(|path| fsfreeze::holds(&mountpoint, path))
+    {
+        return Err(Error::Usage(format!(
+            "{} is on {}, it can't be written while that's frozen",
+            path, mountpoint
+        )));
+    }
+    let timeout = args.fsfreeze_timeout.map(Duration::from_secs);
+    Ok(Some(
+        fsfreeze::Frozen::freeze(&mountpoint, timeout, &driver.messages)
+            .context("freeze", &mountpoint)?,
+    ))
+}
+
+// A sync from a snapshot of the source, which is removed again however
+// the sync ends
+async fn sync_snapshot(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    spec: &lvm::Spec,
+) -> error::Result<Summary> {
+    let origin = resolve_device(args.source.as_ref().unwrap())?;
+    let frozen = freeze(args, driver)?;
+    let snapshot =
+        lvm::Snapshot::create(&origin, spec, &driver.messages).context("snapshot", &origin)?;
+    drop(frozen);
+    driver.say(format_args!("{} -> {}", origin, snapshot.path));
+    let args = SyncArgs {
+        source: Some(snapshot.path.clone()),
+        snapshot_source: None,
+        fsfreeze: None,
+        ..args.clone()
+    };
+    let summary = Box::pin(sync_from(&args, whole, driver, None)).await?;
+    snapshot.check().map_err(Error::Snapshot)?;
+    Ok(summary)
+}
+
+// A sync reading the source given, instead of opening it by itself
+async fn sync_from(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+) -> error::Result<Summary> {
+    if let Some(spec) = &args.snapshot_source {
+        if source.is_some() {
+            return Err(Error::Usage(
+                "--snapshot-source only works with one target".to_string(),
+            ));
+        }
+        return sync_snapshot(args, whole, driver, spec).await;
+    }
+    if args.fsfreeze.is_some() {
+        if source.is_some() {
+            return Err(Error::Usage(
+                "--fsfreeze only works with one target".to_string(),
+            ));
+        }
+        let frozen = freeze(args, driver)?;
+        let args = SyncArgs {
+            fsfreeze: None,
+            ..args.clone()
+        };
+        let summary = Box::pin(sync_from(&args, whole, driver, None)).await;
+        drop(frozen);
+        return summary;
+    }
+    if args.jobs > 1 {
+        if source.is_some() {
+            return Err(Error::Usage(
+                "--jobs only works with one target".to_string(),
+            ));
+        }
+        return regions::sync(args, whole, driver).await;
+    }
+    if let Some(remote) = remote::Remote::parse(args.target.as_ref().unwrap()) {
+        return remote::sync(args, whole, driver, source, remote).await;
+    }
+    let started = Instant::now();
+    if args.compress.is_some() {
+        return Err(Error::Usage(
+            "Only what's sent to a remote target is compressed".to_string(),
+        ));
+    }
+    if args.tls_ca.is_some() || args.tls_cert.is_some() {
+        return Err(Error::Usage(
+            "The TLS options are only for tls:// targets".to_string(),
+        ));
+    }
+    if args.target.as_deref() == Some("-") {
+        return stream::to_stdout(args, driver, source).await;
+    }
+    if let Some(bucket) = s3::Bucket::parse(args.target.as_ref().unwrap()) {
+        return s3::sync(args, driver, source, bucket).await;
+    }
+    if let Some(uri) = nbd::Uri::parse(args.target.as_ref().unwrap()) {
+        return nbd::sync(args, whole, driver, source, uri).await;
+    }
+    if args.preflight {
+        preflight::run(args, whole, driver).await;
+    }
+
+    sync_pair(args, whole, driver, source, started).await
+}
+
+// The source and the target of a sync, opened and checked, with what's
+// held until the sync is done
+struct Pair {
+    // The target as given, resolved
+    target_arg: String,
+    // The target opened, a loop device of it with --loop-setup
+    target_name: String,
+    source_r: Box<dyn BlockSource>,
+    target_r: Box<dyn BlockSource>,
+    // None if nothing is written to the target
+    target_w: Option<File>,
+    direct_w: Option<direct::DirectWriter>,
+    reflink: Option<reflink::Reflink>,
+    smart_before: Option<Vec<smart::Attribute>>,
+    source_size: Option<u64>,
+    // After any truncating or extending
+    target_size: u64,
+    // Dropping it detaches the device
+    loop_device: Option<loopdev::LoopDevice>,
+    _lock: Option<lock::Lock>,
+    _claimed: [Option<std::fs::File>; 2],
+}
+
+// Open the source, unless it's given, and the target, make sure it's the
+// right one and resize it as --size-mismatch has it
+async fn open_pair(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+) -> error::Result<Pair> {
+    ensure_fd_limit(fds_needed(args))?;
+
+    let target_arg = resolve_device(args.target.as_ref().unwrap())?;
+    check_target_type(args, &target_arg)?;
+    check_target_unused(args, &target_arg)?;
+    check_not_source(args, &target_arg)?;
+
+    // Held until the end of the sync, so no other one writes to the target
+    let lock = if args.dry_run || driver.region {
+        None
+    } else {
+        lock::acquire(&target_arg, args.wait_lock, driver).await?
+    };
+
+    // Kept until the end of the sync, dropping it detaches the device
+    let loop_device = if args.loop_setup {
+        let device = loopdev::attach(&target_arg, &driver.messages)
+            .context("attach a loop device to", &target_arg)?;
+        driver.say(format_args!("{} -> {}", target_arg, device.path));
+        Some(device)
+    } else {
+        None
+    };
+    let target_name = &loop_device
+        .as_ref()
+        .map_or(target_arg.clone(), |d| d.path.clone());
+
+    // Before anything is touched, make sure it's the right target
+    for assertion in args.assert_region.iter() {
+        assertion.check(target_name).map_err(Error::AssertRegion)?;
+    }
+
+    // Held until the end of the sync, closing them releases the devices
+    let claimed_target = if args.no_excl || args.dry_run || driver.region {
+        None
+    } else {
+        claim(target_name, "Give --no-excl to open it anyway")?
+    };
+    let claimed_source = match args.source.as_deref() {
+        Some(source) if args.excl_source && !driver.region => claim(
+            &resolve_device(source)?,
+            "Leave out --excl-source to read it anyway",
+        )?,
+        _ => None,
+    };
+
+    // Read both file sizes
+    let mut source_r = match source {
+        Some(source) => source,
+        None => {
+            let spec = args.source.as_ref().unwrap();
+            uncached(
+                args,
+                source::open(spec, args.io_backend, args.direct).await?,
+                spec,
+            )?
+        }
+    };
+    let mut target_r = uncached(
+        args,
+        source::open_file(target_name, args.io_backend, args.direct).await?,
+        target_name,
+    )?;
+    if args.windowed() {
+        if matches!(
+            args.size_mismatch,
+            SizeMismatch::Truncate | SizeMismatch::Extend
+        ) {
+            return Err(Error::Usage(
+                "Only a whole target can be truncated or extended, not a part of it".to_string(),
+            ));
+        }
+        source_r = Box::new(
+            source::Window::new(source_r, args.source_offset, args.length)
+                .await
+                .io_context("skip ahead in", args.source.as_ref().unwrap())?,
+        );
+        target_r = Box::new(
+            source::Window::new(target_r, args.target_offset, args.length)
+                .await
+                .io_context("skip ahead in", target_name)?,
+        );
+    }
+
+    // What the bmap doesn't map is skipped on both sides
+    if let Some(path) = &args.bmap {
+        let bmap = Arc::new(bmap::Bmap::load(path).context("load", path)?);
+        if let Some(size) = source_r.size().await.filter(|s| *s != bmap.image_size) {
+            return Err(Error::Usage(format!(
+                "The bmap is of an image of {} bytes, the source has {}",
+                bmap.image_size, size
+            )));
+        }
+        driver.say(format_args!(
+            "The bmap maps {} of {} bytes.",
+            bmap.mapped_size(),
+            bmap.image_size
+        ));
+        let skipped: Arc<dyn Fn(u64, u64) -> bool + Send + Sync> =
+            Arc::new(move |pos, len| bmap.unmapped(pos, len));
+        source_r = Box::new(source::Skipping::new(source_r, skipped.clone()));
+        target_r = Box::new(source::Skipping::new(target_r, skipped));
+    }
+
+    // A sparse image is written instead of the target. If the target is
+    // the reference, it's only read.
+    let target_w = match (&args.sparse_image_out, &args.oci_out) {
+        _ if args.read_only() => None,
+        (Some(_), _) | (_, Some(_)) => None,
+        // Readable too, discarded blocks are read back
+        (None, None) => Some(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(target_name)
+                .await
+                .context("open for writing", target_name)?,
+        ),
+    };
+
+    let direct_w = match (&target_w, args.direct) {
+        (Some(_), true) => Some(
+            direct::DirectWriter::open(target_name)
+                .context("open for direct writing", target_name)?,
+        ),
+        _ => None,
+    };
+
+    let reflink = match (&target_w, args.reflink) {
+        (Some(_), true) if args.source_offset != args.target_offset => {
+            driver.say("Not using reflinks: the source and the target offsets differ");
+            None
+        }
+        (Some(_), true) => match reflink::open(args.source.as_ref().unwrap(), target_name) {
+            Ok(reflink) => Some(reflink),
+            Err(e) => {
+                driver.say(format_args!("Not using reflinks: {}", e));
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let smart_before = if args.smart_report {
+        smart::read(target_name)
+    } else {
+        None
+    };
+    if args.smart_report && smart_before.is_none() {
+        driver.say(format_args!(
+            "No SMART report, {} is not a block device.",
+            target_name
+        ));
+    }
+
+    // The size of a piped source is only known once it's fully read
+    let source_size = source_r.size().await;
+    if args.verify_after && source_size.is_none() {
+        return Err(Error::Usage(
+            "A piped source can't be read again to verify the target".to_string(),
+        ));
+    }
+    let target_size = match args.target_size {
+        Some(size) => args.window(size, args.target_offset),
+        None => target_r
+            .size()
+            .await
+            .ok_or_else(|| Error::TargetIsPipe(target_name.clone()))?,
+    };
+
+    match source_size {
+        Some(source_size) => driver.say(format_args!("{} -> {}", source_size, target_size)),
+        None => driver.say(format_args!("? -> {}", target_size)),
+    }
+    if args.windowed() {
+        driver.say(format_args!(
+            "Syncing from {} of the source onto {} of the target.",
+            args.source_offset, args.target_offset
+        ));
+    }
+
+    let target_size = match source_size {
+        Some(source_size) if source_size != target_size => {
+            let resized = resize_target(
+                args,
+                driver,
+                target_name,
+                target_w.is_some(),
+                source_size,
+                target_size,
+            )?;
+            if resized != target_size {
+                // Its reader still has the old size
+                target_r = uncached(
+                    args,
+                    source::open_file(target_name, args.io_backend, args.direct).await?,
+                    target_name,
+                )?;
+            } else {
+                driver.say(format_args!(
+                    "Sizes differ, only the first {} bytes are synced.",
+                    std::cmp::min(source_size, target_size)
+                ));
+            }
+            resized
+        }
+        None if args.size_mismatch != SizeMismatch::SyncMin => {
+            return Err(Error::Usage(
+                "The size of a piped source isn't known up front, \
+                 only --size-mismatch sync-min works with it"
+                    .to_string(),
+            ));
+        }
+        _ => target_size,
+    };
+
+    if let Some(source_size) = source_size {
+        if whole && target_size < source_size {
+            return Err(Error::TargetTooSmall {
+                source_size,
+                target_size,
+            });
+        }
+    }
+
+    Ok(Pair {
+        target_arg,
+        target_name: target_name.clone(),
+        source_r,
+        target_r,
+        target_w,
+        direct_w,
+        reflink,
+        smart_before,
+        source_size,
+        target_size,
+        loop_device,
+        _lock: lock,
+        _claimed: [claimed_target, claimed_source],
+    })
+}
+
+// How the blocks of a sync are read and compared
+struct Plan {
+    // What the source should have, with its block size
+    manifest: Option<Arc<manifest::Manifest>>,
+    // What each side is read in
+    source_read: usize,
+    target_read: usize,
+    block_size: usize,
+    // The part both sides have
+    sync_size: u64,
+    same: compare::CompareFn,
+    adaptive: Option<adaptive::Adaptive>,
+}
+
+// The source manifest of --expect-source-manifest, downloaded or loaded
+async fn source_manifest(args: &SyncArgs) -> error::Result<Option<Arc<manifest::Manifest>>> {
+    Ok(match &args.expect_source_manifest {
+        Some(url) if http::is_url(url) => Some(Arc::new(
+            http::manifest(url).await.context("download", url)?,
+        )),
+        Some(path) => Some(Arc::new(
+            manifest::Manifest::load(path).context("load", path)?,
+        )),
+        None => None,
+    })
+}
+
+fn plan_blocks(
+    args: &SyncArgs,
+    driver: &Driver,
+    pair: &Pair,
+    manifest: Option<Arc<manifest::Manifest>>,
+) -> error::Result<Plan> {
+    // Manifest entries are per block, so its block size is used
+    let (source_read, target_read) = read_sizes(args);
+    let block_size = manifest
+        .as_ref()
+        .map_or(gcd(source_read, target_read), |m| m.block_size);
+    // Sides without a read size of their own read the manifest's blocks
+    let (source_read, target_read) = match &manifest {
+        Some(_) => (
+            args.source_block_size.map_or(block_size, |s| s as usize),
+            args.target_block_size.map_or(block_size, |s| s as usize),
+        ),
+        None => (source_read, target_read),
+    };
+
+    // A block larger than what's being synced would only be read short,
+    // so clamp it to the size of the smaller side.
+    let sync_size = pair
+        .source_size
+        .map_or(pair.target_size, |s| std::cmp::min(s, pair.target_size));
+    if !block_size.is_multiple_of(args.compare.unit()) {
+        return Err(Error::Usage(format!(
+            "Block size {} is not a multiple of the {} byte words compared.",
+            block_size,
+            args.compare.unit()
+        )));
+    }
+    if let Some(writer) = pair.direct_w.as_ref() {
+        if !block_size.is_multiple_of(writer.align) {
+            return Err(Error::Usage(format!(
+                "Block size {} is not a multiple of the {} bytes direct writes to {} are aligned to.",
+                block_size, writer.align, pair.target_name
+            )));
+        }
+        if !args.target_offset.is_multiple_of(writer.align as u64) {
+            return Err(Error::Usage(format!(
+                "Target offset {} is not a multiple of the {} bytes direct writes to {} are aligned to.",
+                args.target_offset, writer.align, pair.target_name
+            )));
+        }
+    }
+    let same = args.compare.compare_fn();
+    let adaptive = match args.adaptive {
+        Some(_) if args.compare == compare::Comparison::TextNormalizeEol => {
+            return Err(Error::Usage(
+                "Text compared with line endings normalized can't be narrowed down in pieces."
+                    .to_string(),
+            ))
+        }
+        Some(min) if !min.is_multiple_of(args.compare.unit() as u64) => {
+            return Err(Error::Usage(format!(
+                "Adaptive minimum {} is not a multiple of the {} byte words compared.",
+                min,
+                args.compare.unit()
+            )))
+        }
+        Some(min) => Some(adaptive::Adaptive::new(
+            min as usize,
+            block_size,
+            args.compare.unit(),
+        )),
+        None => None,
+    };
+
+    let block_size = if (block_size as u64) > sync_size {
+        let clamped = std::cmp::max(sync_size, 1) as usize;
+        driver.say(format_args!(
+            "Block size {} is larger than the {} bytes to sync, using {}",
+            block_size, sync_size, clamped
+        ));
+        clamped
+    } else {
+        block_size
+    };
+    Ok(Plan {
+        manifest,
+        source_read,
+        target_read,
+        block_size,
+        sync_size,
+        same,
+        adaptive,
+    })
+}
+
+// The progress bar of a sync, and with --dual-bar the one of its writes
+fn progress_bars(
+    args: &SyncArgs,
+    driver: &Driver,
+    source_size: Option<u64>,
+    sync_size: u64,
+) -> (ProgressBar, Option<ProgressBar>) {
+    // A source of unknown size only gets a spinner
+    let (bar, template) = match source_size {
+        Some(_) => (
+            ProgressBar::new(sync_size),
+            "{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]",
+        ),
+        None => (
+            ProgressBar::new_spinner(),
+            "{spinner} {bytes} [{bytes_per_sec}]",
+        ),
+    };
+
+    // With --dual-bar, writes get their own bar below the scan progress
+    let multi = MultiProgress::new();
+    if !driver.bars {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let prefix = if args.dual_bar { "{prefix:>5} " } else { "" };
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template(&format!("{}{}", prefix, template))
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+    let write_bar = if args.dual_bar {
+        bar.set_prefix("read");
+        let write_bar = ProgressBar::new(sync_size);
+        write_bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{prefix:>5} {wide_bar} [{bytes} written, {bytes_per_sec}]")
+                .expect("Template error")
+                .progress_chars("##-"),
+        );
+        write_bar.set_prefix("write");
+        multi.add(bar.clone());
+        Some(multi.add(write_bar))
+    } else {
+        None
+    };
+    (bar, write_bar)
+}
+
+// The mapfile of --mapfile for a sync of `sync_size` bytes, a new one
+// if there's none yet
+fn load_mapfile(
+    args: &SyncArgs,
+    driver: &Driver,
+    sync_size: u64,
+) -> error::Result<Option<mapfile::Mapfile>> {
+    let mapfile = match &args.mapfile {
+        Some(_) if args.reference == Reference::Target => {
+            return Err(Error::Usage(
+                "A mapfile can't be kept with the target as the reference".to_string(),
+            ))
+        }
+        Some(path) if std::path::Path::new(path).exists() => {
+            mapfile::Mapfile::load(path, sync_size).context("load", path)?
+        }
+        Some(_) => mapfile::Mapfile::new(sync_size),
+        None => return Ok(None),
+    };
+    driver.say(format_args!(
+        "The mapfile has {} bytes finished and {} bad, only the rest is synced.",
+        mapfile.bytes(mapfile::FINISHED),
+        mapfile.bytes(mapfile::BAD)
+    ));
+    Ok(Some(mapfile))
+}
+
+// Where a sync starts: where its region's stripe is, or with --resume where
+// its checkpoint has it. The sync is a single stripe, its layout still has
+// to match the one of the checkpoint resumed from.
+fn resume_point(args: &SyncArgs, driver: &Driver, sync_size: u64) -> error::Result<u64> {
+    match (&driver.part, &args.checkpoint) {
+        (Some(part), _) => {
+            let stripe = part.stripe();
+            Ok(stripe.next - stripe.start)
+        }
+        (None, Some(path)) if args.resume => {
+            let checkpoint = checkpoint::Checkpoint::load(path).context("load", path)?;
+            let points = checkpoint::resume_points(&checkpoint, &[(0, sync_size)])
+                .map_err(|e| Error::Usage(format!("Can't resume from {}: {}", path, e)))?;
+            driver.say(format_args!("Resuming at {}.", points[0]));
+            Ok(points[0])
+        }
+        _ => Ok(0),
+    }
+}
+
+// A sync of a source onto a target that's a file or a device
+async fn sync_pair(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+    started: Instant,
+) -> error::Result<Summary> {
+    let pair = open_pair(args, whole, driver, source).await?;
+    let manifest = source_manifest(args).await?;
+    let plan = plan_blocks(args, driver, &pair, manifest)?;
+    let Plan {
+        block_size,
+        sync_size,
+        ..
+    } = plan;
+    let Pair {
+        target_arg,
+        target_name,
+        mut source_r,
+        mut target_r,
+        target_w,
+        direct_w,
+        reflink,
+        smart_before,
+        source_size,
+        target_size,
+        loop_device,
+        ..
+    } = pair;
+    let target_name = &target_name;
+    let validate = args.reference == Reference::Target;
+    tracing::info!(
+        target = target_name.as_str(),
+        size = sync_size,
+        block_size,
+        "Syncing"
+    );
+
+    // Removable media can vanish in the middle of a sync
+    let removed = Arc::new(AtomicBool::new(false));
+    let presence = device::block_rdev(target_name)
+        .filter(|rdev| device::is_removable(*rdev))
+        .map(|rdev| {
+            tokio::spawn(device::watch_presence(
+                target_name.clone(),
+                rdev,
+                removed.clone(),
+            ))
+        });
+
+    let (bar, write_bar) = progress_bars(args, driver, source_size, sync_size);
+
+    // A queue that can't hold every buffer could fill up all around
+    let n_buffers = args.buffers;
+    let channel_size = args.queue_depth.unwrap_or(n_buffers * 2);
+    if channel_size < n_buffers {
+        return Err(Error::Usage(format!(
+            "A queue depth of {} can't hold the {} buffers.",
+            channel_size, n_buffers
+        )));
+    }
+
+    // What the mapfile has as finished or bad is skipped on both sides,
+    // as it was before the sync
+    let mut mapfile = load_mapfile(args, driver, sync_size)?;
+    if let Some(mapfile) = &mapfile {
+        let settled = Arc::new(mapfile.clone());
+        let skipped: Arc<dyn Fn(u64, u64) -> bool + Send + Sync> =
+            Arc::new(move |pos, len| settled.settled(pos, len));
+        source_r = Box::new(source::Skipping::new(source_r, skipped.clone()));
+        target_r = Box::new(source::Skipping::new(target_r, skipped));
+    }
+
+    // What a download would get that the target has already, as the
+    // manifest tells, isn't downloaded
+    let downloaded = http::is_url(args.source.as_ref().unwrap());
+    let reused = Arc::new(AtomicU64::new(0));
+    if let (Some(manifest), true) = (&plan.manifest, downloaded) {
+        let target = std::fs::File::open(target_name).context("open", target_name)?;
+        source_r = Box::new(http::Reuse::new(
+            source_r,
+            manifest.clone(),
+            target,
+            args.target_offset,
+            reused.clone(),
+        ));
+    }
+
+    // A failed source read is tried again in smaller parts, unless the
+    // sync is to end with it. A pipe can't be read again, and a download
+    // that failed has no bad sectors.
+    let unreadable = source::Unreadable::default();
+    let fill = match args.on_read_error {
+        OnReadError::Abort => None,
+        OnReadError::Skip => Some(0),
+        OnReadError::Fill(byte) => Some(byte),
+    };
+    if let (Some(fill), Some(_), false) = (fill, source_size, downloaded) {
+        source_r = Box::new(source::Rescue::new(source_r, unreadable.clone(), fill));
+    }
+
+    let start = resume_point(args, driver, sync_size)?;
+    if start > 0 {
+        source_r
+            .skip(start)
+            .await
+            .io_context("skip ahead in", args.source.as_ref().unwrap())?;
+        target_r
+            .skip(start)
+            .await
+            .io_context("skip ahead in", target_name)?;
+        bar.set_position(start);
+    }
+
+    // Both readers share the slow read log, lines are tagged by side
+    let slow_log_file = match &args.slow_log {
+        Some(path) => Some(Arc::new(Mutex::new(
+            std::fs::File::create(path).context("create", path)?,
+        ))),
+        None => None,
+    };
+    let slow_log = |label| slow_log_file.clone().map(|f| SlowLog::new(label, f));
+
+    // Each side is read in its own size if that's not the block size
+    let source_r: Box<dyn BlockSource> = if plan.source_read != block_size {
+        Box::new(source::ReadSize::new(source_r, plan.source_read))
+    } else {
+        source_r
+    };
+    let target_r: Box<dyn BlockSource> = if plan.target_read != block_size {
+        Box::new(source::ReadSize::new(target_r, plan.target_read))
+    } else {
+        target_r
+    };
+
+    // Both readers take from the same bucket
+    let read_throttle = args.limit_rate.map(throttle::Throttle::new);
+
+    // Runs of zero blocks are taken at once, unless every block of the
+    // source has its hash taken or checked
+    let zero_runs = args.write_manifest.is_none() && plan.manifest.is_none();
+
+    // How far the sync is, for the control socket and SIGUSR1
+    let control = Arc::new(Control::new(sync_size));
+
+    let writer = target_w.map(|file| WriteTarget {
+        file,
+        name: target_name.clone(),
+        size: target_size,
+        offset: args.target_offset,
+        reflink,
+        direct: direct_w,
+        fsync: args.fsync,
+        fsync_interval: args.fsync_interval,
+        throttle: args.limit_write_rate.map(throttle::Throttle::new),
+        discard_zeroes: args.discard_zeroes,
+        sparse: args.sparse,
+        control: control.clone(),
+        verify: args.verify_writes,
+        drop_cache: args.drop_cache,
+        messages: driver.messages.clone(),
+        uring: args.io_backend == IoBackend::Uring && !args.verify_writes,
+    });
+    let mut pipeline = Pipeline::start(
+        (
+            source_r,
+            Reading {
+                side: "source",
+                slow_log: slow_log("source"),
+                throttle: read_throttle.clone(),
+                runs: zero_runs,
+            },
+        ),
+        (
+            target_r,
+            Reading {
+                side: "target",
+                slow_log: slow_log("target"),
+                throttle: read_throttle,
+                runs: zero_runs,
+            },
+        ),
+        start,
+        channel_size,
+        writer.map(|writer| (writer, write_bar.clone())),
+    );
+    // A reader that failed already is found out once it sends nothing
+    pipeline.fill(n_buffers, block_size).await;
+
+    let mut outputs = Outputs::create(args, sync_size, block_size).await?;
+
+    if let Some(path) = &args.control_socket {
+        let listener = UnixListener::bind(path).context("create", path)?;
+        tokio::spawn(control::serve(control.clone(), listener));
+    }
+    let metrics = match &args.metrics_listen {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .context("listen on", addr)?;
+            Some(tokio::spawn(metrics::serve(control.clone(), listener)))
+        }
+        None => None,
+    };
+    let status = match driver.status {
+        true => Some(control::report_on_signal(control.clone()).map_err(Error::Signal)?),
+        false => None,
+    };
+
+    let mut scan = Scan {
+        args,
+        driver,
+        source_hasher: args.expect_source_hash.as_ref().map(|e| e.algo.hasher()),
+        plan,
+        bar: bar.clone(),
+        control: control.clone(),
+        unreadable: unreadable.clone(),
+        removed: removed.clone(),
+        start,
+        pos: start,
+        total: 0,
+        diff: 0,
+        diff_bytes: 0,
+        source_mismatch: None,
+        source_left: false,
+        cancelled: false,
+        unreported: 0,
+        last_report: Instant::now(),
+    };
+    let scanning = Instant::now();
+    scan.run(&mut pipeline, &mut outputs).await?;
+    scan.hash_rest(&mut pipeline).await;
+    let (read, written) = pipeline.finish().await;
+    let finishing = Instant::now();
+    let Scan {
+        pos,
+        total,
+        diff,
+        cancelled,
+        source_mismatch,
+        ..
+    } = scan;
+
+    if let Some(path) = &args.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    if let Some(presence) = presence {
+        presence.abort();
+    }
+    if let Some(status) = status {
+        status.abort();
+    }
+    if let Some(metrics) = metrics {
+        metrics.abort();
+    }
+
+    bar.finish();
+    if let Some(write_bar) = &write_bar {
+        write_bar.finish();
+    }
+
+    // A device error or cancelling ends the sync, which can then be
+    // resumed from where it stopped instead of from the last checkpoint
+    if written.is_err() || cancelled {
+        scan.save_checkpoint()?;
+    }
+
+    scan.keep_unreadable(mapfile.as_mut(), &written)?;
+
+    // Whatever the writer ran into, it's because the target is gone
+    if removed.load(Ordering::Relaxed) {
+        return Err(Error::TargetRemoved(target_name.clone()));
+    }
+    let written = written?;
+    if cancelled {
+        driver.say(format_args!(
+            "\nStopped at {}. Total: {}, different: {}, written: {} bytes",
+            args.report_units.at(pos, block_size),
+            total,
+            diff,
+            written
+        ));
+        return Err(Error::Cancelled);
+    }
+
+    // Stopping at a bad source block leaves the sync to be continued. A
+    // region's stripe is done, the checkpoint goes once all of them are.
+    if source_mismatch.is_none() {
+        if let Some(part) = &driver.part {
+            part.save(part.stripe().end)
+                .io_context("save", &part.path)?;
+        } else if let Some(path) = &args.checkpoint {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    tracing::info!(blocks = total, different = diff, written, "Finished");
+    scan.report(written, reused.load(Ordering::Relaxed));
+    let unreadable = scan.unreadable_bytes();
+
+    outputs.report(driver, pos);
+
+    if let Some(before) = &smart_before {
+        // The counters only move once the writes reach the device
+        if let Ok(target) = std::fs::File::open(target_name) {
+            let _ = target.sync_all();
+        }
+        let after = smart::read(target_name).unwrap_or_default();
+        driver.say(format_args!(
+            "\n{}",
+            smart::report(before, &after, written).trim_end()
+        ));
+    }
+
+    outputs.finish(args, driver).await?;
+
+    if let Some(footer) = &args.verify_footer {
+        let target = File::open(target_name).await.context("open", target_name)?;
+        verify_footer(target, source_size.unwrap_or(pos), footer, block_size)
+            .await
+            .map_err(Error::Footer)?;
+        driver.say(format_args!("Footer {:?} verified.", footer.algo));
+    }
+
+    if let Some(pos) = source_mismatch {
+        return Err(Error::ManifestMismatch {
+            offset: pos,
+            at: args.report_units.at(pos, block_size),
+        });
+    }
+
+    if let (Some(expected), Some(hasher)) = (&args.expect_source_hash, scan.source_hasher) {
+        let actual = hasher.finalize();
+        if actual != expected.digest {
+            return Err(Error::SourceHash {
+                actual: hash::to_hex(&actual),
+                expected: hash::to_hex(&expected.digest),
+                note: if args.journal.is_some() {
+                    ", use the rollback command to undo it"
+                } else {
+                    ""
+                },
+            });
+        }
+        driver.say(format_args!("Source {:?} hash verified.", expected.algo));
+    }
+
+    // Validation fails if anything deviates from the reference
+    if validate && diff > 0 {
+        return Err(Error::Deviates { diff, total });
+    }
+
+    // The loop device goes away here, hand back the image itself
+    drop(loop_device);
+    Ok(Summary {
+        target: target_arg,
+        blocks: total,
+        different: diff,
+        written,
+        source_size,
+        target_size,
+        scanned: pos - start,
+        read,
+        retries: control.retries.load(Ordering::Relaxed),
+        unreadable,
+        phases: vec![
+            ("prepare", scanning - started),
+            ("sync", finishing - scanning),
+            ("finish", finishing.elapsed()),
+        ],
+    })
+}
+
+// The tasks that read both sides and write the target, and the ends of
+// the channels between them that the comparison has
+struct Pipeline {
+    // Empty buffers go to a reader and come back filled
+    source_tx: mpsc::Sender<Buf>,
+    source_rx: mpsc::Receiver<Buf>,
+    target_tx: mpsc::Sender<Buf>,
+    target_rx: mpsc::Receiver<Buf>,
+    // Blocks to write and where, the writer hands them back to the
+    // source reader once they're written
+    write_tx: mpsc::Sender<(u64, Buf)>,
+    source_task: tokio::task::JoinHandle<error::Result<u64>>,
+    target_task: tokio::task::JoinHandle<error::Result<u64>>,
+    writer_task: Option<tokio::task::JoinHandle<error::Result<u64>>>,
+}
+
+impl Pipeline {
+    // Start reading both sides from `start`, and writing the target if
+    // it's written to
+    fn start(
+        (source, source_reading): (Box<dyn BlockSource>, Reading),
+        (target, target_reading): (Box<dyn BlockSource>, Reading),
+        start: u64,
+        channel_size: usize,
+        writer: Option<(WriteTarget, Option<ProgressBar>)>,
+    ) -> Self {
+        // Channels for talking with the source file reader task
+        let (source_tx, source_fw_rx) = mpsc::channel(channel_size);
+        let (source_bk_tx, source_rx) = mpsc::channel(channel_size);
+
+        // Channels for talking with the target file reader task
+        let (target_tx, target_fw_rx) = mpsc::channel(channel_size);
+        let (target_bk_tx, target_rx) = mpsc::channel(channel_size);
+
+        // Channel for talking with the target file writer task
+        let (write_tx, write_rx) = mpsc::channel(channel_size);
+
+        let source_task = tokio::spawn(read_blocks(
+            source,
+            start,
+            source_fw_rx,
+            source_bk_tx,
+            source_reading,
+        ));
+        let target_task = tokio::spawn(read_blocks(
+            target,
+            start,
+            target_fw_rx,
+            target_bk_tx,
+            target_reading,
+        ));
+
+        // Connected to the source reader's forward channel, so the written
+        // blocks go right back to the reader
+        let writer_task = writer.map(|(target, write_bar)| {
+            tokio::spawn(write_blocks(target, write_rx, source_tx.clone(), write_bar))
+        });
+
+        Pipeline {
+            source_tx,
+            source_rx,
+            target_tx,
+            target_rx,
+            write_tx,
+            source_task,
+            target_task,
+            writer_task,
+        }
+    }
+
+    // Send the first few buffers to the readers, which send them back
+    // once they're filled
+    async fn fill(&self, n_buffers: usize, block_size: usize) {
+        for _ in 0..n_buffers {
+            let _ = join!(
+                self.source_tx.send(Buf::new(block_size)),
+                self.target_tx.send(Buf::new(block_size))
+            );
+        }
+    }
+
+    // Let the tasks end and wait for them. Returns what both readers read
+    // and what was written.
+    async fn finish(self) -> (u64, error::Result<u64>) {
+        let Pipeline {
+            source_tx,
+            source_rx,
+            target_tx,
+            target_rx,
+            write_tx,
+            source_task,
+            target_task,
+            writer_task,
+        } = self;
+        drop((write_tx, source_tx, source_rx, target_tx, target_rx));
+
+        let written = match writer_task {
+            Some(writer_task) => writer_task
+                .await
+                .unwrap_or_else(|e| Err(task_failed("writer", e))),
+            None => Ok(0),
+        };
+        let (source_task, target_task) = join!(source_task, target_task);
+        let read = source_task
+            .unwrap_or_else(|e| Err(task_failed("source reader", e)))
+            .and_then(|s| {
+                target_task
+                    .unwrap_or_else(|e| Err(task_failed("target reader", e)))
+                    .map(|t| s + t)
+            });
+        match (read, written) {
+            (Ok(read), written) => (read, written),
+            (Err(e), _) => (0, Err(e)),
+        }
+    }
+}
+
+// What a sync writes besides the target or instead of it, and what it
+// keeps of the differences
+struct Outputs {
+    histogram: Option<histogram::Histogram>,
+    multigrain: Option<multigrain::MultiGrain>,
+    sparse_image: Option<sparse::SparseImageWriter>,
+    oci: Option<oci::OciWriter>,
+    bitmap: Option<bitmap::Bitmap>,
+    manifest: Option<manifest::ManifestWriter>,
+    journal: Option<journal::Journal>,
+}
+
+impl Outputs {
+    async fn create(args: &SyncArgs, sync_size: u64, block_size: usize) -> error::Result<Self> {
+        let histogram = if args.diff_histogram {
+            Some(histogram::Histogram::new(sync_size))
+        } else {
+            None
+        };
+
+        let multigrain = if args.multigrain.is_empty() {
+            None
+        } else {
+            Some(multigrain::MultiGrain::new(&args.multigrain))
+        };
+
+        let sparse_image = match &args.sparse_image_out {
+            Some(path) => Some(
+                sparse::SparseImageWriter::create(path, sync_size)
+                    .await
+                    .context("create", path)?,
+            ),
+            None => None,
+        };
+
+        let oci = match &args.oci_out {
+            Some(path) => Some(
+                oci::OciWriter::create(path, sync_size)
+                    .await
+                    .context("create", path)?,
+            ),
+            None => None,
+        };
+
+        let bitmap = args
+            .bitmap_out
+            .as_ref()
+            .map(|_| bitmap::Bitmap::new(sync_size, block_size));
+
+        let manifest = match &args.write_manifest {
+            Some(path) => Some(
+                manifest::ManifestWriter::create(path, args.hash, block_size)
+                    .context("create", path)?,
+            ),
+            None => None,
+        };
+
+        let journal = match &args.journal {
+            Some(path) => Some(
+                journal::Journal::create(path)
+                    .await
+                    .context("create", path)?,
+            ),
+            None => None,
+        };
+
+        Ok(Outputs {
+            histogram,
+            multigrain,
+            sparse_image,
+            oci,
+            bitmap,
+            manifest,
+            journal,
+        })
+    }
+
+    // Tell where the differences were, once everything up to `pos` is
+    // compared
+    fn report(&self, driver: &Driver, pos: u64) {
+        if let Some(multigrain) = &self.multigrain {
+            driver.say(format_args!("\n{}", multigrain.report(pos).trim_end()));
+        }
+
+        if let Some(histogram) = &self.histogram {
+            driver.say(format_args!("\n{}", histogram.report().trim_end()));
+        }
+    }
+
+    // Finish the files written and tell what's in them
+    async fn finish(self, args: &SyncArgs, driver: &Driver) -> error::Result<()> {
+        if let Some(image) = self.sparse_image {
+            let (regions, bytes) = image
+                .finish()
+                .await
+                .io_context("write", args.sparse_image_out.as_ref().unwrap())?;
+            driver.say(format_args!(
+                "Sparse image: {} regions, {} bytes.",
+                regions, bytes
+            ));
+        }
+
+        if let Some(bitmap) = &self.bitmap {
+            let path = args.bitmap_out.as_ref().unwrap();
+            let (set, blocks) = bitmap.write(path).io_context("write", path)?;
+            driver.say(format_args!("Bitmap: {} of {} blocks differ.", set, blocks));
+        }
+
+        if let Some(manifest) = self.manifest {
+            let path = args.write_manifest.as_ref().unwrap();
+            manifest.finish().io_context("write", path)?;
+            driver.say(format_args!("Manifest written to {}.", path));
+        }
+
+        if let Some(oci) = self.oci {
+            let (regions, bytes) = oci
+                .finish()
+                .await
+                .io_context("write", args.oci_out.as_ref().unwrap())?;
+            driver.say(format_args!("Tar: {} regions, {} bytes.", regions, bytes));
+        }
+        Ok(())
+    }
+}
+
+// The comparison of a sync, block by block, and how far it got
+struct Scan<'a> {
+    args: &'a SyncArgs,
+    driver: &'a Driver,
+    plan: Plan,
+    bar: ProgressBar,
+    control: Arc<Control>,
+    unreadable: source::Unreadable,
+    // Set once a removable target is gone
+    removed: Arc<AtomicBool>,
+    start: u64,
+    // Every block before it was compared
+    pos: u64,
+    total: u64,
+    diff: u64,
+    diff_bytes: u64,
+    source_hasher: Option<hash::Hasher>,
+    // Where the source isn't what its manifest has
+    source_mismatch: Option<u64>,
+    // Whether the source goes on past the end of the target
+    source_left: bool,
+    cancelled: bool,
+    // Bytes of matching blocks not yet shown on the progress bar
+    unreported: u64,
+    last_report: Instant,
+}
+
+impl Scan<'_> {
+    // Compare the blocks the readers send until a side ends, the sync is
+    // cancelled or the writer goes away
+    async fn run(&mut self, pipeline: &mut Pipeline, outputs: &mut Outputs) -> error::Result<()> {
+        let (args, driver) = (self.args, self.driver);
+        let checkpointing = args.checkpoint.is_some() || driver.part.is_some();
+        let mut last_progress = Instant::now();
+        let mut last_save = Instant::now();
+
+        loop {
+            // Blocks already in flight are finished, no new ones are started
+            self.control.wait_while_paused().await;
+            let cancel = driver
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.load(Ordering::Relaxed));
+            if cancel || self.removed.load(Ordering::Relaxed) {
+                self.cancelled = true;
+                break;
+            }
+
+            // Get a pair of buffers from the readers
+            // A reader only stops sending if it failed, its error is
+            // picked up once the tasks are done
+            let (mut bsrc, mut btgt) =
+                match join!(pipeline.source_rx.recv(), pipeline.target_rx.recv()) {
+                    (Some(bsrc), Some(btgt)) => (bsrc, btgt),
+                    _ => break,
+                };
+
+            // Only the part both sides have is synced. A short block means
+            // one side has ended, so this is the last round.
+            let n = std::cmp::min(bsrc.length, btgt.length);
+            let last = bsrc.length != btgt.length;
+            self.source_left = bsrc.length > btgt.length;
+            let source_length = bsrc.length;
+
+            // Check wether we're done
+            if n == 0 {
+                if let Some(hasher) = &mut self.source_hasher {
+                    hasher.update(bsrc.as_slice());
+                }
+                break;
+            }
+
+            bsrc.length = n;
+
+            // The target has what the source couldn't give, so it's not
+            // overwritten there
+            let skipped = match args.on_read_error {
+                OnReadError::Skip => {
+                    overlapping(&self.unreadable.lock().unwrap(), self.pos, n).collect()
+                }
+                _ => Vec::new(),
+            };
+            for (bad_start, bad_end) in skipped {
+                let range = (bad_start - self.pos) as usize..(bad_end - self.pos) as usize;
+                bsrc.data[range.clone()].copy_from_slice(&btgt.data[range]);
+                bsrc.zero = compare::is_zero(bsrc.as_slice());
+            }
+
+            // With what the source has past the end of the target, the hash
+            // is of the whole source
+            if let Some(hasher) = &mut self.source_hasher {
+                hasher.update(&bsrc.data[..source_length]);
+            }
+
+            if let Some(manifest) = &mut outputs.manifest {
+                manifest
+                    .append(self.pos, bsrc.as_slice())
+                    .io_context("write", args.write_manifest.as_ref().unwrap())?;
+            }
+
+            // A source block that isn't what it should be never gets written
+            if let Some(manifest) = &self.plan.manifest {
+                if !manifest.check(self.pos, bsrc.as_slice()) {
+                    self.source_mismatch = Some(self.pos);
+                    let _ = join!(pipeline.source_tx.send(bsrc), pipeline.target_tx.send(btgt));
+                    break;
+                }
+            }
+
+            self.total += 1;
+
+            // Compare the arrived buffers
+            // If they match:
+            //   Return the buffers to the channel
+            //   Wait for buffers from the readers
+            //   Start from the beginning
+            // Zero blocks on both sides are equal without looking, a zero one
+            // never equals one that isn't. Only if nothing was cut off them.
+            let equal = match (bsrc.zero, btgt.zero) {
+                (true, true) if !last => true,
+                (src, tgt) if src != tgt && !last => false,
+                _ => (self.plan.same)(bsrc.as_slice(), &btgt.as_slice()[..n]),
+            };
+            // Zeroes that go on on both sides are equal as far as both go,
+            // unless the source couldn't be read somewhere in them
+            let mut ran = 0;
+            if let (true, Some(src_run), Some(tgt_run)) = (equal, bsrc.run, btgt.run) {
+                ran = std::cmp::min(src_run, tgt_run);
+                if overlapping(
+                    &self.unreadable.lock().unwrap(),
+                    self.pos + n as u64,
+                    ran as usize,
+                )
+                .next()
+                .is_some()
+                {
+                    ran = 0;
+                }
+                bsrc.run = Some(src_run - ran);
+                btgt.run = Some(tgt_run - ran);
+                if let Some(hasher) = &mut self.source_hasher {
+                    hash_zeroes(hasher, ran);
+                }
+                self.total += ran / n as u64;
+            }
+            if equal {
+                let _ = join!(pipeline.source_tx.send(bsrc), pipeline.target_tx.send(btgt));
+                if let Some(adaptive) = &mut self.plan.adaptive {
+                    adaptive.same();
+                }
+
+                // Progress for matching runs is only reported now and then,
+                // updating the bar on every block is costly on fast devices.
+                self.unreported += n as u64 + ran;
+                if self.last_report.elapsed() >= PROGRESS_INTERVAL {
+                    self.bar.inc(self.unreported);
+                    self.unreported = 0;
+                    self.last_report = Instant::now();
+                }
+            } else if !self.differ(pipeline, outputs, bsrc, btgt, n).await? {
+                break;
+            }
+
+            self.pos += n as u64 + ran;
+
+            if let Some(progress) = &driver.progress {
+                if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                    progress(self.pos, self.plan.sync_size);
+                    last_progress = Instant::now();
+                }
+            }
+
+            self.control.pos.store(self.pos, Ordering::Relaxed);
+            self.control.diff.store(self.diff, Ordering::Relaxed);
+
+            if checkpointing && last_save.elapsed() >= CHECKPOINT_INTERVAL {
+                self.save_checkpoint()?;
+                last_save = Instant::now();
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        self.bar.inc(self.unreported);
+        self.unreported = 0;
+        if let Some(progress) = &driver.progress {
+            progress(self.pos, self.plan.sync_size);
+        }
+        Ok(())
+    }
+
+    // Take a block of the source that differs from the target's: write it,
+    // or whatever's done with it instead. False if the writer went away.
+    async fn differ(
+        &mut self,
+        pipeline: &mut Pipeline,
+        outputs: &mut Outputs,
+        mut bsrc: Buf,
+        btgt: Buf,
+        n: usize,
+    ) -> error::Result<bool> {
+        let (args, pos) = (self.args, self.pos);
+
+        // Only this part of the block is written
+        let (start, end) = match &mut self.plan.adaptive {
+            Some(adaptive) => {
+                adaptive.narrow(bsrc.as_slice(), &btgt.as_slice()[..n], &self.plan.same)
+            }
+            None => (0, n),
+        };
+
+        if args.read_only() {
+            if args.reference == Reference::Target {
+                // Compared byte by byte, it's known where it starts
+                let first = match args.compare {
+                    compare::Comparison::Exact => compare::first_difference(
+                        &bsrc.as_slice()[start..end],
+                        &btgt.as_slice()[start..end],
+                    )
+                    .unwrap_or(0),
+                    _ => 0,
+                };
+                self.bar.suspend(|| {
+                    self.driver.say(format_args!(
+                        "Source deviates at {} ({} bytes)",
+                        args.report_units
+                            .at(pos + (start + first) as u64, self.plan.block_size),
+                        end - start - first
+                    ))
+                });
+            }
+            if let Some(multigrain) = &mut outputs.multigrain {
+                multigrain.record(pos, bsrc.as_slice(), &btgt.as_slice()[..n]);
+            }
+            let _ = join!(pipeline.source_tx.send(bsrc), pipeline.target_tx.send(btgt));
+        } else if let Some(image) = &mut outputs.sparse_image {
+            // Store the source block, the target is left alone
+            image
+                .append(pos + start as u64, &bsrc.as_slice()[start..end])
+                .await
+                .io_context("write", args.sparse_image_out.as_ref().unwrap())?;
+            let _ = join!(pipeline.source_tx.send(bsrc), pipeline.target_tx.send(btgt));
+        } else if let Some(oci) = &mut outputs.oci {
+            oci.append(pos + start as u64, &bsrc.as_slice()[start..end])
+                .await
+                .io_context("write", args.oci_out.as_ref().unwrap())?;
+            let _ = join!(pipeline.source_tx.send(bsrc), pipeline.target_tx.send(btgt));
+        } else {
+            // The old content has to be safe before it's overwritten
+            if let Some(journal) = &mut outputs.journal {
+                journal
+                    .append(
+                        args.target_offset + pos + start as u64,
+                        &btgt.as_slice()[start..end],
+                    )
+                    .await
+                    .io_context("write", args.journal.as_ref().unwrap())?;
+            }
+
+            // Send the one arrived from the source reader to the writer
+            // Send the one arrived from the target reader back to it.
+            // The writer only goes away if it failed.
+            if (start, end) != (0, n) {
+                bsrc.data.copy_within(start..end, 0);
+                bsrc.length = end - start;
+            }
+            bsrc.split = bsrc.run.is_some();
+            let (sent, _) = join!(
+                pipeline.write_tx.send((pos + start as u64, bsrc)),
+                pipeline.target_tx.send(btgt)
+            );
+            if sent.is_err() {
+                return Ok(false);
+            }
+        }
+
+        self.diff += 1;
+        self.diff_bytes += (end - start) as u64;
+        if let Some(bitmap) = &mut outputs.bitmap {
+            bitmap.set(pos);
+        }
+        if let Some(histogram) = &mut outputs.histogram {
+            histogram.record(pos + start as u64, end - start);
+        }
+
+        self.bar.inc(self.unreported + n as u64);
+        self.unreported = 0;
+        self.last_report = Instant::now();
+        Ok(true)
+    }
+
+    // The rest of a source longer than the target is only read for its hash
+    async fn hash_rest(&mut self, pipeline: &mut Pipeline) {
+        let hasher = match &mut self.source_hasher {
+            Some(hasher)
+                if self.source_left && !self.cancelled && self.source_mismatch.is_none() =>
+            {
+                hasher
+            }
+            _ => return,
+        };
+        while let Some(mut bsrc) = pipeline.source_rx.recv().await {
+            if bsrc.length == 0 {
+                break;
+            }
+            hasher.update(bsrc.as_slice());
+            if let Some(run) = bsrc.run {
+                hash_zeroes(hasher, run);
+                bsrc.run = Some(0);
+            }
+            let _ = pipeline.source_tx.send(bsrc).await;
+        }
+    }
+
+    // Every block before pos was compared, but the writes of the last
+    // few may still be on their way. There can't be more of them than
+    // buffers, so the sync is safe to continue that much further back.
+    fn save_checkpoint(&self) -> error::Result<()> {
+        let (args, driver) = (self.args, self.driver);
+        let behind = (args.buffers * self.plan.block_size) as u64;
+        let next = std::cmp::max(self.pos.saturating_sub(behind), self.start);
+        match (&driver.part, &args.checkpoint) {
+            (Some(part), _) => part
+                .save(part.stripe().start + next)
+                .io_context("save", &part.path),
+            (None, Some(path)) => checkpoint::Checkpoint {
+                stripes: vec![checkpoint::Stripe {
+                    start: 0,
+                    end: self.plan.sync_size,
+                    next,
+                }],
+                ..Default::default()
+            }
+            .save(path)
+            .io_context("save", path),
+            (None, None) => Ok(()),
+        }
+    }
+
+    // List what couldn't be read with --badblocks-out, and keep in the
+    // mapfile what's done and what's bad
+    fn keep_unreadable(
+        &self,
+        mapfile: Option<&mut mapfile::Mapfile>,
+        written: &error::Result<u64>,
+    ) -> error::Result<()> {
+        if let Some(path) = &self.args.badblocks_out {
+            let ranges = self.unreadable.lock().unwrap();
+            let report: String = ranges
+                .iter()
+                .map(|(start, end)| format!("{} {}\n", start, end - start))
+                .collect();
+            std::fs::write(path, report).io_context("write", path)?;
+            self.driver.say(format_args!(
+                "Unreadable: {} ranges, listed in {}.",
+                ranges.len(),
+                path
+            ));
+        }
+
+        // Everything compared is finished, unless it wasn't written
+        if let (Some(mapfile), Some(path)) = (mapfile, &self.args.mapfile) {
+            let end = match written {
+                Err(Error::Write { offset, .. }) | Err(Error::WriteMismatch { offset }) => {
+                    std::cmp::min(self.pos, *offset)
+                }
+                _ => self.pos,
+            };
+            mapfile.finish(self.start, end);
+            for &(bad_start, bad_end) in self.unreadable.lock().unwrap().iter() {
+                mapfile.mark(bad_start, bad_end, mapfile::BAD);
+            }
+            mapfile.save(path, end).io_context("save", path)?;
+            self.driver.say(format_args!(
+                "Mapfile: {} bytes finished, {} bad.",
+                mapfile.bytes(mapfile::FINISHED),
+                mapfile.bytes(mapfile::BAD)
+            ));
+        }
+        Ok(())
+    }
+
+    fn unreadable_bytes(&self) -> u64 {
+        self.unreadable
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(start, end)| end - start)
+            .sum()
+    }
+
+    // Tell what the sync came to, once it's finished, with the bytes of
+    // a download the target had already
+    fn report(&self, written: u64, reused: u64) {
+        let (args, driver) = (self.args, self.driver);
+        if args.reference == Reference::Target {
+            driver.say(format_args!(
+                "\nFinished. The source deviates from the target in {} of {} blocks.",
+                self.diff, self.total
+            ));
+        } else if args.dry_run {
+            driver.say(format_args!(
+                "\nFinished. Total: {}, different: {}, would write: {} bytes",
+                self.total, self.diff, self.diff_bytes
+            ));
+        } else {
+            driver.say(format_args!(
+                "\nFinished. Total: {}, different: {}, written: {} bytes",
+                self.total, self.diff, written
+            ));
+        }
+
+        if reused > 0 {
+            driver.say(format_args!(
+                "{} bytes the target had already weren't downloaded.",
+                reused
+            ));
+        }
+
+        let unreadable = self.unreadable_bytes();
+        match args.on_read_error {
+            _ if unreadable == 0 => (),
+            OnReadError::Fill(byte) => driver.say(format_args!(
+                "{} bytes of the source couldn't be read, they were written as 0x{:02x}.",
+                unreadable, byte
+            )),
+            _ => driver.say(format_args!(
+                "{} bytes of the source couldn't be read, the target has what it had there.",
+                unreadable
+            )),
+        }
+    }
+}
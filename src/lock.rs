@@ -0,0 +1,111 @@
+use {
+    crate::{
+        error::{self, Context, Error},
+        Driver,
+    },
+    nix::{
+        errno::Errno,
+        fcntl::{flock, FlockArg},
+        sys::stat::{major, minor},
+    },
+    std::{
+        fs::{File, OpenOptions},
+        io::{Read, Seek, Write},
+        os::unix::{
+            fs::{FileTypeExt, MetadataExt},
+            io::AsRawFd,
+        },
+        sync::atomic::Ordering,
+        time::Duration,
+    },
+};
+
+// Where the lock files are, the first of these there is
+const LOCK_DIRS: [&str; 2] = ["/run/lock", "/tmp"];
+
+// How often a lock that's held is tried again while waiting for it
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A lock on a target, so that no other ssdsync writes to it meanwhile.
+/// It's released when this is dropped, or however the process ends.
+pub struct Lock {
+    _file: File,
+}
+
+/// The lock file of a target: a device by its number, a file by its
+/// inode, so that every name of it has the same. None if it's not there.
+fn lock_path(target: &str) -> Option<String> {
+    let meta = std::fs::metadata(target).ok()?;
+    let key = if meta.file_type().is_block_device() {
+        format!("dev-{}:{}", major(meta.rdev()), minor(meta.rdev()))
+    } else {
+        format!("file-{}-{}", meta.dev(), meta.ino())
+    };
+    let dir = LOCK_DIRS
+        .iter()
+        .find(|dir| std::path::Path::new(dir).is_dir())?;
+    Some(format!("{}/ssdsync-{}.lock", dir, key))
+}
+
+/// Lock the target, waiting for another sync holding it to finish with
+/// `wait`, until the sync of `driver` is cancelled
+pub async fn acquire(target: &str, wait: bool, driver: &Driver) -> error::Result<Option<Lock>> {
+    let path = match lock_path(target) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .context("open the lock file", &path)?;
+    let mut waiting = false;
+    loop {
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => break,
+            Err(Errno::EWOULDBLOCK) if wait => {
+                if !waiting {
+                    driver.say(format_args!(
+                        "Waiting for the other sync onto {} to finish.",
+                        target
+                    ));
+                    waiting = true;
+                }
+                if driver
+                    .cancel
+                    .as_ref()
+                    .is_some_and(|c| c.load(Ordering::Relaxed))
+                {
+                    return Err(Error::Cancelled);
+                }
+                tokio::time::sleep(RETRY_INTERVAL).await;
+            }
+            Err(Errno::EWOULDBLOCK) => {
+                let mut holder = String::new();
+                let _ = file.read_to_string(&mut holder);
+                return Err(Error::Usage(format!(
+                    "Another ssdsync{} is syncing onto {}, give --wait-lock to wait for it",
+                    match holder.trim() {
+                        "" => String::new(),
+                        pid => format!(" (pid {})", pid),
+                    },
+                    target
+                )));
+            }
+            Err(source) => {
+                return Err(Error::System {
+                    action: "lock the target",
+                    source,
+                })
+            }
+        }
+    }
+
+    // Who holds it, for the message of the next one
+    file.set_len(0).context("write", &path)?;
+    file.rewind().context("write", &path)?;
+    write!(file, "{}", std::process::id()).context("write", &path)?;
+    Ok(Some(Lock { _file: file }))
+}
@@ -0,0 +1,47 @@
+use {
+    std::{
+        fs::OpenOptions,
+        io::{self, IsTerminal},
+        sync::{Mutex, OnceLock},
+    },
+    tracing::Level,
+};
+
+// The file logged to, if it's not stderr
+static FILE: OnceLock<String> = OnceLock::new();
+
+/// The file logged to, written all along
+pub fn file() -> Option<&'static str> {
+    FILE.get().map(String::as_str)
+}
+
+/// Log warnings and errors to stderr, or to the end of the file at
+/// `path`. Each -v shows more: what's being done, the decisions taken
+/// on the way, then every block read and written.
+pub fn init(verbose: u8, path: Option<&str>) -> io::Result<()> {
+    let level = match verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_target(false);
+    match path {
+        Some(path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let _ = FILE.set(path.to_string());
+            builder
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .init();
+        }
+        None => builder
+            .without_time()
+            .with_ansi(io::stderr().is_terminal())
+            .with_writer(io::stderr)
+            .init(),
+    }
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use {
+    crate::Messages,
+    nix::{ioctl_none_bad, ioctl_write_int_bad, ioctl_write_ptr_bad},
+    std::{
+        fs::{File, OpenOptions},
+        io,
+        os::unix::io::AsRawFd,
+    },
+};
+
+// See linux/loop.h
+const LOOP_SET_FD: u16 = 0x4C00;
+const LOOP_CLR_FD: u16 = 0x4C01;
+const LOOP_SET_STATUS64: u16 = 0x4C04;
+const LOOP_CTL_GET_FREE: u16 = 0x4C82;
+
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+const LO_NAME_SIZE: usize = 64;
+const LO_KEY_SIZE: usize = 32;
+
+#[repr(C)]
+struct LoopInfo64 {
+    lo_device: u64,
+    lo_inode: u64,
+    lo_rdevice: u64,
+    lo_offset: u64,
+    lo_sizelimit: u64,
+    lo_number: u32,
+    lo_encrypt_type: u32,
+    lo_encrypt_key_size: u32,
+    lo_flags: u32,
+    lo_file_name: [u8; LO_NAME_SIZE],
+    lo_crypt_name: [u8; LO_NAME_SIZE],
+    lo_encrypt_key: [u8; LO_KEY_SIZE],
+    lo_init: [u64; 2],
+}
+
+ioctl_none_bad!(loop_ctl_get_free, LOOP_CTL_GET_FREE);
+ioctl_write_int_bad!(loop_set_fd, LOOP_SET_FD);
+ioctl_none_bad!(loop_clr_fd, LOOP_CLR_FD);
+ioctl_write_ptr_bad!(loop_set_status64, LOOP_SET_STATUS64, LoopInfo64);
+
+// Another process may grab the same free device, try again then
+const ATTACH_ATTEMPTS: usize = 8;
+
+/// An image file attached to a loop device.
+///
+/// The device is set up with autoclear, so the kernel detaches it as soon
+/// as the last descriptor is closed. Holding this keeps it attached, and
+/// it goes away however the process ends.
+pub struct LoopDevice {
+    pub path: String,
+    _device: File,
+}
+
+pub fn attach(image: &str, messages: &Messages) -> io::Result<LoopDevice> {
+    let backing = OpenOptions::new().read(true).write(true).open(image)?;
+    let control = File::open("/dev/loop-control")?;
+
+    // Loop devices are made of whole 512 byte sectors
+    let tail = backing.metadata()?.len() % 512;
+    if tail != 0 {
+        messages.say(format_args!(
+            "The size of {} is not a multiple of 512, the last {} bytes are not synced.",
+            image, tail
+        ));
+    }
+
+    for _ in 0..ATTACH_ATTEMPTS {
+        let n = unsafe { loop_ctl_get_free(control.as_raw_fd()) }?;
+        let path = format!("/dev/loop{}", n);
+        let device = OpenOptions::new().read(true).write(true).open(&path)?;
+        match unsafe { loop_set_fd(device.as_raw_fd(), backing.as_raw_fd()) } {
+            Ok(_) => (),
+            Err(nix::errno::Errno::EBUSY) => continue,
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+        info.lo_flags = LO_FLAGS_AUTOCLEAR;
+        let name = image.as_bytes();
+        let len = std::cmp::min(name.len(), LO_NAME_SIZE - 1);
+        info.lo_file_name[..len].copy_from_slice(&name[..len]);
+        if let Err(e) = unsafe { loop_set_status64(device.as_raw_fd(), &info) } {
+            // Without autoclear it would stay attached, detach it now
+            let _ = unsafe { loop_clr_fd(device.as_raw_fd()) };
+            return Err(e.into());
+        }
+
+        return Ok(LoopDevice {
+            path,
+            _device: device,
+        });
+    }
+
+    Err(io::Error::other("Could not find a free loop device"))
+}
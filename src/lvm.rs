@@ -0,0 +1,123 @@
+use {
+    crate::Messages,
+    std::{io, process::Command},
+};
+
+/// How the source is snapshotted, lvm or lvm:SIZE
+#[derive(Clone, Debug)]
+pub struct Spec {
+    // Room for what changes on the origin during the sync, a fraction of
+    // its size if it's not given. A thin LV doesn't need any.
+    size: Option<u64>,
+}
+
+impl std::str::FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "lvm" => Ok(Spec { size: None }),
+            Some(("lvm", size)) => Ok(Spec {
+                size: Some(crate::parse_size(size)?),
+            }),
+            _ => Err(format!("Expected lvm or lvm:SIZE, got {}", s)),
+        }
+    }
+}
+
+// A thick snapshot gets this much of the origin's size if it's not told
+const DEFAULT_EXTENTS: &str = "10%ORIGIN";
+
+// Run an LVM command, its output or what it complained about
+fn lvm(command: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(command).args(args).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(format!(
+            "{} failed: {}",
+            command,
+            stderr.trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A snapshot of a logical volume, removed again when this is dropped.
+///
+/// Dropping it runs on errors and once a cancelled sync returns, only a
+/// second interrupt, which exits right away, leaves it behind.
+pub struct Snapshot {
+    /// The device the snapshot is read from
+    pub path: String,
+    // VG/LV, as the LVM commands take it
+    name: String,
+    messages: Messages,
+}
+
+impl Snapshot {
+    pub fn create(origin: &str, spec: &Spec, messages: &Messages) -> io::Result<Snapshot> {
+        let fields = lvm(
+            "lvs",
+            &["--noheadings", "-o", "vg_name,lv_name,segtype", origin],
+        )?;
+        let (vg, lv, segtype) = match fields.split_whitespace().collect::<Vec<_>>()[..] {
+            [vg, lv, segtype] => (vg.to_string(), lv.to_string(), segtype.to_string()),
+            _ => {
+                return Err(io::Error::other(format!(
+                    "{} is not a logical volume",
+                    origin
+                )))
+            }
+        };
+
+        let snapshot = format!("{}-ssdsync-{}", lv, std::process::id());
+        let origin_name = format!("{}/{}", vg, lv);
+        let size = spec.size.map(|size| format!("{}b", size));
+        let mut args = vec!["--snapshot", "--name", &snapshot];
+        match &size {
+            Some(size) => args.extend(["--size", size]),
+            None if segtype == "thin" => (),
+            None => args.extend(["--extents", DEFAULT_EXTENTS]),
+        }
+        // Thin snapshots are skipped when activating otherwise
+        args.extend(["--setactivationskip", "n", &origin_name]);
+        lvm("lvcreate", &args)?;
+
+        // Removed again if its path can't be found
+        let mut snapshot = Snapshot {
+            path: String::new(),
+            name: format!("{}/{}", vg, snapshot),
+            messages: messages.clone(),
+        };
+        snapshot.path = lvm("lvs", &["--noheadings", "-o", "lv_path", &snapshot.name])?;
+        Ok(snapshot)
+    }
+
+    /// Whether the snapshot still holds what the origin had, a thick one
+    /// that ran out of room is dropped by LVM
+    pub fn check(&self) -> io::Result<()> {
+        let attr = lvm("lvs", &["--noheadings", "-o", "lv_attr", &self.name])?;
+        if attr.chars().nth(4) == Some('I') {
+            return Err(io::Error::other(format!(
+                "{} ran out of room while the source was read, give it more with --snapshot-source lvm:SIZE",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        match lvm("lvremove", &["--yes", &self.name]) {
+            Ok(_) => self
+                .messages
+                .say(format_args!("Removed the snapshot {}.", self.name)),
+            Err(e) => tracing::error!(
+                "Could not remove the snapshot {}, lvremove it by hand: {}",
+                self.name,
+                e
+            ),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use {
+    crate::hash::{self, HashAlgo},
+    std::{
+        fs::File,
+        io::{self, BufRead, BufReader, BufWriter, Write},
+        os::unix::fs::FileExt,
+    },
+};
+
+// A manifest is a text file with a header line naming the algorithm and
+// the block size, then one "offset length hash" line per block:
+//
+//   # ssdsync manifest sha256 16384
+//   0 16384 9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08
+pub const HEADER: &str = "# ssdsync manifest";
+
+pub struct Entry {
+    pub offset: u64,
+    pub length: usize,
+    pub hash: Vec<u8>,
+}
+
+/// Per-block hashes of an image
+pub struct Manifest {
+    pub algo: HashAlgo,
+    pub block_size: usize,
+    entries: Vec<Entry>,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Manifest {
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(path, BufReader::new(File::open(path)?))
+    }
+
+    /// A manifest read from somewhere else than a file, errors name it
+    /// by `path`
+    pub fn parse(path: &str, reader: impl BufRead) -> io::Result<Self> {
+        let mut lines = reader.lines();
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let fields: Vec<&str> = header
+            .strip_prefix(HEADER)
+            .ok_or_else(|| invalid(format!("{} is not an ssdsync manifest", path)))?
+            .split_whitespace()
+            .collect();
+        if fields.len() != 2 {
+            return Err(invalid(format!("Bad manifest header: {}", header)));
+        }
+        let algo: HashAlgo = fields[0].parse().map_err(invalid)?;
+        let block_size = fields[1]
+            .parse()
+            .map_err(|e| invalid(format!("Bad manifest block size: {}", e)))?;
+
+        let mut entries = Vec::new();
+        for (n, line) in lines.enumerate() {
+            let line = line?;
+            let bad = || invalid(format!("{}:{}: bad manifest line", path, n + 2));
+            let mut fields = line.split_whitespace();
+            let mut next = || fields.next().ok_or_else(bad);
+            let offset = next()?.parse().map_err(|_| bad())?;
+            let length = next()?.parse().map_err(|_| bad())?;
+            let hash = hash::from_hex(next()?).map_err(|_| bad())?;
+            entries.push(Entry {
+                offset,
+                length,
+                hash,
+            });
+        }
+
+        Ok(Manifest {
+            algo,
+            block_size,
+            entries,
+        })
+    }
+
+    /// Entry of the block starting at `offset`
+    pub fn get(&self, offset: u64) -> Option<&Entry> {
+        self.entries
+            .binary_search_by_key(&offset, |e| e.offset)
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    /// Whether the block at `offset` has the recorded length and hash
+    pub fn check(&self, offset: u64, data: &[u8]) -> bool {
+        match self.get(offset) {
+            Some(entry) if entry.length == data.len() => {
+                let mut hasher = self.algo.hasher();
+                hasher.update(data);
+                hasher.finalize() == entry.hash
+            }
+            _ => false,
+        }
+    }
+
+    /// Hash every block of `target` the manifest lists from `start` on,
+    /// without needing the source. `block` is called with the offset and
+    /// the length of each, and whether it matches. A block the target is
+    /// too short to hold doesn't.
+    pub fn verify(
+        &self,
+        target: &str,
+        start: u64,
+        mut block: impl FnMut(u64, usize, bool) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let target = File::open(target)?;
+        let mut buf = vec![0; self.entries.iter().map(|e| e.length).max().unwrap_or(0)];
+        for entry in self.entries.iter().filter(|e| e.offset >= start) {
+            let data = &mut buf[..entry.length];
+            let mut filled = 0;
+            while filled < data.len() {
+                match target.read_at(&mut data[filled..], entry.offset + filled as u64) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            let matches = self.check(entry.offset, &data[..filled]);
+            block(entry.offset, entry.length, matches)?;
+        }
+        Ok(())
+    }
+
+    /// The hashes of all blocks, in order
+    pub fn hashes(&self) -> impl Iterator<Item = &[u8]> {
+        self.entries.iter().map(|e| e.hash.as_slice())
+    }
+
+    /// Sum of the lengths of the blocks from `start` on
+    pub fn size_from(&self, start: u64) -> u64 {
+        self.entries
+            .iter()
+            .filter(|e| e.offset >= start)
+            .map(|e| e.length as u64)
+            .sum()
+    }
+}
+
+/// Writes a manifest of the blocks a sync reads from the source
+pub struct ManifestWriter {
+    algo: HashAlgo,
+    file: BufWriter<File>,
+}
+
+impl ManifestWriter {
+    pub fn create(path: &str, algo: HashAlgo, block_size: usize) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{} {} {}", HEADER, algo.name(), block_size)?;
+        Ok(ManifestWriter { algo, file })
+    }
+
+    /// Hash the block at `offset`, blocks have to come in order
+    pub fn append(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut hasher = self.algo.hasher();
+        hasher.update(data);
+        writeln!(
+            self.file,
+            "{} {} {}",
+            offset,
+            data.len(),
+            hash::to_hex(&hasher.finalize())
+        )
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.file.into_inner()?.sync_all()
+    }
+}
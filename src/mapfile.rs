@@ -0,0 +1,188 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+};
+
+// A GNU ddrescue mapfile: comment lines, then the current position with
+// its status and pass, then one "pos size status" line per region, the
+// numbers usually in hex:
+//
+//   # current_pos  current_status  current_pass
+//   0x00010000     ?               1
+//   #      pos        size  status
+//   0x00000000  0x00010000  +
+//   0x00010000  0x00001000  -
+//   0x00011000  0x000EF000  ?
+//
+// + is finished and - is bad, those are skipped. Whatever isn't either
+// is synced: ? not tried yet, * not trimmed and / not scraped.
+pub const FINISHED: char = '+';
+pub const BAD: char = '-';
+pub const NOT_TRIED: char = '?';
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn number(field: &str) -> Option<u64> {
+    match field
+        .strip_prefix("0x")
+        .or_else(|| field.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => field.parse().ok(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Region {
+    pos: u64,
+    size: u64,
+    status: char,
+}
+
+/// The status of every region of a sync, from start to end
+#[derive(Clone)]
+pub struct Mapfile {
+    size: u64,
+    // Sorted, back to back from 0 to size
+    regions: Vec<Region>,
+}
+
+impl Mapfile {
+    /// Everything not tried yet
+    pub fn new(size: u64) -> Self {
+        let mut mapfile = Mapfile {
+            size,
+            regions: Vec::new(),
+        };
+        mapfile.mark(0, size, NOT_TRIED);
+        mapfile
+    }
+
+    /// The mapfile at `path` for a sync of `size` bytes. A part of the
+    /// sync the mapfile doesn't tell about is not tried yet.
+    pub fn load(path: &str, size: u64) -> io::Result<Self> {
+        let mut mapfile = Mapfile::new(size);
+        let text = fs::read_to_string(path)?;
+        let lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        // The first line is the current position
+        for line in lines.skip(1) {
+            let bad = || invalid(format!("Bad mapfile line: {}", line));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (pos, len, status) = match fields[..] {
+                [pos, len, status] => (
+                    number(pos).ok_or_else(bad)?,
+                    number(len).ok_or_else(bad)?,
+                    status.chars().next().filter(|_| status.len() == 1),
+                ),
+                _ => return Err(bad()),
+            };
+            match status {
+                Some(status @ ('+' | '-' | '?' | '*' | '/')) => {
+                    mapfile.mark(pos, pos.saturating_add(len), status)
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(mapfile)
+    }
+
+    /// Give the bytes from `start` to `end` a status
+    pub fn mark(&mut self, start: u64, end: u64, status: char) {
+        let end = std::cmp::min(end, self.size);
+        if start >= end {
+            return;
+        }
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for r in self.regions.iter() {
+            if r.pos < start {
+                let size = std::cmp::min(r.pos + r.size, start) - r.pos;
+                regions.push(Region { size, ..*r });
+            }
+            if r.pos + r.size > end {
+                let pos = std::cmp::max(r.pos, end);
+                let size = r.pos + r.size - pos;
+                regions.push(Region { pos, size, ..*r });
+            }
+        }
+        regions.push(Region {
+            pos: start,
+            size: end - start,
+            status,
+        });
+        regions.sort_by_key(|r| r.pos);
+
+        // Neighbours of the same status are one region
+        self.regions.clear();
+        for r in regions {
+            match self.regions.last_mut() {
+                Some(last) if last.status == r.status => last.size += r.size,
+                _ => self.regions.push(r),
+            }
+        }
+    }
+
+    /// Give the bytes from `start` to `end` that aren't bad the finished
+    /// status, those that are were skipped
+    pub fn finish(&mut self, start: u64, end: u64) {
+        let bad: Vec<Region> = self
+            .regions
+            .iter()
+            .filter(|r| r.status == BAD && r.pos < end && r.pos + r.size > start)
+            .copied()
+            .collect();
+        self.mark(start, end, FINISHED);
+        for r in bad {
+            self.mark(std::cmp::max(r.pos, start), r.pos + r.size, BAD);
+        }
+    }
+
+    /// Whether all of the `len` bytes at `pos` are finished or bad
+    pub fn settled(&self, pos: u64, len: u64) -> bool {
+        let i = self.regions.partition_point(|r| r.pos + r.size <= pos);
+        self.regions[i..]
+            .iter()
+            .take_while(|r| r.pos < pos + len)
+            .all(|r| r.status == FINISHED || r.status == BAD)
+            && pos + len <= self.size
+    }
+
+    /// Bytes that have the status
+    pub fn bytes(&self, status: char) -> u64 {
+        self.regions
+            .iter()
+            .filter(|r| r.status == status)
+            .map(|r| r.size)
+            .sum()
+    }
+
+    /// Write the mapfile, with the sync at `pos`
+    pub fn save(&self, path: &str, pos: u64) -> io::Result<()> {
+        let done = self.regions.iter().all(|r| r.status != NOT_TRIED);
+        let mut text = format!(
+            "# Mapfile. Created by ssdsync {}\n# Command line: {}\n\
+             # current_pos  current_status  current_pass\n0x{:08X}     {}               1\n\
+             #      pos        size  status\n",
+            env!("CARGO_PKG_VERSION"),
+            std::env::args().collect::<Vec<_>>().join(" "),
+            pos,
+            if done { FINISHED } else { NOT_TRIED }
+        );
+        for r in self.regions.iter() {
+            text.push_str(&format!(
+                "0x{:08X}  0x{:08X}  {}\n",
+                r.pos, r.size, r.status
+            ));
+        }
+        // Never a half written mapfile, whatever happens
+        let tmp = format!("{}.tmp", path);
+        let mut file = File::create(&tmp)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)
+    }
+}
@@ -0,0 +1,103 @@
+use {
+    crate::control::Control,
+    std::sync::{atomic::Ordering, Arc},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+// Seen through the exposition format, one # HELP and # TYPE per metric
+fn exposition(control: &Control) -> String {
+    let scanned = control.pos.load(Ordering::Relaxed);
+    let rate = control.rate();
+    let eta = match rate {
+        _ if scanned >= control.size => 0.0,
+        rate if rate > 0.0 => (control.size - scanned) as f64 / rate,
+        // Nothing to go by yet
+        _ => f64::NAN,
+    };
+    let metrics = [
+        (
+            "bytes_scanned",
+            "counter",
+            "Bytes of the source and the target compared so far",
+            scanned as f64,
+        ),
+        (
+            "bytes_total",
+            "gauge",
+            "Bytes to compare in all",
+            control.size as f64,
+        ),
+        (
+            "bytes_written",
+            "counter",
+            "Bytes written to the target so far",
+            control.written.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "blocks_diff",
+            "counter",
+            "Blocks found differing so far",
+            control.diff.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "throughput_bytes_per_second",
+            "gauge",
+            "Bytes compared per second since the start",
+            rate,
+        ),
+        (
+            "eta_seconds",
+            "gauge",
+            "Seconds until the sync is done at this rate",
+            eta,
+        ),
+    ];
+    metrics
+        .iter()
+        .map(|(name, kind, help, value)| {
+            format!(
+                "# HELP ssdsync_{0} {2}\n# TYPE ssdsync_{0} {1}\nssdsync_{0} {3}\n",
+                name, kind, help, value
+            )
+        })
+        .collect()
+}
+
+// Whatever was asked for, the metrics are the answer
+async fn handle(control: Arc<Control>, stream: TcpStream) -> std::io::Result<()> {
+    let (r, mut w) = stream.into_split();
+    let mut lines = BufReader::new(r).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.is_empty() {
+            break;
+        }
+    }
+    let body = exposition(&control);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    w.write_all(response.as_bytes()).await?;
+    w.shutdown().await
+}
+
+/// Answer every HTTP request with the metrics of the sync, in the
+/// Prometheus text format
+pub async fn serve(control: Arc<Control>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(handle(control.clone(), stream));
+            }
+            Err(e) => {
+                tracing::error!("Metrics endpoint failed: {}", e);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use {
+    crate::source::{self, BlockSource},
+    async_trait::async_trait,
+    nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags},
+    std::{convert::TryFrom, fs::File, io, num::NonZeroUsize, os::unix::io::AsRawFd},
+};
+
+// Pages past the position the kernel is asked to read in ahead, so that
+// copying out of the map seldom waits for a fault
+const WILLNEED: usize = 8 * 1024 * 1024;
+
+/// A file or device mapped into memory and copied out of the map instead
+/// of read. Pages already in the page cache cost no system call at all,
+/// but each block is still copied into the buffer it's read into.
+///
+/// Faulting in a page that isn't blocks the runtime, and a read error of
+/// the device is a SIGBUS, so it best suits files that are cached.
+pub struct MmapSource {
+    file: File,
+    // Null for an empty file, which can't be mapped
+    map: *mut u8,
+    size: u64,
+    pos: u64,
+    regular: bool,
+    // The extent pos is in, as far as it's known
+    extent_end: u64,
+    extent_is_hole: bool,
+    // Up to where the kernel has been told to read in
+    advised: u64,
+}
+
+// The map is only read through, and unmapped with the source
+unsafe impl Send for MmapSource {}
+
+impl MmapSource {
+    /// Map a file or device, None if it's a pipe, which can't be
+    pub async fn open(path: &str) -> io::Result<Option<Self>> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = match crate::get_size(&file).await? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let regular = file.metadata().await?.is_file();
+        let file = file.into_std().await;
+        let length = usize::try_from(size)
+            .map_err(|_| io::Error::other(format!("{} is too large to map", path)))?;
+        let map = match NonZeroUsize::new(length) {
+            Some(length) => {
+                let map = unsafe {
+                    mmap(
+                        None,
+                        length,
+                        ProtFlags::PROT_READ,
+                        MapFlags::MAP_SHARED,
+                        file.as_raw_fd(),
+                        0,
+                    )
+                }?;
+                // Read ahead more eagerly, and dropped sooner, than at random
+                unsafe { madvise(map, length.get(), MmapAdvise::MADV_SEQUENTIAL) }?;
+                map as *mut u8
+            }
+            None => std::ptr::null_mut(),
+        };
+        Ok(Some(MmapSource {
+            file,
+            map,
+            size,
+            pos: 0,
+            regular,
+            extent_end: 0,
+            extent_is_hole: false,
+            advised: 0,
+        }))
+    }
+
+    // Have the kernel read in what comes after pos, without waiting for
+    // it, once less than half of what it was told is left ahead
+    fn advise(&mut self) {
+        if self.advised >= self.size || self.advised > self.pos + WILLNEED as u64 / 2 {
+            return;
+        }
+        let page = 4096;
+        let start = std::cmp::max(self.pos, self.advised) / page * page;
+        let end = std::cmp::min(self.pos + WILLNEED as u64, self.size);
+        // Only advice, a failure is of no consequence
+        let _ = unsafe {
+            madvise(
+                self.map.add(start as usize) as *mut _,
+                (end - start) as usize,
+                MmapAdvise::MADV_WILLNEED,
+            )
+        };
+        self.advised = end;
+    }
+}
+
+#[async_trait]
+impl BlockSource for MmapSource {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len() as u64, self.size.saturating_sub(self.pos)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.advise();
+        let mapped = unsafe { std::slice::from_raw_parts(self.map.add(self.pos as usize), n) };
+        buf[..n].copy_from_slice(mapped);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let end = self.pos + len as u64;
+        if !self.regular || end > self.size {
+            return Ok(false);
+        }
+        if self.pos >= self.extent_end {
+            (self.extent_end, self.extent_is_hole) =
+                source::probe(&self.file, self.pos, self.size)?;
+        }
+        if self.extent_is_hole && end <= self.extent_end {
+            self.pos = end;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn plain_holes(&self) -> bool {
+        true
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos + len, self.size);
+        Ok(())
+    }
+}
+
+impl Drop for MmapSource {
+    fn drop(&mut self) {
+        if !self.map.is_null() {
+            let _ = unsafe { munmap(self.map as *mut _, self.size as usize) };
+        }
+    }
+}
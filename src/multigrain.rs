@@ -0,0 +1,82 @@
+// Difference counters at several granularities at once. Tells whether
+// changes are clustered or scattered, and so which block size would
+// write the least.
+
+struct Grain {
+    size: u64,
+    diff: u64,
+    // Index of the last chunk counted, a chunk larger than the block
+    // size is seen once per block it spans
+    last: Option<u64>,
+}
+
+pub struct MultiGrain {
+    grains: Vec<Grain>,
+}
+
+impl MultiGrain {
+    pub fn new(sizes: &[u64]) -> Self {
+        let mut sizes = sizes.to_vec();
+        sizes.sort_unstable();
+        sizes.dedup();
+        MultiGrain {
+            grains: sizes
+                .into_iter()
+                .map(|size| Grain {
+                    size,
+                    diff: 0,
+                    last: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Count the chunks of a differing block at `pos` that differ
+    pub fn record(&mut self, pos: u64, src: &[u8], tgt: &[u8]) {
+        let end = pos + src.len() as u64;
+        for grain in self.grains.iter_mut() {
+            let mut chunk = pos / grain.size;
+            while chunk * grain.size < end {
+                let from = std::cmp::max(chunk * grain.size, pos);
+                let to = std::cmp::min((chunk + 1) * grain.size, end);
+                let range = (from - pos) as usize..(to - pos) as usize;
+                if grain.last != Some(chunk) && src[range.clone()] != tgt[range] {
+                    grain.diff += 1;
+                    grain.last = Some(chunk);
+                }
+                chunk += 1;
+            }
+        }
+    }
+
+    /// Table of differing chunks and the bytes a sync at each
+    /// granularity would write, relative to the finest one
+    pub fn report(&self, size: u64) -> String {
+        let mut ret = format!(
+            "{:>12} {:>12} {:>12} {:>16} {:>8}\n",
+            "granularity", "chunks", "differing", "bytes to write", "ratio"
+        );
+        let finest = self
+            .grains
+            .first()
+            .map_or(0, |g| std::cmp::min(g.diff * g.size, size));
+        for grain in self.grains.iter() {
+            // The last chunk may be short
+            let bytes = std::cmp::min(grain.diff * grain.size, size);
+            let ratio = if finest > 0 {
+                format!("{:.2}", bytes as f64 / finest as f64)
+            } else {
+                "-".to_string()
+            };
+            ret.push_str(&format!(
+                "{:>12} {:>12} {:>12} {:>16} {:>8}\n",
+                grain.size,
+                size.div_ceil(grain.size),
+                grain.diff,
+                bytes,
+                ratio
+            ));
+        }
+        ret
+    }
+}
@@ -0,0 +1,640 @@
+use {
+    crate::{
+        error::{self, Context, Error},
+        source::{self, BlockSource},
+        Driver, SizeMismatch, Summary, SyncArgs, PROGRESS_INTERVAL,
+    },
+    async_trait::async_trait,
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    std::{convert::TryInto, io, sync::atomic::Ordering, time::Instant},
+    tokio::{
+        fs::File,
+        io::{AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+// NBD, the network block device protocol qemu-nbd and nbdkit serve, with
+// the fixed newstyle handshake, big endian:
+//
+//   server  "NBDMAGIC" "IHAVEOPT" u16 flags
+//   client  u32 flags
+//   client  "IHAVEOPT" u32 option u32 length, data
+//   server  u64 reply magic u32 option u32 type u32 length, data
+//
+// The client asks for the export with NBD_OPT_GO, the server replies
+// with its size and flags, then an ACK. Requests and simple replies
+// follow:
+//
+//   client  u32 magic u16 flags u16 type u64 handle u64 offset u32 length,
+//           the data of a write
+//   server  u32 magic u32 error u64 handle, the data of a read
+pub const NBDMAGIC: u64 = 0x4e42_444d_4147_4943;
+pub const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+pub const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+pub const REQUEST_MAGIC: u32 = 0x2560_9513;
+pub const SIMPLE_REPLY: u32 = 0x6744_6698;
+
+pub const FIXED_NEWSTYLE: u16 = 1;
+pub const NO_ZEROES: u16 = 2;
+
+pub const OPT_EXPORT_NAME: u32 = 1;
+pub const OPT_ABORT: u32 = 2;
+pub const OPT_INFO: u32 = 6;
+pub const OPT_GO: u32 = 7;
+pub const REP_ACK: u32 = 1;
+pub const REP_INFO: u32 = 3;
+pub const REP_ERR: u32 = 1 << 31;
+pub const REP_ERR_UNSUP: u32 = REP_ERR | 1;
+pub const INFO_EXPORT: u16 = 0;
+
+pub const HAS_FLAGS: u16 = 1;
+pub const READ_ONLY: u16 = 2;
+pub const SEND_FLUSH: u16 = 4;
+
+pub const CMD_READ: u16 = 0;
+pub const CMD_WRITE: u16 = 1;
+pub const CMD_DISC: u16 = 2;
+pub const CMD_FLUSH: u16 = 3;
+
+// Errors of replies, as errno values
+pub const EPERM: u32 = 1;
+pub const EINVAL: u32 = 22;
+
+pub const DEFAULT_PORT: u16 = 10809;
+// Larger requests aren't served by everything
+pub const MAX_REQUEST: usize = 32 * 1024 * 1024;
+
+/// An export of an NBD server, nbd://HOST[:PORT]/EXPORT
+#[derive(Clone, Debug)]
+pub struct Uri {
+    address: String,
+    export: String,
+}
+
+impl Uri {
+    pub fn parse(spec: &str) -> Option<Uri> {
+        let rest = spec.strip_prefix("nbd://")?;
+        let (host, export) = rest.split_once('/').unwrap_or((rest, ""));
+        // An IPv6 address is in brackets, it has colons of its own
+        let address = match host.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => host.to_string(),
+            _ => format!("{}:{}", host, DEFAULT_PORT),
+        };
+        Some(Uri {
+            address,
+            export: export.to_string(),
+        })
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A connection to an export
+pub struct Client {
+    stream: TcpStream,
+    pub size: u64,
+    flags: u16,
+    handle: u64,
+}
+
+impl Client {
+    pub async fn connect(uri: &Uri) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(&uri.address).await?;
+        stream.set_nodelay(true)?;
+        if stream.read_u64().await? != NBDMAGIC {
+            return Err(invalid(format!("{} is not an NBD server", uri.address)));
+        }
+        if stream.read_u64().await? != IHAVEOPT {
+            return Err(invalid(format!(
+                "{} only has the oldstyle handshake",
+                uri.address
+            )));
+        }
+        let server_flags = stream.read_u16().await?;
+        if server_flags & FIXED_NEWSTYLE == 0 {
+            return Err(invalid(format!(
+                "{} doesn't have the fixed newstyle handshake",
+                uri.address
+            )));
+        }
+        let flags = FIXED_NEWSTYLE | (server_flags & NO_ZEROES);
+        stream.write_u32(flags as u32).await?;
+
+        // The export's name, no information asked for besides its size
+        let name = uri.export.as_bytes();
+        let mut go = (name.len() as u32).to_be_bytes().to_vec();
+        go.extend_from_slice(name);
+        go.extend_from_slice(&0u16.to_be_bytes());
+        send_option(&mut stream, OPT_GO, &go).await?;
+
+        let mut export = None;
+        loop {
+            if stream.read_u64().await? != REPLY_MAGIC {
+                return Err(invalid("Bad NBD option reply".to_string()));
+            }
+            let _option = stream.read_u32().await?;
+            let kind = stream.read_u32().await?;
+            let mut data = vec![0; stream.read_u32().await? as usize];
+            stream.read_exact(&mut data).await?;
+            match kind {
+                REP_INFO if data.len() >= 12 && data[..2] == INFO_EXPORT.to_be_bytes() => {
+                    let size = u64::from_be_bytes(data[2..10].try_into().unwrap());
+                    export = Some((size, u16::from_be_bytes([data[10], data[11]])));
+                }
+                REP_ACK => break,
+                // An older server, the export is asked for the old way
+                REP_ERR_UNSUP => {
+                    send_option(&mut stream, OPT_EXPORT_NAME, name).await?;
+                    let size = stream.read_u64().await?;
+                    let export_flags = stream.read_u16().await?;
+                    if flags & NO_ZEROES == 0 {
+                        stream.read_exact(&mut [0; 124]).await?;
+                    }
+                    export = Some((size, export_flags));
+                    break;
+                }
+                kind if kind & REP_ERR != 0 => {
+                    return Err(io::Error::other(format!(
+                        "The NBD server refused the export {:?}: {}",
+                        uri.export,
+                        String::from_utf8_lossy(&data)
+                    )))
+                }
+                _ => (),
+            }
+        }
+        let (size, flags) =
+            export.ok_or_else(|| invalid("The NBD server didn't tell the size".to_string()))?;
+        Ok(Client {
+            stream,
+            size,
+            flags,
+            handle: 0,
+        })
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.flags & HAS_FLAGS != 0 && self.flags & READ_ONLY != 0
+    }
+
+    // Send a request and wait for its reply, the data of a read follows it
+    async fn request(&mut self, kind: u16, offset: u64, len: usize, data: &[u8]) -> io::Result<()> {
+        self.handle += 1;
+        let mut request = Vec::with_capacity(28 + data.len());
+        request.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&kind.to_be_bytes());
+        request.extend_from_slice(&self.handle.to_be_bytes());
+        request.extend_from_slice(&offset.to_be_bytes());
+        request.extend_from_slice(&(len as u32).to_be_bytes());
+        request.extend_from_slice(data);
+        self.stream.write_all(&request).await?;
+
+        if self.stream.read_u32().await? != SIMPLE_REPLY {
+            return Err(invalid("Bad NBD reply".to_string()));
+        }
+        let error = self.stream.read_u32().await?;
+        if self.stream.read_u64().await? != self.handle {
+            return Err(invalid("NBD reply to another request".to_string()));
+        }
+        match error {
+            0 => Ok(()),
+            // Errors are errno values
+            errno => Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
+
+    pub async fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        for chunk in buf.chunks_mut(MAX_REQUEST) {
+            self.request(CMD_READ, offset + done, chunk.len(), &[])
+                .await?;
+            self.stream.read_exact(chunk).await?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut done = 0;
+        for chunk in data.chunks(MAX_REQUEST) {
+            self.request(CMD_WRITE, offset + done, chunk.len(), chunk)
+                .await?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Have what's written reach the disk, if the server can be asked to
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if self.flags & SEND_FLUSH == 0 {
+            return Ok(());
+        }
+        self.request(CMD_FLUSH, 0, 0, &[]).await
+    }
+
+    /// End the connection, there's no reply
+    pub async fn disconnect(mut self) -> io::Result<()> {
+        self.handle += 1;
+        let mut request = Vec::with_capacity(28);
+        request.extend_from_slice(&REQUEST_MAGIC.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&CMD_DISC.to_be_bytes());
+        request.extend_from_slice(&self.handle.to_be_bytes());
+        request.extend_from_slice(&[0; 12]);
+        self.stream.write_all(&request).await?;
+        self.stream.shutdown().await
+    }
+}
+
+async fn send_option(stream: &mut TcpStream, option: u32, data: &[u8]) -> io::Result<()> {
+    let mut message = IHAVEOPT.to_be_bytes().to_vec();
+    message.extend_from_slice(&option.to_be_bytes());
+    message.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    message.extend_from_slice(data);
+    stream.write_all(&message).await
+}
+
+/// An export read as a source
+pub struct NbdSource {
+    client: Client,
+    pos: u64,
+}
+
+impl NbdSource {
+    pub async fn open(uri: &Uri) -> io::Result<Self> {
+        Ok(NbdSource {
+            client: Client::connect(uri).await?,
+            pos: 0,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockSource for NbdSource {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.client.size)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len() as u64, self.client.size - self.pos) as usize;
+        self.client.read(self.pos, &mut buf[..n]).await?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos.saturating_add(len), self.client.size);
+        Ok(())
+    }
+}
+
+/// A sync onto an export: each block is read from both sides over the
+/// connection and written if it differs, one request at a time
+pub async fn sync(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+    uri: Uri,
+) -> error::Result<Summary> {
+    let started = Instant::now();
+    let spec = args.target.clone().unwrap();
+    if let Some(option) = crate::local_option(args) {
+        return Err(Error::Usage(format!(
+            "{} doesn't work with an nbd:// target",
+            option
+        )));
+    }
+    let mut source_r = match source {
+        Some(source) => source,
+        None => source::open(args.source.as_ref().unwrap(), args.io_backend, false).await?,
+    };
+    let source_size = source_r.size().await;
+    let mut target = Client::connect(&uri).await.map_err(|source| Error::File {
+        action: "connect to",
+        path: spec.clone(),
+        source,
+    })?;
+    if target.read_only() && !args.dry_run {
+        return Err(Error::Usage(format!(
+            "The NBD export {} is read-only",
+            spec
+        )));
+    }
+    let target_size = target.size;
+
+    match source_size {
+        Some(size) => driver.say(format_args!("{} -> {}", size, target_size)),
+        None => driver.say(format_args!("? -> {}", target_size)),
+    }
+    match (source_size, args.size_mismatch) {
+        (Some(source_size), _) if whole && source_size > target_size => {
+            return Err(Error::TargetTooSmall {
+                source_size,
+                target_size,
+            })
+        }
+        (Some(source_size), _) if source_size == target_size => (),
+        (_, SizeMismatch::Truncate | SizeMismatch::Extend) => {
+            return Err(Error::Usage(
+                "An NBD export can't be truncated or extended".to_string(),
+            ))
+        }
+        (Some(source_size), SizeMismatch::Error) => {
+            return Err(Error::SizeMismatch {
+                source_size,
+                target_size,
+            })
+        }
+        (Some(source_size), SizeMismatch::SyncMin) => driver.say(format_args!(
+            "Sizes differ, only the first {} bytes are synced.",
+            std::cmp::min(source_size, target_size)
+        )),
+        (None, SizeMismatch::Error) => {
+            return Err(Error::Usage(
+                "The size of a piped source isn't known up front, \
+                 only --size-mismatch sync-min works with it"
+                    .to_string(),
+            ))
+        }
+        (None, SizeMismatch::SyncMin) => (),
+    }
+    let sync_size = source_size.map_or(target_size, |s| std::cmp::min(s, target_size));
+    let (source_read, target_read) = crate::read_sizes(args);
+    let block_size = crate::gcd(source_read, target_read);
+
+    let bar = match source_size {
+        Some(_) => ProgressBar::new(sync_size),
+        None => ProgressBar::new_spinner(),
+    };
+    if !driver.bars {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]")
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+
+    let mut bsrc = vec![0; block_size];
+    let mut btgt = vec![0; block_size];
+    let mut pos = 0;
+    let mut total = 0;
+    let mut diff = 0;
+    let mut diff_bytes = 0;
+    let mut cancelled = false;
+    let mut last_progress = Instant::now();
+    let scanning = Instant::now();
+    while pos < sync_size {
+        if driver
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            cancelled = true;
+            break;
+        }
+        let len = std::cmp::min(block_size as u64, sync_size - pos) as usize;
+        let n = source_r
+            .read(&mut bsrc[..len])
+            .await
+            .map_err(|source| Error::Read {
+                side: "source",
+                offset: pos,
+                source,
+            })?;
+        if n == 0 {
+            break;
+        }
+        target
+            .read(pos, &mut btgt[..n])
+            .await
+            .map_err(|source| Error::Read {
+                side: "target",
+                offset: pos,
+                source,
+            })?;
+        total += 1;
+        if bsrc[..n] != btgt[..n] {
+            diff += 1;
+            diff_bytes += n as u64;
+            if !args.dry_run {
+                target
+                    .write(pos, &bsrc[..n])
+                    .await
+                    .map_err(|source| Error::Write {
+                        offset: pos,
+                        source,
+                    })?;
+            }
+        }
+
+        pos += n as u64;
+        bar.set_position(pos);
+        if let Some(progress) = &driver.progress {
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                progress(pos, sync_size);
+                last_progress = Instant::now();
+            }
+        }
+    }
+    let finishing = Instant::now();
+    bar.finish();
+    if let Some(progress) = &driver.progress {
+        progress(pos, sync_size);
+    }
+
+    let written = if args.dry_run { 0 } else { diff_bytes };
+    if written > 0 {
+        target.flush().await.map_err(|source| Error::Write {
+            offset: pos,
+            source,
+        })?;
+    }
+    let _ = target.disconnect().await;
+
+    if cancelled {
+        driver.say(format_args!(
+            "\nStopped at {}. Total: {}, different: {}, written: {} bytes",
+            pos, total, diff, written
+        ));
+        return Err(Error::Cancelled);
+    }
+    if args.dry_run {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, would write: {} bytes",
+            total, diff, diff_bytes
+        ));
+    } else {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, written: {} bytes",
+            total, diff, written
+        ));
+    }
+
+    Ok(Summary {
+        target: spec,
+        blocks: total,
+        different: diff,
+        written,
+        source_size,
+        target_size,
+        scanned: pos,
+        read: 2 * pos,
+        retries: 0,
+        unreadable: 0,
+        phases: vec![
+            ("prepare", scanning - started),
+            ("sync", finishing - scanning),
+            ("finish", finishing.elapsed()),
+        ],
+    })
+}
+
+/// Export a device read-only to NBD clients, one after the other, under
+/// whatever export name they ask for
+pub async fn serve(addr: &str, device: &str, once: bool) -> error::Result<()> {
+    // Checked up front, not only once a client asks
+    let size = crate::get_size(&File::open(device).await.context("open", device)?)
+        .await
+        .context("open", device)?
+        .ok_or_else(|| Error::Usage(format!("{} is a pipe, it can't be exported", device)))?;
+    let listener = TcpListener::bind(addr).await.context("listen on", addr)?;
+    println!(
+        "Exporting {} ({} bytes) on {}",
+        device,
+        size,
+        listener
+            .local_addr()
+            .map_or(addr.to_string(), |a| a.to_string())
+    );
+    loop {
+        let (stream, peer) = listener.accept().await.context("listen on", addr)?;
+        let served = export(stream, device).await.map_err(|source| Error::File {
+            action: "export to",
+            path: peer.to_string(),
+            source,
+        });
+        match &served {
+            Ok(()) => println!("Exported to {}.", peer),
+            Err(e) => println!("Export to {} failed: {}", peer, e),
+        }
+        if once {
+            return served;
+        }
+    }
+}
+
+// The handshake and the requests of one client
+async fn export(stream: TcpStream, device: &str) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut file = File::open(device).await?;
+    let size = crate::get_size(&file).await?.unwrap_or(0);
+    let flags = HAS_FLAGS | READ_ONLY | SEND_FLUSH;
+    let (rx, mut tx) = stream.into_split();
+    let mut rx = BufReader::new(rx);
+
+    let mut greeting = NBDMAGIC.to_be_bytes().to_vec();
+    greeting.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    greeting.extend_from_slice(&(FIXED_NEWSTYLE | NO_ZEROES).to_be_bytes());
+    tx.write_all(&greeting).await?;
+    let client_flags = rx.read_u32().await?;
+    if client_flags & FIXED_NEWSTYLE as u32 == 0 {
+        return Err(invalid(
+            "The client doesn't have the fixed newstyle handshake".to_string(),
+        ));
+    }
+
+    loop {
+        if rx.read_u64().await? != IHAVEOPT {
+            return Err(invalid("Bad NBD option".to_string()));
+        }
+        let option = rx.read_u32().await?;
+        let len = rx.read_u32().await? as usize;
+        if len > 64 * 1024 {
+            return Err(invalid(format!("NBD option of {} bytes", len)));
+        }
+        let mut data = vec![0; len];
+        rx.read_exact(&mut data).await?;
+        let reply = |kind: u32, data: &[u8]| {
+            let mut reply = REPLY_MAGIC.to_be_bytes().to_vec();
+            reply.extend_from_slice(&option.to_be_bytes());
+            reply.extend_from_slice(&kind.to_be_bytes());
+            reply.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            reply.extend_from_slice(data);
+            reply
+        };
+        match option {
+            OPT_EXPORT_NAME => {
+                let mut export = size.to_be_bytes().to_vec();
+                export.extend_from_slice(&flags.to_be_bytes());
+                if client_flags & NO_ZEROES as u32 == 0 {
+                    export.extend_from_slice(&[0; 124]);
+                }
+                tx.write_all(&export).await?;
+                break;
+            }
+            OPT_INFO | OPT_GO => {
+                let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                info.extend_from_slice(&size.to_be_bytes());
+                info.extend_from_slice(&flags.to_be_bytes());
+                let mut replies = reply(REP_INFO, &info);
+                replies.extend(reply(REP_ACK, &[]));
+                tx.write_all(&replies).await?;
+                if option == OPT_GO {
+                    break;
+                }
+            }
+            OPT_ABORT => {
+                tx.write_all(&reply(REP_ACK, &[])).await?;
+                return Ok(());
+            }
+            _ => tx.write_all(&reply(REP_ERR_UNSUP, &[])).await?,
+        }
+    }
+
+    let mut buf = Vec::new();
+    loop {
+        if rx.read_u32().await? != REQUEST_MAGIC {
+            return Err(invalid("Bad NBD request".to_string()));
+        }
+        let _flags = rx.read_u16().await?;
+        let kind = rx.read_u16().await?;
+        let handle = rx.read_u64().await?;
+        let offset = rx.read_u64().await?;
+        let len = rx.read_u32().await? as usize;
+        let error = match kind {
+            CMD_READ if len > MAX_REQUEST || offset.saturating_add(len as u64) > size => EINVAL,
+            CMD_READ => {
+                buf.resize(len, 0);
+                crate::image::read_at(&mut file, offset, &mut buf).await?;
+                0
+            }
+            // The data is read past, it's not written
+            CMD_WRITE => {
+                if len > MAX_REQUEST {
+                    return Err(invalid(format!("NBD write of {} bytes", len)));
+                }
+                buf.resize(len, 0);
+                rx.read_exact(&mut buf).await?;
+                EPERM
+            }
+            // Nothing is written to flush
+            CMD_FLUSH => 0,
+            CMD_DISC => return Ok(()),
+            _ => EINVAL,
+        };
+        let mut reply = SIMPLE_REPLY.to_be_bytes().to_vec();
+        reply.extend_from_slice(&error.to_be_bytes());
+        reply.extend_from_slice(&handle.to_be_bytes());
+        if kind == CMD_READ && error == 0 {
+            reply.extend_from_slice(&buf);
+        }
+        tx.write_all(&reply).await?;
+    }
+}
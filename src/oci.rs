@@ -0,0 +1,173 @@
+use {
+    crate::hash,
+    sha2::{Digest, Sha256},
+    std::io,
+    tokio::{
+        fs::File,
+        io::{AsyncSeekExt, AsyncWriteExt, BufWriter},
+    },
+};
+
+// The differing blocks as a tar stream for registry tooling: one entry
+// per region, named by its offset, and a JSON manifest as the last entry
+// listing every region with its sha256 digest:
+//
+//   {"mediaType":"application/vnd.ssdsync.delta.v1+json","size":1048576,
+//    "regions":[{"path":"blocks/00000000000000016384","offset":16384,
+//                "length":4096,"digest":"sha256:..."}]}
+const MEDIA_TYPE: &str = "application/vnd.ssdsync.delta.v1+json";
+const MANIFEST_NAME: &str = "manifest.json";
+
+const BLOCK: u64 = 512;
+
+// ustar stores sizes in 11 octal digits, a longer region is split
+const MAX_ENTRY: u64 = 0o77777777777;
+
+struct Region {
+    offset: u64,
+    length: u64,
+    digest: String,
+}
+
+fn entry_name(offset: u64) -> String {
+    format!("blocks/{:020}", offset)
+}
+
+// Write `value` as a NUL terminated octal number filling `field`
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(digits.as_bytes());
+}
+
+fn header(name: &str, size: u64) -> [u8; BLOCK as usize] {
+    let mut h = [0; BLOCK as usize];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], 0);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // The checksum is taken with its own field full of spaces
+    h[148..156].copy_from_slice(b"        ");
+    let sum: u64 = h.iter().map(|b| *b as u64).sum();
+    h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    h
+}
+
+fn padding(length: u64) -> usize {
+    ((BLOCK - length % BLOCK) % BLOCK) as usize
+}
+
+/// Collects differing blocks into a tar of regions and a manifest
+pub struct OciWriter {
+    file: BufWriter<File>,
+    size: u64,
+    regions: Vec<Region>,
+    // The region being written: where its header is and its hash so far
+    open: Option<(u64, Sha256)>,
+    end: u64,
+}
+
+impl OciWriter {
+    pub async fn create(path: &str, size: u64) -> io::Result<Self> {
+        Ok(OciWriter {
+            file: BufWriter::new(File::create(path).await?),
+            size,
+            regions: Vec::new(),
+            open: None,
+            end: 0,
+        })
+    }
+
+    /// Add a block of the source at `pos`, adjacent blocks are merged
+    pub async fn append(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+        let adjacent = match (self.regions.last(), &self.open) {
+            (Some(last), Some(_)) => {
+                last.offset + last.length == pos && last.length + data.len() as u64 <= MAX_ENTRY
+            }
+            _ => false,
+        };
+        if !adjacent {
+            self.close().await?;
+            self.file.write_all(&[0; BLOCK as usize]).await?;
+            self.open = Some((self.end, Sha256::new()));
+            self.end += BLOCK;
+            self.regions.push(Region {
+                offset: pos,
+                length: 0,
+                digest: String::new(),
+            });
+        }
+
+        self.file.write_all(data).await?;
+        self.end += data.len() as u64;
+        self.open.as_mut().unwrap().1.update(data);
+        self.regions.last_mut().unwrap().length += data.len() as u64;
+        Ok(())
+    }
+
+    // Pad the open region and fill in its header, now that its size is known
+    async fn close(&mut self) -> io::Result<()> {
+        let (header_pos, hasher) = match self.open.take() {
+            Some(open) => open,
+            None => return Ok(()),
+        };
+        let region = self.regions.last_mut().unwrap();
+        region.digest = format!("sha256:{}", hash::to_hex(&hasher.finalize()));
+
+        let pad = padding(region.length);
+        self.file.write_all(&vec![0; pad]).await?;
+        self.end += pad as u64;
+
+        let header = header(&entry_name(region.offset), region.length);
+        self.file.seek(io::SeekFrom::Start(header_pos)).await?;
+        self.file.write_all(&header).await?;
+        self.file.seek(io::SeekFrom::Start(self.end)).await?;
+        Ok(())
+    }
+
+    /// Write the manifest and end the tar. Returns regions and bytes stored.
+    pub async fn finish(mut self) -> io::Result<(usize, u64)> {
+        self.close().await?;
+
+        let regions = self
+            .regions
+            .iter()
+            .map(|r| {
+                format!(
+                    "{{\"path\":\"{}\",\"offset\":{},\"length\":{},\"digest\":\"{}\"}}",
+                    entry_name(r.offset),
+                    r.offset,
+                    r.length,
+                    r.digest
+                )
+            })
+            .collect::<Vec<_>>();
+        let manifest = format!(
+            "{{\"mediaType\":\"{}\",\"size\":{},\"regions\":[{}]}}\n",
+            MEDIA_TYPE,
+            self.size,
+            regions.join(",")
+        );
+        let manifest = manifest.as_bytes();
+        self.file
+            .write_all(&header(MANIFEST_NAME, manifest.len() as u64))
+            .await?;
+        self.file.write_all(manifest).await?;
+        self.file
+            .write_all(&vec![0; padding(manifest.len() as u64)])
+            .await?;
+
+        // A tar ends with two empty blocks
+        self.file.write_all(&[0; 2 * BLOCK as usize]).await?;
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+
+        let bytes = self.regions.iter().map(|r| r.length).sum();
+        Ok((self.regions.len(), bytes))
+    }
+}
@@ -0,0 +1,267 @@
+use {
+    crate::{source::BlockSource, Driver, SyncArgs},
+    nix::{
+        sys::resource::{getrlimit, Resource},
+        unistd::{access, AccessFlags},
+    },
+    std::{os::unix::fs::FileTypeExt, path::Path},
+    tokio::fs::{File, OpenOptions},
+};
+
+// Everything found wrong, reported together at the end
+type Problems = Vec<String>;
+
+// Resolve a source or target given on the command line
+fn resolve(spec: &str, problems: &mut Problems) -> Option<String> {
+    crate::resolve_device(spec)
+        .map_err(|e| problems.push(e.to_string()))
+        .ok()
+}
+
+// Open a file or device the way the sync would and return its size, or
+// None if it's a pipe. A pipe isn't opened, that would wait for a writer.
+async fn open_size(path: &str, write: bool, problems: &mut Problems) -> Option<Option<u64>> {
+    let meta = match std::fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) => {
+            problems.push(format!("Can't access {}: {}", path, e));
+            return None;
+        }
+    };
+    if meta.file_type().is_fifo() {
+        return Some(None);
+    }
+    let opened = if write {
+        OpenOptions::new().read(true).write(true).open(path).await
+    } else {
+        File::open(path).await
+    };
+    match opened {
+        Ok(file) => match crate::get_size(&file).await {
+            Ok(size) => Some(size),
+            Err(e) => {
+                problems.push(format!("Can't use {}: {}", path, e));
+                None
+            }
+        },
+        Err(e) => {
+            let how = if write { "writing" } else { "reading" };
+            problems.push(format!("Can't open {} for {}: {}", path, how, e));
+            None
+        }
+    }
+}
+
+// A file that's going to be created has to have a writable directory
+fn check_creatable(what: &str, path: &Option<String>, problems: &mut Problems) {
+    let path = match path {
+        Some(path) => Path::new(path),
+        None => return,
+    };
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Err(e) = access(dir, AccessFlags::W_OK) {
+        problems.push(format!(
+            "Can't create the {} {}: {} is not writable ({})",
+            what,
+            path.display(),
+            dir.display(),
+            e
+        ));
+    }
+}
+
+/// Check a sync without doing it: sources and target can be opened as
+/// they will be, sizes fit, the block size suits the target and the
+/// files to create can be. Returns everything that's wrong.
+pub async fn check(args: &SyncArgs, whole: bool, driver: &Driver) -> Problems {
+    let mut problems = Problems::new();
+
+    // Both parts of an overlay are opened, and every segment
+    let source_spec = args.source.as_ref().unwrap();
+    let sources: Vec<String> = if let Some(rest) = source_spec.strip_prefix("overlay:") {
+        match rest.split_once(':') {
+            Some((base, delta)) => vec![base.to_string(), delta.to_string()],
+            None => {
+                problems.push("Overlay sources must be given as overlay:BASE:DELTA".to_string());
+                vec![]
+            }
+        }
+    } else if let Some(path) = source_spec.strip_prefix("segments:") {
+        match crate::source::segment_list(path) {
+            Ok(segments) => segments.into_iter().map(|(_, _, path)| path).collect(),
+            Err(e) => {
+                problems.push(format!("Could not load {}: {}", path, e));
+                vec![]
+            }
+        }
+    } else if let Some((_, path)) = crate::image::parse(source_spec) {
+        vec![path.to_string()]
+    } else if crate::http::is_url(source_spec) || crate::nbd::Uri::parse(source_spec).is_some() {
+        vec![]
+    } else {
+        vec![source_spec.clone()]
+    };
+    let mut source_size = None;
+    // What's downloaded has to be served with ranges
+    if crate::http::is_url(source_spec) {
+        match crate::http::HttpSource::open(source_spec).await {
+            Ok(mut source) => source_size = source.size().await,
+            Err(e) => problems.push(format!("Can't download {}: {}", source_spec, e)),
+        }
+    }
+    if let Some(uri) = crate::nbd::Uri::parse(source_spec) {
+        match crate::nbd::NbdSource::open(&uri).await {
+            Ok(mut source) => source_size = source.size().await,
+            Err(e) => problems.push(format!("Can't connect to {}: {}", source_spec, e)),
+        }
+    }
+    for (i, spec) in sources.iter().enumerate() {
+        if let Some(path) = resolve(spec, &mut problems) {
+            let size = open_size(&path, false, &mut problems).await;
+            // The base decides the size of an overlay
+            if i == 0 {
+                source_size = size.flatten();
+            }
+        }
+    }
+    // A segmented source is as large as its segments reach
+    if let Some(path) = source_spec.strip_prefix("segments:") {
+        source_size = crate::source::segment_list(path)
+            .ok()
+            .and_then(|segments| segments.iter().map(|(o, l, _)| o + l).max());
+    }
+
+    // The target is written unless only compared, or a loop device is
+    // attached to it, which needs the image to be writable too
+    let written = !args.read_only() && args.sparse_image_out.is_none() && args.oci_out.is_none();
+    let target = resolve(args.target.as_ref().unwrap(), &mut problems);
+    let mut target_size = None;
+    if let Some(target) = &target {
+        if let Err(e) = crate::check_target_type(args, target) {
+            problems.push(e.to_string());
+        }
+        match open_size(target, written || args.loop_setup, &mut problems).await {
+            Some(None) => problems.push(format!(
+                "The target {} is a pipe, it has to be read and written",
+                target
+            )),
+            Some(size) => target_size = size,
+            None => (),
+        }
+        if args.loop_setup {
+            if let Err(e) = access("/dev/loop-control", AccessFlags::R_OK | AccessFlags::W_OK) {
+                problems.push(format!(
+                    "Can't set up a loop device: /dev/loop-control: {}",
+                    e
+                ));
+            }
+        }
+    }
+    let target_size = args
+        .target_size
+        .or(target_size)
+        .map(|size| args.window(size, args.target_offset));
+    let source_size = source_size.map(|size| args.window(size, args.source_offset));
+
+    if let Some(target) = &target {
+        for assertion in args.assert_region.iter() {
+            if let Err(e) = assertion.check(target) {
+                problems.push(format!("The target doesn't hold the asserted bytes: {}", e));
+            }
+        }
+    }
+
+    if let (Some(source_size), Some(target_size)) = (source_size, target_size) {
+        driver.say(format_args!("{} -> {}", source_size, target_size));
+        if whole && target_size < source_size {
+            problems.push(format!(
+                "Target is smaller than the source ({} < {} bytes)",
+                target_size, source_size
+            ));
+        }
+    }
+
+    // The manifest sets the block size
+    let (source_read, target_read) = crate::read_sizes(args);
+    let mut block_size = crate::gcd(source_read, target_read);
+    if let Some(path) = &args.expect_source_manifest {
+        match crate::manifest::Manifest::load(path) {
+            Ok(manifest) => block_size = manifest.block_size,
+            Err(e) => problems.push(format!("Could not load {}: {}", path, e)),
+        }
+    }
+    if !block_size.is_multiple_of(args.compare.unit()) {
+        problems.push(format!(
+            "Block size {} is not a multiple of the {} byte words compared",
+            block_size,
+            args.compare.unit()
+        ));
+    }
+
+    // Writes that aren't whole sectors are read-modify-write on the
+    // device, or fail outright with direct I/O
+    if let Some(target) = target.as_ref().filter(|_| written) {
+        if let Ok(file) = std::fs::File::open(target) {
+            if let Some(sector) = crate::device::logical_block_size(&file) {
+                if !block_size.is_multiple_of(sector) {
+                    problems.push(format!(
+                        "Block size {} is not a multiple of the {} byte sectors of {}",
+                        block_size, sector, target
+                    ));
+                }
+                if !target_read.is_multiple_of(sector) {
+                    problems.push(format!(
+                        "Target block size {} is not a multiple of the {} byte sectors of {}",
+                        target_read, sector, target
+                    ));
+                }
+            }
+        }
+    }
+
+    let needed = crate::fds_needed(args);
+    match getrlimit(Resource::RLIMIT_NOFILE) {
+        Ok((_, hard)) if needed > hard => problems.push(format!(
+            "{} file descriptors are needed, but the hard limit is {}",
+            needed, hard
+        )),
+        Ok(_) => (),
+        Err(e) => problems.push(format!("Could not get RLIMIT_NOFILE: {}", e)),
+    }
+
+    check_creatable("slow log", &args.slow_log, &mut problems);
+    check_creatable("journal", &args.journal, &mut problems);
+    check_creatable("sparse image", &args.sparse_image_out, &mut problems);
+    check_creatable("tar", &args.oci_out, &mut problems);
+    check_creatable("manifest", &args.write_manifest, &mut problems);
+    check_creatable("control socket", &args.control_socket, &mut problems);
+    if let Some(path) = args
+        .control_socket
+        .as_ref()
+        .filter(|p| Path::new(p).exists())
+    {
+        problems.push(format!("The control socket {} already exists", path));
+    }
+
+    problems
+}
+
+/// Run the checks, report and exit: with EXIT_CHECK if there are problems
+pub async fn run(args: &SyncArgs, whole: bool, driver: &Driver) -> ! {
+    let problems = check(args, whole, driver).await;
+    if problems.is_empty() {
+        driver.say("Preflight: no problems found.");
+        std::process::exit(0);
+    }
+    driver.say(format_args!(
+        "Preflight: {} problems found:",
+        problems.len()
+    ));
+    for problem in problems.iter() {
+        driver.say(format_args!("  {}", problem));
+    }
+    std::process::exit(crate::error::EXIT_CHECK);
+}
@@ -0,0 +1,163 @@
+use {
+    crate::image::{be32, be64, invalid, read_at, Cluster, Codec, Layout},
+    async_trait::async_trait,
+    std::io,
+    tokio::fs::File,
+};
+
+// qcow2 is the image format of QEMU. Its header is big endian:
+//
+//     0  magic "QFI\xfb"           4  version, 2 or 3
+//     8  backing file offset      16  backing file name length
+//    20  cluster bits             24  size of the content
+//    32  encryption method        36  L1 table entries
+//    40  L1 table offset          72  incompatible features (3)
+//   100  header length (3)       104  compression type (3)
+//
+// The L1 table points at L2 tables, and their entries at the clusters.
+// A cluster at offset 0 isn't allocated, which reads as zeroes, and so
+// does one flagged as zeroes. A compressed cluster has its offset and
+// its length in sectors in the entry.
+const MAGIC: &[u8] = b"QFI\xfb";
+const OFFSET: u64 = 0x00ff_ffff_ffff_fe00;
+const COMPRESSED: u64 = 1 << 62;
+const ZERO: u64 = 1;
+
+// Incompatible features that don't change how the content is read:
+// dirty refcounts and the compression type
+const KNOWN_FEATURES: u64 = 0b1001;
+
+/// The tables of a qcow2 image
+pub struct Qcow2 {
+    size: u64,
+    cluster_bits: u32,
+    l1: Vec<u64>,
+    codec: Codec,
+    // The L2 table used last, by its offset
+    l2: Option<(u64, Vec<u64>)>,
+}
+
+impl Qcow2 {
+    pub async fn open(file: &mut File, path: &str) -> io::Result<Self> {
+        let file_size = file.metadata().await?.len();
+        let mut header = [0; 105];
+        let len = std::cmp::min(file_size, header.len() as u64) as usize;
+        read_at(file, 0, &mut header[..len]).await?;
+        if len < 72 || &header[..4] != MAGIC {
+            return Err(invalid(format!("{} is not a qcow2 image", path)));
+        }
+        let version = be32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(invalid(format!(
+                "qcow2 version {} isn't supported",
+                version
+            )));
+        }
+        if be64(&header, 8) != 0 {
+            return Err(invalid(format!(
+                "{} has a backing file, only a flattened image can be read",
+                path
+            )));
+        }
+        if be32(&header, 32) != 0 {
+            return Err(invalid(format!("{} is encrypted", path)));
+        }
+        let cluster_bits = be32(&header, 20);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(format!("Bad qcow2 cluster bits {}", cluster_bits)));
+        }
+
+        let mut codec = Codec::Deflate;
+        if version == 3 {
+            let features = be64(&header, 72) & !KNOWN_FEATURES;
+            if features != 0 {
+                return Err(invalid(format!(
+                    "{} uses qcow2 features that aren't supported: {:#x}",
+                    path, features
+                )));
+            }
+            if be32(&header, 100) > 104 {
+                codec = match header[104] {
+                    0 => Codec::Deflate,
+                    1 => Codec::Zstd,
+                    other => {
+                        return Err(invalid(format!(
+                            "qcow2 compression type {} isn't supported",
+                            other
+                        )))
+                    }
+                };
+            }
+        }
+
+        let size = be64(&header, 24);
+        let entries = be32(&header, 36) as u64;
+        // Every cluster of the content has to have an L2 table entry
+        let clusters = size.div_ceil(1 << cluster_bits);
+        if entries << (cluster_bits - 3) < clusters {
+            return Err(invalid(format!(
+                "The L1 table of {} is too small for its size",
+                path
+            )));
+        }
+        let at = be64(&header, 40);
+        if at
+            .checked_add(entries * 8)
+            .is_none_or(|end| end > file_size)
+        {
+            return Err(invalid(format!("The L1 table of {} is past its end", path)));
+        }
+        let mut table = vec![0; entries as usize * 8];
+        read_at(file, at, &mut table).await?;
+        let l1 = table.chunks(8).map(|entry| be64(entry, 0)).collect();
+
+        Ok(Qcow2 {
+            size,
+            cluster_bits,
+            l1,
+            codec,
+            l2: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Layout for Qcow2 {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    async fn cluster(&mut self, file: &mut File, index: u64) -> io::Result<Cluster> {
+        let per_table = 1 << (self.cluster_bits - 3);
+        let table = self.l1[(index / per_table) as usize] & OFFSET;
+        if table == 0 {
+            return Ok(Cluster::Zero);
+        }
+        if self.l2.as_ref().map(|(at, _)| *at) != Some(table) {
+            let mut data = vec![0; self.cluster_size() as usize];
+            read_at(file, table, &mut data).await?;
+            self.l2 = Some((table, data.chunks(8).map(|e| be64(e, 0)).collect()));
+        }
+        let entry = self.l2.as_ref().unwrap().1[(index % per_table) as usize];
+
+        if entry & COMPRESSED != 0 {
+            let bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << bits) - 1);
+            let sectors = ((entry >> bits) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            return Ok(Cluster::Compressed {
+                offset,
+                len: (sectors * 512 - (offset & 511)) as usize,
+                codec: self.codec,
+            });
+        }
+        match entry & OFFSET {
+            _ if entry & ZERO != 0 => Ok(Cluster::Zero),
+            0 => Ok(Cluster::Zero),
+            offset => Ok(Cluster::Data(offset)),
+        }
+    }
+}
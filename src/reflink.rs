@@ -0,0 +1,56 @@
+use {
+    nix::ioctl_write_ptr,
+    std::{
+        fs::File,
+        io,
+        os::unix::{fs::MetadataExt, io::AsRawFd},
+    },
+};
+
+// See linux/fs.h
+#[repr(C)]
+struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+}
+
+ioctl_write_ptr!(ficlonerange, 0x94, 13, FileCloneRange);
+
+/// A source file whose blocks can be shared with the target instead of
+/// being copied, on filesystems with reflinks like btrfs or XFS
+pub struct Reflink {
+    source: File,
+}
+
+/// Open the source for reflinking onto the target. Both have to be
+/// regular files on the same filesystem, otherwise the reason is returned.
+pub fn open(source: &str, target: &str) -> Result<Reflink, String> {
+    let source = File::open(source).map_err(|e| e.to_string())?;
+    let source_meta = source.metadata().map_err(|e| e.to_string())?;
+    let target_meta = std::fs::metadata(target).map_err(|e| e.to_string())?;
+    if !source_meta.is_file() || !target_meta.is_file() {
+        return Err("source and target have to be regular files".to_string());
+    }
+    if source_meta.dev() != target_meta.dev() {
+        return Err("source and target are on different filesystems".to_string());
+    }
+    Ok(Reflink { source })
+}
+
+impl Reflink {
+    /// Make `length` bytes of the target at `pos` share the source's
+    /// extents there. Ranges have to be aligned to the filesystem block
+    /// size, except for one reaching the end of the source.
+    pub fn clone_range(&self, target: &impl AsRawFd, pos: u64, length: usize) -> io::Result<()> {
+        let range = FileCloneRange {
+            src_fd: self.source.as_raw_fd() as i64,
+            src_offset: pos,
+            src_length: length as u64,
+            dest_offset: pos,
+        };
+        unsafe { ficlonerange(target.as_raw_fd(), &range) }?;
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+use {
+    crate::{
+        checkpoint::{self, Checkpoint, Stripe},
+        error::{self, Context, Error},
+        lock, nbd, remote, s3, source, Driver, SizeMismatch, Summary, SyncArgs,
+    },
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
+};
+
+// The first option given that needs the sync to go through in one piece,
+// it writes one file along the way or checks the source as a whole
+fn unsplittable_option(args: &SyncArgs) -> Option<&'static str> {
+    let whole_only = [
+        (args.loop_setup, "--loop-setup"),
+        (args.journal.is_some(), "--journal"),
+        (args.sparse_image_out.is_some(), "--sparse-image-out"),
+        (args.bitmap_out.is_some(), "--bitmap-out"),
+        (args.oci_out.is_some(), "--oci-out"),
+        (args.bmap.is_some(), "--bmap"),
+        (args.mapfile.is_some(), "--mapfile"),
+        (args.badblocks_out.is_some(), "--badblocks-out"),
+        (args.write_manifest.is_some(), "--write-manifest"),
+        (args.slow_log.is_some(), "--slow-log"),
+        (args.smart_report, "--smart-report"),
+        (args.verify_footer.is_some(), "--verify-footer"),
+        (args.expect_source_hash.is_some(), "--expect-source-hash"),
+        (
+            args.expect_source_manifest.is_some(),
+            "--expect-source-manifest",
+        ),
+        (args.control_socket.is_some(), "--control-socket"),
+        (args.metrics_listen.is_some(), "--metrics-listen"),
+    ];
+    whole_only
+        .iter()
+        .find(|(set, _)| *set)
+        .map(|(_, option)| *option)
+}
+
+// The sync of a region, which is a sync like any other. Its future is
+// named Send here, it can't be found out from within sync_from, which
+// syncs the regions.
+fn sync_region(
+    args: SyncArgs,
+    driver: Driver,
+) -> Pin<Box<dyn Future<Output = error::Result<Summary>> + Send>> {
+    Box::pin(async move { crate::sync_from(&args, false, &driver, None).await })
+}
+
+/// Sync with --jobs: the pair is split into as many regions, each synced
+/// on its own at the same time, on as many threads as the runtime has.
+/// The target is checked, locked and claimed once for all of them.
+pub async fn sync(args: &SyncArgs, whole: bool, driver: &Driver) -> error::Result<Summary> {
+    let started = Instant::now();
+    let target = args.target.as_ref().unwrap();
+    if remote::Remote::parse(target).is_some()
+        || target == "-"
+        || s3::Bucket::parse(target).is_some()
+        || nbd::Uri::parse(target).is_some()
+    {
+        return Err(Error::Usage(format!(
+            "Only a target that's here can be split into regions for --jobs, {} isn't",
+            target
+        )));
+    }
+    if let Some(option) = unsplittable_option(args) {
+        return Err(Error::Usage(format!("{} doesn't work with --jobs", option)));
+    }
+    if args.preflight {
+        crate::preflight::run(args, whole, driver).await;
+    }
+    crate::ensure_fd_limit(args.jobs as u64 * crate::fds_needed(args))?;
+
+    let target = crate::resolve_device(target)?;
+    crate::check_target_type(args, &target)?;
+    crate::check_target_unused(args, &target)?;
+    crate::check_not_source(args, &target)?;
+    let _lock = if args.dry_run {
+        None
+    } else {
+        lock::acquire(&target, args.wait_lock, driver).await?
+    };
+    let _claimed_target = if args.no_excl || args.dry_run {
+        None
+    } else {
+        crate::claim(&target, "Give --no-excl to open it anyway")?
+    };
+    let source = args.source.as_ref().unwrap();
+    let _claimed_source = if args.excl_source {
+        crate::claim(
+            &crate::resolve_device(source)?,
+            "Leave out --excl-source to read it anyway",
+        )?
+    } else {
+        None
+    };
+
+    // The sizes of both, sized up the way a sync in one piece does
+    let source_size = source::open(source, args.io_backend, args.direct)
+        .await?
+        .size()
+        .await
+        .ok_or_else(|| {
+            Error::Usage("A piped source can't be split into regions for --jobs".to_string())
+        })?;
+    let source_size = args.window(source_size, args.source_offset);
+    let target_size = match args.target_size {
+        Some(size) => size,
+        None => source::open_file(&target, args.io_backend, args.direct)
+            .await?
+            .size()
+            .await
+            .ok_or_else(|| Error::TargetIsPipe(target.clone()))?,
+    };
+    let target_size = args.window(target_size, args.target_offset);
+    driver.say(format_args!("{} -> {}", source_size, target_size));
+    let target_size = if source_size != target_size {
+        if args.windowed()
+            && matches!(
+                args.size_mismatch,
+                SizeMismatch::Truncate | SizeMismatch::Extend
+            )
+        {
+            return Err(Error::Usage(
+                "Only a whole target can be truncated or extended, not a part of it".to_string(),
+            ));
+        }
+        let written = !args.read_only();
+        let resized =
+            crate::resize_target(args, driver, &target, written, source_size, target_size)?;
+        if resized == target_size {
+            driver.say(format_args!(
+                "Sizes differ, only the first {} bytes are synced.",
+                std::cmp::min(source_size, target_size)
+            ));
+        }
+        resized
+    } else {
+        target_size
+    };
+    if whole && target_size < source_size {
+        return Err(Error::TargetTooSmall {
+            source_size,
+            target_size,
+        });
+    }
+
+    // Regions start on a block of both sides, the last one takes the rest
+    let sync_size = std::cmp::min(source_size, target_size);
+    let (source_read, target_read) = crate::read_sizes(args);
+    let align = (source_read / crate::gcd(source_read, target_read) * target_read) as u64;
+    let region_size =
+        std::cmp::max(sync_size.div_ceil(args.jobs as u64).div_ceil(align), 1) * align;
+    let regions: Vec<(u64, u64)> = (0..sync_size)
+        .step_by(region_size as usize)
+        .map(|start| (start, std::cmp::min(region_size, sync_size - start)))
+        .collect();
+    driver.say(format_args!(
+        "Syncing in {} regions of {} bytes.",
+        regions.len(),
+        region_size
+    ));
+
+    // Each region saves how far it got into its own stripe of the
+    // checkpoint, and is resumed from there
+    let parts = match &args.checkpoint {
+        Some(path) => {
+            let mut stripes: Vec<Stripe> = regions
+                .iter()
+                .map(|&(start, length)| Stripe {
+                    start,
+                    end: start + length,
+                    next: start,
+                })
+                .collect();
+            if args.resume {
+                let checkpoint = Checkpoint::load(path).context("load", path)?;
+                let layout: Vec<(u64, u64)> = stripes.iter().map(|s| (s.start, s.end)).collect();
+                let points = checkpoint::resume_points(&checkpoint, &layout)
+                    .map_err(|e| Error::Usage(format!("Can't resume from {}: {}", path, e)))?;
+                for (stripe, next) in stripes.iter_mut().zip(points) {
+                    stripe.next = next;
+                }
+                let done: u64 = stripes.iter().map(|s| s.next - s.start).sum();
+                driver.say(format_args!("Resuming with {} bytes done.", done));
+            }
+            let checkpoint = Checkpoint {
+                stripes,
+                ..Default::default()
+            };
+            checkpoint::parts(checkpoint, path)
+        }
+        None => Vec::new(),
+    };
+
+    let bar = ProgressBar::new(sync_size);
+    if !driver.bars {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]")
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+
+    // A region that fails stops the others, like an interrupt does. One
+    // that deviates from the target as the reference doesn't, the others
+    // have theirs to find.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let done: Arc<Vec<AtomicU64>> = Arc::new(regions.iter().map(|_| AtomicU64::new(0)).collect());
+    let jobs = regions.len() as u64;
+    let mut syncs = Vec::new();
+    for (i, (start, length)) in regions.iter().copied().enumerate() {
+        let args = SyncArgs {
+            jobs: 1,
+            source_offset: args.source_offset + start,
+            target_offset: args.target_offset + start,
+            length: Some(length),
+            target_size: args.target_size,
+            size_mismatch: SizeMismatch::SyncMin,
+            preflight: false,
+            limit_rate: args.limit_rate.map(|rate| std::cmp::max(rate / jobs, 1)),
+            limit_write_rate: args
+                .limit_write_rate
+                .map(|rate| std::cmp::max(rate / jobs, 1)),
+            checkpoint: None,
+            resume: false,
+            ..args.clone()
+        };
+        let done = done.clone();
+        let driver = Driver {
+            bars: false,
+            progress: Some(Box::new(move |pos, _| {
+                done[i].store(pos, Ordering::Relaxed)
+            })),
+            messages: driver.messages.clone(),
+            cancel: Some(cancel.clone()),
+            status: false,
+            region: true,
+            part: parts.get(i).cloned(),
+        };
+        let cancel = cancel.clone();
+        syncs.push(tokio::spawn(async move {
+            let summary = sync_region(args, driver).await;
+            if matches!(&summary, Err(e) if !matches!(e, Error::Deviates { .. })) {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            summary
+        }));
+    }
+
+    // How far all of them are, until each is done
+    loop {
+        if driver
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        let pos = done.iter().map(|d| d.load(Ordering::Relaxed)).sum();
+        bar.set_position(pos);
+        if let Some(progress) = &driver.progress {
+            progress(pos, sync_size);
+        }
+        if syncs.iter().all(|sync| sync.is_finished()) {
+            break;
+        }
+        tokio::time::sleep(crate::PROGRESS_INTERVAL).await;
+    }
+    bar.finish();
+    let mut results = Vec::new();
+    for sync in syncs {
+        results.push(sync.await.unwrap_or(Err(Error::Task("region"))));
+    }
+
+    // What went wrong in a region, before the others that it stopped
+    let mut summaries = Vec::new();
+    let mut cancelled = None;
+    let (mut deviating, mut compared) = (0, 0);
+    for result in results {
+        match result {
+            Ok(summary) => {
+                compared += summary.blocks;
+                summaries.push(summary);
+            }
+            Err(Error::Cancelled) => cancelled = Some(Error::Cancelled),
+            Err(Error::Deviates { diff, total }) => {
+                deviating += diff;
+                compared += total;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(e) = cancelled {
+        return Err(e);
+    }
+    if let Some(path) = &args.checkpoint {
+        let _ = std::fs::remove_file(path);
+    }
+    if deviating > 0 {
+        driver.say(format_args!(
+            "\nFinished {} regions. The source deviates from the target in {} of {} blocks.",
+            regions.len(),
+            deviating,
+            compared
+        ));
+        return Err(Error::Deviates {
+            diff: deviating,
+            total: compared,
+        });
+    }
+
+    let mut summary = Summary {
+        target,
+        blocks: 0,
+        different: 0,
+        written: 0,
+        source_size: Some(source_size),
+        target_size,
+        scanned: 0,
+        read: 0,
+        retries: 0,
+        unreadable: 0,
+        phases: vec![("sync", started.elapsed())],
+    };
+    for region in summaries.iter() {
+        summary.blocks += region.blocks;
+        summary.different += region.different;
+        summary.written += region.written;
+        summary.scanned += region.scanned;
+        summary.read += region.read;
+        summary.retries += region.retries;
+        summary.unreadable += region.unreadable;
+    }
+    driver.say(format_args!(
+        "\nFinished {} regions. Total: {}, different: {}, written: {} bytes",
+        summaries.len(),
+        summary.blocks,
+        summary.different,
+        summary.written
+    ));
+    Ok(summary)
+}
@@ -0,0 +1,932 @@
+use {
+    crate::{
+        error::{self, Context, Error},
+        hash::HashAlgo,
+        source::{self, BlockSource},
+        tls, Driver, IoBackend, SizeMismatch, Summary, SyncArgs,
+    },
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    std::{
+        collections::VecDeque,
+        io,
+        os::unix::fs::FileExt,
+        process::Stdio,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
+    tokio::{
+        fs::OpenOptions,
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+        net::{TcpListener, TcpStream},
+        process::Command,
+        sync::mpsc,
+    },
+    tokio_rustls::TlsAcceptor,
+};
+
+// A sync onto a target on another machine. The source is read here, the
+// target over there by an ssdsync serving it, and only the hashes of the
+// target's blocks and the source's differing blocks go in between. The
+// server is either run over SSH for the sync, talking on its stdin and
+// stdout, or listens on a TCP port for syncs to come.
+//
+// The conversation, little endian throughout:
+//
+//   sync:   MAGIC, block size u64, blocks per group u32, flags u8, and
+//           the name of the hash algorithm, its length u8 and the bytes
+//   server: OK and the target's size, or FAILED
+//   sync:   bytes to sync u64
+//   server: GROUP and the hash of each group of blocks of the target up
+//           to there, in order, while
+//   sync:   BLOCKS and the offset u64 of a group that differs
+//   server: BLOCKS, the offset u64, a count u32 and the hash of each
+//           block of that group, in between the groups
+//   sync:   WRITE, offset u64, length u32 and the bytes, for every block
+//           that differs, or COMPRESSED, offset u64, length u32,
+//           compressed length u32 and the zstd frame, then END
+//   server: the groups up to where it got, then OK and the bytes
+//           written, or FAILED
+//
+// So hashes of single blocks only go over for the groups that differ.
+// FAILED carries a message, its length u32 and the bytes, and may come
+// any time the server can't go on. The sync can send END any time, the
+// groups after it are skipped.
+const MAGIC: &[u8; 8] = b"SSDSYNC2";
+// The server holds a group at a time to hash it, so it takes no larger
+// ones than this
+const MAX_GROUP: u64 = 1 << 30;
+// Nor does the sync take longer messages from the server
+const MAX_MESSAGE: usize = 64 * 1024;
+const DRY_RUN: u8 = 1;
+const FSYNC: u8 = 2;
+
+const OK: u8 = b'K';
+const FAILED: u8 = b'F';
+const GROUP: u8 = b'G';
+const BLOCKS: u8 = b'B';
+const WRITE: u8 = b'W';
+const COMPRESSED: u8 = b'Z';
+const END: u8 = b'E';
+
+/// A target on another machine
+pub enum Remote {
+    /// user@host:PATH, served by an ssdsync run over SSH
+    Ssh { host: String, path: String },
+    /// tcp://HOST:PORT, where `ssdsync serve` listens
+    Tcp { addr: String },
+    /// tls://HOST:PORT, the same over TLS
+    Tls { addr: String },
+}
+
+impl Remote {
+    /// The remote target `spec` names, if it's one. An SSH host has to
+    /// come with a user, so nothing local is taken for one.
+    pub fn parse(spec: &str) -> Option<Remote> {
+        if let Some(addr) = spec.strip_prefix("tcp://") {
+            return Some(Remote::Tcp {
+                addr: addr.to_string(),
+            });
+        }
+        if let Some(addr) = spec.strip_prefix("tls://") {
+            return Some(Remote::Tls {
+                addr: addr.to_string(),
+            });
+        }
+        let (host, path) = spec.split_once(':')?;
+        match host.split_once('@') {
+            Some((user, name)) if !user.is_empty() && !name.is_empty() && !host.contains('/') => {
+                Some(Remote::Ssh {
+                    host: host.to_string(),
+                    path: path.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+// Quoted for the remote shell, which SSH hands the command to
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+async fn read_u64<R: AsyncRead + Unpin>(rx: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    rx.read_exact(&mut bytes).await?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+async fn read_u32<R: AsyncRead + Unpin>(rx: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    rx.read_exact(&mut bytes).await?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn ok(value: u64) -> Vec<u8> {
+    let mut frame = vec![OK];
+    frame.extend_from_slice(&value.to_le_bytes());
+    frame
+}
+
+fn failed(message: &str) -> Vec<u8> {
+    let message = &message.as_bytes()[..std::cmp::min(message.len(), MAX_MESSAGE)];
+    let mut frame = vec![FAILED];
+    frame.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+fn unexpected(tag: u8) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected message {:?}", tag as char),
+    )
+}
+
+// A count of things the other side sends, up to `max`
+async fn count<R: AsyncRead + Unpin>(rx: &mut R, max: usize, what: &str) -> io::Result<usize> {
+    match read_u32(rx).await? as usize {
+        n if n <= max => Ok(n),
+        n => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} {}, more than the {} there can be", n, what, max),
+        )),
+    }
+}
+
+// The answer after a tag of OK or FAILED
+async fn read_answer<R: AsyncRead + Unpin>(rx: &mut R, tag: u8) -> io::Result<Result<u64, String>> {
+    match tag {
+        OK => Ok(Ok(read_u64(rx).await?)),
+        FAILED => {
+            let mut message = vec![0; count(rx, MAX_MESSAGE, "bytes of a message").await?];
+            rx.read_exact(&mut message).await?;
+            Ok(Err(String::from_utf8_lossy(&message).into_owned()))
+        }
+        tag => Err(unexpected(tag)),
+    }
+}
+
+// What the server says, as the sync reads it
+enum Frame {
+    Group(Vec<u8>),
+    Blocks { offset: u64, digests: Vec<u8> },
+    Answer(Result<u64, String>),
+}
+
+// Hashes in it are `digest_len` bytes each, for up to the `group` blocks
+// of a group
+async fn read_frame<R: AsyncRead + Unpin>(
+    rx: &mut R,
+    digest_len: usize,
+    group: usize,
+) -> io::Result<Frame> {
+    let mut tag = [0];
+    rx.read_exact(&mut tag).await?;
+    match tag[0] {
+        GROUP => {
+            let mut digest = vec![0; digest_len];
+            rx.read_exact(&mut digest).await?;
+            Ok(Frame::Group(digest))
+        }
+        BLOCKS => {
+            let offset = read_u64(rx).await?;
+            let blocks = count(rx, group, "hashes of blocks").await?;
+            let mut digests = vec![0; blocks * digest_len];
+            rx.read_exact(&mut digests).await?;
+            Ok(Frame::Blocks { offset, digests })
+        }
+        tag => Ok(Frame::Answer(read_answer(rx, tag).await?)),
+    }
+}
+
+// A frame where another one was expected: the server gave up, or it
+// doesn't go by the protocol
+fn out_of_turn(frame: Frame) -> Error {
+    match frame {
+        Frame::Answer(Err(message)) => Error::Remote(message),
+        _ => Error::Remote("the server answered out of turn".to_string()),
+    }
+}
+
+// Everything the server says goes out through one queue, so groups and
+// the blocks asked for in between don't get mixed up. It never fills up,
+// the other side may be busy sending blocks instead of reading.
+type Frames = mpsc::UnboundedSender<Vec<u8>>;
+
+async fn send_frames<W: AsyncWrite + Unpin>(
+    mut frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    tx: W,
+) -> io::Result<()> {
+    let mut tx = BufWriter::new(tx);
+    while let Some(frame) = frames.recv().await {
+        tx.write_all(&frame).await?;
+        // Flushed once nothing more is waiting
+        while let Ok(frame) = frames.try_recv() {
+            tx.write_all(&frame).await?;
+        }
+        tx.flush().await?;
+    }
+    tx.flush().await
+}
+
+// Hash the target group by group up to `length` and send the hashes, or
+// until told to stop. A failed read is sent instead, the other side waits
+// for the hash.
+async fn send_groups(
+    mut target: Box<dyn BlockSource>,
+    algo: HashAlgo,
+    group_size: usize,
+    length: u64,
+    stop: Arc<AtomicBool>,
+    frames: Frames,
+) -> error::Result<()> {
+    let mut buf = vec![0; group_size];
+    let mut pos = 0;
+    while pos < length && !stop.load(Ordering::Relaxed) {
+        let n = std::cmp::min(group_size as u64, length - pos) as usize;
+        let n = match target.read(&mut buf[..n]).await {
+            Ok(n) => n,
+            Err(source) => {
+                let e = Error::Read {
+                    side: "target",
+                    offset: pos,
+                    source,
+                };
+                let _ = frames.send(failed(&e.to_string()));
+                return Err(e);
+            }
+        };
+        if n == 0 {
+            break;
+        }
+        let mut frame = vec![GROUP];
+        frame.extend_from_slice(&algo.digest(&buf[..n]));
+        if frames.send(frame).is_err() {
+            break;
+        }
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+// The hashes of each block of the group at `offset`, up to `length`
+fn hash_blocks(
+    file: &std::fs::File,
+    algo: HashAlgo,
+    offset: u64,
+    block_size: usize,
+    group_size: usize,
+    length: u64,
+) -> io::Result<Vec<u8>> {
+    let mut data =
+        vec![0; std::cmp::min(group_size as u64, length.saturating_sub(offset)) as usize];
+    file.read_exact_at(&mut data, offset)?;
+    let blocks = data.chunks(block_size);
+    let mut frame = vec![BLOCKS];
+    frame.extend_from_slice(&offset.to_le_bytes());
+    frame.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+    for block in blocks {
+        frame.extend_from_slice(&algo.digest(block));
+    }
+    Ok(frame)
+}
+
+/// Serve a sync onto `target` over a connection: hash its blocks for the
+/// other side and write the ones it sends. What goes wrong with the
+/// target is reported to the other side too.
+pub async fn serve<R, W>(rx: R, tx: W, target: &str) -> error::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut rx = BufReader::new(rx);
+    let talk = |e| Error::Io {
+        action: "talk to the sync of",
+        path: target.to_string(),
+        source: e,
+    };
+    let (frames, queued) = mpsc::unbounded_channel();
+    let sender = tokio::spawn(send_frames(queued, tx));
+
+    let mut magic = [0; 8];
+    rx.read_exact(&mut magic).await.map_err(talk)?;
+    if &magic != MAGIC {
+        return Err(Error::Usage(
+            "The other side doesn't speak the ssdsync protocol".to_string(),
+        ));
+    }
+    let block_size = read_u64(&mut rx).await.map_err(talk)?;
+    let blocks = read_u32(&mut rx).await.map_err(talk)?;
+    let mut flags = [0];
+    rx.read_exact(&mut flags).await.map_err(talk)?;
+    let (dry_run, fsync) = (flags[0] & DRY_RUN != 0, flags[0] & FSYNC != 0);
+    let mut name = [0];
+    rx.read_exact(&mut name).await.map_err(talk)?;
+    let mut name = vec![0; name[0] as usize];
+    rx.read_exact(&mut name).await.map_err(talk)?;
+    let group_size = match block_size.checked_mul(blocks as u64) {
+        Some(size) if block_size > 0 && blocks > 0 && size <= MAX_GROUP => Ok(size as usize),
+        _ => Err(format!(
+            "Not serving groups of {} blocks of {} bytes, they have to be up to {} bytes",
+            blocks, block_size, MAX_GROUP
+        )),
+    };
+    let asked = String::from_utf8_lossy(&name)
+        .parse::<HashAlgo>()
+        .and_then(|algo| group_size.map(|group_size| (algo, group_size)));
+    let (algo, group_size) = match asked {
+        Ok(asked) => asked,
+        Err(message) => {
+            let _ = frames.send(failed(&message));
+            drop(frames);
+            let _ = sender.await;
+            return Err(Error::Usage(message));
+        }
+    };
+
+    let block_size = block_size as usize;
+
+    let opened = async {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!dry_run)
+            .open(target)
+            .await
+            .context("open", target)?;
+        let size = crate::get_size(&file)
+            .await
+            .context("open", target)?
+            .ok_or_else(|| Error::TargetIsPipe(target.to_string()))?;
+        let reader = source::open_file(target, IoBackend::Tokio, false).await?;
+        Ok::<_, Error>((file.into_std().await, size, reader))
+    };
+    let (file, size, reader) = match opened.await {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = frames.send(failed(&e.to_string()));
+            drop(frames);
+            let _ = sender.await;
+            return Err(e);
+        }
+    };
+    let _ = frames.send(ok(size));
+    let length = std::cmp::min(read_u64(&mut rx).await.map_err(talk)?, size);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let groups = tokio::spawn(send_groups(
+        reader,
+        algo,
+        group_size,
+        length,
+        stop.clone(),
+        frames.clone(),
+    ));
+
+    // Blocks are only sent after their hashes, which were read already.
+    // After a failure the rest is taken in, but nothing is done with it.
+    // Nothing else waits on this side, reads and writes may block.
+    let mut written = 0;
+    let mut failure: Option<Error> = None;
+    let mut announced = false;
+    let mut hung_up = None;
+    let mut data = Vec::new();
+    let mut packed = Vec::new();
+    loop {
+        let mut tag = [0];
+        if let Err(e) = rx.read_exact(&mut tag).await {
+            hung_up = Some(e);
+            break;
+        }
+        match tag[0] {
+            BLOCKS => {
+                let offset = read_u64(&mut rx).await.map_err(talk)?;
+                if failure.is_some() {
+                    continue;
+                }
+                match hash_blocks(&file, algo, offset, block_size, group_size, length) {
+                    Ok(frame) => {
+                        let _ = frames.send(frame);
+                    }
+                    // The other side waits for these, it has to know now
+                    Err(source) => {
+                        let e = Error::Read {
+                            side: "target",
+                            offset,
+                            source,
+                        };
+                        let _ = frames.send(failed(&e.to_string()));
+                        announced = true;
+                        failure = Some(e);
+                    }
+                }
+            }
+            tag @ (WRITE | COMPRESSED) => {
+                let offset = read_u64(&mut rx).await.map_err(talk)?;
+                data.resize(
+                    count(&mut rx, block_size, "bytes of a block")
+                        .await
+                        .map_err(talk)?,
+                    0,
+                );
+                let unpacked = if tag == COMPRESSED {
+                    packed.resize(
+                        count(&mut rx, block_size, "bytes of a block")
+                            .await
+                            .map_err(talk)?,
+                        0,
+                    );
+                    rx.read_exact(&mut packed).await.map_err(talk)?;
+                    zstd::bulk::decompress_to_buffer(&packed, &mut data[..]).and_then(|n| {
+                        match n == data.len() {
+                            true => Ok(()),
+                            false => Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "short compressed block",
+                            )),
+                        }
+                    })
+                } else {
+                    rx.read_exact(&mut data).await.map(|_| ()).map_err(talk)?;
+                    Ok(())
+                };
+                if failure.is_some() || dry_run {
+                    continue;
+                }
+                if let Err(source) = unpacked {
+                    failure = Some(Error::Write { offset, source });
+                    continue;
+                }
+                if offset
+                    .checked_add(data.len() as u64)
+                    .is_none_or(|end| end > size)
+                {
+                    failure = Some(Error::Usage(format!(
+                        "Not writing past the end of the target at {}",
+                        size
+                    )));
+                    continue;
+                }
+                match file.write_all_at(&data, offset) {
+                    Ok(()) => written += data.len() as u64,
+                    Err(source) => failure = Some(Error::Write { offset, source }),
+                }
+            }
+            END => break,
+            tag => return Err(talk(unexpected(tag))),
+        }
+    }
+    stop.store(true, Ordering::Relaxed);
+    match groups.await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => {
+            failure = failure.or(Some(e));
+            announced = true;
+        }
+        Err(_) => return Err(Error::Task("hasher")),
+    }
+    // Unless it was told why, the other side shouldn't have gone
+    match (hung_up, announced) {
+        (Some(e), false) => return Err(talk(e)),
+        (Some(_), true) => return Err(failure.unwrap()),
+        (None, _) => (),
+    }
+    if failure.is_none() && fsync && written > 0 {
+        if let Err(e) = file.sync_all().io_context("flush", target) {
+            failure = Some(e);
+        }
+    }
+    let result = match failure {
+        Some(e) => {
+            if !announced {
+                let _ = frames.send(failed(&e.to_string()));
+            }
+            Err(e)
+        }
+        None => {
+            let _ = frames.send(ok(written));
+            Ok(())
+        }
+    };
+    drop(frames);
+    match sender.await {
+        Ok(Ok(())) => result,
+        Ok(Err(e)) => Err(talk(e)),
+        Err(_) => Err(Error::Task("sender")),
+    }
+}
+
+/// Serve syncs onto `target` to whoever connects to `addr`, one at a
+/// time, over TLS if there's an acceptor. A failed sync is reported and
+/// the next one waited for, unless `once` says to stop after the first.
+pub async fn listen(
+    addr: &str,
+    target: &str,
+    once: bool,
+    tls: Option<TlsAcceptor>,
+) -> error::Result<()> {
+    let listener = TcpListener::bind(addr).await.context("listen on", addr)?;
+    println!(
+        "Serving {} on {}",
+        target,
+        listener
+            .local_addr()
+            .map_or(addr.to_string(), |a| a.to_string())
+    );
+    loop {
+        let (stream, peer) = listener.accept().await.context("listen on", addr)?;
+        let served = match &tls {
+            Some(acceptor) => match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let (rx, tx) = tokio::io::split(stream);
+                    serve(rx, tx, target).await
+                }
+                Err(source) => Err(Error::File {
+                    action: "shake hands with",
+                    path: peer.to_string(),
+                    source,
+                }),
+            },
+            None => {
+                let (rx, tx) = stream.into_split();
+                serve(rx, tx, target).await
+            }
+        };
+        match &served {
+            Ok(()) => println!("Synced from {}.", peer),
+            Err(e) => println!("Sync from {} failed: {}", peer, e),
+        }
+        if once {
+            return served;
+        }
+    }
+}
+
+/// Sync onto a target on another machine, by running `ssdsync --server`
+/// there over SSH or connecting to `ssdsync serve`. The source is read
+/// here and compared by its hashes.
+pub async fn sync(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+    remote: Remote,
+) -> error::Result<Summary> {
+    let spec = args.target.clone().unwrap();
+    check_options(args)?;
+
+    let tls_options = args.tls_ca.is_some() || args.tls_cert.is_some();
+    let (host, path) = match remote {
+        Remote::Tls { addr } => {
+            let ca = args.tls_ca.as_ref().ok_or_else(|| {
+                Error::Usage("A tls:// target needs --tls-ca to check the server with".to_string())
+            })?;
+            let connector = tls::connector(ca, args.tls_cert.as_deref(), args.tls_key.as_deref())?;
+            let name = tls::server_name(&addr).context("connect to", &spec)?;
+            let stream = TcpStream::connect(&addr)
+                .await
+                .context("connect to", &spec)?;
+            let stream = connector
+                .connect(name, stream)
+                .await
+                .context("shake hands with", &spec)?;
+            let (rx, tx) = tokio::io::split(stream);
+            return exchange(args, whole, driver, source, rx, tx, &spec).await;
+        }
+        _ if tls_options => {
+            return Err(Error::Usage(
+                "The TLS options are only for tls:// targets".to_string(),
+            ))
+        }
+        Remote::Ssh { host, path } => (host, path),
+        Remote::Tcp { addr } => {
+            let stream = TcpStream::connect(&addr)
+                .await
+                .context("connect to", &spec)?;
+            let (rx, tx) = stream.into_split();
+            return exchange(args, whole, driver, source, rx, tx, &spec).await;
+        }
+    };
+    let mut ssh = args.ssh.split_whitespace();
+    let mut child = Command::new(ssh.next().unwrap_or("ssh"))
+        .args(ssh)
+        .arg(&host)
+        .arg(format!("{} --server {}", args.remote_ssdsync, quote(&path)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("run ssh to reach", &spec)?;
+    let rx = child.stdout.take().unwrap();
+    let tx = child.stdin.take().unwrap();
+
+    let summary = exchange(args, whole, driver, source, rx, tx, &spec).await;
+    let _ = child.wait().await;
+    summary
+}
+
+// Whatever works on the target itself can't be done from here
+fn check_options(args: &SyncArgs) -> error::Result<()> {
+    match crate::local_option(args) {
+        Some(option) => Err(Error::Usage(format!(
+            "{} doesn't work with a remote target",
+            option
+        ))),
+        None => Ok(()),
+    }
+}
+
+// Send a block to write, compressed if that makes it smaller. Returns
+// the bytes it took.
+async fn send_block<W: AsyncWrite + Unpin>(
+    tx: &mut W,
+    compressor: Option<&mut zstd::bulk::Compressor<'static>>,
+    offset: u64,
+    block: &[u8],
+) -> io::Result<usize> {
+    let packed = match compressor {
+        Some(compressor) => Some(compressor.compress(block)?),
+        None => None,
+    };
+    match packed.filter(|p| p.len() < block.len()) {
+        Some(packed) => {
+            tx.write_all(&[COMPRESSED]).await?;
+            tx.write_all(&offset.to_le_bytes()).await?;
+            tx.write_all(&(block.len() as u32).to_le_bytes()).await?;
+            tx.write_all(&(packed.len() as u32).to_le_bytes()).await?;
+            tx.write_all(&packed).await?;
+            Ok(packed.len())
+        }
+        None => {
+            tx.write_all(&[WRITE]).await?;
+            tx.write_all(&offset.to_le_bytes()).await?;
+            tx.write_all(&(block.len() as u32).to_le_bytes()).await?;
+            tx.write_all(block).await?;
+            Ok(block.len())
+        }
+    }
+}
+
+// Run the sync side of the conversation
+async fn exchange<R, W>(
+    args: &SyncArgs,
+    whole: bool,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+    rx: R,
+    tx: W,
+    spec: &str,
+) -> error::Result<Summary>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let mut rx = BufReader::new(rx);
+    let mut tx = BufWriter::new(tx);
+    let talk = |e| Error::Io {
+        action: "talk to the server of",
+        path: spec.to_string(),
+        source: e,
+    };
+
+    let mut source_r = match source {
+        Some(source) => source,
+        None => source::open(args.source.as_ref().unwrap(), args.io_backend, false).await?,
+    };
+    let block_size = crate::read_sizes(args).0;
+
+    let flags = match (args.dry_run, args.fsync) {
+        (true, _) => DRY_RUN,
+        (false, true) => FSYNC,
+        (false, false) => 0,
+    };
+    tx.write_all(MAGIC).await.map_err(talk)?;
+    tx.write_all(&(block_size as u64).to_le_bytes())
+        .await
+        .map_err(talk)?;
+    tx.write_all(&(args.remote_group as u32).to_le_bytes())
+        .await
+        .map_err(talk)?;
+    tx.write_all(&[flags]).await.map_err(talk)?;
+    let algo = args.hash;
+    tx.write_all(&[algo.name().len() as u8])
+        .await
+        .map_err(talk)?;
+    tx.write_all(algo.name().as_bytes()).await.map_err(talk)?;
+    tx.flush().await.map_err(talk)?;
+    let digest_len = algo.digest_len();
+    let target_size = match read_frame(&mut rx, digest_len, args.remote_group)
+        .await
+        .map_err(talk)?
+    {
+        Frame::Answer(answer) => answer.map_err(Error::Remote)?,
+        frame => return Err(out_of_turn(frame)),
+    };
+
+    let source_size = source_r.size().await;
+    match source_size {
+        Some(source_size) => driver.say(format_args!("{} -> {}", source_size, target_size)),
+        None => driver.say(format_args!("? -> {}", target_size)),
+    }
+    if let Some(source_size) = source_size.filter(|s| *s != target_size) {
+        if whole && target_size < source_size {
+            return Err(Error::TargetTooSmall {
+                source_size,
+                target_size,
+            });
+        }
+        match args.size_mismatch {
+            SizeMismatch::SyncMin => driver.say(format_args!(
+                "Sizes differ, only the first {} bytes are synced.",
+                std::cmp::min(source_size, target_size)
+            )),
+            SizeMismatch::Error => {
+                return Err(Error::SizeMismatch {
+                    source_size,
+                    target_size,
+                })
+            }
+            SizeMismatch::Truncate | SizeMismatch::Extend => {
+                return Err(Error::Usage(
+                    "A remote target can't be truncated or extended".to_string(),
+                ))
+            }
+        }
+    }
+    let sync_size = source_size.map_or(target_size, |s| std::cmp::min(s, target_size));
+    tx.write_all(&sync_size.to_le_bytes()).await.map_err(talk)?;
+    tx.flush().await.map_err(talk)?;
+
+    let bar = match source_size {
+        Some(_) => ProgressBar::new(sync_size),
+        None => ProgressBar::new_spinner(),
+    };
+    if !driver.bars {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]")
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+
+    let mut total = 0;
+    let mut diff = 0;
+    let mut diff_bytes = 0;
+    let mut pos = 0;
+    let mut cancelled = false;
+    let group_size = block_size * args.remote_group;
+    let mut buf = vec![0; group_size];
+    // Hashes of groups that came in while waiting for those of blocks
+    let mut groups = VecDeque::new();
+    let mut hash_bytes = 0;
+    let mut sent = 0;
+    let mut compressor = match args.compress {
+        Some(level) => Some(zstd::bulk::Compressor::new(level).map_err(talk)?),
+        None => None,
+    };
+    let scanning = Instant::now();
+    while pos < sync_size {
+        if driver
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            cancelled = true;
+            break;
+        }
+        let want = std::cmp::min(group_size as u64, sync_size - pos) as usize;
+        let n = source_r
+            .read(&mut buf[..want])
+            .await
+            .map_err(|source| Error::Read {
+                side: "source",
+                offset: pos,
+                source,
+            })?;
+        if n == 0 {
+            break;
+        }
+        let group = match groups.pop_front() {
+            Some(group) => group,
+            None => match read_frame(&mut rx, digest_len, args.remote_group)
+                .await
+                .map_err(talk)?
+            {
+                Frame::Group(digest) => digest,
+                frame => return Err(out_of_turn(frame)),
+            },
+        };
+        hash_bytes += digest_len;
+        total += n.div_ceil(block_size) as u64;
+
+        // Only a group that differs is looked at block by block
+        if algo.digest(&buf[..n]) != group {
+            let digests = if n <= block_size {
+                group
+            } else {
+                tx.write_all(&[BLOCKS]).await.map_err(talk)?;
+                tx.write_all(&pos.to_le_bytes()).await.map_err(talk)?;
+                tx.flush().await.map_err(talk)?;
+                loop {
+                    match read_frame(&mut rx, digest_len, args.remote_group)
+                        .await
+                        .map_err(talk)?
+                    {
+                        Frame::Group(digest) => groups.push_back(digest),
+                        Frame::Blocks { offset, digests } if offset == pos => break digests,
+                        frame => return Err(out_of_turn(frame)),
+                    }
+                }
+            };
+            if n > block_size {
+                hash_bytes += digests.len();
+            }
+            for (i, block) in buf[..n].chunks(block_size).enumerate() {
+                let digest = digests.get(i * digest_len..(i + 1) * digest_len);
+                if digest == Some(&algo.digest(block)) {
+                    continue;
+                }
+                if !args.dry_run {
+                    let offset = pos + (i * block_size) as u64;
+                    sent += send_block(&mut tx, compressor.as_mut(), offset, block)
+                        .await
+                        .map_err(talk)? as u64;
+                }
+                diff += 1;
+                diff_bytes += block.len() as u64;
+            }
+        }
+        pos += n as u64;
+        bar.set_position(pos);
+        if let Some(progress) = &driver.progress {
+            progress(pos, sync_size);
+        }
+    }
+
+    // Whatever hashes are still on their way are of no use now
+    tx.write_all(&[END]).await.map_err(talk)?;
+    tx.flush().await.map_err(talk)?;
+    let written = loop {
+        if let Frame::Answer(answer) = read_frame(&mut rx, digest_len, args.remote_group)
+            .await
+            .map_err(talk)?
+        {
+            break answer.map_err(Error::Remote)?;
+        }
+    };
+    let finishing = Instant::now();
+    bar.finish();
+
+    if cancelled {
+        driver.say(format_args!(
+            "\nStopped at {}. Total: {}, different: {}, written: {} bytes",
+            args.report_units.at(pos, block_size),
+            total,
+            diff,
+            written
+        ));
+        return Err(Error::Cancelled);
+    }
+    if args.dry_run {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, would write: {} bytes",
+            total, diff, diff_bytes
+        ));
+    } else {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, written: {} bytes",
+            total, diff, written
+        ));
+    }
+    driver.say(format_args!("Hashes received: {} bytes.", hash_bytes));
+    if args.compress.is_some() && diff_bytes > 0 && !args.dry_run {
+        driver.say(format_args!(
+            "Blocks sent: {} bytes, compressed to {}.",
+            diff_bytes, sent
+        ));
+    }
+    Ok(Summary {
+        target: spec.to_string(),
+        blocks: total,
+        different: diff,
+        written,
+        source_size,
+        target_size,
+        scanned: pos,
+        // The target is read on the other side
+        read: pos,
+        retries: 0,
+        unreadable: 0,
+        phases: vec![
+            ("prepare", scanning - started),
+            ("sync", finishing - scanning),
+            ("finish", finishing.elapsed()),
+        ],
+    })
+}
@@ -0,0 +1,474 @@
+use {
+    crate::{
+        error::{self, Error},
+        hash,
+        manifest::{self, Manifest},
+        source::{self, BlockSource},
+        Driver, Summary, SyncArgs, PROGRESS_INTERVAL,
+    },
+    hmac::{Hmac, Mac},
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::HashSet,
+        io,
+        sync::{atomic::Ordering, Arc},
+        time::{Instant, SystemTime, UNIX_EPOCH},
+    },
+    tokio::task::JoinSet,
+};
+
+// A backup of the source in a bucket of S3, or of a service that speaks
+// its API. Every block is an object named by its hash, and an index, a
+// manifest of the blocks in order, tells where each one goes:
+//
+//   PREFIX/index            # ssdsync manifest blake3 1048576
+//                           0 1048576 9f86d081884c7d659a2feaa0c55ad015...
+//   PREFIX/blocks/9f86d0... the block itself
+//
+// A sync onto a prefix that has an index only stores the blocks it
+// doesn't list yet, the others are shared with the backups before. Blocks
+// are never overwritten, so an older index still has all of its blocks,
+// copy it away to keep that backup.
+
+/// s3://BUCKET/PREFIX
+pub struct Bucket {
+    name: String,
+    prefix: String,
+}
+
+impl Bucket {
+    /// The bucket `spec` names, if it's one
+    pub fn parse(spec: &str) -> Option<Bucket> {
+        let rest = spec.strip_prefix("s3://")?;
+        let (name, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        Some(Bucket {
+            name: name.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        })
+    }
+
+    // The key of an object under the prefix
+    fn key(&self, name: &str) -> String {
+        match self.prefix.as_str() {
+            "" => name.to_string(),
+            prefix => format!("{}/{}", prefix, name),
+        }
+    }
+}
+
+// Signing requests needs a key, without one they go out unsigned
+struct Credentials {
+    key_id: String,
+    secret: String,
+    token: Option<String>,
+}
+
+struct Client {
+    http: reqwest::Client,
+    // Where the service is, with the scheme and without a path
+    endpoint: String,
+    host: String,
+    region: String,
+    credentials: Option<Credentials>,
+}
+
+// Percent-encoded the way the signature needs it, slashes kept
+fn encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            b => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hash::to_hex(&Sha256::digest(data))
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The time as the signature has it, e.g. 20130524T000000Z, and its day
+fn amz_date(now: SystemTime) -> (String, String) {
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // The civil date of a day count, after Howard Hinnant's algorithm
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let time = secs % 86400;
+    let stamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    );
+    (stamp, date)
+}
+
+fn failed(status: reqwest::StatusCode, url: &str) -> io::Error {
+    io::Error::other(format!("{} answered {}", url, status))
+}
+
+impl Client {
+    // Credentials, the region and the endpoint as the AWS tools take
+    // them from the environment, unless the endpoint is given
+    fn from_env(endpoint: Option<&str>) -> Self {
+        let var = |name| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = endpoint
+            .map(str::to_string)
+            .or_else(|| var("AWS_ENDPOINT_URL"))
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let host = endpoint
+            .split_once("://")
+            .map_or(endpoint.as_str(), |(_, rest)| rest)
+            .to_string();
+        let credentials = match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(key_id), Some(secret)) => Some(Credentials {
+                key_id,
+                secret,
+                token: var("AWS_SESSION_TOKEN"),
+            }),
+            _ => None,
+        };
+        Client {
+            http: reqwest::Client::new(),
+            endpoint,
+            host,
+            region,
+            credentials,
+        }
+    }
+
+    // A request for an object, addressed by path so any bucket name works
+    fn request(
+        &self,
+        method: reqwest::Method,
+        bucket: &str,
+        key: &str,
+        body: Vec<u8>,
+    ) -> (String, reqwest::RequestBuilder) {
+        let path = encode(&format!("/{}/{}", bucket, key));
+        let url = format!("{}{}", self.endpoint, path);
+        let payload = sha256_hex(&body);
+        let (stamp, date) = amz_date(SystemTime::now());
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", payload.clone()),
+            ("x-amz-date", stamp.clone()),
+        ];
+        if let Some(token) = self.credentials.as_ref().and_then(|c| c.token.clone()) {
+            headers.push(("x-amz-security-token", token));
+        }
+
+        let mut request = self.http.request(method.clone(), &url);
+        if let Some(credentials) = &self.credentials {
+            // AWS signature version 4
+            let signed = headers
+                .iter()
+                .map(|(name, _)| *name)
+                .collect::<Vec<_>>()
+                .join(";");
+            let canonical = format!(
+                "{}\n{}\n\n{}\n{}\n{}",
+                method,
+                path,
+                headers
+                    .iter()
+                    .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+                    .collect::<String>(),
+                signed,
+                payload
+            );
+            let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+            let to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                stamp,
+                scope,
+                sha256_hex(canonical.as_bytes())
+            );
+            let key = [date.as_str(), &self.region, "s3", "aws4_request"]
+                .iter()
+                .fold(
+                    format!("AWS4{}", credentials.secret).into_bytes(),
+                    |key, part| hmac(&key, part),
+                );
+            request = request.header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    credentials.key_id,
+                    scope,
+                    signed,
+                    hash::to_hex(&hmac(&key, &to_sign))
+                ),
+            );
+        }
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        (url, request.body(body))
+    }
+
+    // The object, None if there's none
+    async fn get(&self, bucket: &str, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let (url, request) = self.request(reqwest::Method::GET, bucket, key, Vec::new());
+        let response = request.send().await.map_err(io::Error::other)?;
+        match response.status() {
+            reqwest::StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let body = response.bytes().await.map_err(io::Error::other)?;
+                Ok(Some(body.to_vec()))
+            }
+            status => Err(failed(status, &url)),
+        }
+    }
+
+    async fn put(&self, bucket: &str, key: &str, body: Vec<u8>) -> io::Result<()> {
+        let (url, request) = self.request(reqwest::Method::PUT, bucket, key, body);
+        let response = request.send().await.map_err(io::Error::other)?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(failed(status, &url)),
+        }
+    }
+}
+
+/// Back the source up into a bucket: store the blocks the index there
+/// doesn't have, then the new index
+pub async fn sync(
+    args: &SyncArgs,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+    bucket: Bucket,
+) -> error::Result<Summary> {
+    let started = Instant::now();
+    let spec = args.target.clone().unwrap();
+    if let Some(option) = crate::local_option(args) {
+        return Err(Error::Usage(format!(
+            "{} doesn't work with an s3:// target",
+            option
+        )));
+    }
+    let mut source_r = match source {
+        Some(source) => source,
+        None => source::open(args.source.as_ref().unwrap(), args.io_backend, false).await?,
+    };
+    let source_size = source_r.size().await;
+    let block_size = crate::read_sizes(args).0;
+    let client = Arc::new(Client::from_env(args.s3_endpoint.as_deref()));
+
+    // Blocks of another size or hash can't be shared
+    let index_key = bucket.key("index");
+    let index = client
+        .get(&bucket.name, &index_key)
+        .await
+        .map_err(|source| Error::File {
+            action: "load",
+            path: format!("{}/index", spec),
+            source,
+        })?;
+    let index = match index {
+        Some(index) => {
+            Some(
+                Manifest::parse(&spec, index.as_slice()).map_err(|source| Error::File {
+                    action: "load",
+                    path: format!("{}/index", spec),
+                    source,
+                })?,
+            )
+        }
+        None => None,
+    };
+    let index = match index {
+        Some(index) if index.algo == args.hash && index.block_size == block_size => Some(index),
+        Some(_) => {
+            driver.say("The index has another hash or block size, every block is stored anew.");
+            None
+        }
+        None => None,
+    };
+    let mut stored: HashSet<Vec<u8>> = index
+        .iter()
+        .flat_map(|index| index.hashes())
+        .map(|hash| hash.to_vec())
+        .collect();
+    match source_size {
+        Some(size) => driver.say(format_args!("{} -> {}", size, spec)),
+        None => driver.say(format_args!("? -> {}", spec)),
+    }
+
+    let bar = match source_size {
+        Some(size) => ProgressBar::new(size),
+        None => ProgressBar::new_spinner(),
+    };
+    if !driver.bars {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{wide_bar} [{percent:>3}% {bytes_per_sec} ETA: {eta_precise}]")
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+
+    let mut new_index = format!("{} {} {}\n", manifest::HEADER, args.hash.name(), block_size);
+    let mut buf = vec![0; block_size];
+    let mut pos = 0;
+    let mut total = 0;
+    let mut diff = 0;
+    let mut written = 0;
+    let mut objects = 0;
+    let mut cancelled = false;
+    let mut last_progress = Instant::now();
+    // As many uploads on their way as there are buffers
+    let mut uploads = JoinSet::new();
+    let scanning = Instant::now();
+    loop {
+        if driver
+            .cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+        {
+            cancelled = true;
+            break;
+        }
+        let n = source_r
+            .read(&mut buf)
+            .await
+            .map_err(|source| Error::Read {
+                side: "source",
+                offset: pos,
+                source,
+            })?;
+        if n == 0 {
+            break;
+        }
+        let block = &buf[..n];
+        let digest = args.hash.digest(block);
+        let hex = hash::to_hex(&digest);
+        new_index.push_str(&format!("{} {} {}\n", pos, n, hex));
+        total += 1;
+
+        let same = index
+            .as_ref()
+            .and_then(|index| index.get(pos))
+            .is_some_and(|entry| entry.length == n && entry.hash == digest);
+        if !same {
+            diff += 1;
+        }
+        if !stored.contains(&digest) && !args.dry_run {
+            while uploads.len() >= args.buffers {
+                finish_upload(&mut uploads).await?;
+            }
+            let client = client.clone();
+            let (name, key, data) = (
+                bucket.name.clone(),
+                bucket.key(&format!("blocks/{}", hex)),
+                block.to_vec(),
+            );
+            uploads.spawn(async move {
+                let result = client.put(&name, &key, data).await;
+                (pos, result)
+            });
+            stored.insert(digest);
+            written += n as u64;
+            objects += 1;
+        }
+
+        pos += n as u64;
+        bar.set_position(pos);
+        if let Some(progress) = &driver.progress {
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                progress(pos, source_size.unwrap_or(pos));
+                last_progress = Instant::now();
+            }
+        }
+    }
+    while !uploads.is_empty() {
+        finish_upload(&mut uploads).await?;
+    }
+    let finishing = Instant::now();
+    bar.finish();
+    if let Some(progress) = &driver.progress {
+        progress(pos, source_size.unwrap_or(pos));
+    }
+
+    // The index before stays as long as not all blocks are stored
+    if cancelled {
+        driver.say(format_args!(
+            "\nStopped at {}. Total: {}, different: {}, stored: {} bytes",
+            pos, total, diff, written
+        ));
+        return Err(Error::Cancelled);
+    }
+    if !args.dry_run {
+        client
+            .put(&bucket.name, &index_key, new_index.into_bytes())
+            .await
+            .map_err(|source| Error::Io {
+                action: "store the index in",
+                path: spec.clone(),
+                source,
+            })?;
+    }
+
+    if args.dry_run {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, would store: {} bytes",
+            total, diff, written
+        ));
+    } else {
+        driver.say(format_args!(
+            "\nFinished. Total: {}, different: {}, stored: {} bytes in {} objects",
+            total, diff, written, objects
+        ));
+    }
+
+    Ok(Summary {
+        target: spec,
+        blocks: total,
+        different: diff,
+        written,
+        source_size,
+        target_size: pos,
+        scanned: pos,
+        read: pos,
+        retries: 0,
+        unreadable: 0,
+        phases: vec![
+            ("prepare", scanning - started),
+            ("sync", finishing - scanning),
+            ("finish", finishing.elapsed()),
+        ],
+    })
+}
+
+// Wait for the next upload to be done, a failed one ends the sync
+async fn finish_upload(uploads: &mut JoinSet<(u64, io::Result<()>)>) -> error::Result<()> {
+    match uploads.join_next().await {
+        Some(Ok((_, Ok(())))) | None => Ok(()),
+        Some(Ok((offset, Err(source)))) => Err(Error::Write { offset, source }),
+        Some(Err(e)) => Err(crate::task_failed("upload", e)),
+    }
+}
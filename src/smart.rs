@@ -0,0 +1,216 @@
+use {
+    nix::{ioctl_readwrite, ioctl_readwrite_bad},
+    std::{fs::File, io, os::unix::io::AsRawFd},
+};
+
+// Wear related counters of a target, read before and after a sync. They
+// come from the NVMe SMART log or the ATA SMART attributes, whichever the
+// device answers, and the kernel's own count of sectors written.
+
+/// One counter, its name says the unit
+pub struct Attribute {
+    pub name: &'static str,
+    pub value: u64,
+}
+
+// See linux/nvme_ioctl.h
+#[repr(C)]
+#[derive(Default)]
+struct NvmeAdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+ioctl_readwrite!(nvme_admin_cmd, b'N', 0x41, NvmeAdminCmd);
+
+const NVME_GET_LOG_PAGE: u8 = 0x02;
+const NVME_LOG_SMART: u32 = 0x02;
+const NVME_NSID_ALL: u32 = 0xffffffff;
+
+// See scsi/sg.h
+#[repr(C)]
+struct SgIoHdr {
+    interface_id: i32,
+    dxfer_direction: i32,
+    cmd_len: u8,
+    mx_sb_len: u8,
+    iovec_count: u16,
+    dxfer_len: u32,
+    dxferp: *mut u8,
+    cmdp: *const u8,
+    sbp: *mut u8,
+    timeout: u32,
+    flags: u32,
+    pack_id: i32,
+    usr_ptr: *mut u8,
+    status: u8,
+    masked_status: u8,
+    msg_status: u8,
+    sb_len_wr: u8,
+    host_status: u16,
+    driver_status: u16,
+    resid: i32,
+    duration: u32,
+    info: u32,
+}
+
+ioctl_readwrite_bad!(sg_io, 0x2285, SgIoHdr);
+
+const SG_DXFER_FROM_DEV: i32 = -3;
+
+// ATA PASS-THROUGH(16) with SMART READ DATA: PIO data in, 1 sector
+const ATA_SMART_READ_DATA: [u8; 16] = [
+    0x85, 0x08, 0x0e, 0x00, 0xd0, 0x00, 0x01, 0x00, 0x00, 0x00, 0x4f, 0x00, 0xc2, 0x00, 0xb0, 0x00,
+];
+
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, b| (n << 8) | *b as u64)
+}
+
+fn nvme(device: &File) -> io::Result<Vec<Attribute>> {
+    let mut log = [0u8; 512];
+    let mut cmd = NvmeAdminCmd {
+        opcode: NVME_GET_LOG_PAGE,
+        nsid: NVME_NSID_ALL,
+        addr: log.as_mut_ptr() as u64,
+        data_len: log.len() as u32,
+        // Number of dwords to read, minus one, and the log page
+        cdw10: ((log.len() as u32 / 4 - 1) << 16) | NVME_LOG_SMART,
+        ..Default::default()
+    };
+    unsafe { nvme_admin_cmd(device.as_raw_fd(), &mut cmd) }?;
+
+    // Data units are thousands of 512 byte sectors
+    Ok(vec![
+        Attribute {
+            name: "data written (bytes)",
+            value: le(&log[48..56]) * 512_000,
+        },
+        Attribute {
+            name: "host write commands",
+            value: le(&log[80..88]),
+        },
+        Attribute {
+            name: "percentage used",
+            value: log[5] as u64,
+        },
+        Attribute {
+            name: "temperature (C)",
+            value: le(&log[1..3]).saturating_sub(273),
+        },
+    ])
+}
+
+fn ata(device: &File) -> io::Result<Vec<Attribute>> {
+    let mut data = [0u8; 512];
+    let mut sense = [0u8; 32];
+    let mut hdr = SgIoHdr {
+        interface_id: b'S' as i32,
+        dxfer_direction: SG_DXFER_FROM_DEV,
+        cmd_len: ATA_SMART_READ_DATA.len() as u8,
+        mx_sb_len: sense.len() as u8,
+        iovec_count: 0,
+        dxfer_len: data.len() as u32,
+        dxferp: data.as_mut_ptr(),
+        cmdp: ATA_SMART_READ_DATA.as_ptr(),
+        sbp: sense.as_mut_ptr(),
+        timeout: 5000,
+        flags: 0,
+        pack_id: 0,
+        usr_ptr: std::ptr::null_mut(),
+        status: 0,
+        masked_status: 0,
+        msg_status: 0,
+        sb_len_wr: 0,
+        host_status: 0,
+        driver_status: 0,
+        resid: 0,
+        duration: 0,
+        info: 0,
+    };
+    unsafe { sg_io(device.as_raw_fd(), &mut hdr) }?;
+    // A check condition is how ATA pass-through reports back, but then
+    // nothing was read if no attribute is there
+    if hdr.host_status != 0 || data[2] == 0 {
+        return Err(io::Error::other("no ATA SMART data"));
+    }
+
+    // 30 attributes of 12 bytes: id, flags, normalized value, worst, raw
+    let mut attributes = Vec::new();
+    for entry in data[2..2 + 30 * 12].chunks(12) {
+        let current = entry[3] as u64;
+        let raw = le(&entry[5..11]);
+        let (name, value) = match entry[0] {
+            241 => ("LBAs written (attribute 241)", raw),
+            177 => ("wear leveling count (attribute 177)", current),
+            173 => ("wear leveling count (attribute 173)", current),
+            233 => ("media wearout indicator (attribute 233)", current),
+            194 => ("temperature (C)", raw & 0xff),
+            _ => continue,
+        };
+        attributes.push(Attribute { name, value });
+    }
+    Ok(attributes)
+}
+
+// The kernel counts the sectors written to every block device
+fn kernel(rdev: u64) -> Option<Attribute> {
+    let dir = crate::device::sysfs_dir(rdev)?;
+    let stat = std::fs::read_to_string(dir.join("stat")).ok()?;
+    let sectors: u64 = stat.split_whitespace().nth(6)?.parse().ok()?;
+    Some(Attribute {
+        name: "written per the kernel (bytes)",
+        value: sectors * 512,
+    })
+}
+
+/// Counters of the block device at `path`, None if it's not one
+pub fn read(path: &str) -> Option<Vec<Attribute>> {
+    let rdev = crate::device::block_rdev(path)?;
+    let mut attributes = File::open(path)
+        .ok()
+        .and_then(|device| nvme(&device).or_else(|_| ata(&device)).ok())
+        .unwrap_or_default();
+    attributes.extend(kernel(rdev));
+    Some(attributes)
+}
+
+/// Table of the counters before and after, with what ssdsync wrote
+pub fn report(before: &[Attribute], after: &[Attribute], written: u64) -> String {
+    let mut ret = format!(
+        "{:<40} {:>20} {:>20} {:>16}\n",
+        "", "before", "after", "change"
+    );
+    for b in before.iter() {
+        if let Some(a) = after.iter().find(|a| a.name == b.name) {
+            ret.push_str(&format!(
+                "{:<40} {:>20} {:>20} {:>+16}\n",
+                b.name,
+                b.value,
+                a.value,
+                a.value as i128 - b.value as i128
+            ));
+        }
+    }
+    ret.push_str(&format!(
+        "{:<40} {:>20} {:>20} {:>+16}\n",
+        "written by ssdsync (bytes)", "", "", written
+    ));
+    ret
+}
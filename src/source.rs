@@ -0,0 +1,874 @@
+use {
+    crate::{
+        error::{Context, Error},
+        mmap::MmapSource,
+        uring::UringSource,
+        IoBackend,
+    },
+    async_trait::async_trait,
+    nix::unistd::{lseek, Whence},
+    std::{
+        io::{self, Read},
+        os::unix::{fs::FileExt, io::AsRawFd},
+        sync::{Arc, Mutex},
+    },
+    tokio::{
+        fs::File,
+        io::{AsyncReadExt, AsyncSeekExt},
+    },
+};
+
+/// Anything the compare loop can read blocks from, implemented with
+/// `#[async_trait]`
+#[async_trait]
+pub trait BlockSource: Send {
+    /// Size of the content in bytes, None if it's not known up front
+    async fn size(&mut self) -> Option<u64>;
+
+    /// Read the next bytes into `buf`. The buffer is filled completely
+    /// unless the end of the content is reached, 0 means end of content.
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// If the next `len` bytes are known to be zeroes without reading them,
+    /// like a hole in a sparse file, skip over them and return true.
+    async fn skip_hole(&mut self, _len: usize) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    /// Whether what skip_hole skips reads as zeroes too, so that more than
+    /// a block can be read at once without asking before each. Not so if
+    /// a hole stands for a part that's left out.
+    fn plain_holes(&self) -> bool {
+        false
+    }
+
+    /// Move `len` bytes ahead without handing them out, to start in the
+    /// middle. Sources that can't seek read them and throw them away.
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        discard(self, len).await
+    }
+}
+
+// Read `len` bytes of a source and throw them away
+async fn discard<S: BlockSource + ?Sized>(source: &mut S, len: u64) -> io::Result<()> {
+    let mut buf = vec![0; 1024 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = std::cmp::min(left, buf.len() as u64) as usize;
+        match source.read(&mut buf[..n]).await? {
+            0 => break,
+            read => left -= read as u64,
+        }
+    }
+    Ok(())
+}
+
+/// A regular file, block device or pipe
+///
+/// Regular files may be sparse. Blocks falling entirely into a hole are
+/// handed out as zeroes without being read.
+///
+/// Files and devices are read at the position with pread, so nothing
+/// depends on where the file position is. A pipe, which has none, is read
+/// through. Reads run on the blocking pool into a buffer of the source.
+pub struct FileSource {
+    file: Arc<std::fs::File>,
+    size: Option<u64>,
+    regular: bool,
+    pos: u64,
+    // The extent pos is in, as found with SEEK_DATA/SEEK_HOLE
+    extent_end: u64,
+    extent_is_hole: bool,
+    // What the blocking pool reads into, taken along while it does
+    scratch: Option<Vec<u8>>,
+}
+
+impl FileSource {
+    pub async fn new(file: File) -> io::Result<Self> {
+        let regular = file.metadata().await.is_ok_and(|m| m.is_file());
+        let size = crate::get_size(&file).await?;
+        Ok(FileSource {
+            file: Arc::new(file.into_std().await),
+            size,
+            regular,
+            pos: 0,
+            extent_end: 0,
+            extent_is_hole: false,
+            scratch: Some(Vec::new()),
+        })
+    }
+
+    // Find the extent at pos
+    fn probe(&mut self, size: u64) -> io::Result<()> {
+        (self.extent_end, self.extent_is_hole) = probe(&*self.file, self.pos, size)?;
+        Ok(())
+    }
+}
+
+/// The extent of a regular file at `pos`, as found with SEEK_DATA and
+/// SEEK_HOLE: where it ends and whether it's a hole. Probing moves the
+/// file position, only positional I/O is safe on the file.
+pub fn probe(file: &impl AsRawFd, pos: u64, size: u64) -> io::Result<(u64, bool)> {
+    let fd = file.as_raw_fd();
+    match lseek(fd, pos as i64, Whence::SeekData) {
+        Ok(data) if data as u64 == pos => {
+            Ok((lseek(fd, pos as i64, Whence::SeekHole)? as u64, false))
+        }
+        Ok(data) => Ok((data as u64, true)),
+        // Only a hole until the end
+        Err(nix::errno::Errno::ENXIO) => Ok((size, true)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl BlockSource for FileSource {
+    async fn size(&mut self) -> Option<u64> {
+        self.size
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut scratch = self.scratch.take().unwrap_or_default();
+        scratch.resize(buf.len(), 0);
+        let file = self.file.clone();
+        let (pos, seekable) = (self.pos, self.size.is_some());
+        let (scratch, filled) = tokio::task::spawn_blocking(move || {
+            let mut filled = 0;
+            while filled < scratch.len() {
+                let read = if seekable {
+                    file.read_at(&mut scratch[filled..], pos + filled as u64)
+                } else {
+                    (&*file).read(&mut scratch[filled..])
+                };
+                match read {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => return (scratch, Err(e)),
+                }
+            }
+            (scratch, Ok(filled))
+        })
+        .await
+        .map_err(io::Error::other)?;
+        if let Ok(n) = filled {
+            buf[..n].copy_from_slice(&scratch[..n]);
+            self.pos += n as u64;
+        }
+        self.scratch = Some(scratch);
+        filled
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let size = match self.size {
+            Some(size) if self.regular => size,
+            _ => return Ok(false),
+        };
+        let end = self.pos + len as u64;
+        if end > size {
+            return Ok(false);
+        }
+        if self.pos >= self.extent_end {
+            self.probe(size)?;
+        }
+        if self.extent_is_hole && end <= self.extent_end {
+            self.pos = end;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // A hole of a file reads as zeroes
+    fn plain_holes(&self) -> bool {
+        true
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        // A pipe can only be read through
+        if self.size.is_none() {
+            return discard(self, len).await;
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// Copy-on-write overlay: a sparse delta file whose allocated extents
+/// take precedence over the base image. Holes in the delta show the
+/// base through, anything past the end of the base reads as zeroes.
+pub struct Overlay {
+    base: File,
+    delta: File,
+    // Separate handle for SEEK_DATA/SEEK_HOLE probing, so the reads
+    // don't have their file position moved under them.
+    probe: std::fs::File,
+    base_size: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl Overlay {
+    pub async fn open(base: &str, delta: &str) -> io::Result<Self> {
+        let base = File::open(base).await?;
+        let delta_file = File::open(delta).await?;
+        let probe = std::fs::File::open(delta)?;
+        let sizes = (
+            crate::get_size(&base).await?,
+            crate::get_size(&delta_file).await?,
+        );
+        let (base_size, delta_size) = match sizes {
+            (Some(base_size), Some(delta_size)) => (base_size, delta_size),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Overlay base and delta can't be pipes",
+                ))
+            }
+        };
+        let size = std::cmp::max(base_size, delta_size);
+        Ok(Overlay {
+            base,
+            delta: delta_file,
+            probe,
+            base_size,
+            size,
+            pos: 0,
+        })
+    }
+
+    // Find where the current extent of the delta ends, and whether it's data.
+    fn extent_at(&self, pos: u64) -> io::Result<(bool, u64)> {
+        let fd = self.probe.as_raw_fd();
+        match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(data) if data as u64 == pos => {
+                let hole = lseek(fd, pos as i64, Whence::SeekHole)?;
+                Ok((true, hole as u64))
+            }
+            Ok(data) => Ok((false, data as u64)),
+            // No more data after pos
+            Err(nix::errno::Errno::ENXIO) => Ok((false, self.size)),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for Overlay {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos + len, self.size);
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = std::cmp::min(self.pos + buf.len() as u64, self.size);
+        let mut filled = 0;
+        while self.pos < end {
+            let (is_data, extent_end) = self.extent_at(self.pos)?;
+            let n = (std::cmp::min(extent_end, end) - self.pos) as usize;
+            let chunk = &mut buf[filled..filled + n];
+            if is_data {
+                self.delta.seek(io::SeekFrom::Start(self.pos)).await?;
+                self.delta.read_exact(chunk).await?;
+            } else if self.pos < self.base_size {
+                // The base may end in the middle of a hole
+                let in_base = std::cmp::min(n as u64, self.base_size - self.pos) as usize;
+                self.base.seek(io::SeekFrom::Start(self.pos)).await?;
+                self.base.read_exact(&mut chunk[..in_base]).await?;
+                chunk[in_base..].fill(0);
+            } else {
+                chunk.fill(0);
+            }
+            filled += n;
+            self.pos += n as u64;
+        }
+        Ok(filled)
+    }
+}
+
+/// Reads a source `size` bytes at a time, whatever the size of the blocks
+/// asked for. Larger reads are kept and handed out over several blocks,
+/// so each side of a sync can be read in the size that suits its device.
+pub struct ReadSize {
+    inner: Box<dyn BlockSource>,
+    size: usize,
+    staged: Vec<u8>,
+    // Where in staged the next block starts
+    at: usize,
+}
+
+impl ReadSize {
+    pub fn new(inner: Box<dyn BlockSource>, size: usize) -> Self {
+        ReadSize {
+            inner,
+            size,
+            staged: Vec::new(),
+            at: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for ReadSize {
+    async fn size(&mut self) -> Option<u64> {
+        self.inner.size().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.at == self.staged.len() {
+                self.staged.resize(self.size, 0);
+                let n = self.inner.read(&mut self.staged).await?;
+                self.staged.truncate(n);
+                self.at = 0;
+                if n == 0 {
+                    break;
+                }
+            }
+            let n = std::cmp::min(buf.len() - filled, self.staged.len() - self.at);
+            buf[filled..filled + n].copy_from_slice(&self.staged[self.at..self.at + n]);
+            filled += n;
+            self.at += n;
+        }
+        Ok(filled)
+    }
+
+    // Holes can only be seen between reads
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        if self.at < self.staged.len() {
+            return Ok(false);
+        }
+        self.inner.skip_hole(len).await
+    }
+
+    fn plain_holes(&self) -> bool {
+        self.inner.plain_holes()
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        let staged = std::cmp::min(len, (self.staged.len() - self.at) as u64);
+        self.at += staged as usize;
+        self.inner.skip(len - staged).await
+    }
+}
+
+/// `length` bytes of a source starting at `offset`, or everything from
+/// there to the end. Reads stop at the end of the window.
+pub struct Window {
+    inner: Box<dyn BlockSource>,
+    size: Option<u64>,
+    // Bytes left in the window, None up to the end of the source
+    left: Option<u64>,
+}
+
+impl Window {
+    pub async fn new(
+        mut inner: Box<dyn BlockSource>,
+        offset: u64,
+        length: Option<u64>,
+    ) -> io::Result<Self> {
+        let size = inner.size().await.map(|size| {
+            let rest = size.saturating_sub(offset);
+            length.map_or(rest, |length| std::cmp::min(rest, length))
+        });
+        inner.skip(offset).await?;
+        Ok(Window {
+            inner,
+            size,
+            left: length,
+        })
+    }
+}
+
+#[async_trait]
+impl BlockSource for Window {
+    async fn size(&mut self) -> Option<u64> {
+        self.size
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.left.map_or(buf.len(), |left| {
+            std::cmp::min(left, buf.len() as u64) as usize
+        });
+        let read = self.inner.read(&mut buf[..n]).await?;
+        if let Some(left) = &mut self.left {
+            *left -= read as u64;
+        }
+        Ok(read)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        if self.left.is_some_and(|left| left < len as u64) {
+            return Ok(false);
+        }
+        let skipped = self.inner.skip_hole(len).await?;
+        if let (true, Some(left)) = (skipped, &mut self.left) {
+            *left -= len as u64;
+        }
+        Ok(skipped)
+    }
+
+    fn plain_holes(&self) -> bool {
+        self.inner.plain_holes()
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        let len = self.left.map_or(len, |left| std::cmp::min(left, len));
+        if let Some(left) = &mut self.left {
+            *left -= len;
+        }
+        self.inner.skip(len).await
+    }
+}
+
+/// A side of a sync with the ranges `skipped` tells skipped as holes,
+/// without reading them. Done on the source and the target alike, they're
+/// equal and never written.
+pub struct Skipping {
+    inner: Box<dyn BlockSource>,
+    skipped: Arc<dyn Fn(u64, u64) -> bool + Send + Sync>,
+    pos: u64,
+}
+
+impl Skipping {
+    pub fn new(
+        inner: Box<dyn BlockSource>,
+        skipped: Arc<dyn Fn(u64, u64) -> bool + Send + Sync>,
+    ) -> Self {
+        Skipping {
+            inner,
+            skipped,
+            pos: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for Skipping {
+    async fn size(&mut self) -> Option<u64> {
+        self.inner.size().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf).await?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let skipped = if (self.skipped)(self.pos, len as u64) {
+            self.inner.skip(len as u64).await?;
+            true
+        } else {
+            self.inner.skip_hole(len).await?
+        };
+        if skipped {
+            self.pos += len as u64;
+        }
+        Ok(skipped)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.inner.skip(len).await?;
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// The smallest part a failed read is split into
+pub const SECTOR: usize = 512;
+
+/// Byte ranges that couldn't be read, sorted and apart
+pub type Unreadable = Arc<Mutex<Vec<(u64, u64)>>>;
+
+/// A source whose failed reads are tried again in halves, and those
+/// again, down to single sectors, to salvage what can still be read the
+/// way ddrescue does. Sectors that can't be are handed out filled with
+/// `fill` and recorded in `unreadable`. Only for sources that can be read
+/// again where a read failed, not for pipes.
+pub struct Rescue {
+    inner: Box<dyn BlockSource>,
+    unreadable: Unreadable,
+    fill: u8,
+    pos: u64,
+}
+
+impl Rescue {
+    pub fn new(inner: Box<dyn BlockSource>, unreadable: Unreadable, fill: u8) -> Self {
+        Rescue {
+            inner,
+            unreadable,
+            fill,
+            pos: 0,
+        }
+    }
+
+    // Read buf in smaller and smaller parts after it failed as a whole
+    async fn split(&mut self, buf: &mut [u8], error: io::Error) -> io::Result<usize> {
+        // What's still to read, the next part last
+        let mut parts = Vec::new();
+        let mut failed = Some((0, buf.len(), error));
+        let mut filled = 0;
+        loop {
+            match failed.take() {
+                Some((at, len, _)) if len > SECTOR => {
+                    let half = (len / 2).div_ceil(SECTOR) * SECTOR;
+                    parts.push((at + half, len - half));
+                    parts.push((at, half));
+                }
+                Some((at, len, e)) => {
+                    tracing::warn!(offset = self.pos, length = len, "Unreadable: {}", e);
+                    self.inner.skip(len as u64).await?;
+                    buf[at..at + len].fill(self.fill);
+                    let end = self.pos + len as u64;
+                    let mut unreadable = self.unreadable.lock().unwrap();
+                    match unreadable.last_mut() {
+                        Some(last) if last.1 == self.pos => last.1 = end,
+                        _ => unreadable.push((self.pos, end)),
+                    }
+                    self.pos = end;
+                    filled = at + len;
+                }
+                None => (),
+            }
+            let (at, len) = match parts.pop() {
+                Some(part) => part,
+                None => break,
+            };
+            match self.inner.read(&mut buf[at..at + len]).await {
+                Ok(n) => {
+                    self.pos += n as u64;
+                    filled = at + n;
+                    // The end of the content
+                    if n < len {
+                        break;
+                    }
+                }
+                Err(e) => failed = Some((at, len, e)),
+            }
+        }
+        Ok(filled)
+    }
+}
+
+#[async_trait]
+impl BlockSource for Rescue {
+    async fn size(&mut self) -> Option<u64> {
+        self.inner.size().await
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf).await {
+            Ok(n) => {
+                self.pos += n as u64;
+                Ok(n)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    offset = self.pos,
+                    length = buf.len(),
+                    "Read failed ({}), trying again in smaller parts",
+                    e
+                );
+                self.split(buf, e).await
+            }
+        }
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let skipped = self.inner.skip_hole(len).await?;
+        if skipped {
+            self.pos += len as u64;
+        }
+        Ok(skipped)
+    }
+
+    fn plain_holes(&self) -> bool {
+        self.inner.plain_holes()
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.inner.skip(len).await?;
+        self.pos += len;
+        Ok(())
+    }
+}
+
+/// A source placed at `offset` in a composite image, `length` bytes of it
+/// from its start
+pub struct Segment {
+    pub offset: u64,
+    pub length: u64,
+    pub source: Box<dyn BlockSource>,
+}
+
+/// A composite image assembled from segments, e.g. an appliance image
+/// built from parts. Gaps between segments read as zeroes, a segment
+/// whose source is shorter than its length is padded with zeroes.
+pub struct Segmented {
+    // Sorted by offset, not overlapping
+    segments: Vec<Segment>,
+    size: u64,
+    pos: u64,
+    // Index of the first segment not yet passed
+    current: usize,
+    // Bytes read from the current segment's source
+    read: u64,
+}
+
+/// Sort segments by their offsets, they can't overlap
+pub fn sort_segments(segments: &mut [Segment]) -> io::Result<()> {
+    segments.sort_by_key(|s| s.offset);
+    for pair in segments.windows(2) {
+        if pair[0].offset + pair[0].length > pair[1].offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Segments at {} and {} overlap",
+                    pair[0].offset, pair[1].offset
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl Segmented {
+    pub fn new(mut segments: Vec<Segment>) -> io::Result<Self> {
+        sort_segments(&mut segments)?;
+        let size = segments.last().map_or(0, |s| s.offset + s.length);
+        Ok(Segmented {
+            segments,
+            size,
+            pos: 0,
+            current: 0,
+            read: 0,
+        })
+    }
+
+    // Leave segments behind once pos is past their end
+    fn advance(&mut self) {
+        while let Some(segment) = self.segments.get(self.current) {
+            if self.pos < segment.offset + segment.length {
+                break;
+            }
+            self.current += 1;
+            self.read = 0;
+        }
+    }
+}
+
+#[async_trait]
+impl BlockSource for Segmented {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.pos = std::cmp::min(self.pos + len, self.size);
+        self.advance();
+        // Landing in a segment, its source has to get there too
+        if let Some(segment) = self.segments.get_mut(self.current) {
+            if self.pos > segment.offset {
+                let into = self.pos - segment.offset;
+                segment.source.skip(into - self.read).await?;
+                self.read = into;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = std::cmp::min(self.pos + buf.len() as u64, self.size);
+        let mut filled = 0;
+        while self.pos < end {
+            self.advance();
+            let segment = &mut self.segments[self.current];
+            if self.pos < segment.offset {
+                // A gap before the segment
+                let n = (std::cmp::min(segment.offset, end) - self.pos) as usize;
+                buf[filled..filled + n].fill(0);
+                filled += n;
+                self.pos += n as u64;
+                continue;
+            }
+            let n = (std::cmp::min(segment.offset + segment.length, end) - self.pos) as usize;
+            let chunk = &mut buf[filled..filled + n];
+            // Past the end of its source only zeroes are left
+            let got = if self.pos - segment.offset == self.read {
+                segment.source.read(chunk).await?
+            } else {
+                0
+            };
+            self.read += got as u64;
+            chunk[got..].fill(0);
+            filled += n;
+            self.pos += n as u64;
+        }
+        Ok(filled)
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        let end = self.pos + len as u64;
+        if len == 0 || end > self.size {
+            return Ok(false);
+        }
+        self.advance();
+        let segment = &mut self.segments[self.current];
+        let skipped = if end <= segment.offset {
+            true
+        } else if self.pos >= segment.offset
+            && end <= segment.offset + segment.length
+            && self.pos - segment.offset == self.read
+        {
+            let skipped = segment.source.skip_hole(len).await?;
+            if skipped {
+                self.read += len as u64;
+            }
+            skipped
+        } else {
+            false
+        };
+        if skipped {
+            self.pos = end;
+        }
+        Ok(skipped)
+    }
+}
+
+// A segments file lists one "offset length path" segment per line. Empty
+// lines and lines starting with # are skipped.
+pub fn segment_list(path: &str) -> io::Result<Vec<(u64, u64, String)>> {
+    let mut segments = Vec::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let bad = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: expected offset, length and path", path, n + 1),
+            )
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(bad());
+        }
+        // Sizes can't be zero, offsets can
+        let offset = match fields[0] {
+            "0" => 0,
+            offset => crate::parse_size(offset).map_err(|_| bad())?,
+        };
+        let length = crate::parse_size(fields[1]).map_err(|_| bad())?;
+        segments.push((offset, length, fields[2].to_string()));
+    }
+    Ok(segments)
+}
+
+/// Number of file descriptors `open` keeps open for the source
+pub fn handle_count(spec: &str) -> u64 {
+    if spec.starts_with("overlay:") {
+        3
+    } else if let Some(path) = spec.strip_prefix("segments:") {
+        segment_list(path).map_or(1, |segments| segments.len() as u64)
+    } else {
+        1
+    }
+}
+
+/// Open a file or device the way `backend` reads it, pipes are always
+/// read the tokio way. With `direct` it's read through io_uring with
+/// O_DIRECT whatever the backend, a pipe is read through the page cache.
+pub async fn open_file(
+    path: &str,
+    backend: IoBackend,
+    direct: bool,
+) -> crate::error::Result<Box<dyn BlockSource>> {
+    if backend == IoBackend::Mmap && !direct {
+        if let Some(source) = MmapSource::open(path).await.context("map", path)? {
+            return Ok(Box::new(source));
+        }
+    }
+    if backend == IoBackend::Uring || direct {
+        if let Some(source) = UringSource::open(path, direct)
+            .await
+            .context("open", path)?
+        {
+            return Ok(Box::new(source));
+        }
+    }
+    let file = File::open(path).await.context("open", path)?;
+    Ok(Box::new(FileSource::new(file).await.context("open", path)?))
+}
+
+/// Open a source given on the command line: either `overlay:BASE:DELTA`,
+/// `segments:FILE`, an image as `FORMAT:PATH`, a URL, an NBD export, `-` for stdin or a file or device, possibly as a UUID=... style
+/// specifier. Only a file or device is read the way `backend` and
+/// `direct` say.
+pub async fn open(
+    spec: &str,
+    backend: IoBackend,
+    direct: bool,
+) -> crate::error::Result<Box<dyn BlockSource>> {
+    if let Some(path) = spec.strip_prefix("segments:") {
+        let list = segment_list(path).context("load", path)?;
+        let mut segments = Vec::new();
+        for (offset, length, path) in list {
+            let resolved = crate::resolve_device(&path)?;
+            let file = File::open(&resolved)
+                .await
+                .context("open the segment", &path)?;
+            segments.push(Segment {
+                offset,
+                length,
+                source: Box::new(
+                    FileSource::new(file)
+                        .await
+                        .context("open the segment", &path)?,
+                ),
+            });
+        }
+        return Ok(Box::new(
+            Segmented::new(segments).context("use the segments in", path)?,
+        ));
+    }
+    if let Some(rest) = spec.strip_prefix("overlay:") {
+        let (base, delta) = rest.split_once(':').ok_or_else(|| {
+            Error::Usage("Overlay sources must be given as overlay:BASE:DELTA".to_string())
+        })?;
+        let base = crate::resolve_device(base)?;
+        let delta = crate::resolve_device(delta)?;
+        let overlay = Overlay::open(&base, &delta)
+            .await
+            .context("open the overlay", rest)?;
+        return Ok(Box::new(overlay));
+    }
+    if let Some((format, path)) = crate::image::parse(spec) {
+        let path = crate::resolve_device(path)?;
+        return crate::image::open(format, &path)
+            .await
+            .context("open the image", &path);
+    }
+    if crate::http::is_url(spec) {
+        let source = crate::http::HttpSource::open(spec)
+            .await
+            .context("download", spec)?;
+        return Ok(Box::new(source));
+    }
+    if let Some(uri) = crate::nbd::Uri::parse(spec) {
+        let source = crate::nbd::NbdSource::open(&uri)
+            .await
+            .context("connect to", spec)?;
+        return Ok(Box::new(source));
+    }
+    // stdin, a pipe or whatever it was redirected from
+    if spec == "-" {
+        return open_file("/dev/stdin", backend, direct).await;
+    }
+    open_file(&crate::resolve_device(spec)?, backend, direct).await
+}
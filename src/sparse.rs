@@ -0,0 +1,172 @@
+use {
+    std::{convert::TryInto, io},
+    tokio::{
+        fs::{File, OpenOptions},
+        io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+    },
+};
+
+// Layout of a sparse image, all integers little endian:
+//
+//   header  MAGIC, u64 size of the image it was taken of
+//   data    the differing regions, packed back to back
+//   index   per region: u64 offset in the image, u64 length, u64 data offset
+//   footer  u64 index offset, u64 region count, INDEX_MAGIC
+const MAGIC: &[u8; 8] = b"SSDSPIM1";
+const INDEX_MAGIC: &[u8; 8] = b"SSDSPIDX";
+
+const HEADER_LEN: u64 = 16;
+const ENTRY_LEN: usize = 24;
+const FOOTER_LEN: usize = 24;
+
+// Regions are copied in pieces of this size when applying
+const COPY_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    offset: u64,
+    length: u64,
+    data_offset: u64,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Collects differing blocks into a sparse image
+pub struct SparseImageWriter {
+    file: BufWriter<File>,
+    regions: Vec<Region>,
+    data_end: u64,
+}
+
+impl SparseImageWriter {
+    pub async fn create(path: &str, size: u64) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        file.write_all(MAGIC).await?;
+        file.write_all(&size.to_le_bytes()).await?;
+        Ok(SparseImageWriter {
+            file,
+            regions: Vec::new(),
+            data_end: HEADER_LEN,
+        })
+    }
+
+    /// Add a block of the source at `pos`, adjacent blocks are merged
+    pub async fn append(&mut self, pos: u64, data: &[u8]) -> io::Result<()> {
+        match self.regions.last_mut() {
+            Some(last) if last.offset + last.length == pos => last.length += data.len() as u64,
+            _ => self.regions.push(Region {
+                offset: pos,
+                length: data.len() as u64,
+                data_offset: self.data_end,
+            }),
+        }
+        self.file.write_all(data).await?;
+        self.data_end += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write the index. Returns the number of regions and bytes stored.
+    pub async fn finish(mut self) -> io::Result<(usize, u64)> {
+        for region in self.regions.iter() {
+            self.file.write_all(&region.offset.to_le_bytes()).await?;
+            self.file.write_all(&region.length.to_le_bytes()).await?;
+            self.file
+                .write_all(&region.data_offset.to_le_bytes())
+                .await?;
+        }
+        self.file.write_all(&self.data_end.to_le_bytes()).await?;
+        self.file
+            .write_all(&(self.regions.len() as u64).to_le_bytes())
+            .await?;
+        self.file.write_all(INDEX_MAGIC).await?;
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        Ok((self.regions.len(), self.data_end - HEADER_LEN))
+    }
+}
+
+async fn read_index(image: &mut File) -> io::Result<(u64, Vec<Region>)> {
+    let mut header = [0; HEADER_LEN as usize];
+    image.read_exact(&mut header).await?;
+    if &header[..8] != MAGIC {
+        return Err(invalid("Not an ssdsync sparse image"));
+    }
+    let size = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+    let mut footer = [0; FOOTER_LEN];
+    image.seek(io::SeekFrom::End(-(FOOTER_LEN as i64))).await?;
+    image.read_exact(&mut footer).await?;
+    if &footer[16..] != INDEX_MAGIC {
+        return Err(invalid("Sparse image is truncated, no index found"));
+    }
+    let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    let count = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+    // The index has to fill exactly the space between data and footer
+    let len = image.metadata().await?.len();
+    let index_len = count.checked_mul(ENTRY_LEN as u64);
+    if index_offset < HEADER_LEN
+        || index_len
+            .and_then(|l| l.checked_add(index_offset))
+            .and_then(|l| l.checked_add(FOOTER_LEN as u64))
+            != Some(len)
+    {
+        return Err(invalid("Sparse image index is corrupt"));
+    }
+    let count = count as usize;
+
+    let mut index = vec![0; count * ENTRY_LEN];
+    image.seek(io::SeekFrom::Start(index_offset)).await?;
+    image.read_exact(&mut index).await?;
+    let regions = index
+        .chunks(ENTRY_LEN)
+        .map(|e| Region {
+            offset: u64::from_le_bytes(e[..8].try_into().unwrap()),
+            length: u64::from_le_bytes(e[8..16].try_into().unwrap()),
+            data_offset: u64::from_le_bytes(e[16..].try_into().unwrap()),
+        })
+        .collect::<Vec<_>>();
+    for region in regions.iter() {
+        let end = region.offset.checked_add(region.length);
+        let data_end = region.data_offset.checked_add(region.length);
+        if end.is_none_or(|end| end > size)
+            || region.data_offset < HEADER_LEN
+            || data_end.is_none_or(|end| end > index_offset)
+        {
+            return Err(invalid("Sparse image index is corrupt"));
+        }
+    }
+    Ok((size, regions))
+}
+
+/// Write every region of a sparse image onto the target. The whole index
+/// is validated before the target is touched. Returns regions and bytes.
+pub async fn apply(image: &str, target: &str) -> io::Result<(usize, u64)> {
+    let mut image = File::open(image).await?;
+    let (size, regions) = read_index(&mut image).await?;
+
+    let mut target = OpenOptions::new().write(true).open(target).await?;
+    match crate::get_size(&target).await? {
+        Some(target_size) if target_size >= size => (),
+        _ => return Err(invalid("The target is smaller than the sparse image")),
+    }
+
+    let mut buf = vec![0; COPY_SIZE];
+    let mut written = 0;
+    for region in regions.iter() {
+        image.seek(io::SeekFrom::Start(region.data_offset)).await?;
+        target.seek(io::SeekFrom::Start(region.offset)).await?;
+        let mut left = region.length;
+        while left > 0 {
+            let n = std::cmp::min(left, COPY_SIZE as u64) as usize;
+            image.read_exact(&mut buf[..n]).await?;
+            target.write_all(&buf[..n]).await?;
+            left -= n as u64;
+        }
+        written += region.length;
+    }
+    target.flush().await?;
+    target.sync_all().await?;
+    Ok((regions.len(), written))
+}
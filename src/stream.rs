@@ -0,0 +1,159 @@
+use {
+    crate::{
+        error::{self, Error},
+        source::{self, BlockSource},
+        Driver, Summary, SyncArgs, PROGRESS_INTERVAL,
+    },
+    indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle},
+    nix::unistd::dup2,
+    std::{io::Write, os::unix::io::AsFd, sync::atomic::Ordering, time::Instant},
+    tokio::io::AsyncWriteExt,
+};
+
+const STDOUT: i32 = 1;
+const STDERR: i32 = 2;
+
+/// A sync onto stdout, a target of `-`, for a pipe to take the source
+/// from. There's no target to compare with, every block is written, one
+/// after the other. The messages go to stderr from here on, stdout only
+/// carries what's synced and ends with it.
+pub async fn to_stdout(
+    args: &SyncArgs,
+    driver: &Driver,
+    source: Option<Box<dyn BlockSource>>,
+) -> error::Result<Summary> {
+    let started = Instant::now();
+    if let Some(option) = crate::local_option(args) {
+        return Err(Error::Usage(format!(
+            "{} doesn't work with a target of -",
+            option
+        )));
+    }
+    if args.dry_run || args.json {
+        return Err(Error::Usage(
+            "Nothing but the source goes to a target of -".to_string(),
+        ));
+    }
+
+    let mut source_r = match source {
+        Some(source) => source,
+        None => source::open(args.source.as_ref().unwrap(), args.io_backend, false).await?,
+    };
+    let source_size = source_r.size().await;
+
+    let _ = std::io::stdout().flush();
+    let data = std::io::stdout()
+        .as_fd()
+        .try_clone_to_owned()
+        .map_err(|source| Error::File {
+            action: "keep",
+            path: "stdout".to_string(),
+            source,
+        })?;
+    dup2(STDERR, STDOUT).map_err(|source| Error::System {
+        action: "send the messages to stderr",
+        source,
+    })?;
+    // The only handle left of stdout, it's closed once all is written
+    let mut target = tokio::fs::File::from_std(std::fs::File::from(data));
+
+    let bar = match source_size {
+        Some(size) => ProgressBar::new(size),
+        None => ProgressBar::new_spinner(),
+    };
+    if !driver.bars {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{wide_bar} [{bytes} {bytes_per_sec}]")
+            .expect("Template error")
+            .progress_chars("##-"),
+    );
+
+    let block_size = crate::read_sizes(args).0;
+    let mut buf = vec![0; block_size];
+    let mut pos = 0;
+    let mut blocks = 0;
+    let mut cancelled = false;
+    let mut last_progress = Instant::now();
+    let scanning = Instant::now();
+    let streamed = async {
+        loop {
+            if driver
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.load(Ordering::Relaxed))
+            {
+                cancelled = true;
+                break;
+            }
+            let n = source_r
+                .read(&mut buf)
+                .await
+                .map_err(|source| Error::Read {
+                    side: "source",
+                    offset: pos,
+                    source,
+                })?;
+            if n == 0 {
+                break;
+            }
+            target
+                .write_all(&buf[..n])
+                .await
+                .map_err(|source| Error::Write {
+                    offset: pos,
+                    source,
+                })?;
+            pos += n as u64;
+            blocks += 1;
+            bar.set_position(pos);
+            if let Some(progress) = &driver.progress {
+                if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                    progress(pos, source_size.unwrap_or(pos));
+                    last_progress = Instant::now();
+                }
+            }
+        }
+        target.flush().await.map_err(|source| Error::Write {
+            offset: pos,
+            source,
+        })
+    };
+    let result = streamed.await;
+    let finishing = Instant::now();
+    bar.finish();
+    if let Some(progress) = &driver.progress {
+        progress(pos, source_size.unwrap_or(pos));
+    }
+
+    drop(target);
+    result?;
+    if cancelled {
+        driver.say(format_args!("\nStopped at {}. Written: {} bytes", pos, pos));
+        return Err(Error::Cancelled);
+    }
+    driver.say(format_args!(
+        "\nFinished. Total: {}, written: {} bytes",
+        blocks, pos
+    ));
+
+    Ok(Summary {
+        target: "-".to_string(),
+        blocks,
+        different: blocks,
+        written: pos,
+        source_size,
+        target_size: pos,
+        scanned: pos,
+        read: pos,
+        retries: 0,
+        unreadable: 0,
+        phases: vec![
+            ("prepare", scanning - started),
+            ("sync", finishing - scanning),
+            ("finish", finishing.elapsed()),
+        ],
+    })
+}
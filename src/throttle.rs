@@ -0,0 +1,50 @@
+// Token bucket rate limiting. Every byte read or written takes a token,
+// tokens come back at the rate and pile up to a tenth of a second's
+// worth. Taking more than there are goes into debt, which is waited off,
+// so blocks larger than the bucket work too. Clones share the bucket,
+// like the source and the target readers do.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Clone)]
+pub struct Throttle(Arc<Mutex<Bucket>>);
+
+impl Throttle {
+    /// At most `rate` bytes per second
+    pub fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Throttle(Arc::new(Mutex::new(Bucket {
+            rate,
+            capacity: rate / 10.0,
+            tokens: rate / 10.0,
+            last: Instant::now(),
+        })))
+    }
+
+    /// Wait until `bytes` may be read or written
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.0.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last).as_secs_f64() * bucket.rate;
+            bucket.tokens = f64::min(bucket.tokens + refill, bucket.capacity);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
@@ -0,0 +1,91 @@
+use {
+    crate::error::{self, Context, Error},
+    std::{
+        fs::File,
+        io::{self, BufReader},
+        sync::Arc,
+    },
+    tokio_rustls::{
+        rustls::{
+            server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey,
+            RootCertStore, ServerConfig,
+        },
+        TlsAcceptor, TlsConnector,
+    },
+};
+
+// The certificates of a PEM file, the chain starting with its own
+fn load_certs(path: &str) -> error::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path).context("open", path)?);
+    let certs = rustls_pemfile::certs(&mut reader).context("load the certificates of", path)?;
+    if certs.is_empty() {
+        return Err(Error::Usage(format!("No certificates found in {}", path)));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+// The first private key of a PEM file, whatever kind it is
+fn load_key(path: &str) -> error::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path).context("open", path)?);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).context("load the key of", path)? {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => (),
+            None => return Err(Error::Usage(format!("No private key found in {}", path))),
+        }
+    }
+}
+
+fn load_roots(path: &str) -> error::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| Error::Usage(format!("Can't trust a certificate of {}: {}", path, e)))?;
+    }
+    Ok(roots)
+}
+
+fn invalid(path: &str, e: impl std::fmt::Display) -> Error {
+    Error::Usage(format!("Can't use {} for TLS: {}", path, e))
+}
+
+/// What a server takes connections with: its certificate and key, and
+/// with `ca` only clients with a certificate it signed are let in
+pub fn acceptor(cert: &str, key: &str, ca: Option<&str>) -> error::Result<TlsAcceptor> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match ca {
+        Some(ca) => builder
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| invalid(cert, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// What a client connects with: the server has to have a certificate
+/// signed by `ca`, and with `cert` and `key` the client shows its own
+pub fn connector(ca: &str, cert: Option<&str>, key: Option<&str>) -> error::Result<TlsConnector> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_roots(ca)?);
+    let config = match (cert, key) {
+        (Some(cert), Some(key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid(cert, e))?,
+        _ => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// The name a server's certificate has to be for, the host of HOST:PORT
+pub fn server_name(addr: &str) -> io::Result<tokio_rustls::rustls::ServerName> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    std::convert::TryFrom::try_from(host)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
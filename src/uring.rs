@@ -0,0 +1,551 @@
+use {
+    crate::{
+        direct::{self, AlignedBuf, ALIGN},
+        source::{self, BlockSource},
+        Buf,
+    },
+    async_trait::async_trait,
+    io_uring::{opcode, types, IoUring},
+    nix::sys::eventfd::{eventfd, EfdFlags},
+    std::{
+        collections::VecDeque,
+        fs::File,
+        io::{self, Read},
+        os::unix::{
+            fs::FileExt,
+            io::{AsRawFd, FromRawFd},
+        },
+        sync::Arc,
+    },
+    tokio::io::unix::AsyncFd,
+};
+
+// A file or device read through io_uring. Reads of CHUNK bytes are kept
+// in flight ahead of the position, DEPTH of them, so the device always has
+// a queue of work instead of one read at a time from the blocking pool.
+// Completions are signalled on an eventfd the runtime polls. With O_DIRECT
+// the reads start at and are rounded up to ALIGN, which CHUNK is a
+// multiple of. Holes of regular files aren't read, their chunks are zeroes
+// from the start.
+const CHUNK: usize = 128 * 1024;
+const DEPTH: usize = 32;
+
+// A ring of `entries` that signals its completions on an eventfd
+fn ring(entries: usize) -> io::Result<(IoUring, AsyncFd<File>)> {
+    let ring = IoUring::new(entries as u32)?;
+    let fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+    // Owned by the File from now on
+    let eventfd = unsafe { File::from_raw_fd(fd) };
+    ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+    Ok((ring, AsyncFd::new(eventfd)?))
+}
+
+// Wait for the eventfd of a ring to be signalled
+async fn signalled(eventfd: &AsyncFd<File>) -> io::Result<()> {
+    let mut guard = eventfd.readable().await?;
+    let mut count = [0; 8];
+    match guard.get_inner().read(&mut count) {
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+        _ => {
+            guard.clear_ready();
+            Ok(())
+        }
+    }
+}
+
+struct Chunk {
+    offset: u64,
+    data: AlignedBuf,
+    // Bytes of data wanted, up to the end
+    len: usize,
+    // Bytes read, once the read is complete
+    done: Option<io::Result<usize>>,
+    // Bytes already handed out
+    used: usize,
+    // All in a hole, data isn't filled
+    hole: bool,
+}
+
+pub struct UringSource {
+    file: File,
+    // The O_DIRECT handle the ring reads from, if there is one. Short reads
+    // are finished through file.
+    direct: Option<File>,
+    size: u64,
+    ring: IoUring,
+    eventfd: AsyncFd<File>,
+    // Reads in order of their offsets, the first one is at pos
+    chunks: VecDeque<Chunk>,
+    spare: Vec<AlignedBuf>,
+    in_flight: usize,
+    // Where the next read goes
+    next: u64,
+    // Bytes at the start of the next read that were skipped, when it had
+    // to start before the skip for alignment
+    lead: usize,
+    regular: bool,
+    // The extent next is in, as far as it's known
+    extent_end: u64,
+    extent_is_hole: bool,
+}
+
+impl UringSource {
+    /// Open a file or device for reading, None if it's a pipe. With
+    /// `direct` it's read with O_DIRECT, bypassing the page cache.
+    pub async fn open(path: &str, direct: bool) -> io::Result<Option<Self>> {
+        let file = tokio::fs::File::open(path).await?;
+        let size = match crate::get_size(&file).await? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let regular = file.metadata().await?.is_file();
+        let file = file.into_std().await;
+        let direct = if direct {
+            Some(direct::open(path, false)?)
+        } else {
+            None
+        };
+        let (ring, eventfd) = ring(DEPTH)?;
+        Ok(Some(UringSource {
+            file,
+            direct,
+            size,
+            ring,
+            eventfd,
+            chunks: VecDeque::with_capacity(DEPTH),
+            spare: Vec::new(),
+            in_flight: 0,
+            next: 0,
+            lead: 0,
+            regular,
+            extent_end: 0,
+            extent_is_hole: false,
+        }))
+    }
+
+    // Queue reads up to the depth, or the end
+    fn submit(&mut self) -> io::Result<()> {
+        let mut queued = false;
+        while self.chunks.len() < DEPTH && self.next < self.size {
+            let mut length = std::cmp::min(CHUNK as u64, self.size - self.next) as usize;
+            if self.regular && self.next >= self.extent_end {
+                (self.extent_end, self.extent_is_hole) =
+                    source::probe(&self.file, self.next, self.size)?;
+            }
+            // Chunks end where extents do, so holes get chunks of their own.
+            // One that wouldn't leave the next read aligned stays in.
+            let to_extent_end = self.extent_end.saturating_sub(self.next);
+            if self.regular
+                && to_extent_end < length as u64
+                && (self.direct.is_none() || to_extent_end.is_multiple_of(ALIGN as u64))
+            {
+                length = to_extent_end as usize;
+            }
+            if self.regular && self.extent_is_hole && to_extent_end >= length as u64 {
+                let data = self.spare.pop().unwrap_or_else(|| AlignedBuf::new(CHUNK));
+                self.chunks.push_back(Chunk {
+                    offset: self.next,
+                    data,
+                    len: length,
+                    done: Some(Ok(length)),
+                    used: std::mem::take(&mut self.lead),
+                    hole: true,
+                });
+                self.next += length as u64;
+                continue;
+            }
+
+            let (fd, asked) = match &self.direct {
+                Some(file) => (file.as_raw_fd(), length.div_ceil(ALIGN) * ALIGN),
+                None => (self.file.as_raw_fd(), length),
+            };
+            let mut data = self.spare.pop().unwrap_or_else(|| AlignedBuf::new(CHUNK));
+            let entry = opcode::Read::new(types::Fd(fd), data.as_mut_ptr(), asked as u32)
+                .offset(self.next)
+                .build()
+                .user_data(self.next);
+            // The buffer stays in chunks until the read is complete
+            unsafe { self.ring.submission().push(&entry) }.map_err(io::Error::other)?;
+            self.chunks.push_back(Chunk {
+                offset: self.next,
+                data,
+                len: length,
+                done: None,
+                used: std::mem::take(&mut self.lead),
+                hole: false,
+            });
+            self.in_flight += 1;
+            self.next += length as u64;
+            queued = true;
+        }
+        if queued {
+            self.ring.submit()?;
+        }
+        Ok(())
+    }
+
+    // Take in the completed reads
+    fn reap(&mut self) {
+        let completed: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (offset, result) in completed {
+            self.in_flight -= 1;
+            if let Some(chunk) = self.chunks.iter_mut().find(|c| c.offset == offset) {
+                chunk.done = Some(if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    // Rounded up reads may go past the end
+                    Ok(std::cmp::min(result as usize, chunk.len))
+                });
+            }
+        }
+    }
+
+    // Wait until the first read is complete, or with `all` every one
+    async fn complete(&mut self, all: bool) -> io::Result<()> {
+        loop {
+            self.reap();
+            let ready = match self.chunks.front() {
+                _ if all => self.in_flight == 0,
+                Some(chunk) => chunk.done.is_some(),
+                None => true,
+            };
+            if ready {
+                return Ok(());
+            }
+            signalled(&self.eventfd).await?;
+        }
+    }
+
+    // The first read, done in full. A short read is finished the plain
+    // way, and one cut off by the end makes that the end.
+    fn finish_front(&mut self) -> io::Result<()> {
+        let chunk = self.chunks.front_mut().unwrap();
+        let mut filled = match &chunk.done {
+            Some(Ok(n)) => *n,
+            Some(Err(e)) => return Err(io::Error::from_raw_os_error(e.raw_os_error().unwrap())),
+            None => 0,
+        };
+        while filled < chunk.len {
+            match self.file.read_at(
+                &mut chunk.data[filled..chunk.len],
+                chunk.offset + filled as u64,
+            ) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        chunk.done = Some(Ok(filled));
+        if filled < chunk.len {
+            chunk.len = filled;
+            self.size = chunk.offset + filled as u64;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlockSource for UringSource {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            self.submit()?;
+            self.complete(false).await?;
+            let whole = match self.chunks.front() {
+                Some(chunk) => matches!(chunk.done, Some(Ok(n)) if n == chunk.len),
+                None => break,
+            };
+            if !whole {
+                self.finish_front()?;
+            }
+
+            let chunk = self.chunks.front_mut().unwrap();
+            let n = std::cmp::min(buf.len() - filled, chunk.len.saturating_sub(chunk.used));
+            if chunk.hole {
+                buf[filled..filled + n].fill(0);
+            } else {
+                buf[filled..filled + n].copy_from_slice(&chunk.data[chunk.used..chunk.used + n]);
+            }
+            chunk.used += n;
+            filled += n;
+            if chunk.used >= chunk.len {
+                let chunk = self.chunks.pop_front().unwrap();
+                self.spare.push(chunk.data);
+            }
+            // Cut off by the end, what was read ahead past it is moot
+            if self.chunks.front().is_some_and(|c| c.offset >= self.size) {
+                self.complete(true).await?;
+                self.chunks.clear();
+            }
+        }
+        Ok(filled)
+    }
+
+    fn plain_holes(&self) -> bool {
+        true
+    }
+
+    async fn skip_hole(&mut self, len: usize) -> io::Result<bool> {
+        // Only if the hole chunks queued cover all of it
+        self.submit()?;
+        let mut covered = 0;
+        for chunk in self.chunks.iter() {
+            if !chunk.hole || covered >= len {
+                break;
+            }
+            covered += chunk.len - chunk.used;
+        }
+        if covered < len {
+            return Ok(false);
+        }
+        let mut left = len;
+        while left > 0 {
+            let chunk = self.chunks.front_mut().unwrap();
+            let n = std::cmp::min(left, chunk.len - chunk.used);
+            chunk.used += n;
+            left -= n;
+            if chunk.used == chunk.len {
+                let chunk = self.chunks.pop_front().unwrap();
+                self.spare.push(chunk.data);
+            }
+        }
+        Ok(true)
+    }
+
+    async fn skip(&mut self, len: u64) -> io::Result<()> {
+        let pos = match self.chunks.front() {
+            Some(chunk) => chunk.offset + chunk.used as u64,
+            None => self.next + self.lead as u64,
+        };
+        // Whatever was read ahead is thrown away
+        self.complete(true).await?;
+        for chunk in self.chunks.drain(..) {
+            self.spare.push(chunk.data);
+        }
+        self.next = std::cmp::min(pos + len, self.size);
+        self.lead = 0;
+        self.extent_end = 0;
+        if self.direct.is_some() && self.next < self.size {
+            self.lead = (self.next % ALIGN as u64) as usize;
+            self.next -= self.lead as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for UringSource {
+    fn drop(&mut self) {
+        // The kernel may still write into the buffers of reads in flight
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(self.in_flight).is_err() {
+                break;
+            }
+            self.reap();
+        }
+    }
+}
+
+/// A write through a ring, once it's complete
+pub struct Written {
+    /// Where it went
+    pub pos: u64,
+    pub buf: Buf,
+    /// Bytes written, all of the block unless it failed
+    pub done: usize,
+    pub failed: Option<io::Error>,
+    /// Times it was cut short or interrupted and carried on with
+    pub retries: u64,
+}
+
+/// Blocks written to a file or device through a ring of their own. Up to
+/// DEPTH writes are in flight, so the device has a queue of them like it
+/// has of the reads. Each block is held until its write is complete, then
+/// handed back with how it went.
+pub struct UringWriter {
+    file: Arc<File>,
+    ring: IoUring,
+    eventfd: AsyncFd<File>,
+    // The writes in flight, each in the slot its user_data is the index of
+    slots: Vec<Option<(u64, Buf)>>,
+    in_flight: usize,
+    // Complete, not handed back yet
+    done: VecDeque<Written>,
+}
+
+impl UringWriter {
+    pub fn new(file: Arc<File>) -> io::Result<Self> {
+        let (ring, eventfd) = ring(DEPTH)?;
+        Ok(UringWriter {
+            file,
+            ring,
+            eventfd,
+            slots: (0..DEPTH).map(|_| None).collect(),
+            in_flight: 0,
+            done: VecDeque::new(),
+        })
+    }
+
+    /// Whether any write isn't handed back yet
+    pub fn busy(&self) -> bool {
+        self.in_flight > 0 || !self.done.is_empty()
+    }
+
+    /// Queue a write of `buf` at `pos`, once there's room for it. One that
+    /// can't be queued is handed back as failed.
+    pub async fn write(&mut self, pos: u64, buf: Buf) {
+        if let Err(e) = self.wait(true).await {
+            return self.failed(pos, buf, e);
+        }
+        let slot = self.slots.iter().position(Option::is_none).unwrap();
+        // The buffer's data stays where it is until the write is complete
+        let data = buf.as_slice();
+        let entry = opcode::Write::new(
+            types::Fd(self.file.as_raw_fd()),
+            data.as_ptr(),
+            data.len() as u32,
+        )
+        .offset(pos)
+        .build()
+        .user_data(slot as u64);
+        let pushed = unsafe { self.ring.submission().push(&entry) };
+        if let Err(e) = pushed {
+            return self.failed(pos, buf, io::Error::other(e));
+        }
+        self.slots[slot] = Some((pos, buf));
+        self.in_flight += 1;
+        if let Err(e) = self.ring.submit() {
+            // It may still have been taken, the ring says once it's done
+            tracing::warn!(offset = pos, "Can't submit a write: {}", e);
+        }
+    }
+
+    /// The next write that's complete, without waiting for one
+    pub fn ready(&mut self) -> Option<Written> {
+        self.reap();
+        self.done.pop_front()
+    }
+
+    /// The next write that's complete, waiting for one if there are any
+    /// in flight
+    pub async fn next(&mut self) -> Option<Written> {
+        self.complete().await;
+        self.done.pop_front()
+    }
+
+    /// Wait until a write is complete, if there are any in flight,
+    /// leaving it to `ready` to take
+    pub async fn complete(&mut self) {
+        if let Err(e) = self.wait(false).await {
+            // Without the eventfd the ring is waited on right here
+            tracing::warn!("Can't wait for the writes: {}", e);
+            if self.done.is_empty() && self.in_flight > 0 {
+                let _ = self.ring.submit_and_wait(1);
+            }
+            self.reap();
+        }
+    }
+
+    fn failed(&mut self, pos: u64, buf: Buf, e: io::Error) {
+        self.done.push_back(Written {
+            pos,
+            buf,
+            done: 0,
+            failed: Some(e),
+            retries: 0,
+        });
+    }
+
+    // Wait until a write is complete, or with `room` until another one
+    // can be queued
+    async fn wait(&mut self, room: bool) -> io::Result<()> {
+        loop {
+            self.reap();
+            let ready = match room {
+                true => self.in_flight < DEPTH,
+                false => !self.done.is_empty() || self.in_flight == 0,
+            };
+            if ready {
+                return Ok(());
+            }
+            signalled(&self.eventfd).await?;
+        }
+    }
+
+    // Take in the completed writes
+    fn reap(&mut self) {
+        let completed: Vec<(u64, i32)> = self
+            .ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (slot, result) in completed {
+            self.in_flight -= 1;
+            if let Some((pos, buf)) = self.slots[slot as usize].take() {
+                let written = self.finish(pos, buf, result);
+                self.done.push_back(written);
+            }
+        }
+    }
+
+    // A write cut short or interrupted already changed the target, the
+    // rest of it is written the plain way
+    fn finish(&self, pos: u64, buf: Buf, result: i32) -> Written {
+        let mut written = Written {
+            pos,
+            buf,
+            done: 0,
+            failed: None,
+            retries: 0,
+        };
+        match result {
+            n if n >= 0 => written.done = n as usize,
+            n if -n == nix::libc::EINTR || -n == nix::libc::EAGAIN => (),
+            n => {
+                written.failed = Some(io::Error::from_raw_os_error(-n));
+                return written;
+            }
+        }
+        let length = written.buf.length;
+        while written.done < length {
+            written.retries += 1;
+            let rest = &written.buf.as_slice()[written.done..];
+            match self.file.write_at(rest, pos + written.done as u64) {
+                Ok(0) => {
+                    written.failed = Some(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        format!("only {} of {} bytes written", written.done, length),
+                    ));
+                    break;
+                }
+                Ok(n) => written.done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    written.failed = Some(e);
+                    break;
+                }
+            }
+        }
+        written
+    }
+}
+
+impl Drop for UringWriter {
+    fn drop(&mut self) {
+        // The kernel may still read from the buffers of writes in flight
+        while self.in_flight > 0 {
+            if self.ring.submit_and_wait(self.in_flight).is_err() {
+                break;
+            }
+            self.reap();
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use {
+    crate::image::{be32, be64, invalid, read_at, Cluster, Layout},
+    async_trait::async_trait,
+    std::io,
+    tokio::fs::File,
+};
+
+// A VHD, of Virtual PC and older Hyper-V, ends in a footer of 512 bytes,
+// big endian:
+//
+//     0  cookie "conectix"        16  dynamic header offset
+//    48  size of the content      60  disk type: 2 fixed, 3 dynamic,
+//                                     4 differencing
+//
+// A fixed VHD is the content followed by the footer. A dynamic one has a
+// copy of the footer at the start and a dynamic header, at the offset the
+// footer gives:
+//
+//     0  cookie "cxsparse"        16  block allocation table offset
+//    28  table entries            32  block size
+//
+// Each table entry is the sector a block starts at, 0xFFFFFFFF if it
+// isn't allocated. A block starts with a bitmap of the sectors written,
+// the block itself follows it.
+const FOOTER: &[u8] = b"conectix";
+const DYNAMIC: &[u8] = b"cxsparse";
+const SECTOR: u64 = 512;
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+// A fixed VHD is read in clusters of this size
+const FIXED_CLUSTER: u64 = 1024 * 1024;
+
+/// The block allocation table of a VHD, or none for a fixed one
+pub struct Vhd {
+    size: u64,
+    block_size: u64,
+    // Sectors the blocks start at and the size of their bitmaps
+    table: Option<(Vec<u32>, u64)>,
+}
+
+impl Vhd {
+    pub async fn open(file: &mut File, path: &str) -> io::Result<Self> {
+        let file_size = file.metadata().await?.len();
+        let mut footer = [0; 512];
+        if file_size >= SECTOR {
+            read_at(file, file_size - SECTOR, &mut footer).await?;
+        }
+        // A dynamic VHD still has the copy at the start if the end is lost
+        if &footer[..8] != FOOTER && file_size >= SECTOR {
+            read_at(file, 0, &mut footer).await?;
+        }
+        if &footer[..8] != FOOTER {
+            return Err(invalid(format!("{} is not a VHD", path)));
+        }
+        let size = be64(&footer, 48);
+        match be32(&footer, 60) {
+            2 if size.checked_add(SECTOR).is_some_and(|end| end <= file_size) => Ok(Vhd {
+                size,
+                block_size: FIXED_CLUSTER,
+                table: None,
+            }),
+            2 => Err(invalid(format!("{} is shorter than its content", path))),
+            3 => {
+                let mut header = [0; 1024];
+                read_at(file, be64(&footer, 16), &mut header).await?;
+                if &header[..8] != DYNAMIC {
+                    return Err(invalid(format!("{} has no dynamic header", path)));
+                }
+                let block_size = be32(&header, 32) as u64;
+                if block_size == 0 || !block_size.is_multiple_of(SECTOR) {
+                    return Err(invalid(format!("Bad VHD block size {}", block_size)));
+                }
+                let entries = be32(&header, 28) as u64;
+                if entries * block_size < size {
+                    return Err(invalid(format!(
+                        "The block table of {} is too small for its size",
+                        path
+                    )));
+                }
+                let at = be64(&header, 16);
+                if at
+                    .checked_add(entries * 4)
+                    .is_none_or(|end| end > file_size)
+                {
+                    return Err(invalid(format!(
+                        "The block table of {} is past its end",
+                        path
+                    )));
+                }
+                let mut table = vec![0; entries as usize * 4];
+                read_at(file, at, &mut table).await?;
+                let table = table.chunks(4).map(|entry| be32(entry, 0)).collect();
+                // A bit per sector, taking up whole sectors
+                let bitmap = (block_size / SECTOR).div_ceil(8).div_ceil(SECTOR) * SECTOR;
+                Ok(Vhd {
+                    size,
+                    block_size,
+                    table: Some((table, bitmap)),
+                })
+            }
+            4 => Err(invalid(format!(
+                "{} is a differencing VHD, only one without a parent can be read",
+                path
+            ))),
+            other => Err(invalid(format!("VHD disk type {} isn't supported", other))),
+        }
+    }
+}
+
+#[async_trait]
+impl Layout for Vhd {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    async fn cluster(&mut self, _file: &mut File, index: u64) -> io::Result<Cluster> {
+        match &self.table {
+            None => Ok(Cluster::Data(index * self.block_size)),
+            Some((table, bitmap)) => match table[index as usize] {
+                UNALLOCATED => Ok(Cluster::Zero),
+                sector => Ok(Cluster::Data(sector as u64 * SECTOR + bitmap)),
+            },
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use {
+    crate::image::{invalid, le16, le32, le64, read_at, Cluster, Layout},
+    async_trait::async_trait,
+    std::io,
+    tokio::fs::File,
+};
+
+// A VHDX, of newer Hyper-V, is little endian. It starts with a file type
+// identifier "vhdxfile", followed by two headers at 64K and 128K, the one
+// with the higher sequence number and a good checksum is current:
+//
+//     0  signature "head"          4  CRC-32C, over it as 0
+//     8  sequence number          48  log GUID, zero unless there's
+//                                     a log to replay
+//
+// A region table at 192K lists where the block allocation table and the
+// metadata are, by their GUIDs:
+//
+//     0  signature "regi"          4  CRC-32C     8  entries
+//    16  entries of 32 bytes: GUID, offset u64, length u32, required u32
+//
+// The metadata has a table of items too, their offsets from its start:
+//
+//     0  signature "metadata"     10  entries
+//    32  entries of 32 bytes: GUID, offset u32, length u32, flags u32
+//
+// The allocation table interleaves the entries of the blocks with one of
+// a sector bitmap after every chunk of them. A block's entry has its
+// state in the low 3 bits and its offset in MB from bit 20 on.
+const SIGNATURE: &[u8] = b"vhdxfile";
+const HEADERS: [u64; 2] = [64 * 1024, 128 * 1024];
+const HEADER_SIZE: usize = 4096;
+const REGIONS: u64 = 192 * 1024;
+const REGIONS_SIZE: usize = 64 * 1024;
+
+const BAT: &str = "2DC27766-F623-4200-9D64-115E9BFD4A08";
+const METADATA: &str = "8B7CA206-4790-4B9A-B8FE-575F050F886E";
+const FILE_PARAMETERS: &str = "CAA16737-FA36-4D43-B3B6-33F0AA44E76B";
+const DISK_SIZE: &str = "2FA54224-CD1B-4876-B211-5DBED83BF4B8";
+const LOGICAL_SECTOR: &str = "8141BF1D-A96F-4709-BA47-F233A8FAAB5F";
+// Required, but not for reading the content
+const PHYSICAL_SECTOR: &str = "CDA348C7-445D-4471-9CC9-E9885251C556";
+const PAGE_83: &str = "BECA12AB-B2E6-4523-93EF-C309E000C746";
+
+const FULLY_PRESENT: u64 = 6;
+const PARTIALLY_PRESENT: u64 = 7;
+const HAS_PARENT: u32 = 2;
+
+// A GUID as it's stored, the first three parts little endian
+fn guid(text: &str) -> [u8; 16] {
+    let hex: Vec<u8> = text
+        .split('-')
+        .flat_map(|part| {
+            (0..part.len())
+                .step_by(2)
+                .map(move |i| u8::from_str_radix(&part[i..i + 2], 16).unwrap())
+        })
+        .collect();
+    let mut guid = [0; 16];
+    guid.copy_from_slice(&hex);
+    guid[..4].reverse();
+    guid[4..6].reverse();
+    guid[6..8].reverse();
+    guid
+}
+
+// Whether the CRC-32C of `data`, with the one in it taken as 0, is that
+fn checksum_ok(data: &[u8]) -> bool {
+    let mut zeroed = data.to_vec();
+    zeroed[4..8].fill(0);
+    crc32c::crc32c(&zeroed) == le32(data, 4)
+}
+
+// Find the region or metadata item `id` in a table of 32 byte entries
+fn entry<'a>(table: &'a [u8], start: usize, count: usize, id: &str) -> Option<&'a [u8]> {
+    let id = guid(id);
+    table[start..]
+        .chunks(32)
+        .take(count)
+        .find(|entry| entry[..16] == id)
+}
+
+/// The block allocation table of a VHDX
+pub struct Vhdx {
+    size: u64,
+    block_size: u64,
+    // Blocks per sector bitmap entry
+    chunk: u64,
+    table: Vec<u64>,
+}
+
+impl Vhdx {
+    pub async fn open(file: &mut File, path: &str) -> io::Result<Self> {
+        let mut identifier = [0; 8];
+        read_at(file, 0, &mut identifier).await?;
+        if identifier != SIGNATURE {
+            return Err(invalid(format!("{} is not a VHDX", path)));
+        }
+
+        let mut current: Option<Vec<u8>> = None;
+        for offset in HEADERS {
+            let mut header = vec![0; HEADER_SIZE];
+            read_at(file, offset, &mut header).await?;
+            if &header[..4] != b"head" || !checksum_ok(&header) {
+                continue;
+            }
+            if current
+                .as_ref()
+                .is_none_or(|c| le64(c, 8) < le64(&header, 8))
+            {
+                current = Some(header);
+            }
+        }
+        let header = current.ok_or_else(|| invalid(format!("{} has no good header", path)))?;
+        if header[48..64].iter().any(|b| *b != 0) {
+            return Err(invalid(format!(
+                "{} has a log to replay first, Hyper-V or qemu-img check -r all replays it",
+                path
+            )));
+        }
+
+        let mut regions = vec![0; REGIONS_SIZE];
+        read_at(file, REGIONS, &mut regions).await?;
+        if &regions[..4] != b"regi" || !checksum_ok(&regions) {
+            return Err(invalid(format!("{} has a bad region table", path)));
+        }
+        let count = le32(&regions, 8) as usize;
+        if 16 + count * 32 > REGIONS_SIZE {
+            return Err(invalid(format!("{} has a bad region table", path)));
+        }
+        for region in regions[16..].chunks(32).take(count) {
+            let known = [guid(BAT), guid(METADATA)];
+            if le32(region, 28) & 1 != 0 && !known.iter().any(|id| region[..16] == *id) {
+                return Err(invalid(format!(
+                    "{} has a region that isn't supported",
+                    path
+                )));
+            }
+        }
+        let region = |id| {
+            entry(&regions, 16, count, id)
+                .map(|e| (le64(e, 16), le32(e, 24) as usize))
+                .ok_or_else(|| invalid(format!("{} has no {} region", path, id)))
+        };
+        let (bat_offset, bat_len) = region(BAT)?;
+        let (metadata_offset, metadata_len) = region(METADATA)?;
+
+        let mut metadata = vec![0; metadata_len];
+        read_at(file, metadata_offset, &mut metadata).await?;
+        if metadata.len() < 32 || &metadata[..8] != b"metadata" {
+            return Err(invalid(format!("{} has bad metadata", path)));
+        }
+        let count = le16(&metadata, 10) as usize;
+        if 32 + count * 32 > metadata.len() {
+            return Err(invalid(format!("{} has bad metadata", path)));
+        }
+        for item in metadata[32..].chunks(32).take(count) {
+            let known = [
+                FILE_PARAMETERS,
+                DISK_SIZE,
+                LOGICAL_SECTOR,
+                PHYSICAL_SECTOR,
+                PAGE_83,
+            ];
+            if le32(item, 24) & 4 != 0 && !known.iter().any(|id| item[..16] == guid(id)) {
+                return Err(invalid(format!(
+                    "{} has metadata that isn't supported",
+                    path
+                )));
+            }
+        }
+        let item = |id, len| {
+            entry(&metadata, 32, count, id)
+                .map(|e| le32(e, 16) as usize)
+                .filter(|offset| offset + len <= metadata.len())
+                .map(|offset| &metadata[offset..offset + len])
+                .ok_or_else(|| invalid(format!("{} has no {} metadata", path, id)))
+        };
+        let parameters = item(FILE_PARAMETERS, 8)?;
+        if le32(parameters, 4) & HAS_PARENT != 0 {
+            return Err(invalid(format!(
+                "{} is a differencing VHDX, only one without a parent can be read",
+                path
+            )));
+        }
+        let block_size = le32(parameters, 0) as u64;
+        let size = le64(item(DISK_SIZE, 8)?, 0);
+        let sector = le32(item(LOGICAL_SECTOR, 4)?, 0) as u64;
+        if block_size == 0 || sector == 0 || !((1 << 23) * sector).is_multiple_of(block_size) {
+            return Err(invalid(format!(
+                "Bad VHDX block size {} with sectors of {}",
+                block_size, sector
+            )));
+        }
+        let chunk = (1 << 23) * sector / block_size;
+
+        // The entry of the last block has to be there
+        let blocks = size.div_ceil(block_size);
+        if blocks > 0 && (blocks - 1 + (blocks - 1) / chunk + 1) * 8 > bat_len as u64 {
+            return Err(invalid(format!(
+                "The block allocation table of {} is too small for its size",
+                path
+            )));
+        }
+        let mut table = vec![0; bat_len];
+        read_at(file, bat_offset, &mut table).await?;
+        let table = table.chunks(8).map(|entry| le64(entry, 0)).collect();
+
+        Ok(Vhdx {
+            size,
+            block_size,
+            chunk,
+            table,
+        })
+    }
+}
+
+#[async_trait]
+impl Layout for Vhdx {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.block_size
+    }
+
+    async fn cluster(&mut self, _file: &mut File, index: u64) -> io::Result<Cluster> {
+        let entry = self.table[(index + index / self.chunk) as usize];
+        match entry & 7 {
+            FULLY_PRESENT => Ok(Cluster::Data(entry & !0xF_FFFF)),
+            PARTIALLY_PRESENT => Err(invalid(
+                "A block of the VHDX is only partly present, it needs its parent".to_string(),
+            )),
+            // Not present, undefined, zero or unmapped
+            0..=3 => Ok(Cluster::Zero),
+            state => Err(invalid(format!("Bad VHDX block state {}", state))),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use {
+    crate::image::{invalid, le32, le64, read_at, Cluster, Codec, Layout},
+    async_trait::async_trait,
+    std::io,
+    tokio::fs::File,
+};
+
+// A monolithic sparse VMDK, of VMware, is a single file starting with a
+// header, little endian, sizes and offsets in sectors:
+//
+//     0  magic "KDMV"              8  flags
+//    12  capacity                 20  grain size
+//    28  descriptor offset        36  descriptor size
+//    44  entries per grain table  56  grain directory offset
+//    77  compression, 1 is deflate
+//
+// The grain directory points at grain tables, and their entries at the
+// grains. A grain at 0 isn't allocated, and with the zeroed grain flag
+// one at 1 is zeroes. A stream optimized VMDK has its grains compressed,
+// each behind a marker of its sector and its length, and its grain
+// directory at the end, which a copy of the header there tells.
+//
+// A monolithic flat VMDK is only a text descriptor, the content is in a
+// raw file next to it.
+const MAGIC: &[u8] = b"KDMV";
+const SECTOR: u64 = 512;
+const ZEROED_GRAIN: u32 = 1 << 2;
+const COMPRESSED: u32 = 1 << 16;
+const GD_AT_END: u64 = u64::MAX;
+// Sector and length before the data of a compressed grain
+const MARKER: u64 = 12;
+
+/// The grain tables of a VMDK
+pub struct Vmdk {
+    size: u64,
+    grain: u64,
+    per_table: u64,
+    directory: Vec<u32>,
+    zeroed: bool,
+    compressed: bool,
+    // The grain table used last, by its sector
+    table: Option<(u32, Vec<u32>)>,
+}
+
+impl Vmdk {
+    pub async fn open(file: &mut File, path: &str) -> io::Result<Self> {
+        let file_size = file.metadata().await?.len();
+        let mut header = [0; 512];
+        let len = std::cmp::min(file_size, SECTOR) as usize;
+        read_at(file, 0, &mut header[..len]).await?;
+        if header.starts_with(b"# Disk DescriptorFile") {
+            return Err(invalid(format!(
+                "{} is only the descriptor of its extents, a flat one can be synced as it is",
+                path
+            )));
+        }
+        if &header[..4] != MAGIC {
+            return Err(invalid(format!("{} is not a sparse VMDK", path)));
+        }
+        // The header at the end has the grain directory
+        if le64(&header, 56) == GD_AT_END {
+            if file_size < 2 * SECTOR {
+                return Err(invalid(format!("{} has no footer", path)));
+            }
+            read_at(file, file_size - 2 * SECTOR, &mut header).await?;
+            if &header[..4] != MAGIC || le64(&header, 56) == GD_AT_END {
+                return Err(invalid(format!("{} has no footer", path)));
+            }
+        }
+
+        let flags = le32(&header, 8);
+        let compressed = flags & COMPRESSED != 0;
+        if compressed && header[77] != 1 {
+            return Err(invalid(format!(
+                "VMDK compression {} isn't supported",
+                header[77]
+            )));
+        }
+        // What the header says is read from the file has to be in it
+        let bad = || invalid(format!("{} has a bad header", path));
+        let sectors = |at| le64(&header, at).checked_mul(SECTOR).ok_or_else(bad);
+        let within = |at: u64, len: u64| at.checked_add(len).is_some_and(|end| end <= file_size);
+        let grain = sectors(20)?;
+        let per_table = le32(&header, 44) as u64;
+        if grain == 0 || per_table == 0 || per_table * 4 > file_size {
+            return Err(bad());
+        }
+
+        // Only a delta has a parent
+        let (at, len) = (sectors(28)?, sectors(36)?);
+        if !within(at, len) {
+            return Err(bad());
+        }
+        let mut descriptor = vec![0; len as usize];
+        read_at(file, at, &mut descriptor).await?;
+        let descriptor = String::from_utf8_lossy(&descriptor);
+        let parent = descriptor
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("parentCID"))
+            .any(|cid| cid.trim_start_matches(['=', ' ']).trim() != "ffffffff");
+        if parent {
+            return Err(invalid(format!(
+                "{} is a delta VMDK, only one without a parent can be read",
+                path
+            )));
+        }
+
+        let size = sectors(12)?;
+        let tables = size.div_ceil(grain.checked_mul(per_table).ok_or_else(bad)?);
+        let at = sectors(56)?;
+        if !within(at, tables * 4) {
+            return Err(bad());
+        }
+        let mut directory = vec![0; tables as usize * 4];
+        read_at(file, at, &mut directory).await?;
+        Ok(Vmdk {
+            size,
+            grain,
+            per_table,
+            directory: directory.chunks(4).map(|entry| le32(entry, 0)).collect(),
+            zeroed: flags & ZEROED_GRAIN != 0,
+            compressed,
+            table: None,
+        })
+    }
+}
+
+#[async_trait]
+impl Layout for Vmdk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.grain
+    }
+
+    async fn cluster(&mut self, file: &mut File, index: u64) -> io::Result<Cluster> {
+        let table = self.directory[(index / self.per_table) as usize];
+        if table == 0 {
+            return Ok(Cluster::Zero);
+        }
+        if self.table.as_ref().map(|(at, _)| *at) != Some(table) {
+            let mut data = vec![0; self.per_table as usize * 4];
+            read_at(file, table as u64 * SECTOR, &mut data).await?;
+            self.table = Some((table, data.chunks(4).map(|e| le32(e, 0)).collect()));
+        }
+        let sector = self.table.as_ref().unwrap().1[(index % self.per_table) as usize] as u64;
+        match sector {
+            0 => Ok(Cluster::Zero),
+            1 if self.zeroed => Ok(Cluster::Zero),
+            _ if self.compressed => {
+                let mut marker = [0; MARKER as usize];
+                read_at(file, sector * SECTOR, &mut marker).await?;
+                Ok(Cluster::Compressed {
+                    offset: sector * SECTOR + MARKER,
+                    len: le32(&marker, 8) as usize,
+                    codec: Codec::Zlib,
+                })
+            }
+            _ => Ok(Cluster::Data(sector * SECTOR)),
+        }
+    }
+}
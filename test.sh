@@ -28,8 +28,13 @@ TESTPATH=/dev/shm/ssdsync-test
 
 mkdir -p $TESTPATH
 
+# Not the configuration file of whoever runs the tests
+export XDG_CONFIG_HOME=$TESTPATH
+
 F1=$TESTPATH/f1
 F2=$TESTPATH/f2
+F3=$TESTPATH/f3
+F4=$TESTPATH/f4
 
 # Two identical files, all zeroes
 
@@ -53,4 +58,2092 @@ $SSDSYNC -b 17 $F1 $F2
 
 assert_eq $F1 $F2
 
+# Block size much larger than the files
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1
+dd if=/dev/zero of=$F2 bs=1000 count=1
+
+$SSDSYNC -b 1048576 $F1 $F2
+
+assert_eq $F1 $F2
+
+# Blocks that take more than one write call
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/zero of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 300000 $F1 $F2
+
+assert_eq $F1 $F2
+
+# Target size override smaller than the target, nothing past it is written
+
+dd if=/dev/urandom of=$F1 bs=1000 count=2
+dd if=/dev/zero of=$F2 bs=1000 count=2
+dd if=/dev/zero of=$F3 bs=1000 count=2
+dd if=$F1 of=$F3 bs=500 count=3 conv=notrunc
+
+$SSDSYNC -b 1000 --target-size 1500 $F1 $F2
+
+assert_eq $F2 $F3
+
+# Sparse files, mostly holes on both sides
+
+rm -f $F1 $F2
+truncate -s 100M $F1 $F2
+dd if=/dev/urandom of=$F1 bs=1000 seek=50000 count=1 conv=notrunc
+
+$SSDSYNC $F1 $F2
+
+assert_eq $F1 $F2
+
+# Only a block in the middle differs
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/zero of=$F2 bs=1000 seek=5 count=1 conv=notrunc
+
+assert_ne $F1 $F2
+
+$SSDSYNC -b 1000 $F1 $F2
+
+assert_eq $F1 $F2
+
+# Source is a named pipe
+
+FIFO=$TESTPATH/fifo
+mkfifo $FIFO
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/zero of=$F2 bs=1000 count=10
+
+cat $F1 > $FIFO &
+$SSDSYNC -b 4096 $FIFO $F2
+
+assert_eq $F1 $F2
+
+# Journal and roll back a sync
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+
+$SSDSYNC -b 1000 --journal $TESTPATH/journal $F1 $F2
+
+assert_eq $F1 $F2
+
+$SSDSYNC rollback --journal $TESTPATH/journal $F2
+
+assert_eq $F2 $F3
+
+# Source and target read in different sizes
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+
+$SSDSYNC --source-block-size 64K --target-block-size 3000 $F1 $F2
+
+assert_eq $F1 $F2
+
+# Compared in blocks of 4K that both reads end on, not of the smaller 8K
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+
+if $SSDSYNC --source-block-size 12K --target-block-size 8K $F1 $F2 | grep -q "Total: 25,"; then
+    echo "OK: compared in blocks of both read sizes"
+else
+    echo "FAILED: not compared in blocks of both read sizes"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Resuming a sync leaves what the checkpoint says is done alone
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=5 seek=5 conv=notrunc
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 10000 5000\n' > $TESTPATH/checkpoint
+
+$SSDSYNC -b 1000 --checkpoint $TESTPATH/checkpoint --resume $F1 $F2
+
+assert_eq $F2 $F3
+
+# Both sides read and the target written through io_uring, resumed in the
+# middle too
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 3000 --io-backend uring $F1 $F2
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=5 seek=5 conv=notrunc
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 10000 5000\n' > $TESTPATH/checkpoint
+
+$SSDSYNC -b 1000 --io-backend uring --checkpoint $TESTPATH/checkpoint --resume $F1 $F2
+
+assert_eq $F2 $F3
+
+# Writes through io_uring with one buffer and with more than the ring
+# holds, flushed and dropped from the cache along the way, with a short
+# block at the end
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+cp $F2 $F3
+
+$SSDSYNC -b 4096 --io-backend uring --buffers 1 --queue-depth 1 $F1 $F2
+
+assert_eq $F1 $F2
+
+$SSDSYNC -b 4096 --io-backend uring --buffers 64 --fsync-interval 100000 --drop-cache $F3 $F2
+
+assert_eq $F3 $F2
+
+# As few and as many buffers in flight as there can be
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+cp $F2 $F3
+
+$SSDSYNC -b 1000 --buffers 1 --queue-depth 1 $F1 $F2
+
+assert_eq $F1 $F2
+
+$SSDSYNC -b 1000 --buffers 64 --queue-depth 256 $F3 $F2
+
+assert_eq $F3 $F2
+
+if $SSDSYNC -b 1000 --buffers 8 --queue-depth 4 $F1 $F2; then
+    echo "FAILED: a queue shallower than the buffers was accepted"
+    exit 1
+else
+    echo "OK: a queue shallower than the buffers is refused"
+fi
+
+assert_eq $F3 $F2
+
+# A sparse source read through io_uring, directly too, or mapped, with
+# data across chunk boundaries and holes that don't fill whole blocks
+
+rm -f $F1
+truncate -s 8M $F1
+dd if=/dev/urandom of=$F1 bs=4096 seek=100 count=3 conv=notrunc
+dd if=/dev/urandom of=$F1 bs=4096 seek=1000 count=40 conv=notrunc
+
+for backend in "--io-backend uring" "--direct" "--io-backend mmap"; do
+    dd if=/dev/urandom of=$F2 bs=1M count=8
+    $SSDSYNC -b 65536 $backend $F1 $F2
+    assert_eq $F1 $F2
+done
+
+# Direct I/O, with a short block at the end written the usual way and a
+# resume in the middle of an aligned read
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 8192 --direct $F1 $F2
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=4096 skip=3 seek=3 conv=notrunc
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 100000 12288\n' > $TESTPATH/checkpoint
+
+$SSDSYNC -b 4096 --direct --checkpoint $TESTPATH/checkpoint --resume $F1 $F2
+
+assert_eq $F2 $F3
+
+# Durable writes, synced every 10K and at the end
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+
+$SSDSYNC -b 1000 --fsync --fsync-interval 10K $F1 $F2
+
+assert_eq $F1 $F2
+
+# Rate limits: 2 MB read at 2 MB/s and 1 MB written at 1 MB/s both take
+# about a second
+
+dd if=/dev/urandom of=$F1 bs=1M count=1
+dd if=/dev/urandom of=$F2 bs=1M count=1
+
+for limit in "--limit-rate 2M" "--limit-write-rate 1M"; do
+    dd if=/dev/urandom of=$F2 bs=1M count=1
+    START=$(date +%s%N)
+    $SSDSYNC $limit $F1 $F2
+    MILLIS=$(( ($(date +%s%N) - START) / 1000000 ))
+    if [ $MILLIS -ge 700 ]; then
+        echo "OK: sync with $limit took $MILLIS ms"
+    else
+        echo "FAILED: sync with $limit took only $MILLIS ms"
+        exit 1
+    fi
+    assert_eq $F1 $F2
+done
+
+# Discarding zeroes only applies to block devices, a file still ends up
+# with the zeroes
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/zero of=$F1 bs=1000 seek=2 count=5 conv=notrunc
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+
+$SSDSYNC -b 1000 --discard-zeroes $F1 $F2
+
+assert_eq $F1 $F2
+
+# Holes punched for zero blocks make the target sparse
+
+dd if=/dev/zero of=$F1 bs=1M count=8
+dd if=/dev/urandom of=$F1 bs=1M count=1 conv=notrunc
+dd if=/dev/urandom of=$F2 bs=1M count=8
+
+$SSDSYNC -b 65536 --sparse $F1 $F2
+
+assert_eq $F1 $F2
+
+USED=$(du -k $F2 | cut -f1)
+if [ $USED -le 2048 ]; then
+    echo "OK: sparse target uses $USED KiB"
+else
+    echo "FAILED: sparse target uses $USED KiB"
+    exit 1
+fi
+
+# Size mismatch policies: fail, sync what both have, cut the target down
+# or grow it
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=12
+cp $F2 $F3
+
+if $SSDSYNC -b 1000 --size-mismatch error $F1 $F2; then
+    echo "FAILED: sizes that differ were accepted"
+    exit 1
+else
+    echo "OK: sizes that differ are refused"
+fi
+
+assert_eq $F2 $F3
+
+$SSDSYNC -b 1000 --size-mismatch sync-min $F1 $F2
+
+head -c 10000 $F2 > $F3
+assert_eq $F1 $F3
+
+$SSDSYNC -b 1000 --size-mismatch truncate $F1 $F2
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F1 bs=1000 count=15
+
+$SSDSYNC -b 1000 --size-mismatch extend $F1 $F2
+
+assert_eq $F1 $F2
+
+# Only a part of each side: 3000 bytes of F1 from 2000 on go onto F2 at
+# 5000, the rest of F2 stays as it was
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=2 seek=5 count=3 conv=notrunc
+
+$SSDSYNC -b 1000 --source-offset 2000 --target-offset 5000 --length 3000 $F1 $F2
+
+assert_eq $F2 $F3
+
+# Several targets from one read of the source, each one synced on its own
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+cp $F1 $F3
+dd if=/dev/urandom of=$F3 bs=1000 count=10 seek=50 conv=notrunc
+
+$SSDSYNC -b 1000 $F1 $F2 $F3
+
+assert_eq $F1 $F2
+assert_eq $F1 $F3
+
+# A remote target, with an ssh that runs the command right here
+
+SSH=$TESTPATH/ssh
+printf '#!/bin/sh\nshift\nexec sh -c "$*"\n' > $SSH
+chmod +x $SSH
+REMOTE="--ssh $SSH --remote-ssdsync $(realpath $SSDSYNC)"
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=10 seek=20 conv=notrunc
+
+$SSDSYNC -b 1000 $REMOTE $F1 user@localhost:$F2 > $TESTPATH/remote.out
+
+assert_eq $F1 $F2
+
+# Hashes of the two groups, and of the blocks of the first one, which differs
+if grep -q "^Hashes received: 2112 bytes" $TESTPATH/remote.out; then
+    echo "OK: only the differing group was hashed block by block"
+else
+    echo "FAILED: $(grep Hashes $TESTPATH/remote.out)"
+    exit 1
+fi
+
+# Compressed, zeroes take next to nothing
+
+dd if=/dev/zero of=$F1 bs=1000 count=100
+$SSDSYNC -b 1000 --compress $REMOTE $F1 user@localhost:$F2 > $TESTPATH/remote.out
+
+assert_eq $F1 $F2
+
+if grep -q "^Blocks sent: 100000 bytes" $TESTPATH/remote.out; then
+    echo "OK: $(grep Blocks $TESTPATH/remote.out)"
+else
+    echo "FAILED: no compressed blocks sent"
+    exit 1
+fi
+
+# A server claiming a message too long to hold is given up on
+
+if command -v python3 > /dev/null; then
+    python3 -c '
+import socket, struct, sys
+listener = socket.create_server(("127.0.0.1", 19485))
+conn = listener.accept()[0]
+conn.recv(1024)
+conn.sendall(b"F" + struct.pack("<I", 1 << 31))
+conn.recv(1024)
+' &
+    SERVER=$!
+    sleep 0.5
+    if $SSDSYNC -b 1000 $F1 tcp://127.0.0.1:19485 2> $TESTPATH/remote.err; then
+        echo "FAILED: a message of 2 GB was taken"
+        exit 1
+    elif grep -q "more than the 65536 there can be" $TESTPATH/remote.err; then
+        echo "OK: a message too long is refused"
+    else
+        echo "FAILED: $(cat $TESTPATH/remote.err)"
+        exit 1
+    fi
+    wait $SERVER
+fi
+
+# Groups too large for the server to hold are refused
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+cp $F2 $F3
+
+if $SSDSYNC -b 1000000 --remote-group 2000 $REMOTE $F1 user@localhost:$F2 2> $TESTPATH/remote.err; then
+    echo "FAILED: groups of 2 GB were served"
+    exit 1
+elif grep -q "Not serving groups of 2000 blocks" $TESTPATH/remote.err; then
+    echo "OK: groups too large are refused"
+else
+    echo "FAILED: $(cat $TESTPATH/remote.err)"
+    exit 1
+fi
+
+assert_eq $F2 $F3
+
+# The same over TCP, to a server that stops after one sync
+
+dd if=/dev/urandom of=$F2 bs=1000 count=10 seek=70 conv=notrunc
+
+$SSDSYNC serve --once --listen 127.0.0.1:19473 $F2 > $TESTPATH/serve.out &
+SERVER=$!
+until grep -q Serving $TESTPATH/serve.out; do sleep 0.1; done
+
+$SSDSYNC -b 1000 --hash xxh3 $F1 tcp://127.0.0.1:19473
+
+if wait $SERVER; then
+    echo "OK: the server finished"
+else
+    echo "FAILED: the server failed"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# And over TLS, with certificates for both sides
+
+if command -v openssl > /dev/null; then
+    T=$TESTPATH/tls
+    openssl req -x509 -newkey rsa:2048 -nodes -days 1 -subj /CN=ca \
+        -keyout $T-ca.key -out $T-ca.pem 2> /dev/null
+    for SIDE in server client; do
+        openssl req -newkey rsa:2048 -nodes -subj /CN=localhost \
+            -keyout $T-$SIDE.key -out $T-$SIDE.csr 2> /dev/null
+        printf "subjectAltName=DNS:localhost" > $T.ext
+        openssl x509 -req -days 1 -in $T-$SIDE.csr -CA $T-ca.pem -CAkey $T-ca.key \
+            -CAcreateserial -extfile $T.ext -out $T-$SIDE.pem 2> /dev/null
+    done
+
+    dd if=/dev/urandom of=$F2 bs=1000 count=10 seek=30 conv=notrunc
+
+    $SSDSYNC serve --once --listen 127.0.0.1:19474 --tls-cert $T-server.pem \
+        --tls-key $T-server.key --tls-ca $T-ca.pem $F2 > $TESTPATH/serve.out &
+    SERVER=$!
+    until grep -q Serving $TESTPATH/serve.out; do sleep 0.1; done
+
+    $SSDSYNC -b 1000 --tls-ca $T-ca.pem --tls-cert $T-client.pem --tls-key $T-client.key \
+        $F1 tls://localhost:19474
+
+    if wait $SERVER; then
+        echo "OK: the TLS server finished"
+    else
+        echo "FAILED: the TLS server failed"
+        exit 1
+    fi
+
+    assert_eq $F1 $F2
+fi
+
+# A patch of the differences, the target is left alone
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=3 seek=20 conv=notrunc
+cp $F2 $F3
+
+$SSDSYNC diff -b 1000 --output $TESTPATH/changes.ssdp $F1 $F2
+
+assert_eq $F2 $F3
+
+$SSDSYNC apply $TESTPATH/changes.ssdp $F3
+
+assert_eq $F1 $F3
+
+# A cut off patch is refused before anything is written
+
+cp $F2 $F3
+head -c 2000 $TESTPATH/changes.ssdp > $TESTPATH/cut.ssdp
+tail -c 24 $TESTPATH/changes.ssdp >> $TESTPATH/cut.ssdp
+
+if $SSDSYNC apply $TESTPATH/cut.ssdp $F3; then
+    echo "FAILED: a cut off patch was applied"
+    exit 1
+else
+    echo "OK: a cut off patch is refused"
+fi
+
+assert_eq $F2 $F3
+
+# Only a JSON document of the result with --json
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=2 seek=4 conv=notrunc
+
+OUT=$($SSDSYNC -b 1000 --json $F1 $F2)
+if echo "$OUT" | grep -q '^{.*"blocks_scanned":10,"blocks_different":2,"blocks_written":2,"bytes_written":2000,.*"errors":\[\]}$'; then
+    echo "OK: --json reports the result"
+else
+    echo "FAILED: --json printed $OUT"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Every block written is logged with -vvv
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=2 seek=4 conv=notrunc
+rm -f $TESTPATH/log
+
+$SSDSYNC -b 1000 -vvv --log-file $TESTPATH/log $F1 $F2
+
+if [ "$(grep -c 'Write offset=' $TESTPATH/log)" == 2 ] && grep -q 'Write offset=5000 ' $TESTPATH/log; then
+    echo "OK: the blocks written are logged"
+else
+    echo "FAILED: the log has $(grep -c 'Write offset=' $TESTPATH/log) blocks written"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# How far it is on SIGUSR1, on stderr
+
+dd if=/dev/urandom of=$F1 bs=1000 count=2000
+dd if=/dev/urandom of=$F2 bs=1000 count=2000
+
+$SSDSYNC -b 1000 --limit-rate 2M $F1 $F2 2> $TESTPATH/status &
+SYNC=$!
+sleep 0.5
+kill -USR1 $SYNC
+wait $SYNC
+
+if grep -aq "^At [0-9]* of 2000000 bytes after .*, different: [0-9]*, written: [0-9]* bytes" $TESTPATH/status; then
+    echo "OK: SIGUSR1 prints the status"
+else
+    echo "FAILED: no status on SIGUSR1"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Prometheus metrics while it runs
+
+if command -v curl > /dev/null; then
+    dd if=/dev/urandom of=$F1 bs=1000 count=2000
+    dd if=/dev/urandom of=$F2 bs=1000 count=2000
+
+    $SSDSYNC -b 1000 --limit-rate 2M --metrics-listen 127.0.0.1:19475 $F1 $F2 &
+    SYNC=$!
+    sleep 0.5
+    curl -s http://127.0.0.1:19475/metrics > $TESTPATH/metrics
+    wait $SYNC
+
+    if grep -q "^ssdsync_bytes_total 2000000$" $TESTPATH/metrics && grep -q "^ssdsync_blocks_diff [1-9]" $TESTPATH/metrics; then
+        echo "OK: the metrics are served"
+    else
+        echo "FAILED: the metrics served are $(cat $TESTPATH/metrics)"
+        exit 1
+    fi
+
+    assert_eq $F1 $F2
+fi
+
+# Statistics of the run in a file
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=2 seek=4 conv=notrunc
+rm -f $TESTPATH/stats.json
+
+$SSDSYNC -b 1000 --stats-file $TESTPATH/stats.json $F1 $F2
+
+if grep -q '"bytes_read":20000,"bytes_written":2000,"bytes_skipped":8000,.*"diff_ratio":0.200000,"retries":0,"bytes_unreadable":0}' $TESTPATH/stats.json; then
+    echo "OK: the statistics are written"
+else
+    echo "FAILED: the statistics are $(cat $TESTPATH/stats.json)"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# A bitmap of the blocks that differ
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=1 seek=1 conv=notrunc
+dd if=/dev/urandom of=$F2 bs=1000 count=2 seek=9 conv=notrunc
+
+$SSDSYNC -b 1000 --bitmap-out $TESTPATH/bitmap $F1 $F2
+
+BITS=$(od -An -tx1 -j 24 $TESTPATH/bitmap | tr -d ' \n')
+if [ "$BITS" == "020600" ]; then
+    echo "OK: the bitmap marks the blocks that differ"
+else
+    echo "FAILED: the bitmap is $BITS"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F2 bs=1000 count=1 seek=1 conv=notrunc
+dd if=/dev/urandom of=$F2 bs=1000 count=2 seek=9 conv=notrunc
+
+$SSDSYNC -b 1000 --bitmap-out $TESTPATH/bitmap --verify-after $F1 $F2
+
+BITS=$(od -An -tx1 -j 24 $TESTPATH/bitmap | tr -d ' \n')
+if [ "$BITS" == "020600" ]; then
+    echo "OK: the pass of --verify-after leaves the bitmap alone"
+else
+    echo "FAILED: the bitmap is $BITS after --verify-after"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Only the blocks a bmap maps
+
+dd if=/dev/urandom of=$F1 bs=4096 count=10
+dd if=/dev/urandom of=$F2 bs=4096 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=4096 count=2 conv=notrunc
+dd if=$F1 of=$F3 bs=4096 skip=5 seek=5 count=1 conv=notrunc
+cat > $TESTPATH/f1.bmap <<EOF
+<?xml version="1.0" ?>
+<bmap version="2.0">
+    <ImageSize> 40960 </ImageSize>
+    <BlockSize> 4096 </BlockSize>
+    <BlocksCnt> 10 </BlocksCnt>
+    <MappedBlocksCnt> 3 </MappedBlocksCnt>
+    <BlockMap>
+        <Range chksum="0"> 0-1 </Range>
+        <Range chksum="0"> 5 </Range>
+    </BlockMap>
+</bmap>
+EOF
+
+$SSDSYNC -b 4096 --bmap $TESTPATH/f1.bmap $F1 $F2
+
+assert_eq $F2 $F3
+
+# A ddrescue mapfile, what's finished or bad is left alone
+
+dd if=/dev/urandom of=$F1 bs=4096 count=10
+dd if=/dev/urandom of=$F2 bs=4096 count=10
+cp $F1 $F3
+dd if=$F2 of=$F3 bs=4096 count=3 conv=notrunc
+cat > $TESTPATH/mapfile <<EOF
+# current_pos  current_status  current_pass
+0x00003000     ?               1
+#      pos        size  status
+0x00000000  0x00002000  +
+0x00002000  0x00001000  -
+0x00003000  0x00007000  ?
+EOF
+
+$SSDSYNC -b 4096 --mapfile $TESTPATH/mapfile $F1 $F2
+
+assert_eq $F2 $F3
+
+REGIONS=$(grep -v '^#' $TESTPATH/mapfile | tail -n +2 | tr -s ' ' | tr '\n' ';')
+if [ "$REGIONS" == "0x00000000 0x00002000 +;0x00002000 0x00001000 -;0x00003000 0x00007000 +;" ]; then
+    echo "OK: the mapfile has the rest finished"
+else
+    echo "FAILED: the mapfile has $REGIONS"
+    exit 1
+fi
+
+# The pass of --verify-after doesn't take up the mapfile again, which is
+# all finished by then
+
+dd if=/dev/urandom of=$F2 bs=4096 count=10
+rm -f $TESTPATH/mapfile
+
+if $SSDSYNC -b 4096 --mapfile $TESTPATH/mapfile --badblocks-out $TESTPATH/badblocks --verify-after $F1 $F2; then
+    echo "OK: --mapfile works with --verify-after"
+else
+    echo "FAILED: --mapfile failed with --verify-after"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Nothing unreadable, nothing listed
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+
+$SSDSYNC -b 1000 --on-read-error fill=0xff --badblocks-out $TESTPATH/badblocks $F1 $F2
+
+assert_eq $F1 $F2
+
+if [ -f $TESTPATH/badblocks ] && [ ! -s $TESTPATH/badblocks ]; then
+    echo "OK: no unreadable ranges are listed"
+else
+    echo "FAILED: unreadable ranges are listed"
+    exit 1
+fi
+
+if $SSDSYNC --on-read-error fill=0x100 $F1 $F2 2>/dev/null; then
+    echo "FAILED: a fill byte out of range is taken"
+    exit 1
+else
+    echo "OK: a fill byte out of range is refused"
+fi
+
+# Every write read back
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 count=3 seek=4 conv=notrunc
+
+$SSDSYNC -b 1000 --verify-writes $F1 $F2
+
+assert_eq $F1 $F2
+
+# Manifests hashed with each algorithm
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+
+for ALGO in blake3 sha256 xxh3 crc32c; do
+    $SSDSYNC -b 1000 --hash $ALGO --write-manifest $TESTPATH/manifest $F1 $F2
+    if head -1 $TESTPATH/manifest | grep -q "manifest $ALGO 1000" && $SSDSYNC verify --source-manifest $TESTPATH/manifest $F2; then
+        echo "OK: a $ALGO manifest verifies the target"
+    else
+        echo "FAILED: a $ALGO manifest doesn't verify the target"
+        exit 1
+    fi
+done
+
+# Streaming from stdin and to stdout
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+dd if=/dev/urandom of=$F2 bs=1000 count=20
+
+gzip -c $F1 | zcat | $SSDSYNC -b 1000 - $F2
+
+assert_eq $F1 $F2
+
+$SSDSYNC -b 1000 $F1 - 2> $TESTPATH/stream.err | gzip -c > $TESTPATH/stream.gz
+zcat $TESTPATH/stream.gz > $F3
+
+assert_eq $F1 $F3
+
+if grep -q "Finished" $TESTPATH/stream.err; then
+    echo "OK: the messages of a sync to stdout go to stderr"
+else
+    echo "FAILED: no messages on stderr"
+    exit 1
+fi
+
+if $SSDSYNC --sparse $F1 - > /dev/null 2>&1; then
+    echo "FAILED: a sync to stdout took an option of the target"
+    exit 1
+else
+    echo "OK: a sync to stdout refuses options of the target"
+fi
+
+# Backing up into S3-compatible object storage
+
+if command -v python3 > /dev/null; then
+    # Objects are files under $TESTPATH/s3, no signatures checked
+    cat > $TESTPATH/s3.py <<'EOF'
+import http.server, os, sys
+root = sys.argv[2]
+class Handler(http.server.BaseHTTPRequestHandler):
+    def reply(self, status, body=b''):
+        self.send_response(status)
+        self.send_header('content-length', str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+    def do_PUT(self):
+        path = root + self.path
+        os.makedirs(os.path.dirname(path), exist_ok=True)
+        with open(path, 'wb') as f:
+            f.write(self.rfile.read(int(self.headers['content-length'])))
+        self.reply(200)
+    def do_GET(self):
+        if os.path.isfile(root + self.path):
+            self.reply(200, open(root + self.path, 'rb').read())
+        else:
+            self.reply(404)
+    def log_message(self, *args):
+        pass
+http.server.ThreadingHTTPServer(('127.0.0.1', int(sys.argv[1])), Handler).serve_forever()
+EOF
+    rm -rf $TESTPATH/s3
+    python3 $TESTPATH/s3.py 19481 $TESTPATH/s3 &
+    S3=$!
+    sleep 1
+
+    dd if=/dev/urandom of=$F1 bs=1000 count=20
+    $SSDSYNC -b 1000 --s3-endpoint http://127.0.0.1:19481 $F1 s3://backups/disk
+    dd if=/dev/urandom of=$F1 bs=1000 count=1 seek=7 conv=notrunc
+    $SSDSYNC -b 1000 --s3-endpoint http://127.0.0.1:19481 $F1 s3://backups/disk > $TESTPATH/s3.out
+    kill $S3
+
+    if grep -q "different: 1, stored: 1000 bytes in 1 objects" $TESTPATH/s3.out; then
+        echo "OK: only the blocks not in the bucket are stored"
+    else
+        echo "FAILED: storing the blocks said $(cat $TESTPATH/s3.out)"
+        exit 1
+    fi
+
+    rm -f $F3
+    grep -v "^#" $TESTPATH/s3/backups/disk/index | while read offset length hash; do
+        cat $TESTPATH/s3/backups/disk/blocks/$hash >> $F3
+    done
+
+    assert_eq $F1 $F3
+fi
+
+# Downloading the source in Range requests
+
+if command -v python3 > /dev/null; then
+    # Serves the files in its directory, a Range of them too
+    cat > $TESTPATH/ranges.py <<'EOF'
+import http.server, os, sys
+os.chdir(sys.argv[2])
+class Handler(http.server.BaseHTTPRequestHandler):
+    def do_GET(self):
+        data = open('.' + self.path, 'rb').read()
+        start, end, status = 0, len(data) - 1, 200
+        if 'range' in self.headers:
+            first, last = self.headers['range'][len('bytes='):].split('-')
+            start, end, status = int(first), min(int(last), len(data) - 1), 206
+        self.send_response(status)
+        self.send_header('content-length', str(end - start + 1))
+        if status == 206:
+            self.send_header('content-range', 'bytes %d-%d/%d' % (start, end, len(data)))
+        self.end_headers()
+        self.wfile.write(data[start:end + 1])
+    def log_message(self, *args):
+        pass
+http.server.ThreadingHTTPServer(('127.0.0.1', int(sys.argv[1])), Handler).serve_forever()
+EOF
+    rm -rf $TESTPATH/www
+    mkdir $TESTPATH/www
+    python3 $TESTPATH/ranges.py 19482 $TESTPATH/www &
+    WWW=$!
+    sleep 1
+
+    dd if=/dev/urandom of=$TESTPATH/www/image.raw bs=1000 count=20
+    dd if=/dev/urandom of=$F2 bs=1000 count=20
+
+    $SSDSYNC -b 1000 http://127.0.0.1:19482/image.raw $F2
+
+    assert_eq $TESTPATH/www/image.raw $F2
+
+    # A new image, published with its manifest
+    dd if=/dev/urandom of=$TESTPATH/www/image.raw bs=1000 count=2 seek=5 conv=notrunc
+    $SSDSYNC -b 1000 --write-manifest $TESTPATH/www/image.manifest $TESTPATH/www/image.raw $F3
+    $SSDSYNC --expect-source-manifest http://127.0.0.1:19482/image.manifest \
+        http://127.0.0.1:19482/image.raw $F2 > $TESTPATH/www.out
+    kill $WWW
+
+    if grep -q "^18000 bytes the target had already weren't downloaded" $TESTPATH/www.out; then
+        echo "OK: only the blocks the target doesn't have are downloaded"
+    else
+        echo "FAILED: the download said $(cat $TESTPATH/www.out)"
+        exit 1
+    fi
+
+    assert_eq $TESTPATH/www/image.raw $F2
+fi
+
+# Reading the content of a qcow2 image
+
+if command -v python3 > /dev/null; then
+    # An image of 64k clusters with data, unallocated, zero and compressed
+    # ones, the last one half in the content, and the raw content
+    cat > $TESTPATH/qcow2.py <<'EOF'
+import os, struct, sys, zlib
+C = 65536
+size = 7 * C + C // 2
+text = b''.join(b'line %d of the compressed cluster\n' % i for i in range(4000))[:C]
+clusters = [os.urandom(C), None, 'zero', text, os.urandom(C), text[::-1], None, os.urandom(C)]
+layout = bytearray(5 * C)
+raw = bytearray(size)
+l2 = [0] * (C // 8)
+for i, data in enumerate(clusters):
+    if data is None or data == 'zero':
+        l2[i] = 1 if data == 'zero' else 0
+        continue
+    raw[i * C:(i + 1) * C] = data[:size - i * C]
+    if i in (3, 5):
+        deflate = zlib.compressobj(9, zlib.DEFLATED, -12)
+        packed = deflate.compress(data) + deflate.flush()
+        offset = len(layout) + 100
+        layout += bytes(100) + packed
+        sectors = (offset + len(packed) - 1) // 512 - offset // 512
+        l2[i] = 1 << 62 | sectors << 54 | offset
+    else:
+        layout += bytes(-len(layout) % C)
+        l2[i] = 1 << 63 | len(layout)
+        layout += data
+layout += bytes(-len(layout) % C)
+header = struct.pack('>4sIQIIQIIQQIIQQQQII', b'QFI\xfb', 3, 0, 0, 16, size, 0, 1, C,
+                     2 * C, 1, 0, 0, 0, 0, 0, 4, 104)
+layout[:len(header)] = header
+layout[C:C + 8] = struct.pack('>Q', 1 << 63 | 4 * C)
+layout[2 * C:2 * C + 8] = struct.pack('>Q', 3 * C)
+for i in range(len(layout) // C):
+    layout[3 * C + 2 * i:3 * C + 2 * i + 2] = struct.pack('>H', 1)
+layout[4 * C:5 * C] = struct.pack('>%dQ' % len(l2), *l2)
+open(sys.argv[1], 'wb').write(layout)
+open(sys.argv[2], 'wb').write(raw)
+EOF
+    python3 $TESTPATH/qcow2.py $TESTPATH/image.qcow2 $F1
+    dd if=/dev/urandom of=$F2 bs=4096 count=120
+
+    $SSDSYNC -b 4096 qcow2:$TESTPATH/image.qcow2 $F2
+
+    assert_eq $F1 $F2
+
+    if $SSDSYNC qcow2:$F1 $F2 > /dev/null 2>&1; then
+        echo "FAILED: a raw image was read as qcow2"
+        exit 1
+    else
+        echo "OK: a raw image isn't read as qcow2"
+    fi
+
+    # An L1 table said to be larger than the image
+    cp $TESTPATH/image.qcow2 $F3
+    printf '\xff\xff\xff\xff' | dd of=$F3 bs=1 seek=36 conv=notrunc 2> /dev/null
+    $SSDSYNC qcow2:$F3 $F2 2> $TESTPATH/image.err
+    STATUS=$?
+    if [ $STATUS == 3 ] && grep -q "L1 table of $F3 is past its end" $TESTPATH/image.err; then
+        echo "OK: an L1 table past the end is refused"
+    else
+        echo "FAILED: exit status $STATUS for an L1 table past the end: $(cat $TESTPATH/image.err)"
+        exit 1
+    fi
+fi
+
+# Reading the content of VHD, VHDX and VMDK images
+
+if command -v python3 > /dev/null; then
+    # The same content in every layout, with blocks and grains of zeroes
+    # left out, and the raw content
+    cat > $TESTPATH/images.py <<'EOF'
+import os, struct, sys, zlib
+M, S, G = 1 << 20, 512, 65536
+size = 3 * M + M // 2
+text = b''.join(b'line %d of the last block\n' % i for i in range(40000))
+raw = os.urandom(M) + bytes(M) + os.urandom(M // 2) + bytes(G) + os.urandom(M // 2 - G) + text[:M // 2]
+out = sys.argv[2]
+open(sys.argv[1], 'wb').write(raw)
+blocks = [raw[i:i + M].ljust(M, b'\0') for i in range(0, size, M)]
+
+def pad(data, to):
+    return data + bytes(-len(data) % to)
+
+# VHD, fixed and dynamic
+def footer(kind, offset):
+    f = bytearray(struct.pack('>8sIIQI4sIIQQIII', b'conectix', 2, 0x10000, offset, 0, b'ssds',
+                              0x10000, 0, size, size, 0, kind, 0).ljust(S, b'\0'))
+    f[64:68] = struct.pack('>I', ~sum(f) & 0xffffffff)
+    return bytes(f)
+open(out + '/fixed.vhd', 'wb').write(raw + footer(2, 2**64 - 1))
+image = bytearray(footer(3, S))
+header = struct.pack('>8sQQIII', b'cxsparse', 2**64 - 1, 3 * S, 0x10000, len(blocks), M)
+image += header.ljust(1024, b'\0')
+bat = [0xffffffff] * len(blocks)
+data = b''
+for i, block in enumerate(blocks):
+    if block.strip(b'\0'):
+        bat[i] = (3 * S + S + len(data)) // S
+        data += b'\xff' * S + block
+image += pad(struct.pack('>%dI' % len(bat), *bat), S) + data + footer(3, S)
+open(out + '/dynamic.vhd', 'wb').write(image)
+
+# VHDX, blocks of 1M from 3M on
+poly = 0x82f63b78
+table = []
+for n in range(256):
+    for _ in range(8):
+        n = n >> 1 ^ (poly if n & 1 else 0)
+    table.append(n)
+def crc32c(data):
+    crc = 0xffffffff
+    for b in data:
+        crc = table[(crc ^ b) & 0xff] ^ crc >> 8
+    return crc ^ 0xffffffff
+def signed(data):
+    data = bytearray(data)
+    data[4:8] = struct.pack('<I', crc32c(data))
+    return bytes(data)
+def guid(text):
+    b = bytes.fromhex(text.replace('-', ''))
+    return b[3::-1] + b[5:3:-1] + b[7:5:-1] + b[8:]
+image = bytearray(6 * M)
+image[:8] = b'vhdxfile'
+for at, seq in ((64 * 1024, 1), (128 * 1024, 2)):
+    head = struct.pack('<4sIQ16s16s16sHHIQ', b'head', 0, seq, os.urandom(16), os.urandom(16),
+                       bytes(16), 0, 1, M, M)
+    image[at:at + 4096] = signed(head.ljust(4096, b'\0'))
+regions = struct.pack('<4sIII', b'regi', 0, 2, 0)
+regions += guid('2DC27766-F623-4200-9D64-115E9BFD4A08') + struct.pack('<QII', 2 * M, M, 1)
+regions += guid('8B7CA206-4790-4B9A-B8FE-575F050F886E') + struct.pack('<QII', M, M, 1)
+image[192 * 1024:256 * 1024] = signed(regions.ljust(64 * 1024, b'\0'))
+items = [('CAA16737-FA36-4D43-B3B6-33F0AA44E76B', struct.pack('<II', M, 0)),
+         ('2FA54224-CD1B-4876-B211-5DBED83BF4B8', struct.pack('<Q', size)),
+         ('BECA12AB-B2E6-4523-93EF-C309E000C746', os.urandom(16)),
+         ('8141BF1D-A96F-4709-BA47-F233A8FAAB5F', struct.pack('<I', S)),
+         ('CDA348C7-445D-4471-9CC9-E9885251C556', struct.pack('<I', 4096))]
+metadata = struct.pack('<8sHH20x', b'metadata', 0, len(items))
+at = 64 * 1024
+for i, (id, value) in enumerate(items):
+    metadata += guid(id) + struct.pack('<IIII', at, len(value), 4 | 2 * (i != 0), 0)
+    image[M + at:M + at + len(value)] = value
+    at += 4096
+image[M:M + len(metadata)] = metadata
+bat = []
+for i, block in enumerate(blocks):
+    if block.strip(b'\0'):
+        bat.append(len(image) | 6)
+        image += block
+    else:
+        bat.append(2)
+image[2 * M:2 * M + 8 * len(bat)] = struct.pack('<%dQ' % len(bat), *bat)
+open(out + '/image.vhdx', 'wb').write(image)
+
+# VMDK, sparse and stream optimized, grains of 64k
+def vmdk(stream):
+    descriptor = pad(('# Disk DescriptorFile\nversion=1\nCID=12345678\nparentCID=ffffffff\n'
+                      'createType="%s"\nRW %d SPARSE "image.vmdk"\n'
+                      % ('streamOptimized' if stream else 'monolithicSparse', size // S)).encode(), S)
+    flags = 1 | 3 << 16 if stream else 1 | 4
+    def header(gd):
+        return struct.pack('<4sIIQQQQIQQQB4sH', b'KDMV', 3 if stream else 1, flags, size // S,
+                           G // S, 1, len(descriptor) // S, 512, 0, gd, 0, 0, b'\n \r\n',
+                           int(stream)).ljust(S, b'\0')
+    image = bytearray(S) + descriptor
+    if not stream:
+        gd_at = len(image)
+        image += bytes(S) + bytes(4 * S)
+    gt = []
+    for i in range(0, size, G):
+        grain = raw[i:i + G]
+        if not grain.strip(b'\0'):
+            gt.append(1 if 2 * M <= i < 3 * M and not stream else 0)
+            continue
+        gt.append(len(image) // S)
+        if stream:
+            packed = zlib.compress(grain)
+            image += pad(struct.pack('<QI', i // S, len(packed)) + packed, S)
+        else:
+            image += grain
+    gt = struct.pack('<512I', *(gt + [0] * (512 - len(gt))))
+    if stream:
+        image += struct.pack('<QII', 4, 0, 1).ljust(S, b'\0')
+        gt_at = len(image) // S
+        image += gt
+        image += struct.pack('<QII', 1, 0, 2).ljust(S, b'\0')
+        gd_at = len(image)
+        image += pad(struct.pack('<I', gt_at), S)
+        image += struct.pack('<QII', 1, 0, 3).ljust(S, b'\0')
+        image += header(gd_at // S) + bytes(S)
+        image[:S] = header(2**64 - 1)
+    else:
+        image[gd_at:gd_at + 4] = struct.pack('<I', gd_at // S + 1)
+        image[gd_at + S:gd_at + 5 * S] = gt
+        image[:S] = header(gd_at // S)
+    return image
+open(out + '/image.vmdk', 'wb').write(vmdk(False))
+open(out + '/stream.vmdk', 'wb').write(vmdk(True))
+EOF
+    rm -rf $TESTPATH/images
+    mkdir $TESTPATH/images
+    python3 $TESTPATH/images.py $F1 $TESTPATH/images
+
+    for image in vhd:fixed.vhd vhd:dynamic.vhd vhdx:image.vhdx vmdk:image.vmdk vmdk:stream.vmdk; do
+        dd if=/dev/urandom of=$F2 bs=4096 count=896
+        $SSDSYNC -b 4096 ${image%%:*}:$TESTPATH/images/${image#*:} $F2
+        assert_eq $F1 $F2
+    done
+
+    # Headers with sizes that overflow or go past the end are refused
+    python3 -c '
+import shutil, struct, sys
+def corrupt(name, copy, at, value):
+    image = bytearray(open(sys.argv[1] + "/" + name, "rb").read())
+    at = at if at >= 0 else len(image) + at
+    image[at:at + len(value)] = value
+    open(sys.argv[1] + "/" + copy, "wb").write(image)
+corrupt("fixed.vhd", "huge.vhd", -512 + 48, struct.pack(">Q", 2**64 - 256))
+corrupt("dynamic.vhd", "table.vhd", 512 + 28, struct.pack(">I", 2**32 - 1))
+corrupt("image.vmdk", "descriptor.vmdk", 36, struct.pack("<Q", 2**60))
+corrupt("image.vmdk", "grain.vmdk", 20, struct.pack("<Q", 2**60))
+' $TESTPATH/images
+    for image in vhd:huge.vhd vhd:table.vhd vmdk:descriptor.vmdk vmdk:grain.vmdk; do
+        $SSDSYNC -b 4096 ${image%%:*}:$TESTPATH/images/${image#*:} $F2 2> $TESTPATH/image.err
+        STATUS=$?
+        if [ $STATUS == 3 ] && ! grep -q panicked $TESTPATH/image.err; then
+            echo "OK: $image is refused"
+        else
+            echo "FAILED: exit status $STATUS for $image: $(cat $TESTPATH/image.err)"
+            exit 1
+        fi
+    done
+fi
+
+# Syncing from and onto an NBD export
+
+if command -v python3 > /dev/null; then
+    # Serves a file as its only export, one connection at a time
+    cat > $TESTPATH/nbd.py <<'EOF'
+import socket, struct, sys
+def recv(conn, n):
+    data = b''
+    while len(data) < n:
+        chunk = conn.recv(n - len(data))
+        if not chunk:
+            raise EOFError
+        data += chunk
+    return data
+listener = socket.create_server(('127.0.0.1', int(sys.argv[1])))
+while True:
+    conn = listener.accept()[0]
+    image = open(sys.argv[2], 'r+b')
+    size = image.seek(0, 2)
+    try:
+        conn.sendall(b'NBDMAGICIHAVEOPT' + struct.pack('>H', 3))
+        recv(conn, 4)
+        while True:
+            _, option, length = struct.unpack('>QII', recv(conn, 16))
+            recv(conn, length)
+            if option == 7:
+                break
+            conn.sendall(struct.pack('>QIII', 0x3e889045565a9, option, 1 << 31 | 1, 0))
+        info = struct.pack('>HQH', 0, size, 1 | 4)
+        conn.sendall(struct.pack('>QIII', 0x3e889045565a9, 7, 3, len(info)) + info)
+        conn.sendall(struct.pack('>QIII', 0x3e889045565a9, 7, 1, 0))
+        while True:
+            _, _, kind, handle, offset, length = struct.unpack('>IHHQQI', recv(conn, 28))
+            if kind == 2:
+                break
+            reply = struct.pack('>IIQ', 0x67446698, 0, handle)
+            if kind == 0:
+                image.seek(offset)
+                reply += image.read(length)
+            elif kind == 1:
+                image.seek(offset)
+                image.write(recv(conn, length))
+            elif kind == 3:
+                image.flush()
+            conn.sendall(reply)
+    except EOFError:
+        pass
+    image.close()
+    conn.close()
+EOF
+    dd if=/dev/urandom of=$F1 bs=1000 count=20
+    dd if=/dev/urandom of=$F3 bs=1000 count=20
+    python3 $TESTPATH/nbd.py 19483 $F3 &
+    NBD=$!
+    sleep 1
+
+    $SSDSYNC -b 1000 $F1 nbd://127.0.0.1:19483/disk
+
+    assert_eq $F1 $F3
+
+    dd if=/dev/urandom of=$F2 bs=1000 count=20
+    $SSDSYNC -b 1000 nbd://127.0.0.1:19483/disk $F2
+    kill $NBD
+
+    assert_eq $F3 $F2
+fi
+
+# Exported read-only by ssdsync nbd-serve, for the other end to sync from
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+dd if=/dev/urandom of=$F2 bs=1000 count=20
+$SSDSYNC nbd-serve --listen 127.0.0.1:19484 $F1 > $TESTPATH/nbd-serve.out &
+NBD=$!
+until grep -q Exporting $TESTPATH/nbd-serve.out; do sleep 0.1; done
+
+$SSDSYNC -b 1000 nbd://127.0.0.1:19484/ $F2
+
+assert_eq $F1 $F2
+
+cp $F1 $F3
+dd if=/dev/urandom of=$F2 bs=1000 count=1 seek=5 conv=notrunc
+if $SSDSYNC -b 1000 $F2 nbd://127.0.0.1:19484/; then
+    echo "FAILED: synced onto a read-only export"
+    exit 1
+else
+    echo "OK: a read-only export isn't synced onto"
+fi
+kill $NBD
+
+assert_eq $F1 $F3
+
+# Synced from a snapshot of an LV, with stand-ins for the LVM commands that
+# take a copy for the snapshot and log what they were asked
+
+LVM=$TESTPATH/lvm
+mkdir -p $LVM
+cat > $LVM/lvs <<EOF
+#!/bin/bash
+case "\$*" in
+    *vg_name*) echo "  vg0 data linear" ;;
+    *lv_path*) echo "  $LVM/snapshot" ;;
+    *lv_attr*) echo "  swi-\${LV_STATE:-a}-s---" ;;
+esac
+EOF
+cat > $LVM/lvcreate <<EOF
+#!/bin/bash
+echo "lvcreate \$*" >> $LVM/log
+cp $F1 $LVM/snapshot
+EOF
+cat > $LVM/lvremove <<EOF
+#!/bin/bash
+echo "lvremove \$*" >> $LVM/log
+rm $LVM/snapshot
+EOF
+chmod +x $LVM/lvs $LVM/lvcreate $LVM/lvremove
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+dd if=/dev/urandom of=$F2 bs=1000 count=20
+PATH=$LVM:$PATH $SSDSYNC -b 1000 --snapshot-source lvm:64M $F1 $F2
+
+assert_eq $F1 $F2
+
+if grep -q "lvcreate --snapshot --name data-ssdsync-[0-9]* --size 67108864b" $LVM/log \
+    && grep -q "lvremove --yes vg0/data-ssdsync-" $LVM/log && [ ! -e $LVM/snapshot ]; then
+    echo "OK: the snapshot was taken and removed"
+else
+    echo "FAILED: the snapshot wasn't taken and removed"
+    cat $LVM/log
+    exit 1
+fi
+
+# One that ran out of room fails the sync, and is removed all the same
+dd if=/dev/urandom of=$F1 bs=1000 count=1 seek=3 conv=notrunc
+LV_STATE=I PATH=$LVM:$PATH $SSDSYNC -b 1000 --snapshot-source lvm $F1 $F2
+STATUS=$?
+if [ $STATUS == 4 ] && grep -q -- "--extents 10%ORIGIN" $LVM/log && [ ! -e $LVM/snapshot ]; then
+    echo "OK: an overflowed snapshot fails the sync"
+else
+    echo "FAILED: exit status $STATUS with an overflowed snapshot"
+    exit 1
+fi
+
+# A mounted filesystem frozen while its device is read, where loop devices
+# can be mounted
+
+MNT=$TESTPATH/mnt
+mkdir -p $MNT
+truncate -s 8M $TESTPATH/fs.img
+if mkfs.ext4 -q $TESTPATH/fs.img 2> /dev/null \
+    && DEV=$(losetup -f --show $TESTPATH/fs.img 2> /dev/null) \
+    && mount $DEV $MNT 2> /dev/null; then
+    dd if=/dev/urandom of=$MNT/data bs=1000 count=100 2> /dev/null
+    truncate -s 8M $F2
+    $SSDSYNC --fsfreeze auto $DEV $F2 > $TESTPATH/freeze.out
+
+    # Thawing updates the superblock, everything after it is the same, and
+    # the copy is clean
+    if cmp -s -i 2048 $DEV $F2 && e2fsck -fn $F2 > /dev/null 2>&1; then
+        echo "OK: the frozen filesystem was copied clean"
+    else
+        echo "FAILED: the frozen filesystem wasn't copied clean"
+        exit 1
+    fi
+
+    if grep -q "Froze $MNT." $TESTPATH/freeze.out && grep -q "Thawed $MNT." $TESTPATH/freeze.out; then
+        echo "OK: the filesystem was frozen and thawed"
+    else
+        echo "FAILED: the filesystem wasn't frozen and thawed"
+        cat $TESTPATH/freeze.out
+        exit 1
+    fi
+
+    # Thawed before the sync is done, it takes about 4 seconds
+    $SSDSYNC --limit-rate 2M --fsfreeze $MNT --fsfreeze-timeout 1 $DEV $F2 > $TESTPATH/freeze.out
+    if grep -q "Thawed $MNT after 1 seconds" $TESTPATH/freeze.out; then
+        echo "OK: the filesystem was thawed after the timeout"
+    else
+        echo "FAILED: the filesystem wasn't thawed after the timeout"
+        cat $TESTPATH/freeze.out
+        exit 1
+    fi
+
+    $SSDSYNC --fsfreeze $MNT $DEV $MNT/copy
+    STATUS=$?
+    if [ $STATUS == 2 ] && [ ! -e $MNT/copy ]; then
+        echo "OK: a target on the frozen filesystem is refused"
+    else
+        echo "FAILED: exit status $STATUS with a target on the frozen filesystem"
+        exit 1
+    fi
+
+    # As is anything else written along the way, like a checkpoint
+    $SSDSYNC --fsfreeze $MNT --checkpoint $MNT/checkpoint $DEV $F2 > $TESTPATH/freeze.out 2>&1
+    STATUS=$?
+    if [ $STATUS == 2 ] && grep -q "$MNT/checkpoint is on $MNT" $TESTPATH/freeze.out; then
+        echo "OK: a checkpoint on the frozen filesystem is refused"
+    else
+        echo "FAILED: exit status $STATUS with a checkpoint on the frozen filesystem"
+        exit 1
+    fi
+
+    # Not written while it's mounted
+    $SSDSYNC $F1 $DEV > $TESTPATH/mounted.out 2>&1
+    STATUS=$?
+    if [ $STATUS == 2 ] && grep -q "$DEV is mounted on $MNT" $TESTPATH/mounted.out; then
+        echo "OK: a mounted target is refused"
+    else
+        echo "FAILED: exit status $STATUS with a mounted target"
+        cat $TESTPATH/mounted.out
+        exit 1
+    fi
+
+    # Nor with --force, the kernel doesn't open it exclusively
+    $SSDSYNC --force $F1 $DEV > $TESTPATH/mounted.out 2>&1
+    STATUS=$?
+    if [ $STATUS == 2 ] && grep -q "won't open it exclusively" $TESTPATH/mounted.out; then
+        echo "OK: a mounted target isn't opened exclusively"
+    else
+        echo "FAILED: exit status $STATUS with a mounted target and --force"
+        cat $TESTPATH/mounted.out
+        exit 1
+    fi
+    $SSDSYNC --excl-source $DEV $F2 > $TESTPATH/mounted.out 2>&1
+    STATUS=$?
+    if [ $STATUS == 2 ] && grep -q "Leave out --excl-source" $TESTPATH/mounted.out; then
+        echo "OK: a mounted source isn't opened exclusively"
+    else
+        echo "FAILED: exit status $STATUS with a mounted source and --excl-source"
+        cat $TESTPATH/mounted.out
+        exit 1
+    fi
+    umount $MNT
+
+    # Once it's unmounted, it's opened exclusively for the whole sync
+    $SSDSYNC --limit-rate 4M $F2 $DEV > /dev/null &
+    SYNC=$!
+    sleep 1
+    if mount $DEV $MNT 2> /dev/null; then
+        echo "FAILED: mounted while it was synced"
+        umount $MNT
+        exit 1
+    else
+        echo "OK: not mounted while it was synced"
+    fi
+    wait $SYNC
+    assert_eq $F2 $DEV
+    losetup -d $DEV
+fi
+
+# The source under another name, by a link to it, isn't a target
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20
+ln -sf $F1 $TESTPATH/symlink
+ln -f $F1 $TESTPATH/hardlink
+for LINK in $TESTPATH/symlink $TESTPATH/hardlink; do
+    $SSDSYNC $F1 $LINK > $TESTPATH/same.out 2>&1
+    STATUS=$?
+    if [ $STATUS == 2 ] && grep -q "is the source $F1" $TESTPATH/same.out; then
+        echo "OK: $LINK is refused as the source"
+    else
+        echo "FAILED: exit status $STATUS with $LINK"
+        cat $TESTPATH/same.out
+        exit 1
+    fi
+done
+rm $TESTPATH/symlink $TESTPATH/hardlink
+
+# A second sync onto the same target fails while the first one runs, or
+# waits for it with --wait-lock
+
+dd if=/dev/urandom of=$F1 bs=1M count=4 2> /dev/null
+dd if=/dev/urandom of=$F2 bs=1M count=4 2> /dev/null
+$SSDSYNC --limit-rate 4M $F1 $F2 > /dev/null &
+FIRST=$!
+sleep 0.5
+ln -sf $F2 $TESTPATH/symlink
+$SSDSYNC $F1 $TESTPATH/symlink > $TESTPATH/lock.out 2>&1
+STATUS=$?
+if [ $STATUS == 2 ] && grep -q "Another ssdsync (pid $FIRST) is syncing onto" $TESTPATH/lock.out; then
+    echo "OK: a second sync onto the target is refused"
+else
+    echo "FAILED: exit status $STATUS with a second sync onto the target"
+    cat $TESTPATH/lock.out
+    exit 1
+fi
+$SSDSYNC --wait-lock $F1 $F2 > $TESTPATH/lock.out
+if grep -q "Waiting for the other sync" $TESTPATH/lock.out \
+    && grep -q "different: 0" $TESTPATH/lock.out; then
+    echo "OK: --wait-lock waited for the first sync"
+else
+    echo "FAILED: --wait-lock didn't wait for the first sync"
+    cat $TESTPATH/lock.out
+    exit 1
+fi
+wait $FIRST
+rm $TESTPATH/symlink
+
+assert_eq $F1 $F2
+
+# Options from a configuration file, with a profile by its name or by its
+# source and target, and the command line's winning over them
+
+CONFIG=$TESTPATH/config.toml
+cat > $CONFIG <<EOF
+block-size = "500"
+hash = "sha256"
+
+[profiles.pair]
+source = "$F1"
+target = "$F2"
+block-size = 1000
+EOF
+dd if=/dev/urandom of=$F1 bs=1000 count=20 2> /dev/null
+dd if=/dev/urandom of=$F2 bs=1000 count=20 2> /dev/null
+for RUN in "--profile pair:Total: 20," ":Total: 20," "-b 2000 --profile pair:Total: 10,"; do
+    OPTIONS=${RUN%%:*}
+    EXPECTED=${RUN#*:}
+    if [ "$OPTIONS" == "--profile pair" ]; then
+        $SSDSYNC --config $CONFIG $OPTIONS > $TESTPATH/config.out
+    else
+        $SSDSYNC --config $CONFIG $OPTIONS $F1 $F2 > $TESTPATH/config.out
+    fi
+    if grep -q "$EXPECTED" $TESTPATH/config.out; then
+        echo "OK: ${OPTIONS:-the profile of the pair} synced with $EXPECTED"
+    else
+        echo "FAILED: ${OPTIONS:-the profile of the pair} didn't sync with $EXPECTED"
+        cat $TESTPATH/config.out
+        exit 1
+    fi
+done
+
+assert_eq $F1 $F2
+
+# Without a profile, the options for every sync
+dd if=/dev/urandom of=$F3 bs=1000 count=20 2> /dev/null
+$SSDSYNC --config $CONFIG $F1 $F3 > $TESTPATH/config.out
+if grep -q "Total: 40," $TESTPATH/config.out; then
+    echo "OK: synced with the options for every sync"
+else
+    echo "FAILED: didn't sync with the options for every sync"
+    cat $TESTPATH/config.out
+    exit 1
+fi
+
+# The sync subcommand is the same as none, the hash subcommand hashes a
+# source and writes a manifest of it that verify checks a target against
+
+dd if=/dev/urandom of=$F1 bs=1000 count=20 2> /dev/null
+dd if=/dev/urandom of=$F2 bs=1000 count=20 2> /dev/null
+$SSDSYNC sync -b 1000 $F1 $F2
+
+assert_eq $F1 $F2
+
+HASH=$($SSDSYNC hash --hash sha256 -b 1000 --write-manifest $TESTPATH/manifest $F1 | tail -1)
+if [ "$HASH" == "$(sha256sum $F1)" ]; then
+    echo "OK: hashed like sha256sum"
+else
+    echo "FAILED: hashed as $HASH"
+    exit 1
+fi
+if $SSDSYNC verify --source-manifest $TESTPATH/manifest $F2; then
+    echo "OK: the target matches the manifest of the source"
+else
+    echo "FAILED: the target doesn't match the manifest of the source"
+    exit 1
+fi
+
+# Both sides mapped, resumed in the middle too, and an empty pair, which
+# can't be mapped
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 3000 --io-backend mmap $F1 $F2
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=5 seek=5 conv=notrunc
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 10000 5000\n' > $TESTPATH/checkpoint
+
+$SSDSYNC -b 1000 --io-backend mmap --checkpoint $TESTPATH/checkpoint --resume $F1 $F2
+
+assert_eq $F2 $F3
+
+: > $F1
+: > $F2
+if $SSDSYNC --io-backend mmap $F1 $F2; then
+    echo "OK: an empty pair is synced mapped"
+else
+    echo "FAILED: an empty pair could not be synced mapped"
+    exit 1
+fi
+
+# Regions synced at the same time, with what's left over in the last one,
+# within a window too, and an option that needs the sync in one piece
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 4096 --jobs 3 $F1 $F2
+
+assert_eq $F1 $F2
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=10 seek=20 count=50 conv=notrunc
+
+$SSDSYNC -b 1000 -j 4 --source-offset 10000 --target-offset 20000 --length 50000 $F1 $F2
+
+assert_eq $F2 $F3
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1000
+dd if=/dev/urandom of=$F2 bs=1000 count=1000
+
+$SSDSYNC -b 4096 -j 3 --verify-after $F1 $F2
+
+assert_eq $F1 $F2
+
+printf 'xx' | dd of=$F2 bs=1 seek=900000 conv=notrunc
+if $SSDSYNC -b 4096 -j 3 --reference target $F1 $F2; then
+    echo "FAILED: a region deviating from the reference passed"
+    exit 1
+else
+    echo "OK: a region deviating from the reference fails the check"
+fi
+
+# Each region of --jobs is resumed from its own stripe of the checkpoint
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+dd if=$F1 of=$F3 bs=1000 skip=2 seek=2 count=3 conv=notrunc
+dd if=$F1 of=$F3 bs=1000 skip=8 seek=8 conv=notrunc
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 5000 2000\nstripe 5000 10000 8000\n' > $TESTPATH/checkpoint
+
+$SSDSYNC -b 1000 -j 2 --checkpoint $TESTPATH/checkpoint --resume $F1 $F2
+
+assert_eq $F2 $F3
+
+if [ -e $TESTPATH/checkpoint ]; then
+    echo "FAILED: the checkpoint of the regions was left behind"
+    exit 1
+else
+    echo "OK: the checkpoint of the regions is removed once they're done"
+fi
+
+printf '# ssdsync checkpoint\nnext 0\nstripe 0 10000 5000\n' > $TESTPATH/checkpoint
+if $SSDSYNC -b 1000 -j 2 --checkpoint $TESTPATH/checkpoint --resume $F1 $F2 2>&1 | grep -q "this sync has 2 stripes"; then
+    echo "OK: a checkpoint of another layout isn't resumed with --jobs"
+else
+    echo "FAILED: a checkpoint of another layout was resumed with --jobs"
+    exit 1
+fi
+
+# Nothing of the pair is left in the page cache with --drop-cache. The
+# files are on the disk the build is, the pages of tmpfs can't be dropped.
+
+D1=./target/drop-cache-1
+D2=./target/drop-cache-2
+dd if=/dev/urandom of=$D1 bs=1M count=100
+dd if=/dev/urandom of=$D2 bs=1M count=50
+dd if=$D1 of=$D2 bs=1M skip=50 seek=50 count=50
+# Or what dd wrote would still be waiting to be written back
+sync
+
+$SSDSYNC -b 1M --drop-cache $D1 $D2
+
+# Checked before comparing them, which reads them into the cache again
+if ! command -v fincore > /dev/null; then
+    echo "OK: fincore isn't there to tell what's cached"
+elif [ "$(fincore --bytes --noheadings -o RES $D1 $D2 | awk '{ s += $1 } END { print s }')" -lt 1048576 ]; then
+    echo "OK: --drop-cache leaves nothing of the pair cached"
+else
+    echo "FAILED: --drop-cache left the pair cached"
+    fincore $D1 $D2
+    exit 1
+fi
+
+assert_eq $D1 $D2
+rm -f $D1 $D2
+
+if $SSDSYNC --drop-cache --io-backend mmap $F1 $F2 2>&1 | grep -q "doesn't work with --io-backend mmap"; then
+    echo "OK: --drop-cache is refused with a mapped pair"
+else
+    echo "FAILED: --drop-cache was taken with a mapped pair"
+    exit 1
+fi
+
+# Cloning a disk with a GPT onto a larger one, with new GUIDs
+
+if command -v python3 > /dev/null; then
+    # Makes a disk of 512 byte sectors with two partitions, checks that a
+    # clone of it has a GPT at both ends with new GUIDs, or breaks one
+    cat > $TESTPATH/gpt.py <<'EOF'
+import os, struct, sys, zlib
+S = 512
+def header(disk, lba):
+    h = bytearray(disk[lba * S:lba * S + 92])
+    crc = struct.unpack_from('<I', h, 16)[0]
+    struct.pack_into('<I', h, 16, 0)
+    assert h[:8] == b'EFI PART' and zlib.crc32(h) == crc, 'bad header at %d' % lba
+    return h
+def seal(h):
+    struct.pack_into('<I', h, 16, 0)
+    struct.pack_into('<I', h, 16, zlib.crc32(h))
+if sys.argv[1] == 'make':
+    sectors = int(sys.argv[3])
+    entries = bytearray(128 * 128)
+    for i, (first, last) in enumerate([(34, 999), (1000, sectors - 34)]):
+        struct.pack_into('<16s16sQQ', entries, i * 128, os.urandom(16), os.urandom(16), first, last)
+    h = bytearray(92)
+    struct.pack_into('<8sIIIIQQQQ16sQIII', h, 0, b'EFI PART', 0x10000, 92, 0, 0, 1, sectors - 1,
+                     34, sectors - 34, os.urandom(16), 2, 128, 128, zlib.crc32(entries))
+    seal(h)
+    b = bytearray(h)
+    struct.pack_into('<QQ', b, 24, sectors - 1, 1)
+    struct.pack_into('<Q', b, 72, sectors - 33)
+    seal(b)
+    disk = bytearray(os.urandom(sectors * S))
+    disk[S:2 * S] = h + bytes(S - 92)
+    disk[2 * S:34 * S] = entries
+    disk[(sectors - 33) * S:(sectors - 1) * S] = entries
+    disk[(sectors - 1) * S:] = b + bytes(S - 92)
+    open(sys.argv[2], 'wb').write(disk)
+elif sys.argv[1] == 'check':
+    source, clone = open(sys.argv[2], 'rb').read(), open(sys.argv[3], 'rb').read()
+    last = len(clone) // S - 1
+    old, p, b = header(source, 1), header(clone, 1), header(clone, last)
+    assert struct.unpack_from('<QQ', p, 24) == (1, last) and struct.unpack_from('<QQ', b, 24) == (last, 1)
+    assert struct.unpack_from('<Q', p, 48)[0] == last - 33 and struct.unpack_from('<Q', b, 72)[0] == last - 32
+    assert p[56:72] == b[56:72] != old[56:72], 'disk GUID'
+    entries = clone[2 * S:34 * S]
+    assert entries == clone[(last - 32) * S:last * S]
+    for i in range(2):
+        e, o = entries[i * 128:i * 128 + 128], source[2 * S + i * 128:2 * S + i * 128 + 128]
+        assert e[:16] == o[:16] and e[32:] == o[32:] and e[16:32] != o[16:32], 'partition GUID'
+    assert clone[len(source) - S:len(source) - S + 8] != b'EFI PART', 'stale backup'
+else:
+    disk = bytearray(open(sys.argv[2], 'rb').read())
+    h = bytearray(disk[S:S + 92])
+    struct.pack_into('<I', h, 84, 0)
+    seal(h)
+    disk[S:S + 92] = h
+    open(sys.argv[2], 'wb').write(disk)
+EOF
+    python3 $TESTPATH/gpt.py make $F1 2048
+    dd if=/dev/urandom of=$F2 bs=512 count=4096
+
+    $SSDSYNC clone --new-guid -b 4096 $F1 $F2 > /dev/null
+
+    if python3 $TESTPATH/gpt.py check $F1 $F2; then
+        echo "OK: a larger clone has its backup GPT at its end, with new GUIDs"
+    else
+        echo "FAILED: the GPT of the clone is wrong"
+        exit 1
+    fi
+
+    python3 $TESTPATH/gpt.py break $F1
+    if $SSDSYNC clone --new-guid -b 4096 $F1 $F2 2>&1 | grep -q "Bad GPT partition entry size 0"; then
+        echo "OK: a GPT with entries of no size is refused"
+    else
+        echo "FAILED: a GPT with entries of no size was taken"
+        exit 1
+    fi
+fi
+
+# The source's hash is checked over all of it, a source longer than the
+# target too
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=6
+HASH=$(sha256sum $F1 | cut -d' ' -f1)
+
+if $SSDSYNC -b 1000 --expect-source-hash $HASH:sha256 $F1 $F2 | grep -q "hash verified"; then
+    echo "OK: the hash of a source longer than the target is verified"
+else
+    echo "FAILED: the hash of a source longer than the target didn't match"
+    exit 1
+fi
+
+if $SSDSYNC -b 1000 --expect-source-hash $HASH:sha256 - $F2 < $F1 | grep -q "hash verified"; then
+    echo "OK: the hash of a piped source longer than the target is verified"
+else
+    echo "FAILED: the hash of a piped source longer than the target didn't match"
+    exit 1
+fi
+
+dd if=/dev/urandom of=$F1 bs=1000 count=1 seek=8 conv=notrunc
+if $SSDSYNC -b 1000 --expect-source-hash $HASH:sha256 $F1 $F2 > /dev/null 2>&1; then
+    echo "FAILED: a source changed past the end of the target passed its hash"
+    exit 1
+else
+    echo "OK: a source changed past the end of the target fails its hash"
+fi
+
+# Zero blocks on both sides are taken a run at a time, even where they're
+# written out and not holes
+
+dd if=/dev/zero of=$F1 bs=1000 count=3000
+dd if=/dev/zero of=$F2 bs=1000 count=3000
+dd if=/dev/urandom of=$F1 bs=1000 count=5 seek=1200 conv=notrunc
+dd if=/dev/urandom of=$F2 bs=1000 count=40 seek=2100 conv=notrunc
+rm -f $TESTPATH/log
+
+$SSDSYNC -b 1000 -vvv --log-file $TESTPATH/log $F1 $F2
+
+assert_eq $F1 $F2
+
+if [ "$(grep -c 'Read side="target"' $TESTPATH/log)" -lt 1000 ]; then
+    echo "OK: runs of zero blocks are handed on as one"
+else
+    echo "FAILED: runs of zero blocks were handed on block by block"
+    exit 1
+fi
+
+# A deviating block is reported from its first differing byte on
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+printf 'x' | dd of=$F2 bs=1 seek=2345 conv=notrunc
+
+if $SSDSYNC -b 1000 --reference target $F1 $F2 | grep -q "Source deviates at 2345 (655 bytes)"; then
+    echo "OK: a deviation is reported where it starts"
+else
+    echo "FAILED: a deviation wasn't reported where it starts"
+    exit 1
+fi
+
+# Exit statuses tell what kind of failure it was
+
+for expected in "3 $TESTPATH/missing $F2" "2 --buffers 8 --queue-depth 4 $F1 $F2" \
+    "4 --write-manifest /dev/full $F1 $F2"; do
+    set -- $expected
+    STATUS_WANTED=$1
+    shift
+    $SSDSYNC "$@"
+    STATUS=$?
+    if [ $STATUS == $STATUS_WANTED ]; then
+        echo "OK: exit status $STATUS for $*"
+    else
+        echo "FAILED: exit status $STATUS instead of $STATUS_WANTED for $*"
+        exit 1
+    fi
+done
+
+# SIGTERM in the middle stops the sync with what it wrote so far intact
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+dd if=/dev/urandom of=$F2 bs=1000 count=100
+
+(for i in $(seq 0 99); do dd if=$F1 bs=1000 skip=$i count=1; sleep 0.02; done) |
+    $SSDSYNC -b 1000 /dev/stdin $F2 > $TESTPATH/out &
+sleep 1
+kill -TERM $!
+wait $!
+STATUS=$?
+
+STOPPED=$(grep -o 'Stopped at [0-9]*' $TESTPATH/out | grep -o '[0-9]*$')
+if [ $STATUS == 130 ] && [ -n "$STOPPED" ] && [ "$STOPPED" -lt 100000 ]; then
+    echo "OK: interrupted sync stopped at $STOPPED with status 130"
+else
+    echo "FAILED: interrupted sync exited with $STATUS, stopped at '$STOPPED'"
+    exit 1
+fi
+
+head -c $STOPPED $F1 > $F3
+head -c $STOPPED $F2 > $TESTPATH/f2-head
+
+assert_eq $F3 $TESTPATH/f2-head
+
+# Sparse image of the differences, applied afterwards
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/zero of=$F2 bs=1000 seek=2 count=2 conv=notrunc
+dd if=/dev/zero of=$F2 bs=1000 seek=7 count=1 conv=notrunc
+cp $F2 $F3
+
+$SSDSYNC -b 1000 --sparse-image-out $TESTPATH/image $F1 $F2
+
+assert_eq $F2 $F3
+
+$SSDSYNC --apply-sparse-image $TESTPATH/image $F2
+
+assert_eq $F1 $F2
+
+# Tar of the differing regions, the target is left alone
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/zero of=$F2 bs=1000 seek=3 count=2 conv=notrunc
+cp $F2 $F3
+
+$SSDSYNC -b 1000 --oci-out $TESTPATH/delta.tar $F1 $F2
+
+assert_eq $F2 $F3
+
+mkdir -p $TESTPATH/delta
+tar -xf $TESTPATH/delta.tar -C $TESTPATH/delta
+dd if=$TESTPATH/delta/blocks/00000000000000003000 of=$F2 bs=1000 seek=3 conv=notrunc
+
+assert_eq $F1 $F2
+
+# Preflight reports problems without touching the target
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+cp $F2 $F3
+
+if $SSDSYNC --preflight --journal $TESTPATH/nonexistent/journal --compare words:3 $F1 $F2; then
+    echo "FAILED: preflight found no problems"
+    exit 1
+else
+    echo "OK: preflight found problems"
+fi
+
+assert_eq $F2 $F3
+
+# Asserted target bytes, a mismatch stops the sync before any write
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+printf 'SIG1' | dd of=$F2 bs=1 seek=512 conv=notrunc
+cp $F2 $F3
+
+if $SSDSYNC --assert-region 512:53494732 $F1 $F2; then
+    echo "FAILED: sync went ahead with the wrong signature"
+    exit 1
+else
+    echo "OK: sync stopped on the wrong signature"
+fi
+
+assert_eq $F2 $F3
+
+$SSDSYNC --assert-region 512:53494731 $F1 $F2
+
+assert_eq $F1 $F2
+
+# Required target type, a regular file isn't taken for a device
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F2 $F3
+
+if $SSDSYNC --require-block-device $F1 $F2; then
+    echo "FAILED: synced onto a regular file as a block device"
+    exit 1
+else
+    echo "OK: regular file refused as a block device"
+fi
+
+assert_eq $F2 $F3
+
+$SSDSYNC --require-regular-file $F1 $F2
+
+assert_eq $F1 $F2
+
+# Diff histogram, only the bucket with the change has a bar
+
+dd if=/dev/urandom of=$F1 bs=1000 count=100
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 seek=42 count=1 conv=notrunc
+
+BARS=$($SSDSYNC --diff-histogram -b 1000 $F1 $F2 | grep -c '|#')
+if [ "$BARS" = 1 ]; then
+    echo "OK: one bucket in the histogram differs"
+else
+    echo "FAILED: $BARS buckets in the histogram differ"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# Dry run, the target is only compared
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+dd if=/dev/urandom of=$F2 bs=1000 seek=3 count=2 conv=notrunc
+cp $F2 $F3
+
+if $SSDSYNC --dry-run -b 1000 $F1 $F2 | grep -q "different: 2, would write: 2000 bytes"; then
+    echo "OK: dry run found 2 blocks to write"
+else
+    echo "FAILED: dry run didn't find 2 blocks to write"
+    exit 1
+fi
+
+assert_eq $F2 $F3
+
+# Adaptive block size: a byte changed near the start of 4 blocks in a row
+# is written in a half, a quarter, an eighth of a block and so on
+
+dd if=/dev/urandom of=$F1 bs=65536 count=8
+cp $F1 $F2
+for block in 0 1 2 3; do
+    printf 'x' | dd of=$F2 bs=1 seek=$((block * 65536 + 100)) conv=notrunc
+done
+
+if $SSDSYNC --adaptive 4K -b 64K $F1 $F2 | grep -q "different: 4, written: 122880 bytes"; then
+    echo "OK: adaptive sync wrote 122880 bytes"
+else
+    echo "FAILED: adaptive sync didn't write 122880 bytes"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+
+# With a block size that isn't a power of two the grain stays a multiple of
+# the words compared: after the first block it's a word, not half a block,
+# so the second word of the second block, the same in its first half only,
+# is written too
+
+python3 -c '
+import sys
+source = bytes(range(1, 49))
+target = bytes(24) + source[24:32][::-1] + source[32:]
+open(sys.argv[1], "wb").write(source)
+open(sys.argv[2], "wb").write(target)
+' $F1 $F2
+
+if $SSDSYNC --adaptive 8 -b 24 --compare words:8:swap $F1 $F2 | grep -q "written: 40 bytes"; then
+    echo "OK: adaptive pieces of whole words"
+else
+    echo "FAILED: adaptive pieces cut words in two"
+    exit 1
+fi
+
+# Verify after the sync, the target matches what was written
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+
+$SSDSYNC --verify-after --repair $F1 $F2
+
+assert_eq $F1 $F2
+
+# Segmented source, assembled from parts with zeroes in the gaps
+
+dd if=/dev/urandom of=$F3 bs=1000 count=3
+dd if=/dev/urandom of=$F4 bs=1000 count=2
+printf "0 3000 $F3\n5000 4000 $F4\n" > $TESTPATH/segments
+dd if=/dev/urandom of=$F2 bs=1000 count=9
+
+$SSDSYNC -b 1000 segments:$TESTPATH/segments $F2
+
+dd if=/dev/zero of=$F1 bs=1000 count=9
+dd if=$F3 of=$F1 conv=notrunc
+dd if=$F4 of=$F1 bs=1000 seek=5 conv=notrunc
+
+assert_eq $F1 $F2
+
+# Batch of pairs, a failed one doesn't stop the rest
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+dd if=/dev/urandom of=$F3 bs=1000 count=10
+dd if=/dev/urandom of=$F4 bs=1000 count=10
+printf "$F1 $F2\n$TESTPATH/missing $F2\n$F3 $F4\n" > $TESTPATH/pairs
+
+if $SSDSYNC batch $TESTPATH/pairs -- -b 1000; then
+    echo "FAILED: batch with a missing source succeeded"
+    exit 1
+else
+    echo "OK: batch with a missing source failed"
+fi
+
+assert_eq $F1 $F2
+assert_eq $F3 $F4
+
+dd if=/dev/urandom of=$F2 bs=1000 count=3 conv=notrunc
+dd if=/dev/urandom of=$F4 bs=1000 count=2 conv=notrunc
+printf "$F1 $F2\n$F3 $F4\n" > $TESTPATH/pairs
+
+if $SSDSYNC batch $TESTPATH/pairs -- -b 1000 --json | grep -q "Batch finished. Pairs: 2, failed: 0, written: 5000 bytes"; then
+    echo "OK: batch counts what was written with --json"
+else
+    echo "FAILED: batch miscounted what was written with --json"
+    exit 1
+fi
+
+assert_eq $F1 $F2
+assert_eq $F3 $F4
+
+# Line endings don't matter with text:normalize-eol, other changes do
+
+printf 'one\r\ntwo\n' > $F1
+printf 'one\ntwo\r\n' > $F2
+cp $F2 $F3
+
+$SSDSYNC --compare text:normalize-eol $F1 $F2
+
+assert_eq $F2 $F3
+
+printf 'one\r\nTwo\n' > $F1
+
+$SSDSYNC --compare text:normalize-eol $F1 $F2
+
+assert_ne $F2 $F3
+
+# Reflinks fall back to copying where the filesystem has none
+
+dd if=/dev/urandom of=$F1 bs=4096 count=10
+dd if=/dev/urandom of=$F2 bs=4096 count=10
+
+$SSDSYNC -b 4096 --reflink $F1 $F2
+
+assert_eq $F1 $F2
+
+# Byte-swapped words compare equal with words:2:swap
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=$F1 of=$F2 conv=swab
+
+if $SSDSYNC -b 1000 --reference target --compare words:2:swap $F1 $F2; then
+    echo "OK: $F1 == $F2 swapped"
+else
+    echo "FAILED: $F1 != $F2 swapped"
+    exit 1
+fi
+
+# Overlay source, a sparse delta merged onto a base image
+
+dd if=/dev/urandom of=$F1 bs=4096 count=25
+dd if=/dev/urandom of=$F3 bs=4096 count=1
+rm -f $F4
+dd if=$F3 of=$F4 bs=4096 seek=10 conv=notrunc
+truncate -s 102400 $F4
+dd if=/dev/zero of=$F2 bs=4096 count=25
+
+$SSDSYNC -b 1000 overlay:$F1:$F4 $F2
+
+dd if=$F3 of=$F1 bs=4096 seek=10 conv=notrunc
+
+assert_eq $F1 $F2
+
+# Verifying a target against a manifest of the source, without the source
+
+M=$TESTPATH/manifest
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+cp $F1 $F2
+echo "# ssdsync manifest sha256 1000" > $M
+for i in $(seq 0 9); do
+    echo "$((i * 1000)) 1000 $(dd if=$F1 bs=1000 skip=$i count=1 2>/dev/null | sha256sum | cut -d' ' -f1)" >> $M
+done
+
+if $SSDSYNC verify --source-manifest $M $F2; then
+    echo "OK: $F2 matches $M"
+else
+    echo "FAILED: $F2 doesn't match $M"
+    exit 1
+fi
+
+dd if=/dev/zero of=$F2 bs=1000 seek=4 count=1 conv=notrunc
+
+if $SSDSYNC verify --source-manifest $M $F2; then
+    echo "FAILED: $F2 matches $M"
+    exit 1
+else
+    echo "OK: $F2 doesn't match $M"
+fi
+
+# Resuming skips what the checkpoint says was checked already
+
+printf '# ssdsync checkpoint\nnext 5000\n' > $TESTPATH/checkpoint
+
+if $SSDSYNC verify --source-manifest $M --checkpoint $TESTPATH/checkpoint --resume $F2; then
+    echo "OK: $F2 matches $M from 5000 on"
+else
+    echo "FAILED: $F2 doesn't match $M from 5000 on"
+    exit 1
+fi
+
+# A manifest written while syncing matches the target afterwards
+
+dd if=/dev/urandom of=$F1 bs=1000 count=10
+dd if=/dev/urandom of=$F2 bs=1000 count=10
+
+$SSDSYNC -b 1000 --write-manifest $M $F1 $F2
+
+assert_eq $F1 $F2
+
+if $SSDSYNC verify --source-manifest $M $F2; then
+    echo "OK: $F2 matches the written $M"
+else
+    echo "FAILED: $F2 doesn't match the written $M"
+    exit 1
+fi
+
 rm -rf $TESTPATH
@@ -0,0 +1,196 @@
+// Property tests of the compare loop: random sources, targets derived from
+// them with a few changes, and random block sizes, synced with a SyncEngine.
+
+use {
+    proptest::prelude::*,
+    ssdsync::{Error, Summary, SyncEngine},
+    std::path::PathBuf,
+};
+
+#[derive(Debug)]
+struct Case {
+    source: Vec<u8>,
+    target: Vec<u8>,
+    block_size: usize,
+}
+
+// A target is mostly the source, so that most blocks match: cut or
+// extended to another length, with some bytes changed
+fn case() -> impl Strategy<Value = Case> {
+    (
+        prop::collection::vec(any::<u8>(), 0..20000),
+        0..20000usize,
+        prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..8),
+        1..5000usize,
+    )
+        .prop_map(|(source, target_len, changes, block_size)| {
+            let mut target = source.clone();
+            target.resize(target_len, 0xaa);
+            if !target.is_empty() {
+                for (index, value) in changes {
+                    let i = index.index(target.len());
+                    target[i] = value;
+                }
+            }
+            Case {
+                source,
+                target,
+                block_size,
+            }
+        })
+}
+
+// The same from sources that are mostly runs of zeroes, like raw images,
+// with zeroes beyond the end of the source on the target. Zero blocks take
+// their own way through the compare loop.
+fn zero_case() -> impl Strategy<Value = Case> {
+    (
+        prop::collection::vec((0..3000usize, any::<bool>()), 0..12),
+        0..20000usize,
+        prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..8),
+        1..5000usize,
+    )
+        .prop_map(|(runs, target_len, changes, block_size)| {
+            let mut source = Vec::new();
+            for (length, zero) in runs {
+                let start = source.len();
+                source.resize(start + length, 0);
+                if !zero {
+                    for (i, b) in source[start..].iter_mut().enumerate() {
+                        *b = (i % 251) as u8 + 1;
+                    }
+                }
+            }
+            let mut target = source.clone();
+            target.resize(target_len, 0);
+            if !target.is_empty() {
+                for (index, value) in changes {
+                    let i = index.index(target.len());
+                    target[i] = value;
+                }
+            }
+            Case {
+                source,
+                target,
+                block_size,
+            }
+        })
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ssdsync-proptest-{}-{}", std::process::id(), name))
+}
+
+// Blocks are clamped to the synced size, like a sync does
+fn block_size(case: &Case, n: usize) -> usize {
+    if case.block_size > n {
+        std::cmp::max(n, 1)
+    } else {
+        case.block_size
+    }
+}
+
+// The files of a case, written out for `engine` to sync, and what came of
+// it with the target afterwards. The tests run in parallel, `name` keeps
+// their files apart.
+fn run_case(
+    name: &str,
+    case: &Case,
+    engine: impl FnOnce(SyncEngine) -> SyncEngine,
+) -> (Result<Summary, Error>, Vec<u8>) {
+    let source = temp_path(&format!("{}-source", name));
+    let target = temp_path(&format!("{}-target", name));
+    std::fs::write(&source, &case.source).unwrap();
+    std::fs::write(&target, &case.target).unwrap();
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(
+        engine(
+            SyncEngine::new(source.to_str().unwrap(), target.to_str().unwrap())
+                .block_size(case.block_size),
+        )
+        .run(),
+    );
+    let written = std::fs::read(&target).unwrap();
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&target);
+    (result, written)
+}
+
+fn sync_case(name: &str, case: Case) -> Result<(), TestCaseError> {
+    let (result, written) = run_case(name, &case, |engine| engine);
+    let summary = result.map_err(|e| TestCaseError::fail(e.to_string()))?;
+
+    // Only what both sides have is synced, the rest of the target stays
+    let n = std::cmp::min(case.source.len(), case.target.len());
+    let mut expected = case.source[..n].to_vec();
+    expected.extend_from_slice(&case.target[n..]);
+    prop_assert_eq!(&written, &expected);
+
+    let block_size = block_size(&case, n);
+    let mut different = 0;
+    let mut bytes = 0;
+    for start in (0..n).step_by(block_size) {
+        let end = std::cmp::min(start + block_size, n);
+        if case.source[start..end] != case.target[start..end] {
+            different += 1;
+            bytes += (end - start) as u64;
+        }
+    }
+    prop_assert_eq!(summary.different, different);
+    prop_assert_eq!(summary.written, bytes);
+    Ok(())
+}
+
+// Checked against the target as the reference, every differing block is
+// counted as deviating and the target is left as it was
+fn validate_case(name: &str, case: Case) -> Result<(), TestCaseError> {
+    let (result, written) = run_case(name, &case, SyncEngine::validate);
+    prop_assert_eq!(&written, &case.target);
+
+    let n = std::cmp::min(case.source.len(), case.target.len());
+    let block_size = block_size(&case, n);
+    let blocks = (0..n).step_by(block_size);
+    let total = blocks.len() as u64;
+    let mut different = 0;
+    for start in blocks {
+        let end = std::cmp::min(start + block_size, n);
+        if case.source[start..end] != case.target[start..end] {
+            different += 1;
+        }
+    }
+    match result {
+        Ok(summary) => {
+            prop_assert_eq!(different, 0);
+            prop_assert_eq!(summary.written, 0);
+        }
+        Err(Error::Deviates {
+            diff,
+            total: compared,
+        }) => {
+            prop_assert_eq!(diff, different);
+            prop_assert_eq!(compared, total);
+        }
+        Err(e) => return Err(TestCaseError::fail(e.to_string())),
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn target_ends_up_as_source(case in case()) {
+        sync_case("random", case)?;
+    }
+
+    #[test]
+    fn zero_runs_end_up_as_source(case in zero_case()) {
+        sync_case("zeroes", case)?;
+    }
+
+    #[test]
+    fn deviating_blocks_are_counted(case in case()) {
+        validate_case("validate", case)?;
+    }
+}
@@ -0,0 +1,165 @@
+// The library interface: a sync run with a SyncEngine instead of the binary
+
+use {
+    async_trait::async_trait,
+    ssdsync::{BlockSource, Error, Segment, SyncEngine},
+    std::{
+        io,
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    },
+};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("ssdsync-engine-{}-{}", std::process::id(), name))
+}
+
+// A source and a target of 10 blocks of 1000 bytes, differing in two
+fn files(name: &str) -> (PathBuf, PathBuf) {
+    let source: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut target = source.clone();
+    target[1500] ^= 1;
+    target[9999] ^= 1;
+    let paths = (
+        temp_path(&format!("{}-source", name)),
+        temp_path(&format!("{}-target", name)),
+    );
+    std::fs::write(&paths.0, source).unwrap();
+    std::fs::write(&paths.1, target).unwrap();
+    paths
+}
+
+// A segment's content, held in memory
+struct Bytes(Vec<u8>, usize);
+
+#[async_trait]
+impl BlockSource for Bytes {
+    async fn size(&mut self) -> Option<u64> {
+        Some(self.0.len() as u64)
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = std::cmp::min(buf.len(), self.0.len() - self.1);
+        buf[..n].copy_from_slice(&self.0[self.1..self.1 + n]);
+        self.1 += n;
+        Ok(n)
+    }
+}
+
+#[tokio::test]
+async fn syncs_and_reports_progress() {
+    let (source, target) = files("sync");
+    let done = Arc::new(AtomicU64::new(0));
+    let progress = done.clone();
+
+    let summary = SyncEngine::new(source.to_str().unwrap(), target.to_str().unwrap())
+        .block_size(1000)
+        .buffers(2)
+        .on_progress(move |pos, _| progress.store(pos, Ordering::Relaxed))
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read(&source).unwrap(),
+        std::fs::read(&target).unwrap()
+    );
+    assert_eq!(
+        (summary.blocks, summary.different, summary.written),
+        (10, 2, 2000)
+    );
+    assert_eq!(done.load(Ordering::Relaxed), 10000);
+    let _ = std::fs::remove_file(source);
+    let _ = std::fs::remove_file(target);
+}
+
+#[tokio::test]
+async fn cancelled_before_the_start_writes_nothing() {
+    let (source, target) = files("cancel");
+    let before = std::fs::read(&target).unwrap();
+
+    let result = SyncEngine::new(source.to_str().unwrap(), target.to_str().unwrap())
+        .block_size(1000)
+        .cancel_on(Arc::new(AtomicBool::new(true)))
+        .run()
+        .await;
+
+    assert!(matches!(result, Err(Error::Cancelled)));
+    assert_eq!(std::fs::read(&target).unwrap(), before);
+    let _ = std::fs::remove_file(source);
+    let _ = std::fs::remove_file(target);
+}
+
+#[tokio::test]
+async fn messages_go_to_the_callback() {
+    let (source, target) = files("messages");
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let said = messages.clone();
+
+    SyncEngine::new(source.to_str().unwrap(), target.to_str().unwrap())
+        .block_size(1000)
+        .on_message(move |message| said.lock().unwrap().push(message.to_string()))
+        .run()
+        .await
+        .unwrap();
+
+    let messages = messages.lock().unwrap();
+    assert_eq!(messages[0], "10000 -> 10000");
+    assert!(messages
+        .iter()
+        .any(|m| m == "Finished. Total: 10, different: 2, written: 2000 bytes"));
+    let _ = std::fs::remove_file(source);
+    let _ = std::fs::remove_file(target);
+}
+
+#[tokio::test]
+async fn segments_are_synced_on_their_own() {
+    let (source, target) = files("segments");
+    let before = std::fs::read(&target).unwrap();
+    let mut first = before[1000..3000].to_vec();
+    first[1200] ^= 1;
+    let second = before[5000..8000].to_vec();
+    let segment = |offset, data: Vec<u8>| Segment {
+        offset,
+        length: data.len() as u64,
+        source: Box::new(Bytes(data, 0)),
+    };
+
+    let summaries = SyncEngine::onto(target.to_str().unwrap())
+        .block_size(1000)
+        .run_segments(vec![segment(5000, second), segment(1000, first.clone())])
+        .await
+        .unwrap();
+
+    let counts: Vec<_> = summaries
+        .iter()
+        .map(|s| (s.blocks, s.different, s.written))
+        .collect();
+    assert_eq!(counts, [(2, 1, 1000), (3, 0, 0)]);
+    let mut expected = before;
+    expected[1000..3000].copy_from_slice(&first);
+    assert_eq!(std::fs::read(&target).unwrap(), expected);
+    let _ = std::fs::remove_file(source);
+    let _ = std::fs::remove_file(target);
+}
+
+#[tokio::test]
+async fn overlapping_segments_are_refused() {
+    let (source, target) = files("overlap");
+    let segment = |offset| Segment {
+        offset,
+        length: 2000,
+        source: Box::new(Bytes(vec![0; 2000], 0)),
+    };
+
+    let result = SyncEngine::onto(target.to_str().unwrap())
+        .run_segments(vec![segment(0), segment(1000)])
+        .await;
+
+    assert!(matches!(result, Err(Error::Usage(_))));
+    let _ = std::fs::remove_file(source);
+    let _ = std::fs::remove_file(target);
+}